@@ -1,234 +1,2114 @@
-use std::collections::{HashMap, HashSet};
+mod kafka;
+mod prometheus;
+mod quorum;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use parking_lot::RwLock;
+use tokio::sync::{Mutex, Notify};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use serde::{Serialize, Deserialize};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::tcp::OwnedWriteHalf;
 use uuid::Uuid;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use rafka_core::message::{AckStatus, BrokerError, ErrorCode, Leader, MessageAck};
+use rafka_core::partitioner::{PartitionStrategy, Partitioner};
+use rafka_protocol::codec::split_message;
+use rafka_protocol::{
+    AclBinding, Acks, ApiKey, ApiVersionRange, ApiVersionsResponse, BrokerMessage, IDEMPOTENCE, BrokerResponse, ClientSession, Connection,
+    BrokerMetadata, ConnectionError, EncodedBatch, FetchResponse, FrameError, MetadataResponse, ConsumerLag, MetricsResponse, PartitionStats,
+    PartitionDescription, PartitionMetadata, TopicConfig, TopicDescription, TopicMetadata, FrameReader, FrameWriter, Record, RecordBatch, RecordsResponse, Request,
+    RequestHeader, Response, IsolationLevel, MetadataRecord, ProducerIdentity, TopicPartitions, TxnOffset,
+};
+use rafka_storage::db::{BatchKind, RetentionPolicy, Storage, StoredMessage};
 
-// Message types to replace gRPC messages
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum BrokerMessage {
-    Publish {
-        key: String,
-        topic: String,
-        payload: Vec<u8>,
-    },
-    Subscribe {
-        consumer_id: String,
-        topic: String,
-    },
-    Consume {
-        consumer_id: String,
-    },
-    Register {
-        client_id: String,
-        client_type: String,
-    },
-    UpdateOffset {
-        consumer_id: String,
-        topic: String,
-        offset: i64,
-    },
-    GetMetrics,
+use crate::coordinator::{GroupCoordinator, JoinRequest};
+use crate::credit::CreditAccount;
+use crate::idempotence::{Appended, ProducerStates};
+use crate::metrics::Metrics;
+use crate::raft::Raft;
+use crate::registry::ClientRegistry;
+use crate::replication::ReplicaTracker;
+use crate::shutdown::{Shutdown, ShutdownHandle};
+use crate::transactions::{Transaction, TransactionCoordinator};
+
+// How long shutdown waits for connections to finish what they are doing
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+// Followers that haven't caught up with the leader for this long are no
+// longer in sync
+const DEFAULT_REPLICA_LAG_TIME: Duration = Duration::from_secs(10);
+// How long a follower waits before fetching again when there was nothing new
+const REPLICA_FETCH_INTERVAL: Duration = Duration::from_millis(20);
+const REPLICA_FETCH_BYTES: u32 = 1024 * 1024;
+// How long a controller goes without hearing from a quorum leader before it
+// stands for election, give or take as much again
+const DEFAULT_ELECTION_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_METADATA_SNAPSHOT_ENTRIES: usize = 1000;
+
+// Partition p of a topic is led by broker p % broker_count, so a broker
+// hosts any number of partitions, each with its own offsets. With a
+// replication factor above one the brokers after it keep copies, see
+// assign_replicas. Where each partition lives is fixed when its topic is
+// created, along with the rest of the topic's metadata.
+pub struct Broker {
+    topics: Arc<RwLock<HashMap<String, TopicState>>>,
+    broker_id: u32,
+    broker_count: u32,
+    // Partition count of topics created by publishing or subscribing to them
+    default_partitions: u32,
+    // Create unknown topics on first use instead of answering UnknownTopic
+    auto_create_topics: bool,
+    // Every published batch, consumers read from here
+    storage: Arc<Storage>,
+    // Wakes consumers and Kafka fetches that are waiting for new records
+    appended: Notify,
+    coordinator: GroupCoordinator,
+    // Where this broker serves, once it does
+    addr: Option<String>,
+    // Flow control of each consumer's current Consume
+    credits: RwLock<HashMap<String, Arc<CreditAccount>>>,
+    kafka_addr: Option<String>,
+    metrics: Metrics,
+    clients: ClientRegistry,
+    producers: ProducerStates,
+    // Transactions of the producers registered here
+    transactions: TransactionCoordinator,
+    shutdown: Arc<Shutdown>,
+    // Where Prometheus can scrape /metrics, if anywhere
+    metrics_addr: Option<String>,
+    // Address of every broker in the cluster by broker id, empty if unknown
+    peers: Vec<String>,
+    // Proxy publishes for partitions hosted elsewhere instead of answering NotLeader
+    forwarding: bool,
+    peer_connections: Mutex<HashMap<u32, Arc<Connection>>>,
+    // How many brokers keep each partition, the leader included
+    replication_factor: u32,
+    // Followers of the partitions this broker leads
+    replication: ReplicaTracker,
+    // Brokers voting on metadata changes. With none every broker keeps its
+    // own metadata, with some it is shared through their Raft log.
+    controllers: Vec<u32>,
+    election_timeout: Duration,
+    // Where the metadata log is kept across restarts, if anywhere
+    metadata_dir: Option<PathBuf>,
+    // Log entries kept before the metadata is snapshotted instead
+    metadata_snapshot_entries: usize,
+    // Set up by serve when there are controllers
+    quorum: Option<quorum::Quorum>,
+    acls: RwLock<Vec<AclBinding>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ConsumeResponse {
-    message_id: String,
-    topic: String,
-    payload: Vec<u8>,
-    sent_at: i64,
-    offset: i64,
+struct TopicState {
+    partitions: u32,
+    // The brokers keeping each partition, its leader first
+    replicas: Vec<Vec<u32>>,
+    strategy: PartitionStrategy,
+    partitioner: Arc<dyn Partitioner>,
+    config: TopicConfig,
+    // Consumers subscribed to the topic
+    consumers: HashSet<String>,
 }
 
-pub struct Broker {
-    topics: Arc<RwLock<HashMap<String, HashSet<String>>>>,
-    messages: Arc<RwLock<HashMap<u32, broadcast::Sender<ConsumeResponse>>>>,
-    message_counter: AtomicUsize,
-    broadcast_capacity: usize,
-    partition_id: u32,
-    total_partitions: u32,
-    consumer_offsets: Arc<RwLock<HashMap<(String, String), i64>>>,
+// What a Consume asked to be streamed, see deliver
+struct ConsumeRequest {
+    consumer_id: String,
+    topics: Vec<String>,
+    group_id: Option<String>,
+    isolation: IsolationLevel,
+}
+
+// Where a produce got to on this broker
+enum Produced {
+    Done(BrokerResponse),
+    // Appended, but acks=All holds the ack back until every in-sync replica
+    // has the log up to `end`
+    Replicating { ack: MessageAck, end: i64, timeout: Duration },
+}
+
+// The other end of a client connection
+struct Peer {
+    addr: String,
+    connected_at_ms: i64,
 }
 
 impl Broker {
-    pub fn new(partition_id: u32, total_partitions: u32) -> Self {
-        const BROADCAST_CAPACITY: usize = 1024 * 16;
-        
+    // Topics get one partition per broker unless told otherwise. A broker
+    // count of 0 is taken as 1, there is always at least this broker.
+    pub fn new(broker_id: u32, broker_count: u32, retention_policy: Option<RetentionPolicy>) -> Self {
+        let broker_count = broker_count.max(1);
         Self {
             topics: Arc::new(RwLock::new(HashMap::new())),
-            messages: Arc::new(RwLock::new(HashMap::new())),
-            message_counter: AtomicUsize::new(0),
-            broadcast_capacity: BROADCAST_CAPACITY,
-            partition_id,
-            total_partitions,
-            consumer_offsets: Arc::new(RwLock::new(HashMap::new())),
+            broker_id,
+            broker_count,
+            default_partitions: broker_count,
+            auto_create_topics: true,
+            storage: Arc::new(Storage::with_retention_policy(retention_policy.unwrap_or_default())),
+            appended: Notify::new(),
+            coordinator: GroupCoordinator::new(),
+            addr: None,
+            credits: RwLock::new(HashMap::new()),
+            kafka_addr: None,
+            metrics: Metrics::new(),
+            clients: ClientRegistry::new(),
+            producers: ProducerStates::new(broker_id, broker_count),
+            transactions: TransactionCoordinator::new(),
+            shutdown: Arc::new(Shutdown::new()),
+            metrics_addr: None,
+            peers: Vec::new(),
+            forwarding: false,
+            peer_connections: Mutex::new(HashMap::new()),
+            replication_factor: 1,
+            replication: ReplicaTracker::new(DEFAULT_REPLICA_LAG_TIME),
+            controllers: Vec::new(),
+            election_timeout: DEFAULT_ELECTION_TIMEOUT,
+            metadata_dir: None,
+            metadata_snapshot_entries: DEFAULT_METADATA_SNAPSHOT_ENTRIES,
+            quorum: None,
+            acls: RwLock::new(Vec::new()),
         }
     }
 
+    // Lets NotLeader errors say where to go, and is needed for forwarding
+    pub fn with_peers(mut self, peers: Vec<String>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    pub fn with_forwarding(mut self) -> Self {
+        self.forwarding = true;
+        self
+    }
+
+    // Keep every partition on this many brokers, capped at the broker count.
+    // Followers copy from the leader, so the peers have to be known.
+    pub fn with_replication_factor(mut self, replication_factor: u32) -> Self {
+        self.replication_factor = replication_factor.max(1);
+        self
+    }
+
+    // How long a follower may fall behind before the leader stops waiting for it
+    pub fn with_replica_lag_time(mut self, lag_time: Duration) -> Self {
+        self.replication = ReplicaTracker::new(lag_time);
+        self
+    }
+
+    // Share topics, where their partitions live, their configs and ACLs
+    // through a Raft log these brokers vote on. Every broker of the cluster
+    // gets the same list, and the peers have to be known.
+    pub fn with_controllers(mut self, controllers: Vec<u32>) -> Self {
+        self.controllers = controllers;
+        self
+    }
+
+    pub fn with_election_timeout(mut self, election_timeout: Duration) -> Self {
+        self.election_timeout = election_timeout;
+        self
+    }
+
+    // Keep the metadata log in `dir`, so a restarted broker has it back
+    pub fn with_metadata_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.metadata_dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_metadata_snapshot_entries(mut self, entries: usize) -> Self {
+        self.metadata_snapshot_entries = entries.max(1);
+        self
+    }
+
+    // At least one, a topic without partitions can't take any records
+    pub fn with_default_partitions(mut self, partitions: u32) -> Self {
+        self.default_partitions = partitions.max(1);
+        self
+    }
+
+    // With this off topics only come from CreateTopic
+    pub fn with_auto_create_topics(mut self, enabled: bool) -> Self {
+        self.auto_create_topics = enabled;
+        self
+    }
+
+    // Also accept Kafka clients on `addr` once the broker is serving
+    pub fn with_kafka_listener(mut self, addr: &str) -> Self {
+        self.kafka_addr = Some(addr.to_string());
+        self
+    }
+
+    // Serve Prometheus metrics over HTTP on `addr` once the broker is serving
+    pub fn with_metrics_listener(mut self, addr: &str) -> Self {
+        self.metrics_addr = Some(addr.to_string());
+        self
+    }
+
+    // For stopping the broker once it is serving
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
     async fn handle_client(
         broker: Arc<Self>,
-        mut socket: TcpStream,
+        socket: TcpStream,
+        peer_addr: SocketAddr,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buffer = vec![0; 1024 * 64]; // 64KB buffer
+        let peer = Peer {
+            addr: peer_addr.to_string(),
+            connected_at_ms: Utc::now().timestamp_millis(),
+        };
+        let (reader, writer) = socket.into_split();
+        let mut reader = FrameReader::new(reader);
+        // Shared with the consume tasks spawned for this connection
+        let writer = Arc::new(Mutex::new(FrameWriter::new(writer)));
 
         loop {
-            let n = socket.read(&mut buffer).await?;
-            if n == 0 {
-                break; // Connection closed
+            // Requests already read get answered, the rest of the client's work
+            // will have to go to another broker. The notice goes out on no
+            // request's id, so idle clients hear about it too.
+            let frame = tokio::select! {
+                frame = reader.read_frame() => frame,
+                _ = broker.shutdown.requested() => {
+                    let notice = BrokerResponse::Error(Self::shutting_down());
+                    let _ = Self::reply(&writer, 0, notice).await;
+                    break;
+                }
+            };
+            let frame = match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break, // Connection closed
+                Err(e @ FrameError::FrameTooLarge { .. }) => {
+                    // The rest of the oversized frame is still in flight, so there is no way to resync
+                    let error = BrokerError::new(ErrorCode::FrameTooLarge, e.to_string());
+                    let _ = Self::reply(&writer, 0, BrokerResponse::Error(error)).await;
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let (Request { correlation_id, mut message }, data) = match split_message(&frame) {
+                Ok(request) => request,
+                Err(e) => {
+                    // Still answer on the right id if the envelope itself was readable
+                    let correlation_id = split_message::<RequestHeader>(&frame)
+                        .map(|(header, _)| header.correlation_id)
+                        .unwrap_or(0);
+                    let error = BrokerError::new(ErrorCode::MalformedRequest, e.to_string());
+                    Self::reply(&writer, correlation_id, BrokerResponse::Error(error)).await?;
+                    continue;
+                }
+            };
+            message.attach(data);
+
+            // Consume never finishes and Fetch times itself once it is answered
+            let api_key = message.api_key();
+            let started = Instant::now();
+            Self::handle_request(&broker, &writer, &peer, correlation_id, message).await?;
+            if !matches!(api_key, ApiKey::Consume | ApiKey::Fetch) {
+                broker.metrics.observe_request(api_key, started.elapsed());
             }
+        }
 
-            let message: BrokerMessage = serde_json::from_slice(&buffer[..n])?;
-            
-            match message {
-                BrokerMessage::Publish { key, topic, payload } => {
-                    if !broker.owns_partition(&key) {
-                        let error = format!(
-                            "Message belongs to partition {} not {}", 
-                            broker.hash_key(&key) % broker.total_partitions,
-                            broker.partition_id
-                        );
-                        socket.write_all(error.as_bytes()).await?;
-                        continue;
-                    }
+        Ok(())
+    }
 
-                    broker.ensure_topic(&topic).await;
-                    
-                    let message_id = Uuid::new_v4().to_string();
-                    let offset = broker.message_counter.fetch_add(1, Ordering::SeqCst) as i64;
+    async fn handle_request(
+        broker: &Arc<Self>,
+        writer: &Arc<Mutex<FrameWriter<OwnedWriteHalf>>>,
+        peer: &Peer,
+        correlation_id: u64,
+        message: BrokerMessage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match message {
+            BrokerMessage::ApiVersions => {
+                let response = ApiVersionsResponse {
+                    apis: Self::supported_apis(),
+                };
+                Self::reply(writer, correlation_id, BrokerResponse::ApiVersions(response)).await?;
+            }
 
-                    let response = ConsumeResponse {
-                        message_id: message_id.clone(),
-                        topic: topic.clone(),
-                        payload,
-                        sent_at: SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs() as i64,
-                        offset,
-                    };
+            BrokerMessage::Publish { key, topic, payload, headers, acks } => {
+                // Stored and delivered like a batch holding just this record
+                let record = Record::new(0, Some(Bytes::from(key)), Bytes::from(payload))
+                    .with_headers(headers);
+                let batch = RecordBatch::new(0, Utc::now().timestamp_millis(), vec![record]);
+                let produced = broker.produce(topic, None, batch.encoded(), acks).await;
+                if acks != Acks::None {
+                    Self::reply_produced(broker, writer, correlation_id, produced).await?;
+                }
+            }
 
-                    let sender = broker.ensure_channel(broker.partition_id).await;
-                    if let Err(e) = sender.send(response) {
-                        eprintln!("Failed to broadcast message: {}", e);
+            BrokerMessage::Produce { topic, partition, records, acks } => {
+                let produced = match EncodedBatch::split_all(records) {
+                    Ok(batches) if batches.len() == 1 => {
+                        let batch = batches.into_iter().next().unwrap();
+                        broker.produce(topic, partition, batch, acks).await
                     }
-
-                    let success = format!("Published to partition {} with offset {}", 
-                        broker.partition_id, offset);
-                    socket.write_all(success.as_bytes()).await?;
+                    Ok(batches) => Produced::Done(BrokerResponse::Error(BrokerError::new(
+                        ErrorCode::MalformedRequest,
+                        format!("Expected one record batch, got {}", batches.len()),
+                    ))),
+                    Err(e) => Produced::Done(BrokerResponse::Error(BrokerError::new(
+                        ErrorCode::MalformedRequest,
+                        e.to_string(),
+                    ))),
+                };
+                // Errors included, the producer asked not to hear back
+                if acks != Acks::None {
+                    Self::reply_produced(broker, writer, correlation_id, produced).await?;
                 }
+            }
 
-                BrokerMessage::Subscribe { consumer_id, topic } => {
-                    broker.ensure_topic(&topic).await;
-                    
-                    let mut topics = broker.topics.write().await;
-                    topics
-                        .entry(topic.clone())
-                        .or_insert_with(HashSet::new)
-                        .insert(consumer_id.clone());
-
-                    socket.write_all(b"Subscribed successfully").await?;
-                }
-
-                BrokerMessage::Consume { consumer_id } => {
-                    let sender = broker.ensure_channel(broker.partition_id).await;
-                    let mut rx = sender.subscribe();
-
-                    // Spawn a task to handle this consumer
-                    let mut socket = socket.try_clone().await?;
-                    tokio::spawn(async move {
-                        while let Ok(msg) = rx.recv().await {
-                            if let Ok(data) = serde_json::to_vec(&msg) {
-                                if socket.write_all(&data).await.is_err() {
-                                    break;
-                                }
-                            }
+            BrokerMessage::Register { client_id, client_type, client_version, features } => {
+                let idempotent = client_type == "producer" && features.iter().any(|feature| feature == IDEMPOTENCE);
+                let session = ClientSession {
+                    client_id: client_id.clone(),
+                    client_type,
+                    addr: peer.addr.clone(),
+                    connected_at_ms: peer.connected_at_ms,
+                    client_version,
+                    features,
+                };
+                let response = match broker.clients.register(session) {
+                    Ok(()) => {
+                        let producer = idempotent.then(|| broker.producers.assign(&client_id));
+                        if let Some(stale) = producer.and_then(|producer| broker.transactions.take_stale(producer)) {
+                            broker.abort_transaction(stale).await;
                         }
-                    });
+                        BrokerResponse::Registered { client_id, producer }
+                    }
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::ListClients => {
+                let clients = broker.clients.list();
+                Self::reply(writer, correlation_id, BrokerResponse::ClientList { clients }).await?;
+            }
+
+            BrokerMessage::Subscribe { consumer_id, topic } => {
+                if broker.ensure_topic(&topic).is_none() {
+                    let error = Self::unknown_topic(&topic);
+                    Self::reply(writer, correlation_id, BrokerResponse::Error(error)).await?;
+                    return Ok(());
+                }
+                if let Some(state) = broker.topics.write().get_mut(&topic) {
+                    state.consumers.insert(consumer_id);
                 }
 
-                BrokerMessage::UpdateOffset { consumer_id, topic, offset } => {
-                    if offset < 0 {
-                        socket.write_all(b"Offset cannot be negative").await?;
-                        continue;
-                    }
+                Self::reply(writer, correlation_id, BrokerResponse::Subscribed { topic }).await?;
+            }
+
+            BrokerMessage::Consume { consumer_id, topics, group_id, credit, isolation } => {
+                let subscriptions = broker.subscriptions(&consumer_id);
+                let unsubscribed = match &group_id {
+                    Some(_) => None,
+                    None => topics.iter().find(|topic| !subscriptions.contains(topic)),
+                };
+                if let Some(topic) = unsubscribed {
+                    let error = BrokerError::new(
+                        ErrorCode::NotSubscribed,
+                        format!("{} is not subscribed to {}", consumer_id, topic),
+                    );
+                    Self::reply(writer, correlation_id, BrokerResponse::Error(error)).await?;
+                    return Ok(());
+                }
 
-                    let topics = broker.topics.read().await;
-                    if !topics.contains_key(&topic) {
-                        socket.write_all(b"Topic not found").await?;
-                        continue;
+                // A new Consume starts over with the credit it brings
+                let credit = Arc::new(CreditAccount::new(credit));
+                broker.credits.write().insert(consumer_id.clone(), credit.clone());
+
+                // Spawn a task to handle this consumer
+                let in_flight = broker.shutdown.track();
+                let request = ConsumeRequest {
+                    consumer_id,
+                    topics,
+                    group_id,
+                    isolation,
+                };
+                let deliver = Self::deliver(broker.clone(), writer.clone(), correlation_id, request, credit);
+                tokio::spawn(async move {
+                    let _in_flight = in_flight;
+                    deliver.await;
+                });
+            }
+
+            BrokerMessage::GrantCredit { consumer_id, credit } => {
+                let account = broker.credits.read().get(&consumer_id).cloned();
+                let response = match account {
+                    Some(account) => {
+                        account.grant(credit);
+                        BrokerResponse::CreditGranted
                     }
+                    None => BrokerResponse::Error(BrokerError::new(
+                        ErrorCode::NotSubscribed,
+                        format!("{} is not consuming", consumer_id),
+                    )),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::Fetch { topic, partition, offset, max_bytes, replica_id: Some(replica_id), .. } => {
+                let response = broker.replica_fetch(topic, partition, offset, max_bytes, replica_id);
+                Self::reply(writer, correlation_id, response).await?;
+            }
 
-                    broker.set_consumer_offset(&consumer_id, &topic, offset).await;
-                    socket.write_all(format!("Offset updated to {}", offset).as_bytes()).await?;
+            BrokerMessage::Fetch { topic, partition, offset, max_bytes, max_wait_ms, isolation, replica_id: None } => {
+                // Long polls must not hold up the connection's other requests
+                let broker = broker.clone();
+                let writer = writer.clone();
+                let in_flight = broker.shutdown.track();
+                tokio::spawn(async move {
+                    let _in_flight = in_flight;
+                    let started = Instant::now();
+                    let max_wait = Duration::from_millis(max_wait_ms);
+                    let response = broker.fetch(topic, partition, offset, max_bytes, max_wait, isolation).await;
+                    let _ = Self::reply(&writer, correlation_id, response).await;
+                    broker.metrics.observe_request(ApiKey::Fetch, started.elapsed());
+                });
+            }
+
+            BrokerMessage::UpdateOffset { consumer_id, topic, partition, offset } => {
+                if offset < 0 {
+                    let error = BrokerError::new(ErrorCode::InvalidOffset, "Offset cannot be negative");
+                    Self::reply(writer, correlation_id, BrokerResponse::Error(error)).await?;
+                    return Ok(());
                 }
 
-                _ => {
-                    socket.write_all(b"Unsupported operation").await?;
+                if !broker.storage.has_partition(&topic, partition as i32) {
+                    let error = BrokerError::new(
+                        ErrorCode::UnknownTopic,
+                        format!("Partition {} of topic {} is not on this broker", partition, topic),
+                    );
+                    Self::reply(writer, correlation_id, BrokerResponse::Error(error)).await?;
+                    return Ok(());
                 }
+
+                broker
+                    .storage
+                    .update_consumer_offset(&consumer_id, &topic, partition as i32, offset);
+                let response = BrokerResponse::OffsetUpdated { topic, partition, offset };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::JoinGroup {
+                group_id,
+                member_id,
+                topics,
+                strategies,
+                session_timeout_ms,
+            } => {
+                if let Some(topic) = topics.iter().find(|topic| broker.ensure_topic(topic).is_none()) {
+                    let error = Self::unknown_topic(topic);
+                    Self::reply(writer, correlation_id, BrokerResponse::Error(error)).await?;
+                    return Ok(());
+                }
+                let request = JoinRequest {
+                    member_id,
+                    topics,
+                    strategies,
+                    session_timeout: Duration::from_millis(session_timeout_ms),
+                };
+                let response = match broker.coordinator.join(&group_id, request, |topic| {
+                    broker.hosted_partitions(topic)
+                }) {
+                    Ok(joined) => BrokerResponse::GroupJoined(joined),
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::SyncGroup { group_id, member_id } => {
+                let response = match broker.coordinator.sync(&group_id, &member_id) {
+                    Ok(assignment) => BrokerResponse::GroupAssignment(assignment),
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::Heartbeat { group_id, member_id, generation } => {
+                let response = match broker.coordinator.heartbeat(&group_id, &member_id, generation) {
+                    Ok(()) => BrokerResponse::HeartbeatAck,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::LeaveGroup { group_id, member_id } => {
+                let response = match broker.coordinator.leave(&group_id, &member_id, |topic| {
+                    broker.hosted_partitions(topic)
+                }) {
+                    Ok(()) => BrokerResponse::GroupLeft,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::CreateTopic { topic, partitions, partitioner, config } => {
+                let response = match broker.create_topic(&topic, partitions, partitioner, config).await {
+                    Ok(()) => BrokerResponse::TopicCreated { topic, partitions },
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::DeleteTopic { topic } => {
+                let record = MetadataRecord::DeleteTopic { topic: topic.clone() };
+                let response = match broker.commit_metadata(record).await {
+                    Ok(()) => BrokerResponse::TopicDeleted { topic },
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::ListTopics => {
+                let mut topics: Vec<String> = broker.topics.read().keys().cloned().collect();
+                topics.sort();
+                Self::reply(writer, correlation_id, BrokerResponse::TopicList { topics }).await?;
+            }
+
+            BrokerMessage::DescribeTopic { topic } => {
+                let response = match broker.describe_topic(&topic) {
+                    Some(description) => BrokerResponse::TopicDescribed(description),
+                    None => BrokerResponse::Error(Self::unknown_topic(&topic)),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::Metadata { topics } => {
+                let response = BrokerResponse::Metadata(broker.metadata(topics));
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::GetMetrics => {
+                let response = BrokerResponse::Metrics(broker.metrics_report());
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::BeginTransaction { producer, timeout_ms } => {
+                let begun = broker.producers.check_current(producer).and_then(|()| {
+                    broker.transactions.begin(producer, Duration::from_millis(timeout_ms))
+                });
+                let response = match begun {
+                    Ok(()) => BrokerResponse::TransactionBegun,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::AddPartitionsToTxn { producer, partitions } => {
+                let response = match broker.add_partitions_to_txn(producer, partitions) {
+                    Ok(()) => BrokerResponse::PartitionsAddedToTxn,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::TxnCommitOffsets { producer, offsets } => {
+                let response = match broker.add_offsets_to_txn(producer, offsets) {
+                    Ok(()) => BrokerResponse::TxnOffsetsAdded,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::EndTransaction { producer, commit } => {
+                let response = match broker.end_transaction(producer, commit).await {
+                    Ok(()) => BrokerResponse::TransactionEnded { committed: commit },
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::WriteTxnMarkers { producer, commit, partitions, offsets } => {
+                broker.write_txn_markers(producer, commit, &partitions, &offsets);
+                Self::reply(writer, correlation_id, BrokerResponse::TxnMarkersWritten).await?;
+            }
+
+            request @ (BrokerMessage::RequestVote { .. }
+            | BrokerMessage::AppendEntries { .. }
+            | BrokerMessage::InstallSnapshot { .. }) => {
+                let response = broker.raft_request(request);
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            // Clients change metadata through CreateTopic and the like, which
+            // check what they ask for
+            BrokerMessage::ProposeMetadata { .. } if !broker.clients.is_broker(&peer.addr) => {
+                let error = BrokerError::new(
+                    ErrorCode::UnsupportedOperation,
+                    "Only brokers propose metadata records",
+                );
+                Self::reply(writer, correlation_id, BrokerResponse::Error(error)).await?;
+            }
+
+            BrokerMessage::ProposeMetadata { record } => {
+                // Other brokers wait for their own copy to get there, which
+                // could take a while, so this doesn't hold up the connection
+                let broker = broker.clone();
+                let writer = writer.clone();
+                let in_flight = broker.shutdown.track();
+                tokio::spawn(async move {
+                    let _in_flight = in_flight;
+                    let response = match broker.propose_metadata(record).await {
+                        Ok(index) => BrokerResponse::MetadataCommitted { index },
+                        Err(error) => BrokerResponse::Error(error),
+                    };
+                    let _ = Self::reply(&writer, correlation_id, response).await;
+                });
+            }
+
+            BrokerMessage::AddController { broker_id } => {
+                let response = match broker.change_controllers(broker_id, true).await {
+                    Ok(controllers) => BrokerResponse::ControllersChanged { controllers },
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::RemoveController { broker_id } => {
+                let response = match broker.change_controllers(broker_id, false).await {
+                    Ok(controllers) => BrokerResponse::ControllersChanged { controllers },
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::CreateAcl { acl } => {
+                let response = match broker.commit_metadata(MetadataRecord::CreateAcl(acl)).await {
+                    Ok(()) => BrokerResponse::AclCreated,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::DeleteAcl { acl } => {
+                let response = match broker.commit_metadata(MetadataRecord::DeleteAcl(acl)).await {
+                    Ok(()) => BrokerResponse::AclDeleted,
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
+            }
+
+            BrokerMessage::ListAcls => {
+                let acls = broker.acls.read().clone();
+                Self::reply(writer, correlation_id, BrokerResponse::AclList { acls }).await?;
+            }
+
+            BrokerMessage::DescribeQuorum => {
+                let response = match broker.describe_quorum() {
+                    Ok(description) => BrokerResponse::QuorumDescribed(description),
+                    Err(error) => BrokerResponse::Error(error),
+                };
+                Self::reply(writer, correlation_id, response).await?;
             }
         }
 
         Ok(())
     }
 
-    pub async fn serve(self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let addr = addr.parse()?;
+    pub async fn serve(mut self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.addr = Some(addr.to_string());
+        let addr: SocketAddr = addr.parse()?;
+        let mut requested_topics = None;
+        if !self.controllers.is_empty() {
+            if let Some(controller) = self.controllers.iter().find(|controller| self.peers.get(**controller as usize).is_none()) {
+                return Err(format!("Controller {} is not one of the peers", controller).into());
+            }
+            let path = match &self.metadata_dir {
+                Some(dir) => {
+                    std::fs::create_dir_all(dir)?;
+                    Some(dir.join(format!("metadata-{}.json", self.broker_id)))
+                }
+                None => None,
+            };
+            let raft = Raft::open(self.broker_id, self.controllers.clone(), self.election_timeout, path)?;
+            let (quorum, requested) = quorum::Quorum::new(raft);
+            self.quorum = Some(quorum);
+            requested_topics = Some(requested);
+        }
         let listener = TcpListener::bind(addr).await?;
         println!("Broker listening on {}", addr);
 
         let broker = Arc::new(self);
+        // Stopped as soon as shutdown starts
+        let mut background = vec![
+            tokio::spawn(Self::enforce_retention(broker.clone())),
+            tokio::spawn(Self::expire_group_members(broker.clone())),
+            tokio::spawn(Self::expire_transactions(broker.clone())),
+            tokio::spawn(Self::sample_metrics(broker.clone())),
+            tokio::spawn(Self::track_in_sync_replicas(broker.clone())),
+        ];
+        // The leaders' metadata says which of their partitions to copy
+        if broker.replication_factor > 1 {
+            for leader in (0..broker.peers.len() as u32).filter(|leader| *leader != broker.broker_id) {
+                background.push(tokio::spawn(Self::replicate(broker.clone(), leader)));
+            }
+        }
+        if let Some(requested) = requested_topics {
+            background.push(tokio::spawn(Self::run_quorum(broker.clone())));
+            background.push(tokio::spawn(Self::apply_metadata(broker.clone())));
+            background.push(tokio::spawn(Self::create_requested_topics(broker.clone(), requested)));
+        }
+
+        if let Some(kafka_addr) = &broker.kafka_addr {
+            let kafka_addr: SocketAddr = kafka_addr.parse()?;
+            let kafka_listener = TcpListener::bind(kafka_addr).await?;
+            println!("Kafka listener on {}", kafka_addr);
+            background.push(tokio::spawn(Self::serve_kafka(broker.clone(), kafka_listener)));
+        }
+
+        if let Some(metrics_addr) = &broker.metrics_addr {
+            let metrics_addr: SocketAddr = metrics_addr.parse()?;
+            let metrics_listener = TcpListener::bind(metrics_addr).await?;
+            println!("Metrics on http://{}/metrics", metrics_addr);
+            background.push(tokio::spawn(Self::serve_prometheus(broker.clone(), metrics_listener)));
+        }
 
         loop {
-            let (socket, _) = listener.accept().await?;
+            let (socket, peer_addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = broker.shutdown.requested() => break,
+            };
             let broker = broker.clone();
+            let in_flight = broker.shutdown.track();
             
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_client(broker, socket).await {
+                let _in_flight = in_flight;
+                broker.metrics.client_connected();
+                if let Err(e) = Self::handle_client(broker.clone(), socket, peer_addr).await {
                     eprintln!("Error handling client: {}", e);
                 }
+                broker.clients.disconnected(&peer_addr.to_string());
+                broker.metrics.client_disconnected();
             });
         }
+
+        println!("Broker on {} shutting down", addr);
+        drop(listener);
+        for task in background {
+            task.abort();
+        }
+        // Connections stop reading once shutdown starts, consumers and long
+        // polls are answered with what there is and told to go elsewhere.
+        // Storage lives in memory, so there is nothing to flush after.
+        if tokio::time::timeout(DRAIN_TIMEOUT, broker.shutdown.drained()).await.is_err() {
+            eprintln!("Gave up waiting for connections to drain");
+        }
+        Ok(())
+    }
+
+    // Everything handle_client answers
+    fn supported_apis() -> Vec<ApiVersionRange> {
+        ApiKey::ALL.into_iter().map(ApiVersionRange::current).collect()
+    }
+
+    async fn reply(
+        writer: &Mutex<FrameWriter<OwnedWriteHalf>>,
+        correlation_id: u64,
+        response: BrokerResponse,
+    ) -> Result<(), FrameError> {
+        let response = Response {
+            correlation_id,
+            response,
+        };
+        let mut writer = writer.lock().await;
+        writer.write_message_with_data(&response, response.response.data()).await
+    }
+
+    // Answer a produce, from a task of its own when the ack waits on the
+    // replicas so the connection's other requests don't wait with it
+    async fn reply_produced(
+        broker: &Arc<Self>,
+        writer: &Arc<Mutex<FrameWriter<OwnedWriteHalf>>>,
+        correlation_id: u64,
+        produced: Produced,
+    ) -> Result<(), FrameError> {
+        let (ack, end, timeout) = match produced {
+            Produced::Done(response) => return Self::reply(writer, correlation_id, response).await,
+            Produced::Replicating { ack, end, timeout } => (ack, end, timeout),
+        };
+        let broker = broker.clone();
+        let writer = writer.clone();
+        let in_flight = broker.shutdown.track();
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            let response = match broker.await_in_sync_replicas(&ack.topic, ack.partition, end, timeout).await {
+                Ok(()) => BrokerResponse::Ack(ack),
+                Err(error) => BrokerResponse::Error(error),
+            };
+            let _ = Self::reply(&writer, correlation_id, response).await;
+        });
+        Ok(())
+    }
+
+    // Append a batch to one of the topic's partitions, answering with an Ack
+    // carrying the batch's base offset, held back for acks=All until the
+    // in-sync replicas have it too
+    async fn produce(&self, topic: String, partition: Option<u32>, batch: EncodedBatch, acks: Acks) -> Produced {
+        // Decoded only to check it, consumers get the bytes the producer sent
+        let records = match batch.decode() {
+            Ok(decoded) => decoded.records,
+            Err(e) => {
+                let code = if batch.compression().is_err() {
+                    ErrorCode::UnsupportedOperation
+                } else {
+                    ErrorCode::MalformedRequest
+                };
+                return Produced::Done(BrokerResponse::Error(BrokerError::new(code, e.to_string())));
+            }
+        };
+
+        // Markers only ever come from a transaction's coordinator
+        if batch.is_control() {
+            let error = BrokerError::new(ErrorCode::MalformedRequest, "Control batches can't be produced");
+            return Produced::Done(BrokerResponse::Error(error));
+        }
+        let kind = match batch.is_transactional() {
+            false => BatchKind::Plain,
+            true if batch.producer_id() >= 0 => BatchKind::Transactional {
+                producer_id: batch.producer_id(),
+            },
+            true => {
+                let error = BrokerError::new(ErrorCode::MalformedRequest, "Transactional batches need a producer id");
+                return Produced::Done(BrokerResponse::Error(error));
+            }
+        };
+
+        let Some(partitions) = self.ensure_topic(&topic) else {
+            return Produced::Done(BrokerResponse::Error(Self::unknown_topic(&topic)));
+        };
+        let partition = match partition {
+            Some(partition) if partition >= partitions => {
+                let error = BrokerError::new(
+                    ErrorCode::UnknownTopic,
+                    format!("Topic {} has no partition {}", topic, partition),
+                );
+                return Produced::Done(BrokerResponse::Error(error));
+            }
+            Some(partition) => partition,
+            None => match self.choose_partition(&topic, &records, partitions) {
+                Some(partition) => partition,
+                None => {
+                    let error = BrokerError::new(
+                        ErrorCode::MalformedRequest,
+                        "Record keys map to different partitions",
+                    );
+                    return Produced::Done(BrokerResponse::Error(error));
+                }
+            },
+        };
+
+        if !self.hosts(&topic, partition) {
+            let owner = self.owner(&topic, partition);
+            if let (true, Some(addr)) = (self.forwarding, self.peers.get(owner as usize)) {
+                match self.forward(owner, addr, &topic, partition, &batch, acks).await {
+                    Ok(response) => return Produced::Done(response),
+                    Err(e) => eprintln!("Could not forward to broker {}: {}", owner, e),
+                }
+            }
+
+            let error = self.not_leader(&topic, partition);
+            let ack = MessageAck {
+                message_id: String::new(),
+                topic,
+                partition,
+                offset: -1,
+                timestamp: Utc::now(),
+                status: AckStatus::Error(error),
+            };
+            return Produced::Done(BrokerResponse::Ack(ack));
+        }
+
+        let (messages, bytes) = (batch.offset_count() as u64, batch.as_bytes().len() as u64);
+        let appended = self.producers.append_once(&topic, partition, &batch, || {
+            self.storage.append_batch_of(
+                &topic,
+                partition as i32,
+                batch.offset_count(),
+                kind,
+                |offset| batch.with_base_offset(offset).into_bytes(),
+            )
+        });
+        let offset = match appended {
+            Ok(Some(Appended::Stored(offset))) => {
+                self.metrics.record_in(messages, bytes);
+                self.appended.notify_waiters();
+                offset
+            }
+            // A retry, acked again without storing it twice
+            Ok(Some(Appended::Duplicate(offset))) => offset,
+            Ok(None) => return Produced::Done(BrokerResponse::Error(Self::unknown_topic(&topic))),
+            Err(error) => return Produced::Done(BrokerResponse::Error(error)),
+        };
+
+        let ack = MessageAck {
+            message_id: Uuid::new_v4().to_string(),
+            topic,
+            partition,
+            offset,
+            timestamp: Utc::now(),
+            status: AckStatus::Success,
+        };
+        match acks {
+            Acks::All { timeout_ms } => Produced::Replicating {
+                ack,
+                end: offset + batch.offset_count(),
+                timeout: Duration::from_millis(timeout_ms),
+            },
+            _ => Produced::Done(BrokerResponse::Ack(ack)),
+        }
+    }
+
+    // Wait until every in-sync replica of a partition has its log up to
+    // `end`, which is when the high watermark gets there
+    async fn await_in_sync_replicas(
+        &self,
+        topic: &str,
+        partition: u32,
+        end: i64,
+        timeout: Duration,
+    ) -> Result<(), BrokerError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Registered before checking so an advance in between still wakes us
+            let advanced = self.appended.notified();
+            tokio::pin!(advanced);
+            advanced.as_mut().enable();
+
+            let committed = self
+                .storage
+                .log_offsets(topic, partition as i32)
+                .map_or(end, |(_, high_watermark)| high_watermark);
+            if committed >= end {
+                return Ok(());
+            }
+            if tokio::time::timeout_at(deadline, advanced).await.is_err() {
+                let replicas = self.replicas(topic, partition);
+                let in_sync = self.in_sync_replicas(topic, partition);
+                return Err(BrokerError::new(
+                    ErrorCode::NotEnoughReplicas,
+                    format!(
+                        "{} partition {} is committed up to {} not {} after {:?}, in-sync replicas are {:?} of {:?}",
+                        topic, partition, committed, end, timeout, in_sync, replicas
+                    ),
+                ));
+            }
+        }
     }
 
-    async fn ensure_channel(&self, partition_id: u32) -> broadcast::Sender<ConsumeResponse> {
-        let mut channels = self.messages.write().await;
-        if let Some(sender) = channels.get(&partition_id) {
-            if sender.receiver_count() > 0 {
-                return sender.clone();
+    // Read a partition from `offset`, waiting up to `max_wait` for something to
+    // be appended if there is nothing there yet
+    async fn fetch(
+        &self,
+        topic: String,
+        partition: u32,
+        offset: i64,
+        max_bytes: u32,
+        max_wait: Duration,
+        isolation: IsolationLevel,
+    ) -> BrokerResponse {
+        match self.partition_count(&topic) {
+            Some(partitions) if partition < partitions => {}
+            _ => {
+                let error = BrokerError::new(
+                    ErrorCode::UnknownTopic,
+                    format!("Topic {} has no partition {}", topic, partition),
+                );
+                return BrokerResponse::Error(error);
+            }
+        }
+        if !self.hosts(&topic, partition) {
+            return BrokerResponse::Error(self.not_leader(&topic, partition));
+        }
+
+        let deadline = tokio::time::Instant::now() + max_wait;
+        loop {
+            // Registered before reading so an append in between still wakes us
+            let appended = self.appended.notified();
+            tokio::pin!(appended);
+            appended.as_mut().enable();
+
+            let (log_start_offset, high_watermark) = self
+                .storage
+                .log_offsets(&topic, partition as i32)
+                .unwrap_or((0, 0));
+            if offset < log_start_offset || offset > high_watermark {
+                let error = BrokerError::new(
+                    ErrorCode::InvalidOffset,
+                    format!(
+                        "Offset {} is outside {}..={} of {} partition {}",
+                        offset, log_start_offset, high_watermark, topic, partition
+                    ),
+                );
+                return BrokerResponse::Error(error);
+            }
+
+            let (batches, next_offset) = self.read_isolated(&topic, partition, offset, isolation);
+            let timed_out = tokio::time::Instant::now() >= deadline || self.shutdown.is_requested();
+            // Skipping records the reader doesn't get to see counts as an answer too
+            if !batches.is_empty() || next_offset > offset || timed_out {
+                let last_stable_offset = self
+                    .storage
+                    .last_stable_offset(&topic, partition as i32)
+                    .unwrap_or(high_watermark);
+                let read_to = batches.last().map(|batch| batch.last_offset + 1);
+                let (records, messages, end) = Self::record_batches(batches, max_bytes as usize)
+                    .unwrap_or((Bytes::new(), 0, offset));
+                // What follows the batches is only skipped if they all fit
+                let next_offset = match read_to {
+                    Some(read_to) if end < read_to => end,
+                    _ => next_offset,
+                };
+                self.metrics.record_out(messages, records.len() as u64);
+                return BrokerResponse::Fetched(FetchResponse {
+                    topic,
+                    partition,
+                    high_watermark,
+                    log_start_offset,
+                    last_stable_offset,
+                    next_offset,
+                    records,
+                });
+            }
+
+            // Once the deadline passes, go round once more to answer with what is there
+            tokio::select! {
+                _ = tokio::time::timeout_at(deadline, appended) => {}
+                _ = self.shutdown.requested() => {}
             }
         }
-        
-        let (new_tx, _) = broadcast::channel(self.broadcast_capacity);
-        channels.insert(partition_id, new_tx.clone());
-        new_tx
     }
 
-    fn owns_partition(&self, message_key: &str) -> bool {
-        let hash = self.hash_key(message_key);
-        hash % self.total_partitions == self.partition_id
+    // Stored batches back to back, as many as fit in `limit`. The first batch
+    // is always included, or a client could never get past a large one.
+    // Also says how many messages made it in and the offset after the last one.
+    fn record_batches(batches: Vec<StoredMessage>, limit: usize) -> Option<(Bytes, u64, i64)> {
+        if batches.is_empty() {
+            return None;
+        }
+
+        let mut out = BytesMut::new();
+        let mut messages = 0;
+        let mut end = 0;
+        for batch in batches {
+            if !out.is_empty() && out.len() + batch.payload.len() > limit {
+                break;
+            }
+            out.extend_from_slice(&batch.payload);
+            messages += (batch.last_offset - batch.offset + 1) as u64;
+            end = batch.last_offset + 1;
+        }
+        Some((out.freeze(), messages, end))
     }
 
-    fn hash_key(&self, key: &str) -> u32 {
-        key.bytes().fold(0u32, |acc, b| acc.wrapping_add(b as u32))
+    // Stored batches of a partition from `offset` that a reader with this
+    // isolation level gets, and where its next read starts. Transaction
+    // markers are left out either way, only the Kafka listener serves them.
+    fn read_isolated(
+        &self,
+        topic: &str,
+        partition: u32,
+        offset: i64,
+        isolation: IsolationLevel,
+    ) -> (Vec<StoredMessage>, i64) {
+        match isolation {
+            IsolationLevel::ReadUncommitted => {
+                let mut batches = self.storage.read(topic, partition as i32, offset).unwrap_or_default();
+                let next_offset = batches.last().map_or(offset, |batch| batch.last_offset + 1);
+                batches.retain(|batch| !matches!(batch.kind, BatchKind::Marker { .. }));
+                (batches, next_offset)
+            }
+            IsolationLevel::ReadCommitted => match self.storage.read_committed(topic, partition as i32, offset) {
+                Some(read) => (read.messages, read.next_offset),
+                None => (Vec::new(), offset),
+            },
+        }
     }
 
-    async fn ensure_topic(&self, topic: &str) {
-        let topics = self.topics.read().await;
+    // Answer a follower with what it doesn't have yet, committed or not. Where
+    // it asks to start from is where its copy ends, which may commit more.
+    fn replica_fetch(
+        &self,
+        topic: String,
+        partition: u32,
+        offset: i64,
+        max_bytes: u32,
+        replica_id: u32,
+    ) -> BrokerResponse {
+        if !self.hosts(&topic, partition) {
+            return BrokerResponse::Error(self.not_leader(&topic, partition));
+        }
+        if replica_id == self.broker_id || !self.replicas(&topic, partition).contains(&replica_id) {
+            let error = BrokerError::new(
+                ErrorCode::MalformedRequest,
+                format!("Broker {} does not follow partition {}", replica_id, partition),
+            );
+            return BrokerResponse::Error(error);
+        }
+        let Some(log_end) = self.storage.log_end_offset(&topic, partition as i32) else {
+            let error = BrokerError::new(
+                ErrorCode::UnknownTopic,
+                format!("Topic {} has no partition {}", topic, partition),
+            );
+            return BrokerResponse::Error(error);
+        };
+
+        self.replication.fetched(&topic, partition, replica_id, offset, log_end);
+        self.update_high_watermark(&topic, partition);
+
+        let batches = self.storage.read_log(&topic, partition as i32, offset).unwrap_or_default();
+        let (records, _, next_offset) =
+            Self::record_batches(batches, max_bytes as usize).unwrap_or((Bytes::new(), 0, offset));
+        let (log_start_offset, high_watermark) = self.storage.log_offsets(&topic, partition as i32).unwrap_or((0, 0));
+        let last_stable_offset = self
+            .storage
+            .last_stable_offset(&topic, partition as i32)
+            .unwrap_or(high_watermark);
+        BrokerResponse::Fetched(FetchResponse {
+            topic,
+            partition,
+            high_watermark,
+            log_start_offset,
+            last_stable_offset,
+            next_offset,
+            records,
+        })
+    }
+
+    // Records are committed once every in-sync replica has them. With no
+    // follower in sync, that is as soon as the leader does.
+    fn update_high_watermark(&self, topic: &str, partition: u32) {
+        let followers: Vec<u32> = self
+            .replicas(topic, partition)
+            .into_iter()
+            .filter(|replica| *replica != self.broker_id)
+            .collect();
+        if followers.is_empty() {
+            return;
+        }
+        let high_watermark = self
+            .replication
+            .in_sync(topic, partition, &followers)
+            .into_iter()
+            .map(|(_, log_end)| log_end)
+            .min();
+        if self.storage.set_high_watermark(topic, partition as i32, high_watermark) {
+            // Consumers and acks=All produces waiting on it
+            self.appended.notify_waiters();
+        }
+    }
+
+    // The leader first, then followers in sync. Only the leader knows which
+    // those are, anywhere else every replica is listed.
+    fn in_sync_replicas(&self, topic: &str, partition: u32) -> Vec<u32> {
+        let replicas = self.replicas(topic, partition);
+        if replicas[0] != self.broker_id {
+            return replicas;
+        }
+        let in_sync = self.replication.in_sync(topic, partition, &replicas[1..]);
+        replicas
+            .into_iter()
+            .filter(|replica| *replica == self.broker_id || in_sync.iter().any(|(follower, _)| follower == replica))
+            .collect()
+    }
+
+    // Followers that stop fetching fall out of sync without the leader
+    // hearing from them, which can commit what only the others have
+    async fn track_in_sync_replicas(broker: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            let topics: Vec<String> = broker.topics.read().keys().cloned().collect();
+            for topic in topics {
+                for partition in broker.hosted_partitions(&topic) {
+                    broker.update_high_watermark(&topic, partition);
+                }
+            }
+        }
+    }
+
+    // Keeps copying from `leader` for as long as the broker runs. Failed
+    // rounds are retried on a fresh connection.
+    async fn replicate(broker: Arc<Self>, leader: u32) {
+        let addr = broker.peers[leader as usize].clone();
+        loop {
+            let copied = match broker.fetch_from_leader(leader, &addr).await.ok() {
+                Some(copied) => copied,
+                None => {
+                    broker.peer_connections.lock().await.remove(&leader);
+                    false
+                }
+            };
+            if !copied {
+                tokio::time::sleep(REPLICA_FETCH_INTERVAL).await;
+            }
+        }
+    }
+
+    // One fetch for every partition `leader` leads that this broker keeps a
+    // copy of. Topics this broker hasn't seen yet are created like they are
+    // on the leader. Says whether anything was copied.
+    async fn fetch_from_leader(&self, leader: u32, addr: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let connection = self.peer_connection(leader, addr).await?;
+        let metadata = match connection.request(BrokerMessage::Metadata { topics: None }).await? {
+            BrokerResponse::Metadata(metadata) => metadata,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to metadata: {:?}", other).into()),
+        };
+
+        let mut copied = false;
+        for topic in metadata.topics {
+            let partitions: Vec<u32> = topic
+                .partitions
+                .iter()
+                .filter(|partition| partition.leader == leader && partition.replicas.contains(&self.broker_id))
+                .map(|partition| partition.partition)
+                .collect();
+            if partitions.is_empty() {
+                continue;
+            }
+            if self.partition_count(&topic.topic).is_none() {
+                // With controllers, topics only ever come from the metadata log
+                if self.quorum.is_some() {
+                    continue;
+                }
+                self.adopt_topic(&connection, &topic.topic).await?;
+            }
+            for partition in partitions {
+                copied |= self.copy_partition(&connection, &topic.topic, partition).await?;
+            }
+        }
+        Ok(copied)
+    }
+
+    async fn adopt_topic(&self, connection: &Connection, topic: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let describe = BrokerMessage::DescribeTopic { topic: topic.to_string() };
+        let description = match connection.request(describe).await? {
+            BrokerResponse::TopicDescribed(description) => description,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to describe topic: {:?}", other).into()),
+        };
+        let mut topics = self.topics.write();
         if !topics.contains_key(topic) {
-            drop(topics);
-            let mut topics = self.topics.write().await;
-            if !topics.contains_key(topic) {
-                topics.insert(topic.to_string(), HashSet::new());
+            let replicas = self.assign_replicas(description.partitions.len() as u32);
+            self.add_topic(&mut topics, topic, replicas, description.partitioner, description.config);
+        }
+        Ok(())
+    }
+
+    // Appends what the leader has past the end of this broker's copy
+    async fn copy_partition(
+        &self,
+        connection: &Connection,
+        topic: &str,
+        partition: u32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        if !self.storage.has_partition(topic, partition as i32) {
+            self.storage.ensure_partition(topic, partition as i32);
+            self.storage.set_high_watermark(topic, partition as i32, Some(0));
+        }
+        let offset = self.storage.log_end_offset(topic, partition as i32).unwrap_or(0);
+        let fetch = BrokerMessage::Fetch {
+            topic: topic.to_string(),
+            partition,
+            offset,
+            max_bytes: REPLICA_FETCH_BYTES,
+            max_wait_ms: 0,
+            isolation: IsolationLevel::ReadUncommitted,
+            replica_id: Some(self.broker_id),
+        };
+        let fetched = match connection.request(fetch).await? {
+            BrokerResponse::Fetched(fetched) => fetched,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to fetch: {:?}", other).into()),
+        };
+
+        let batches = EncodedBatch::split_all(fetched.records)?;
+        let copied = !batches.is_empty();
+        for batch in batches {
+            let (offset, offset_count, kind) = (batch.base_offset(), batch.offset_count(), Self::batch_kind(&batch));
+            self.storage
+                .append_replica(topic, partition as i32, offset, offset_count, kind, batch.into_bytes());
+        }
+        // A copy is committed as far as the leader's is
+        self.storage
+            .set_high_watermark(topic, partition as i32, Some(fetched.high_watermark));
+        Ok(copied)
+    }
+
+    fn batch_kind(batch: &EncodedBatch) -> BatchKind {
+        match batch.marker_commits() {
+            Some(commit) => BatchKind::Marker {
+                producer_id: batch.producer_id(),
+                commit,
+            },
+            None if batch.is_transactional() => BatchKind::Transactional {
+                producer_id: batch.producer_id(),
+            },
+            None => BatchKind::Plain,
+        }
+    }
+
+    // Hand a batch to the broker that hosts its partition and relay the answer.
+    // Connections to peers are opened on first use and kept.
+    async fn forward(
+        &self,
+        owner: u32,
+        addr: &str,
+        topic: &str,
+        partition: u32,
+        batch: &EncodedBatch,
+        acks: Acks,
+    ) -> Result<BrokerResponse, ConnectionError> {
+        let connection = self.peer_connection(owner, addr).await?;
+        let produce = BrokerMessage::Produce {
+            topic: topic.to_string(),
+            partition: Some(partition),
+            records: batch.as_bytes().clone(),
+            // The producer may not want an answer, but this broker does
+            acks: if acks == Acks::None { Acks::Leader } else { acks },
+        };
+        let response = connection.request(produce).await;
+        if response.is_err() {
+            // Reconnect next time
+            self.peer_connections.lock().await.remove(&owner);
+        }
+        response
+    }
+
+    // Connections to peers are opened on first use and kept. They register as
+    // a broker, which some requests are only taken from.
+    async fn peer_connection(&self, owner: u32, addr: &str) -> Result<Arc<Connection>, ConnectionError> {
+        let mut connections = self.peer_connections.lock().await;
+        match connections.get(&owner) {
+            Some(connection) => Ok(connection.clone()),
+            None => {
+                let connection = Arc::new(Connection::connect(addr).await?);
+                if connection.versions().supports(ApiKey::Register) {
+                    let register = BrokerMessage::Register {
+                        client_id: format!("broker-{}-{}", self.broker_id, Uuid::new_v4()),
+                        client_type: "broker".to_string(),
+                        client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                        features: Vec::new(),
+                    };
+                    connection.request(register).await?;
+                }
+                connections.insert(owner, connection.clone());
+                Ok(connection)
+            }
+        }
+    }
+
+    // Partitions have to exist and be on this broker or one it can reach, or
+    // the transaction could never be ended on them
+    fn add_partitions_to_txn(
+        &self,
+        producer: ProducerIdentity,
+        partitions: Vec<TopicPartitions>,
+    ) -> Result<(), BrokerError> {
+        self.producers.check_current(producer)?;
+        for topic in &partitions {
+            for partition in &topic.partitions {
+                self.check_txn_partition(&topic.topic, *partition)?;
+            }
+        }
+        let partitions = partitions.into_iter().flat_map(|topic| {
+            let name = topic.topic;
+            topic.partitions.into_iter().map(move |partition| (name.clone(), partition))
+        });
+        self.transactions.add_partitions(producer, partitions)
+    }
+
+    fn add_offsets_to_txn(&self, producer: ProducerIdentity, offsets: Vec<TxnOffset>) -> Result<(), BrokerError> {
+        self.producers.check_current(producer)?;
+        for offset in &offsets {
+            if offset.offset < 0 {
+                return Err(BrokerError::new(ErrorCode::InvalidOffset, "Offset cannot be negative"));
+            }
+            self.check_txn_partition(&offset.topic, offset.partition)?;
+        }
+        self.transactions.add_offsets(producer, offsets)
+    }
+
+    fn check_txn_partition(&self, topic: &str, partition: u32) -> Result<(), BrokerError> {
+        match self.ensure_topic(topic) {
+            None => return Err(Self::unknown_topic(topic)),
+            Some(partitions) if partition >= partitions => {
+                return Err(BrokerError::new(
+                    ErrorCode::UnknownTopic,
+                    format!("Topic {} has no partition {}", topic, partition),
+                ));
+            }
+            Some(_) => {}
+        }
+        let owner = self.owner(topic, partition);
+        if owner != self.broker_id && self.peers.get(owner as usize).is_none() {
+            return Err(BrokerError::new(
+                ErrorCode::UnsupportedOperation,
+                format!(
+                    "Broker {} can't reach broker {}, which hosts {} partition {}",
+                    self.broker_id, owner, topic, partition
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn end_transaction(&self, producer: ProducerIdentity, commit: bool) -> Result<(), BrokerError> {
+        self.producers.check_current(producer)?;
+        let transaction = self.transactions.end(producer)?;
+        self.complete_transaction(transaction, commit).await
+    }
+
+    // For transactions their producer can no longer end
+    async fn abort_transaction(&self, transaction: Transaction) {
+        let producer_id = transaction.producer.producer_id;
+        if let Err(e) = self.complete_transaction(transaction, false).await {
+            eprintln!("Could not abort the transaction of producer {}: {}", producer_id, e);
+        }
+    }
+
+    // Writes the transaction's markers on every broker hosting one of its
+    // partitions, along with its offsets if it committed. A broker that can't
+    // be reached leaves its partitions open to read_committed readers.
+    async fn complete_transaction(&self, transaction: Transaction, commit: bool) -> Result<(), BrokerError> {
+        let mut brokers: BTreeMap<u32, (Vec<TopicPartitions>, Vec<TxnOffset>)> = BTreeMap::new();
+        for (topic, partition) in transaction.partitions {
+            let (partitions, _) = brokers.entry(self.owner(&topic, partition)).or_default();
+            match partitions.iter_mut().find(|added| added.topic == topic) {
+                Some(added) => added.partitions.push(partition),
+                None => partitions.push(TopicPartitions {
+                    topic,
+                    partitions: vec![partition],
+                }),
+            }
+        }
+        if commit {
+            for offset in transaction.offsets {
+                brokers.entry(self.owner(&offset.topic, offset.partition)).or_default().1.push(offset);
+            }
+        }
+
+        // Every broker gets its markers even if one fails, so as little as
+        // possible stays open
+        let producer = transaction.producer;
+        let mut failed = None;
+        for (owner, (partitions, offsets)) in brokers {
+            if owner == self.broker_id {
+                self.write_txn_markers(producer, commit, &partitions, &offsets);
+            } else if let Err(error) = self.send_txn_markers(owner, producer, commit, partitions, offsets).await {
+                failed = Some(error);
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    async fn send_txn_markers(
+        &self,
+        owner: u32,
+        producer: ProducerIdentity,
+        commit: bool,
+        partitions: Vec<TopicPartitions>,
+        offsets: Vec<TxnOffset>,
+    ) -> Result<(), BrokerError> {
+        let failed = |reason: String| {
+            BrokerError::new(
+                ErrorCode::Unknown,
+                format!("Could not end the transaction on broker {}: {}", owner, reason),
+            )
+        };
+        let addr = self
+            .peers
+            .get(owner as usize)
+            .ok_or_else(|| failed("its address is unknown".to_string()))?;
+        let connection = self.peer_connection(owner, addr).await.map_err(|e| failed(e.to_string()))?;
+
+        let markers = BrokerMessage::WriteTxnMarkers {
+            producer,
+            commit,
+            partitions,
+            offsets,
+        };
+        match connection.request(markers).await {
+            Ok(BrokerResponse::TxnMarkersWritten) => Ok(()),
+            Ok(BrokerResponse::Error(error)) => Err(error),
+            Ok(other) => Err(failed(format!("unexpected response {:?}", other))),
+            Err(e) => {
+                // Reconnect next time
+                self.peer_connections.lock().await.remove(&owner);
+                Err(failed(e.to_string()))
+            }
+        }
+    }
+
+    // Ends the producer's transaction on partitions of this broker. One that
+    // isn't stored here was never written to, so there is nothing to end.
+    fn write_txn_markers(
+        &self,
+        producer: ProducerIdentity,
+        commit: bool,
+        partitions: &[TopicPartitions],
+        offsets: &[TxnOffset],
+    ) {
+        let kind = BatchKind::Marker {
+            producer_id: producer.producer_id,
+            commit,
+        };
+        let timestamp = Utc::now().timestamp_millis();
+        let marker = RecordBatch::control_marker(producer.producer_id, producer.producer_epoch, commit, timestamp)
+            .encoded();
+        for topic in partitions {
+            for partition in &topic.partitions {
+                self.storage.append_batch_of(
+                    &topic.topic,
+                    *partition as i32,
+                    marker.offset_count(),
+                    kind,
+                    |offset| marker.with_base_offset(offset).into_bytes(),
+                );
+            }
+        }
+        for offset in offsets {
+            self.storage
+                .update_consumer_offset(&offset.consumer_id, &offset.topic, offset.partition as i32, offset.offset);
+        }
+        self.appended.notify_waiters();
+    }
+
+    // Stream the stored batches of the consumer's topics from every partition
+    // hosted here, starting after its committed offset in each partition, or
+    // at the oldest batch still retained. A group member gets the partitions
+    // assigned to it instead and resumes from the group's committed offsets.
+    // With no topics listed that is whatever it is subscribed to at the time.
+    async fn deliver(
+        broker: Arc<Self>,
+        writer: Arc<Mutex<FrameWriter<OwnedWriteHalf>>>,
+        correlation_id: u64,
+        request: ConsumeRequest,
+        credit: Arc<CreditAccount>,
+    ) {
+        let ConsumeRequest {
+            consumer_id,
+            topics,
+            group_id,
+            isolation,
+        } = request;
+        let offsets_owner = group_id.clone().unwrap_or_else(|| consumer_id.clone());
+        let mut positions: HashMap<(String, u32), i64> = HashMap::new();
+
+        loop {
+            if broker.shutdown.is_requested() {
+                let error = Self::shutting_down();
+                let _ = Self::reply(&writer, correlation_id, BrokerResponse::Error(error)).await;
+                broker.forget_credit(&consumer_id, &credit);
+                return;
+            }
+
+            // Registered before reading so an append in between still wakes us
+            let appended = broker.appended.notified();
+            let rebalanced = broker.coordinator.rebalanced.notified();
+            let granted = credit.granted.notified();
+            tokio::pin!(appended);
+            tokio::pin!(rebalanced);
+            tokio::pin!(granted);
+            appended.as_mut().enable();
+            rebalanced.as_mut().enable();
+            granted.as_mut().enable();
+
+            let partitions: Vec<(String, u32)> = match &group_id {
+                Some(group_id) => match broker.coordinator.assignment(group_id, &consumer_id) {
+                    Some(assigned) => assigned,
+                    None => {
+                        let error = BrokerError::new(
+                            ErrorCode::UnknownMember,
+                            format!("{} is not a member of group {}", consumer_id, group_id),
+                        );
+                        let _ = Self::reply(&writer, correlation_id, BrokerResponse::Error(error)).await;
+                        broker.forget_credit(&consumer_id, &credit);
+                        return;
+                    }
+                },
+                None => {
+                    let topics = if topics.is_empty() {
+                        broker.subscriptions(&consumer_id)
+                    } else {
+                        topics.clone()
+                    };
+                    topics
+                        .into_iter()
+                        .flat_map(|topic| {
+                            let hosted = broker.hosted_partitions(&topic);
+                            hosted.into_iter().map(move |partition| (topic.clone(), partition))
+                        })
+                        .collect()
+                }
+            };
+            // Partitions taken away by a rebalance start over from the committed
+            // offset if they come back
+            positions.retain(|partition, _| partitions.contains(partition));
+
+            let mut delivered = false;
+            let mut out_of_credit = false;
+            'partitions: for (topic, partition) in partitions {
+                let position = positions.entry((topic.clone(), partition)).or_insert_with(|| {
+                    broker
+                        .storage
+                        .get_consumer_offset(&offsets_owner, &topic, partition as i32)
+                        .map(|offset| offset + 1)
+                        .unwrap_or(0)
+                });
+
+                let (batches, next_offset) = broker.read_isolated(&topic, partition, *position, isolation);
+                for batch in batches {
+                    let (messages, bytes) = ((batch.last_offset - batch.offset + 1) as u64, batch.payload.len() as u64);
+                    if !credit.take(messages, bytes) {
+                        out_of_credit = true;
+                        break 'partitions;
+                    }
+                    *position = batch.last_offset + 1;
+                    delivered = true;
+
+                    let response = BrokerResponse::Records(RecordsResponse {
+                        topic: topic.clone(),
+                        partition,
+                        records: batch.payload,
+                    });
+                    if Self::reply(&writer, correlation_id, response).await.is_err() {
+                        broker.forget_credit(&consumer_id, &credit);
+                        return;
+                    }
+                    broker.metrics.record_out(messages, bytes);
+                }
+                // Past markers and records hidden by the isolation level
+                if *position < next_offset {
+                    *position = next_offset;
+                    delivered = true;
+                }
+            }
+
+            // Paused until the consumer grants more, however much gets appended
+            if out_of_credit {
+                tokio::select! {
+                    _ = granted => {}
+                    _ = rebalanced => {}
+                    _ = broker.shutdown.requested() => {}
+                }
+            } else if !delivered {
+                tokio::select! {
+                    _ = appended => {}
+                    _ = rebalanced => {}
+                    _ = broker.shutdown.requested() => {}
+                }
+            }
+        }
+    }
+
+    // Appends already trim partitions that grew too big, this also ages out
+    // batches on partitions nobody is writing to
+    async fn enforce_retention(broker: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            broker.storage.cleanup_old_messages().await;
+        }
+    }
+
+    // Keeps the per second rates in the metrics current
+    async fn sample_metrics(broker: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            broker.metrics.sample();
+        }
+    }
+
+    // The topic's partitioner is asked once per distinct key, so a batch of
+    // unkeyed records stays together. None when the keys disagree.
+    fn choose_partition(&self, topic: &str, records: &[Record], partitions: u32) -> Option<u32> {
+        let partitioner = self.topics.read().get(topic)?.partitioner.clone();
+
+        let mut keys: Vec<Option<&[u8]>> = Vec::new();
+        let mut chosen = None;
+        for record in records {
+            let key = record.key.as_deref();
+            if keys.contains(&key) {
+                continue;
+            }
+            keys.push(key);
+
+            let partition = partitioner.partition(topic, key, partitions);
+            match chosen {
+                Some(chosen) if chosen != partition => return None,
+                _ => chosen = Some(partition),
+            }
+        }
+        Some(chosen.unwrap_or(0))
+    }
+
+    async fn expire_group_members(broker: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            broker.coordinator.expire(|topic| broker.hosted_partitions(topic));
+        }
+    }
+
+    // Aborts transactions that ran past their timeout. The producer is fenced,
+    // so it can't go on writing as if its transaction were still open.
+    async fn expire_transactions(broker: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            for transaction in broker.transactions.take_expired() {
+                broker.producers.bump_epoch(transaction.producer.producer_id);
+                broker.abort_transaction(transaction).await;
+            }
+        }
+    }
+
+    fn owner(&self, topic: &str, partition: u32) -> u32 {
+        self.replicas(topic, partition)[0]
+    }
+
+    fn hosts(&self, topic: &str, partition: u32) -> bool {
+        self.owner(topic, partition) == self.broker_id
+    }
+
+    // Brokers keeping the partition, its leader first, as assigned when the
+    // topic was created
+    fn replicas(&self, topic: &str, partition: u32) -> Vec<u32> {
+        let assigned = self
+            .topics
+            .read()
+            .get(topic)
+            .and_then(|state| state.replicas.get(partition as usize).cloned());
+        assigned.unwrap_or_else(|| self.default_replicas(partition))
+    }
+
+    // Partition p is led by broker p % broker_count, with the brokers after
+    // it as followers. Without peers to copy from that is the leader alone.
+    fn default_replicas(&self, partition: u32) -> Vec<u32> {
+        let owner = partition % self.broker_count;
+        let count = if self.peers.is_empty() {
+            1
+        } else {
+            self.replication_factor.min(self.broker_count)
+        };
+        (0..count).map(|i| (owner + i) % self.broker_count).collect()
+    }
+
+    fn assign_replicas(&self, partitions: u32) -> Vec<Vec<u32>> {
+        (0..partitions).map(|partition| self.default_replicas(partition)).collect()
+    }
+
+    // Unless a newer Consume from the same consumer has replaced it
+    fn forget_credit(&self, consumer_id: &str, credit: &Arc<CreditAccount>) {
+        let mut credits = self.credits.write();
+        if credits.get(consumer_id).is_some_and(|current| Arc::ptr_eq(current, credit)) {
+            credits.remove(consumer_id);
+        }
+    }
+
+    // Points the client at the broker that does host the partition
+    fn not_leader(&self, topic: &str, partition: u32) -> BrokerError {
+        let owner = self.owner(topic, partition);
+        let leader = Leader {
+            broker_id: owner,
+            addr: self.peers.get(owner as usize).cloned(),
+        };
+        BrokerError::not_leader(
+            format!("Partition {} is on broker {} not {}", partition, owner, self.broker_id),
+            leader,
+        )
+    }
+
+    fn partition_count(&self, topic: &str) -> Option<u32> {
+        self.topics.read().get(topic).map(|state| state.partitions)
+    }
+
+    // Partitions of the topic that live on this broker, none if the topic is unknown
+    fn hosted_partitions(&self, topic: &str) -> Vec<u32> {
+        let topics = self.topics.read();
+        let Some(state) = topics.get(topic) else {
+            return Vec::new();
+        };
+        (0..state.partitions)
+            .filter(|partition| state.replicas[*partition as usize][0] == self.broker_id)
+            .collect()
+    }
+
+    // Topics the consumer subscribed to, in name order
+    fn subscriptions(&self, consumer_id: &str) -> Vec<String> {
+        let topics = self.topics.read();
+        let mut subscribed: Vec<String> = topics
+            .iter()
+            .filter(|(_, state)| state.consumers.contains(consumer_id))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        subscribed.sort();
+        subscribed
+    }
+
+    // Without controllers only this broker knows about the topic, other
+    // brokers create it with their default partition count the first time
+    // they see it. With them every broker does.
+    async fn create_topic(
+        &self,
+        topic: &str,
+        partitions: u32,
+        partitioner: PartitionStrategy,
+        config: TopicConfig,
+    ) -> Result<(), BrokerError> {
+        if partitions == 0 {
+            return Err(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                "A topic needs at least one partition",
+            ));
+        }
+        let record = MetadataRecord::CreateTopic {
+            topic: topic.to_string(),
+            partitioner,
+            config,
+            replicas: self.assign_replicas(partitions),
+        };
+        self.commit_metadata(record).await
+    }
+
+    // Drops the topic from this broker, whatever the others do
+    fn delete_topic(&self, topic: &str) -> bool {
+        if self.topics.write().remove(topic).is_none() {
+            return false;
+        }
+        self.storage.delete_topic(topic);
+        self.producers.forget_topic(topic);
+        self.replication.forget_topic(topic);
+        true
+    }
+
+    fn describe_topic(&self, topic: &str) -> Option<TopicDescription> {
+        let (replicas, partitioner, config) = {
+            let topics = self.topics.read();
+            let state = topics.get(topic)?;
+            (state.replicas.clone(), state.strategy, state.config)
+        };
+
+        let partitions = (0..replicas.len() as u32)
+            .map(|partition| {
+                let leader = replicas[partition as usize][0];
+                let offsets = match leader == self.broker_id {
+                    true => self.storage.log_offsets(topic, partition as i32),
+                    false => None,
+                };
+                PartitionDescription {
+                    partition,
+                    leader,
+                    log_start_offset: offsets.map(|(start, _)| start),
+                    high_watermark: offsets.map(|(_, end)| end),
+                }
+            })
+            .collect();
+        Some(TopicDescription {
+            topic: topic.to_string(),
+            partitioner,
+            config,
+            partitions,
+        })
+    }
+
+    // The broker's counters plus what storage says about its partitions
+    fn metrics_report(&self) -> MetricsResponse {
+        let snapshot = self.metrics.snapshot();
+
+        let mut partitions: Vec<PartitionStats> = self
+            .storage
+            .partition_metrics()
+            .into_iter()
+            .map(|partition| PartitionStats {
+                topic: partition.topic,
+                partition: partition.partition_id as u32,
+                log_start_offset: partition.log_start_offset,
+                high_watermark: partition.high_watermark,
+                size_bytes: partition.size_bytes as u64,
+            })
+            .collect();
+        partitions.sort_by(|a, b| (&a.topic, a.partition).cmp(&(&b.topic, b.partition)));
+
+        // Committed offsets are the last message consumed, not the next one
+        let mut consumer_lag: Vec<ConsumerLag> = self
+            .storage
+            .all_consumer_offsets()
+            .into_iter()
+            .filter_map(|(consumer_id, topic, partition, committed_offset)| {
+                let stats = partitions
+                    .iter()
+                    .find(|stats| stats.topic == topic && stats.partition == partition as u32)?;
+                let lag = (stats.high_watermark - committed_offset - 1).max(0);
+                Some(ConsumerLag {
+                    consumer_id,
+                    topic,
+                    partition: partition as u32,
+                    committed_offset,
+                    lag,
+                })
+            })
+            .collect();
+        consumer_lag.sort_by(|a, b| {
+            (&a.consumer_id, &a.topic, a.partition).cmp(&(&b.consumer_id, &b.topic, b.partition))
+        });
+
+        let [messages_in, bytes_in, messages_out, bytes_out] = snapshot.totals;
+        let [messages_in_per_sec, bytes_in_per_sec, messages_out_per_sec, bytes_out_per_sec] =
+            snapshot.per_sec;
+        MetricsResponse {
+            broker_id: self.broker_id,
+            uptime_secs: snapshot.uptime.as_secs(),
+            messages_in,
+            bytes_in,
+            messages_out,
+            bytes_out,
+            messages_in_per_sec,
+            bytes_in_per_sec,
+            messages_out_per_sec,
+            bytes_out_per_sec,
+            connected_clients: snapshot.connected_clients,
+            partitions,
+            consumer_lag,
+            request_latency: snapshot.request_latency,
+        }
+    }
+
+    // What this broker knows of the cluster. Topics are only those it has seen,
+    // in name order.
+    fn metadata(&self, topics: Option<Vec<String>>) -> MetadataResponse {
+        if let Some(topics) = &topics {
+            for topic in topics {
+                self.ensure_topic(topic);
             }
         }
+
+        let brokers = (0..self.broker_count)
+            .map(|broker_id| {
+                let addr = match self.peers.get(broker_id as usize) {
+                    Some(addr) => Some(addr.clone()),
+                    None if broker_id == self.broker_id => self.addr.clone(),
+                    None => None,
+                };
+                BrokerMetadata { broker_id, addr }
+            })
+            .collect();
+
+        let mut known: Vec<(String, PartitionStrategy, Vec<Vec<u32>>)> = {
+            let known = self.topics.read();
+            let wanted = |topic: &String| topics.as_ref().is_none_or(|topics| topics.contains(topic));
+            known
+                .iter()
+                .filter(|(topic, _)| wanted(topic))
+                .map(|(topic, state)| (topic.clone(), state.strategy, state.replicas.clone()))
+                .collect()
+        };
+        known.sort_by(|a, b| a.0.cmp(&b.0));
+        let topics = known
+            .into_iter()
+            .map(|(topic, partitioner, replicas)| {
+                let partitions = replicas
+                    .into_iter()
+                    .enumerate()
+                    .map(|(partition, replicas)| PartitionMetadata {
+                        partition: partition as u32,
+                        leader: replicas[0],
+                        in_sync_replicas: self.in_sync_replicas(&topic, partition as u32),
+                        replicas,
+                    })
+                    .collect();
+                TopicMetadata {
+                    topic,
+                    partitioner,
+                    partitions,
+                }
+            })
+            .collect();
+
+        MetadataResponse {
+            broker_id: self.broker_id,
+            brokers,
+            topics,
+        }
     }
 
-    async fn set_consumer_offset(&self, consumer_id: &str, topic: &str, offset: i64) {
-        let mut offsets = self.consumer_offsets.write().await;
-        offsets.insert((consumer_id.to_string(), topic.to_string()), offset);
+    // Creates the topic with the default partition count if it is new and
+    // auto-creation is on, returning how many partitions it has. With
+    // controllers that takes a trip through the quorum, so the topic is
+    // only asked for and doesn't exist yet.
+    fn ensure_topic(&self, topic: &str) -> Option<u32> {
+        if let Some(partitions) = self.partition_count(topic) {
+            return Some(partitions);
+        }
+        if !self.auto_create_topics {
+            return None;
+        }
+        if let Some(quorum) = &self.quorum {
+            let _ = quorum.topic_requests.send(topic.to_string());
+            return None;
+        }
+
+        let mut topics = self.topics.write();
+        match topics.get(topic) {
+            Some(state) => Some(state.partitions),
+            None => {
+                let strategy = PartitionStrategy::default();
+                let replicas = self.assign_replicas(self.default_partitions);
+                self.add_topic(&mut topics, topic, replicas, strategy, TopicConfig::default());
+                Some(self.default_partitions)
+            }
+        }
     }
-}
\ No newline at end of file
+
+    // Called with the topics locked, so it goes by `replicas` alone
+    fn add_topic(
+        &self,
+        topics: &mut HashMap<String, TopicState>,
+        topic: &str,
+        replicas: Vec<Vec<u32>>,
+        strategy: PartitionStrategy,
+        config: TopicConfig,
+    ) {
+        let mut retention_policy = self.storage.get_retention_policy();
+        if let Some(retention_ms) = config.retention_ms {
+            retention_policy.max_age = Duration::from_millis(retention_ms);
+        }
+        if let Some(retention_bytes) = config.retention_bytes {
+            retention_policy.max_bytes = retention_bytes as usize;
+        }
+
+        self.storage.create_topic(topic.to_string());
+        for (partition, kept_by) in replicas.iter().enumerate() {
+            if !kept_by.contains(&self.broker_id) {
+                continue;
+            }
+            self.storage.create_partition_with_retention(topic, partition as i32, retention_policy);
+            // Nothing is committed before the followers have it
+            if kept_by.len() > 1 {
+                self.storage.set_high_watermark(topic, partition as i32, Some(0));
+            }
+        }
+        let state = TopicState {
+            partitions: replicas.len() as u32,
+            replicas,
+            strategy,
+            partitioner: strategy.partitioner(),
+            config,
+            consumers: HashSet::new(),
+        };
+        topics.insert(topic.to_string(), state);
+    }
+
+    fn unknown_topic(topic: &str) -> BrokerError {
+        BrokerError::new(ErrorCode::UnknownTopic, format!("Topic {} not found", topic))
+    }
+
+    fn shutting_down() -> BrokerError {
+        BrokerError::new(ErrorCode::ShuttingDown, "Broker is shutting down, reconnect to another one")
+    }
+}
@@ -0,0 +1,719 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_protocol::kafka::{
+    self, api_key, error_code, DecodeError, DecodeResult, Decoder, EncodedBatch, Encoder,
+    RequestHeader,
+};
+use rafka_protocol::{FrameReader, FrameWriter};
+
+use super::Broker;
+use crate::idempotence::Appended;
+
+// Only the partitions hosted on this broker can be read and written here.
+// Requests for the others are answered with the usual Kafka leadership errors
+// so clients go looking elsewhere.
+
+struct Partition<T> {
+    index: i32,
+    request: T,
+}
+
+struct Topic<T> {
+    name: String,
+    partitions: Vec<Partition<T>>,
+}
+
+fn topics<T>(
+    decoder: &mut Decoder,
+    mut partition: impl FnMut(&mut Decoder) -> DecodeResult<T>,
+) -> DecodeResult<Vec<Topic<T>>> {
+    decoder.array(|decoder| {
+        let name = decoder.string()?;
+        let partitions = decoder.array(|decoder| {
+            let index = decoder.i32()?;
+            Ok(Partition {
+                index,
+                request: partition(decoder)?,
+            })
+        })?;
+        Ok(Topic { name, partitions })
+    })
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+// Fetch isolation_level asking to be kept from open transactions
+const READ_COMMITTED: i8 = 1;
+
+// Kafka has no way to tell an idle client the broker is going away. Once
+// shutdown is asked for, requests are still answered until none have come for
+// this long, with partitions reported as led elsewhere so clients move on.
+const DRAIN_GRACE: Duration = Duration::from_millis(100);
+
+struct FetchPartition {
+    fetch_offset: i64,
+    partition_max_bytes: i32,
+}
+
+impl Broker {
+    pub(super) async fn serve_kafka(broker: Arc<Self>, listener: TcpListener) {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    eprintln!("Error accepting Kafka client: {}", e);
+                    continue;
+                }
+            };
+
+            let broker = broker.clone();
+            let in_flight = broker.shutdown.track();
+            tokio::spawn(async move {
+                let _in_flight = in_flight;
+                broker.metrics.client_connected();
+                if let Err(e) = Self::handle_kafka_client(broker.clone(), socket).await {
+                    eprintln!("Error handling Kafka client: {}", e);
+                }
+                broker.metrics.client_disconnected();
+            });
+        }
+    }
+
+    async fn handle_kafka_client(
+        broker: Arc<Self>,
+        socket: TcpStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Metadata points clients back at whichever address they reached us on
+        let advertised = socket.local_addr()?;
+        let (reader, writer) = socket.into_split();
+        let mut reader = FrameReader::new(reader);
+        let mut writer = FrameWriter::new(writer);
+
+        loop {
+            let frame = tokio::select! {
+                frame = reader.read_frame() => frame?,
+                _ = broker.shutdown.requested() => match tokio::time::timeout(DRAIN_GRACE, reader.read_frame()).await {
+                    Ok(frame) => frame?,
+                    Err(_) => break,
+                },
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            let (header, body) = RequestHeader::decode(frame)?;
+
+            let Some(response) = broker.handle_kafka_request(&header, body, advertised).await? else {
+                continue;
+            };
+
+            let mut out = Encoder::new();
+            out.i32(header.correlation_id).raw(&response);
+            writer.write_frame(&out.finish()).await?;
+        }
+
+        Ok(())
+    }
+
+    // Ok(None) means the request gets no response, as with acks=0 produces
+    async fn handle_kafka_request(
+        &self,
+        header: &RequestHeader,
+        mut body: Decoder,
+        advertised: SocketAddr,
+    ) -> DecodeResult<Option<Bytes>> {
+        let version = header.api_version;
+
+        if !kafka::supports(header.api_key, version) {
+            // Clients send ApiVersions at their newest version first and retry
+            // at whatever the v0 error response says we support
+            if header.api_key == api_key::API_VERSIONS {
+                return Ok(Some(Self::kafka_api_versions(0, error_code::UNSUPPORTED_VERSION)));
+            }
+            // Kafka brokers close the connection, the client can't read a reply it doesn't know
+            return Err(DecodeError(format!(
+                "unsupported api key {} version {}",
+                header.api_key, version
+            )));
+        }
+
+        let response = match header.api_key {
+            api_key::API_VERSIONS => Self::kafka_api_versions(version, error_code::NONE),
+            api_key::METADATA => self.kafka_metadata(version, &mut body, advertised).await?,
+            api_key::PRODUCE => return self.kafka_produce(version, &mut body).await,
+            api_key::FETCH => self.kafka_fetch(version, &mut body).await?,
+            api_key::LIST_OFFSETS => self.kafka_list_offsets(version, &mut body)?,
+            api_key::OFFSET_COMMIT => self.kafka_offset_commit(version, &mut body)?,
+            api_key::OFFSET_FETCH => self.kafka_offset_fetch(version, &mut body)?,
+            api_key::FIND_COORDINATOR => self.kafka_find_coordinator(version, &mut body, advertised)?,
+            _ => unreachable!("kafka::supports only allows the keys above"),
+        };
+
+        Ok(Some(response))
+    }
+
+    fn kafka_api_versions(version: i16, error: i16) -> Bytes {
+        let mut out = Encoder::new();
+        out.i16(error).array(&kafka::SUPPORTED_APIS, |out, (key, min, max)| {
+            out.i16(*key).i16(*min).i16(*max);
+        });
+        if version >= 1 {
+            out.i32(0); // throttle_time_ms
+        }
+        out.finish()
+    }
+
+    fn kafka_node_id(&self) -> i32 {
+        self.broker_id as i32
+    }
+
+    // Why this broker can't serve a Kafka partition, None when it is the leader
+    fn kafka_partition_error(&self, topic: &str, index: i32) -> Option<i16> {
+        let partitions = self.partition_count(topic).unwrap_or(0);
+        if index < 0 || index as u32 >= partitions {
+            Some(error_code::UNKNOWN_TOPIC_OR_PARTITION)
+        } else if !self.hosts(topic, index as u32) || self.shutdown.is_requested() {
+            Some(error_code::NOT_LEADER_OR_FOLLOWER)
+        } else {
+            None
+        }
+    }
+
+    async fn kafka_metadata(
+        &self,
+        version: i16,
+        body: &mut Decoder,
+        advertised: SocketAddr,
+    ) -> DecodeResult<Bytes> {
+        let requested = body.nullable_array(|decoder| decoder.string())?;
+        let auto_create = if version >= 4 { body.bool()? } else { true };
+
+        // v0 asks for every topic with an empty list, later versions with null
+        let names = match requested {
+            Some(names) if !(version == 0 && names.is_empty()) => {
+                if auto_create {
+                    for name in &names {
+                        self.ensure_topic(name);
+                    }
+                }
+                names
+            }
+            _ => self.topics.read().keys().cloned().collect(),
+        };
+
+        let node_id = self.kafka_node_id();
+        let mut out = Encoder::new();
+
+        if version >= 3 {
+            out.i32(0); // throttle_time_ms
+        }
+
+        out.array(&[advertised], |out, addr| {
+            out.i32(node_id).string(&addr.ip().to_string()).i32(addr.port() as i32);
+            if version >= 1 {
+                out.nullable_string(None); // rack
+            }
+        });
+        if version >= 2 {
+            out.nullable_string(None); // cluster_id
+        }
+        if version >= 1 {
+            out.i32(node_id); // controller_id
+        }
+
+        out.array(&names, |out, name| {
+            let Some(count) = self.partition_count(name) else {
+                out.i16(error_code::UNKNOWN_TOPIC_OR_PARTITION).string(name);
+                if version >= 1 {
+                    out.bool(false);
+                }
+                out.i32(0);
+                return;
+            };
+
+            out.i16(error_code::NONE).string(name);
+            if version >= 1 {
+                out.bool(false); // is_internal
+            }
+
+            let partitions: Vec<i32> = (0..count as i32).collect();
+            out.array(&partitions, |out, index| {
+                if self.hosts(name, *index as u32) && !self.shutdown.is_requested() {
+                    out.i16(error_code::NONE)
+                        .i32(*index)
+                        .i32(node_id)
+                        .array(&[node_id], |out, id| {
+                            out.i32(*id);
+                        })
+                        .array(&[node_id], |out, id| {
+                            out.i32(*id);
+                        });
+                } else {
+                    out.i16(error_code::LEADER_NOT_AVAILABLE)
+                        .i32(*index)
+                        .i32(-1)
+                        .i32(0)
+                        .i32(0);
+                }
+            });
+        });
+
+        Ok(out.finish())
+    }
+
+    async fn kafka_produce(&self, version: i16, body: &mut Decoder) -> DecodeResult<Option<Bytes>> {
+        let transactional_id = body.nullable_string()?;
+        let acks = body.i16()?;
+        let timeout_ms = body.i32()?;
+        let topics = topics(body, |decoder| decoder.nullable_bytes())?;
+
+        let mut results = Vec::with_capacity(topics.len());
+        for topic in topics {
+            self.ensure_topic(&topic.name);
+
+            let mut partitions = Vec::with_capacity(topic.partitions.len());
+            for partition in topic.partitions {
+                // Kafka clients can't begin transactions here, their batches would
+                // never be committed or aborted
+                let result = match self.kafka_partition_error(&topic.name, partition.index) {
+                    _ if transactional_id.is_some() => (error_code::INVALID_TXN_STATE, -1),
+                    Some(error) => (error, -1),
+                    None => self.kafka_append(
+                        &topic.name,
+                        partition.index,
+                        partition.request.unwrap_or_default(),
+                    ),
+                };
+                partitions.push((partition.index, result));
+            }
+            results.push((topic.name, partitions));
+        }
+
+        self.appended.notify_waiters();
+
+        if acks == 0 {
+            return Ok(None);
+        }
+        // acks=-1 waits for every in-sync replica to have the batches
+        if acks == -1 {
+            let deadline = Instant::now() + Duration::from_millis(timeout_ms.max(0) as u64);
+            for (name, partitions) in &mut results {
+                for (index, (error, _)) in partitions.iter_mut().filter(|(_, (error, _))| *error == error_code::NONE) {
+                    let end = self.storage.log_end_offset(name, *index).unwrap_or(0);
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if self.await_in_sync_replicas(name, *index as u32, end, timeout).await.is_err() {
+                        *error = error_code::REQUEST_TIMED_OUT;
+                    }
+                }
+            }
+        }
+
+        let log_start = |topic: &str, index: i32| {
+            self.storage
+                .log_offsets(topic, index)
+                .map(|(start, _)| start)
+                .unwrap_or(-1)
+        };
+
+        let mut out = Encoder::new();
+        out.array(&results, |out, (name, partitions)| {
+            out.string(name).array(partitions, |out, (index, (error, base_offset))| {
+                out.i32(*index).i16(*error).i64(*base_offset).i64(-1); // log_append_time_ms
+                if version >= 5 {
+                    out.i64(log_start(name, *index));
+                }
+            });
+        });
+        out.i32(0); // throttle_time_ms
+
+        Ok(Some(out.finish()))
+    }
+
+    // Store the batches of a produce as they are, returning (error code, base offset)
+    fn kafka_append(&self, topic: &str, partition: i32, records: Bytes) -> (i16, i64) {
+        let batches = match EncodedBatch::split_all(records) {
+            Ok(batches) => batches,
+            Err(e) => {
+                eprintln!("Rejected Kafka produce to {}: {}", topic, e);
+                return (error_code::CORRUPT_MESSAGE, -1);
+            }
+        };
+        if batches.is_empty() {
+            return (error_code::INVALID_REQUEST, -1);
+        }
+
+        // Check everything before storing anything
+        for batch in &batches {
+            if let Err(e) = batch.compression() {
+                eprintln!("Rejected Kafka produce to {}: {}", topic, e);
+                return (error_code::UNSUPPORTED_COMPRESSION_TYPE, -1);
+            }
+            if let Err(e) = batch.decode() {
+                eprintln!("Rejected Kafka produce to {}: {}", topic, e);
+                return (error_code::CORRUPT_MESSAGE, -1);
+            }
+            if batch.is_control() {
+                return (error_code::INVALID_REQUEST, -1);
+            }
+            if batch.is_transactional() {
+                return (error_code::INVALID_TXN_STATE, -1);
+            }
+        }
+
+        let mut base_offset = None;
+        for batch in batches {
+            let (messages, bytes) = (batch.offset_count() as u64, batch.as_bytes().len() as u64);
+            // Batches carrying a producer id are checked and deduplicated like native produces
+            let appended = self.producers.append_once(topic, partition as u32, &batch, || {
+                self.storage.append_batch(
+                    topic,
+                    partition,
+                    batch.offset_count(),
+                    |offset| batch.with_base_offset(offset).into_bytes(),
+                )
+            });
+            match appended {
+                Ok(Some(Appended::Stored(offset))) => {
+                    self.metrics.record_in(messages, bytes);
+                    base_offset.get_or_insert(offset);
+                }
+                Ok(Some(Appended::Duplicate(offset))) => {
+                    base_offset.get_or_insert(offset);
+                }
+                Ok(None) => return (error_code::UNKNOWN_TOPIC_OR_PARTITION, -1),
+                Err(error) => {
+                    eprintln!("Rejected Kafka produce to {}: {}", topic, error.message);
+                    return (Self::kafka_error_code(&error), -1);
+                }
+            }
+        }
+
+        (error_code::NONE, base_offset.unwrap_or(-1))
+    }
+
+    fn kafka_error_code(error: &BrokerError) -> i16 {
+        match error.code {
+            ErrorCode::OutOfOrderSequence => error_code::OUT_OF_ORDER_SEQUENCE_NUMBER,
+            ErrorCode::ProducerFenced => error_code::INVALID_PRODUCER_EPOCH,
+            _ => error_code::UNKNOWN_SERVER_ERROR,
+        }
+    }
+
+    async fn kafka_fetch(&self, version: i16, body: &mut Decoder) -> DecodeResult<Bytes> {
+        let _replica_id = body.i32()?;
+        let max_wait = Duration::from_millis(body.i32()?.max(0) as u64);
+        let min_bytes = body.i32()?.max(0) as usize;
+        let max_bytes = body.i32()?.max(0) as usize;
+        let read_committed = body.i8()? == READ_COMMITTED;
+        if version >= 7 {
+            let _session_id = body.i32()?;
+            let _session_epoch = body.i32()?;
+        }
+        let topics = topics(body, |decoder| {
+            if version >= 9 {
+                let _current_leader_epoch = decoder.i32()?;
+            }
+            let fetch_offset = decoder.i64()?;
+            if version >= 5 {
+                let _log_start_offset = decoder.i64()?;
+            }
+            let partition_max_bytes = decoder.i32()?;
+            Ok(FetchPartition {
+                fetch_offset,
+                partition_max_bytes,
+            })
+        })?;
+        // forgotten_topics_data and rack_id only matter to fetch sessions, which we don't keep
+
+        // Hold the response back until min_bytes arrive or max_wait is up
+        let deadline = Instant::now() + max_wait;
+        loop {
+            let appended = self.appended.notified();
+            tokio::pin!(appended);
+            appended.as_mut().enable();
+
+            let (response, size, messages) = self.kafka_fetch_response(version, &topics, max_bytes, read_committed);
+            if size >= min_bytes.max(1) || Instant::now() >= deadline {
+                self.metrics.record_out(messages, size as u64);
+                return Ok(response);
+            }
+            if tokio::time::timeout_at(deadline, appended).await.is_err() {
+                let (response, size, messages) = self.kafka_fetch_response(version, &topics, max_bytes, read_committed);
+                self.metrics.record_out(messages, size as u64);
+                return Ok(response);
+            }
+        }
+    }
+
+    fn kafka_fetch_response(
+        &self,
+        version: i16,
+        topics: &[Topic<FetchPartition>],
+        max_bytes: usize,
+        read_committed: bool,
+    ) -> (Bytes, usize, u64) {
+        let mut size = 0;
+        let mut message_count = 0;
+        let mut out = Encoder::new();
+
+        out.i32(0); // throttle_time_ms
+        if version >= 7 {
+            out.i16(error_code::NONE).i32(0); // session_id, sessions are not supported
+        }
+
+        out.array(topics, |out, topic| {
+            out.string(&topic.name).array(&topic.partitions, |out, partition| {
+                let offsets = match self.kafka_partition_error(&topic.name, partition.index) {
+                    Some(error) => Err(error),
+                    None => self
+                        .storage
+                        .log_offsets(&topic.name, partition.index)
+                        .ok_or(error_code::UNKNOWN_TOPIC_OR_PARTITION),
+                };
+
+                let stable = self
+                    .storage
+                    .last_stable_offset(&topic.name, partition.index)
+                    .unwrap_or(-1);
+                let mut aborted = None;
+                let (error, high_watermark, log_start, records) = match offsets {
+                    Err(error) => (error, -1, -1, None),
+                    Ok((start, end)) if partition.request.fetch_offset < start
+                        || partition.request.fetch_offset > end =>
+                    {
+                        (error_code::OFFSET_OUT_OF_RANGE, end, start, None)
+                    }
+                    Ok((start, end)) => {
+                        // Always return at least one batch, even past the limits,
+                        // or a client can never get past a large one
+                        let limit = (partition.request.partition_max_bytes.max(0) as usize)
+                            .min(max_bytes.saturating_sub(size));
+                        let mut messages = self
+                            .storage
+                            .read(&topic.name, partition.index, partition.request.fetch_offset)
+                            .unwrap_or_default();
+                        // Kafka clients drop aborted records and markers themselves,
+                        // they only need to be kept from open transactions
+                        if read_committed {
+                            messages.retain(|message| message.offset < stable);
+                            aborted = Some(self.storage.aborted_transactions(
+                                &topic.name,
+                                partition.index,
+                                partition.request.fetch_offset,
+                                stable,
+                            ));
+                        }
+                        let records = Self::record_batches(messages, limit).map(|(records, count, _)| {
+                            size += records.len();
+                            message_count += count;
+                            records
+                        });
+                        (error_code::NONE, end, start, records)
+                    }
+                };
+
+                out.i32(partition.index).i16(error).i64(high_watermark).i64(stable);
+                if version >= 5 {
+                    out.i64(log_start);
+                }
+                match &aborted {
+                    Some(aborted) => out.array(aborted, |out, aborted| {
+                        out.i64(aborted.producer_id).i64(aborted.first_offset);
+                    }),
+                    None => out.i32(-1),
+                };
+                if version >= 11 {
+                    out.i32(-1); // preferred_read_replica
+                }
+                out.nullable_bytes(records.as_deref());
+            });
+        });
+
+        (out.finish(), size, message_count)
+    }
+
+    fn kafka_list_offsets(&self, version: i16, body: &mut Decoder) -> DecodeResult<Bytes> {
+        const LATEST: i64 = -1;
+        const EARLIEST: i64 = -2;
+
+        let _replica_id = body.i32()?;
+        if version >= 2 {
+            let _isolation_level = body.i8()?;
+        }
+        let topics = topics(body, |decoder| {
+            if version >= 4 {
+                let _current_leader_epoch = decoder.i32()?;
+            }
+            decoder.i64()
+        })?;
+
+        let mut out = Encoder::new();
+        if version >= 2 {
+            out.i32(0); // throttle_time_ms
+        }
+
+        out.array(&topics, |out, topic| {
+            out.string(&topic.name).array(&topic.partitions, |out, partition| {
+                let offsets = match self.kafka_partition_error(&topic.name, partition.index) {
+                    Some(error) => Err(error),
+                    None => self
+                        .storage
+                        .log_offsets(&topic.name, partition.index)
+                        .ok_or(error_code::UNKNOWN_TOPIC_OR_PARTITION),
+                };
+
+                let (error, timestamp, offset) = match (offsets, partition.request) {
+                    (Err(error), _) => (error, -1, -1),
+                    (Ok((_, end)), LATEST) => (error_code::NONE, -1, end),
+                    (Ok((start, _)), EARLIEST) => (error_code::NONE, -1, start),
+                    (Ok((_, end)), timestamp) => {
+                        let time = UNIX_EPOCH + Duration::from_millis(timestamp.max(0) as u64);
+                        match self.storage.find_by_timestamp(&topic.name, partition.index, time) {
+                            Some(message) => (error_code::NONE, millis(message.timestamp), message.offset),
+                            // Nothing that recent, Kafka answers with the log end
+                            None => (error_code::NONE, -1, end),
+                        }
+                    }
+                };
+
+                out.i32(partition.index).i16(error).i64(timestamp).i64(offset);
+                if version >= 4 {
+                    out.i32(-1); // leader_epoch
+                }
+            });
+        });
+
+        Ok(out.finish())
+    }
+
+    // Group offsets are kept as the storage consumer offsets of a consumer named after the group
+    fn kafka_offset_commit(&self, version: i16, body: &mut Decoder) -> DecodeResult<Bytes> {
+        let group_id = body.string()?;
+        let _generation_id = body.i32()?;
+        let _member_id = body.string()?;
+        if version <= 4 {
+            let _retention_time_ms = body.i64()?;
+        }
+        if version >= 7 {
+            let _group_instance_id = body.nullable_string()?;
+        }
+        let topics = topics(body, |decoder| {
+            let offset = decoder.i64()?;
+            if version >= 6 {
+                let _committed_leader_epoch = decoder.i32()?;
+            }
+            let _metadata = decoder.nullable_string()?;
+            Ok(offset)
+        })?;
+
+        let mut out = Encoder::new();
+        if version >= 3 {
+            out.i32(0); // throttle_time_ms
+        }
+
+        out.array(&topics, |out, topic| {
+            out.string(&topic.name).array(&topic.partitions, |out, partition| {
+                let error = match self.kafka_partition_error(&topic.name, partition.index) {
+                    Some(error) => error,
+                    None => {
+                        // Kafka commits the next offset to read, we keep the last one read
+                        self.storage.update_consumer_offset(
+                            &group_id,
+                            &topic.name,
+                            partition.index,
+                            partition.request.max(0) - 1,
+                        );
+                        error_code::NONE
+                    }
+                };
+                out.i32(partition.index).i16(error);
+            });
+        });
+
+        Ok(out.finish())
+    }
+
+    fn kafka_offset_fetch(&self, version: i16, body: &mut Decoder) -> DecodeResult<Bytes> {
+        let group_id = body.string()?;
+        let requested = body.nullable_array(|decoder| {
+            let name = decoder.string()?;
+            let partitions = decoder.array(|decoder| decoder.i32())?;
+            Ok((name, partitions))
+        })?;
+
+        // A null topic list (v2+) asks for everything the group has committed
+        let topics = requested.unwrap_or_else(|| {
+            let mut topics: Vec<(String, Vec<i32>)> = Vec::new();
+            for (topic, partition, _) in self.storage.consumer_offsets(&group_id) {
+                match topics.iter_mut().find(|(name, _)| *name == topic) {
+                    Some((_, partitions)) => partitions.push(partition),
+                    None => topics.push((topic, vec![partition])),
+                }
+            }
+            topics
+        });
+
+        let mut out = Encoder::new();
+        if version >= 3 {
+            out.i32(0); // throttle_time_ms
+        }
+
+        out.array(&topics, |out, (name, partitions)| {
+            out.string(name).array(partitions, |out, index| {
+                // No committed offset is -1 with no error. What we keep is the
+                // last offset read, Kafka wants the next one.
+                let offset = self
+                    .storage
+                    .get_consumer_offset(&group_id, name, *index)
+                    .map_or(-1, |last_read| last_read + 1);
+                out.i32(*index).i64(offset);
+                if version >= 5 {
+                    out.i32(-1); // committed_leader_epoch
+                }
+                out.nullable_string(Some("")).i16(error_code::NONE);
+            });
+        });
+        if version >= 2 {
+            out.i16(error_code::NONE);
+        }
+
+        Ok(out.finish())
+    }
+
+    // Every group is coordinated by the broker that was asked
+    fn kafka_find_coordinator(
+        &self,
+        version: i16,
+        body: &mut Decoder,
+        advertised: SocketAddr,
+    ) -> DecodeResult<Bytes> {
+        let _key = body.string()?;
+        if version >= 1 {
+            let _key_type = body.i8()?;
+        }
+
+        let mut out = Encoder::new();
+        if version >= 1 {
+            out.i32(0); // throttle_time_ms
+        }
+        out.i16(error_code::NONE);
+        if version >= 1 {
+            out.nullable_string(None); // error_message
+        }
+        out.i32(self.kafka_node_id())
+            .string(&advertised.ip().to_string())
+            .i32(advertised.port() as i32);
+
+        Ok(out.finish())
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use rafka_protocol::{MetricsResponse, PartitionStats};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::Broker;
+
+// Requests bigger than this are not something Prometheus would send
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+impl Broker {
+    // Just enough HTTP for a Prometheus scraper: GET /metrics, one request
+    // per connection
+    pub(super) async fn serve_prometheus(broker: Arc<Self>, listener: TcpListener) {
+        loop {
+            let socket = match listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    eprintln!("Error accepting metrics scrape: {}", e);
+                    continue;
+                }
+            };
+
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_scrape(broker, socket).await {
+                    eprintln!("Error answering metrics scrape: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_scrape(broker: Arc<Self>, mut socket: TcpStream) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = socket.read(&mut buf).await?;
+            if read == 0 || request.len() + read > MAX_REQUEST_BYTES {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..read]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+        let (status, body) = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", render(&broker.metrics_report())),
+            _ => ("404 Not Found", String::from("Only GET /metrics is served here\n")),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown().await
+    }
+}
+
+type PartitionGauge = fn(&PartitionStats) -> i64;
+
+// Prometheus text exposition format. Rates are left out, Prometheus works
+// them out from the counters itself.
+pub(crate) fn render(metrics: &MetricsResponse) -> String {
+    let mut out = String::new();
+    let broker = format!("broker=\"{}\"", metrics.broker_id);
+
+    let mut single = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{}{{{}}} {}", name, broker, value);
+    };
+    single("rafka_uptime_seconds", "gauge", "Time since the broker started.", metrics.uptime_secs);
+    single("rafka_messages_in_total", "counter", "Messages published to this broker.", metrics.messages_in);
+    single("rafka_bytes_in_total", "counter", "Bytes of record batches published to this broker.", metrics.bytes_in);
+    single("rafka_messages_out_total", "counter", "Messages sent to consumers.", metrics.messages_out);
+    single("rafka_bytes_out_total", "counter", "Bytes of record batches sent to consumers.", metrics.bytes_out);
+    single("rafka_connected_clients", "gauge", "Open client connections.", metrics.connected_clients);
+
+    let partition_gauges: [(&str, &str, PartitionGauge); 3] = [
+        ("rafka_partition_log_size_bytes", "Bytes retained by the partition.", |p| p.size_bytes as i64),
+        ("rafka_partition_high_watermark", "Offset the next message appended will get.", |p| p.high_watermark),
+        ("rafka_partition_log_start_offset", "Oldest offset still retained.", |p| p.log_start_offset),
+    ];
+    for (name, help, value) in partition_gauges {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for partition in &metrics.partitions {
+            let _ = writeln!(
+                out,
+                "{}{{{},topic=\"{}\",partition=\"{}\"}} {}",
+                name,
+                broker,
+                escape(&partition.topic),
+                partition.partition,
+                value(partition)
+            );
+        }
+    }
+
+    let _ = writeln!(out, "# HELP rafka_consumer_lag Messages appended after the consumer's committed offset.");
+    let _ = writeln!(out, "# TYPE rafka_consumer_lag gauge");
+    for lag in &metrics.consumer_lag {
+        let _ = writeln!(
+            out,
+            "rafka_consumer_lag{{{},consumer=\"{}\",topic=\"{}\",partition=\"{}\"}} {}",
+            broker,
+            escape(&lag.consumer_id),
+            escape(&lag.topic),
+            lag.partition,
+            lag.lag
+        );
+    }
+
+    let name = "rafka_request_latency_seconds";
+    let _ = writeln!(out, "# HELP {} Time taken to answer requests.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for histogram in &metrics.request_latency {
+        let labels = format!("{},api=\"{}\"", broker, histogram.api_key);
+        for bucket in &histogram.buckets {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bucket.le_secs, bucket.count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum_secs);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+    }
+
+    out
+}
+
+// Label values may not hold raw backslashes, quotes or newlines
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,470 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_core::partitioner::PartitionStrategy;
+use rafka_protocol::{BrokerMessage, BrokerResponse, MetadataEntry, MetadataRecord, QuorumDescription, TopicConfig};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use super::Broker;
+use crate::raft::{Committed, Raft, Refused};
+
+// How often the quorum looks for something to send, entries are sent as soon
+// as they are appended regardless
+const QUORUM_TICK: Duration = Duration::from_millis(10);
+// How long a metadata change may take to be committed and applied
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// This broker's part in the metadata quorum, see with_controllers
+pub(super) struct Quorum {
+    pub(super) raft: Raft,
+    // Changes proposed here as leader, by log index. Answered once applied.
+    proposals: Mutex<HashMap<u64, Proposal>>,
+    // How far the broker's metadata has got with the log
+    applied: watch::Sender<u64>,
+    // Topics to create through the quorum, see ensure_topic
+    pub(super) topic_requests: mpsc::UnboundedSender<String>,
+}
+
+struct Proposal {
+    term: u64,
+    applied: oneshot::Sender<Result<(), BrokerError>>,
+}
+
+impl Quorum {
+    pub(super) fn new(raft: Raft) -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (topic_requests, requested) = mpsc::unbounded_channel();
+        let quorum = Self {
+            raft,
+            proposals: Mutex::new(HashMap::new()),
+            applied: watch::channel(0).0,
+            topic_requests,
+        };
+        (quorum, requested)
+    }
+
+    // Whoever proposed the entry learns how applying it went, unless another
+    // leader's entry took its place
+    fn resolve(&self, entry: &MetadataEntry, result: Result<(), BrokerError>) {
+        if let Some(proposal) = self.proposals.lock().remove(&entry.index) {
+            let result = match proposal.term == entry.term {
+                true => result,
+                false => Err(Broker::not_committed()),
+            };
+            let _ = proposal.applied.send(result);
+        }
+    }
+}
+
+impl Broker {
+    // Sends the leader's entries and heartbeats, or a candidate's vote
+    // requests, for as long as the broker runs
+    pub(super) async fn run_quorum(broker: Arc<Self>) {
+        let Some(quorum) = &broker.quorum else {
+            return;
+        };
+        let brokers: Vec<u32> = (0..broker.broker_count).collect();
+        let mut interval = tokio::time::interval(QUORUM_TICK);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = quorum.raft.appended.notified() => {}
+            }
+            for (to, message) in quorum.raft.poll(&brokers) {
+                tokio::spawn(Self::send_to_quorum(broker.clone(), to, message));
+            }
+        }
+    }
+
+    // A request that takes longer than an election timeout is as good as lost
+    async fn send_to_quorum(broker: Arc<Self>, to: u32, message: BrokerMessage) {
+        let Some(quorum) = &broker.quorum else {
+            return;
+        };
+        let Some(addr) = broker.peers.get(to as usize) else {
+            quorum.raft.unreachable(to);
+            return;
+        };
+        let request = async {
+            let connection = broker.peer_connection(to, addr).await?;
+            connection.request(message).await
+        };
+        match tokio::time::timeout(broker.election_timeout, request).await {
+            Ok(Ok(BrokerResponse::VoteResult { term, granted })) => quorum.raft.vote_received(to, term, granted),
+            Ok(Ok(BrokerResponse::EntriesAppended { term, success, match_index })) => {
+                quorum.raft.entries_appended(to, term, success, match_index)
+            }
+            Ok(Err(_)) => {
+                // Reconnect next time
+                broker.peer_connections.lock().await.remove(&to);
+                quorum.raft.unreachable(to);
+            }
+            _ => quorum.raft.unreachable(to),
+        }
+    }
+
+    // Raft's own requests, from the other brokers of the quorum
+    pub(super) fn raft_request(&self, message: BrokerMessage) -> BrokerResponse {
+        let Some(quorum) = &self.quorum else {
+            return BrokerResponse::Error(self.no_controllers());
+        };
+        let raft = &quorum.raft;
+        match message {
+            BrokerMessage::RequestVote { term, candidate_id, last_log_index, last_log_term } => {
+                let (term, granted) = raft.request_vote(term, candidate_id, last_log_index, last_log_term);
+                BrokerResponse::VoteResult { term, granted }
+            }
+            BrokerMessage::AppendEntries { term, leader_id, prev_log_index, prev_log_term, entries, leader_commit } => {
+                let (term, success, match_index) =
+                    raft.append_entries(term, leader_id, prev_log_index, prev_log_term, entries, leader_commit);
+                BrokerResponse::EntriesAppended { term, success, match_index }
+            }
+            BrokerMessage::InstallSnapshot { term, leader_id, snapshot } => {
+                let (term, success, match_index) = raft.install_snapshot(term, leader_id, snapshot);
+                BrokerResponse::EntriesAppended { term, success, match_index }
+            }
+            other => BrokerResponse::Error(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                format!("{} is not a Raft request", other.api_key()),
+            )),
+        }
+    }
+
+    // Applies committed entries to the broker's metadata as they come, and
+    // snapshots the metadata once the log gets long
+    pub(super) async fn apply_metadata(broker: Arc<Self>) {
+        let Some(quorum) = &broker.quorum else {
+            return;
+        };
+        let mut applied = 0;
+        loop {
+            while let Some(committed) = quorum.raft.committed_since(applied) {
+                match committed {
+                    Committed::Snapshot(snapshot) => {
+                        broker.restore_metadata(snapshot.records);
+                        applied = snapshot.last_index;
+                        // Whether they made it is anyone's guess
+                        quorum.proposals.lock().retain(|index, _| *index > applied);
+                    }
+                    Committed::Entries(entries) => {
+                        for entry in entries {
+                            let result = broker.apply_metadata_record(&entry.record);
+                            quorum.resolve(&entry, result);
+                            applied = entry.index;
+                        }
+                    }
+                }
+                quorum.applied.send_replace(applied);
+            }
+            if quorum.raft.log_len() >= broker.metadata_snapshot_entries {
+                quorum.raft.compact(applied, broker.metadata_records());
+            }
+            quorum.raft.committed.notified().await;
+        }
+    }
+
+    // Topics that ensure_topic was asked for, created one at a time so a
+    // burst of requests for the same topic creates it once
+    pub(super) async fn create_requested_topics(broker: Arc<Self>, mut requested: mpsc::UnboundedReceiver<String>) {
+        while let Some(topic) = requested.recv().await {
+            if broker.partition_count(&topic).is_some() {
+                continue;
+            }
+            let record = MetadataRecord::CreateTopic {
+                topic: topic.clone(),
+                partitioner: PartitionStrategy::default(),
+                config: TopicConfig::default(),
+                replicas: broker.assign_replicas(broker.default_partitions),
+            };
+            match broker.commit_metadata(record).await {
+                Err(error) if error.code != ErrorCode::TopicAlreadyExists => {
+                    eprintln!("Could not create topic {}: {}", topic, error);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Makes a metadata change and returns once this broker has applied it.
+    // Without controllers that is right away, with them the change is
+    // handed to the quorum leader, waiting for one to be elected if need be.
+    pub(super) async fn commit_metadata(&self, record: MetadataRecord) -> Result<(), BrokerError> {
+        let Some(quorum) = &self.quorum else {
+            return self.apply_metadata_record(&record);
+        };
+        let deadline = tokio::time::Instant::now() + PROPOSAL_TIMEOUT;
+        let leader = loop {
+            match quorum.raft.leader() {
+                Some(leader) => break leader,
+                None if tokio::time::Instant::now() >= deadline => return Err(self.not_controller(None)),
+                None => tokio::time::sleep(QUORUM_TICK).await,
+            }
+        };
+        if leader == self.broker_id {
+            return self.propose_metadata(record).await.map(|_| ());
+        }
+
+        let index = self.forward_proposal(leader, record).await?;
+        let mut applied = quorum.applied.subscribe();
+        let caught_up = tokio::time::timeout_at(deadline, applied.wait_for(|applied| *applied >= index))
+            .await
+            .is_ok_and(|waited| waited.is_ok());
+        match caught_up {
+            true => Ok(()),
+            false => Err(BrokerError::new(
+                ErrorCode::NotController,
+                format!("Committed as entry {} but broker {} has not got that far yet", index, self.broker_id),
+            )),
+        }
+    }
+
+    // Appends the record to the log as the quorum leader, answering with its
+    // index once it is applied here
+    pub(super) async fn propose_metadata(&self, record: MetadataRecord) -> Result<u64, BrokerError> {
+        let Some(quorum) = &self.quorum else {
+            return Err(self.no_controllers());
+        };
+        self.check_proposal(&record)?;
+        let (index, applied) = {
+            // Held while proposing so the entry can't be applied before it is listed
+            let mut proposals = quorum.proposals.lock();
+            let (index, term) = quorum.raft.propose(record).map_err(|refused| match refused {
+                Refused::NotLeader(leader) => self.not_controller(leader),
+                Refused::Invalid(error) => error,
+            })?;
+            let (sender, applied) = oneshot::channel();
+            proposals.insert(index, Proposal { term, applied: sender });
+            (index, applied)
+        };
+        match tokio::time::timeout(PROPOSAL_TIMEOUT, applied).await {
+            Ok(Ok(result)) => result.map(|()| index),
+            Ok(Err(_)) => Err(Self::not_committed()),
+            Err(_) => {
+                quorum.proposals.lock().remove(&index);
+                Err(Self::not_committed())
+            }
+        }
+    }
+
+    async fn forward_proposal(&self, leader: u32, record: MetadataRecord) -> Result<u64, BrokerError> {
+        let unreachable = |reason: String| {
+            BrokerError::new(
+                ErrorCode::NotController,
+                format!("Could not reach broker {}, the quorum leader: {}", leader, reason),
+            )
+        };
+        let addr = self
+            .peers
+            .get(leader as usize)
+            .ok_or_else(|| unreachable("its address is unknown".to_string()))?;
+        let connection = self.peer_connection(leader, addr).await.map_err(|e| unreachable(e.to_string()))?;
+        match connection.request(BrokerMessage::ProposeMetadata { record }).await {
+            Ok(BrokerResponse::MetadataCommitted { index }) => Ok(index),
+            Ok(BrokerResponse::Error(error)) => Err(error),
+            Ok(other) => Err(unreachable(format!("unexpected response {:?}", other))),
+            Err(e) => {
+                // Reconnect next time
+                self.peer_connections.lock().await.remove(&leader);
+                Err(unreachable(e.to_string()))
+            }
+        }
+    }
+
+    // Adds or removes one controller, answering with the controllers after
+    pub(super) async fn change_controllers(&self, broker_id: u32, add: bool) -> Result<Vec<u32>, BrokerError> {
+        let Some(quorum) = &self.quorum else {
+            return Err(self.no_controllers());
+        };
+        if self.peers.get(broker_id as usize).is_none() {
+            return Err(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                format!("Broker {} is not one of the cluster's brokers", broker_id),
+            ));
+        }
+        let mut controllers = quorum.raft.controllers();
+        if controllers.contains(&broker_id) == add {
+            let state = if add { "already" } else { "not" };
+            return Err(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                format!("Broker {} is {} a controller", broker_id, state),
+            ));
+        }
+        if add {
+            controllers.push(broker_id);
+            controllers.sort_unstable();
+        } else {
+            controllers.retain(|controller| *controller != broker_id);
+        }
+        let record = MetadataRecord::SetControllers {
+            controllers: controllers.clone(),
+        };
+        self.commit_metadata(record).await?;
+        Ok(controllers)
+    }
+
+    pub(super) fn describe_quorum(&self) -> Result<QuorumDescription, BrokerError> {
+        match &self.quorum {
+            Some(quorum) => Ok(quorum.raft.describe(*quorum.applied.borrow())),
+            None => Err(self.no_controllers()),
+        }
+    }
+
+    // The same on every broker, the log being the same everywhere
+    pub(super) fn apply_metadata_record(&self, record: &MetadataRecord) -> Result<(), BrokerError> {
+        match record {
+            MetadataRecord::CreateTopic { topic, partitioner, config, replicas } => {
+                self.check_replicas(replicas)?;
+                let mut topics = self.topics.write();
+                if topics.contains_key(topic) {
+                    return Err(BrokerError::new(
+                        ErrorCode::TopicAlreadyExists,
+                        format!("Topic {} already exists", topic),
+                    ));
+                }
+                self.add_topic(&mut topics, topic, replicas.clone(), *partitioner, *config);
+                Ok(())
+            }
+            MetadataRecord::DeleteTopic { topic } => match self.delete_topic(topic) {
+                true => Ok(()),
+                false => Err(Self::unknown_topic(topic)),
+            },
+            MetadataRecord::CreateAcl(acl) => {
+                let mut acls = self.acls.write();
+                if !acls.contains(acl) {
+                    acls.push(acl.clone());
+                }
+                Ok(())
+            }
+            MetadataRecord::DeleteAcl(acl) => {
+                let mut acls = self.acls.write();
+                let before = acls.len();
+                acls.retain(|kept| kept != acl);
+                match acls.len() < before {
+                    true => Ok(()),
+                    false => Err(BrokerError::new(ErrorCode::MalformedRequest, format!("No such ACL: {:?}", acl))),
+                }
+            }
+            // Only the log itself cares about these
+            MetadataRecord::LeaderChange { .. } | MetadataRecord::SetControllers { .. } => Ok(()),
+        }
+    }
+
+    // Checked again when applying, but a bad record is better kept out of the log
+    fn check_proposal(&self, record: &MetadataRecord) -> Result<(), BrokerError> {
+        match record {
+            MetadataRecord::CreateTopic { replicas, .. } => self.check_replicas(replicas),
+            MetadataRecord::SetControllers { controllers } => {
+                match controllers.iter().find(|controller| self.peers.get(**controller as usize).is_none()) {
+                    Some(unknown) => Err(BrokerError::new(
+                        ErrorCode::MalformedRequest,
+                        format!("Broker {} is not one of the {} brokers", unknown, self.peers.len()),
+                    )),
+                    None => Ok(()),
+                }
+            }
+            MetadataRecord::LeaderChange { .. } => Err(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                "Leader changes are only recorded by the new leader",
+            )),
+            MetadataRecord::DeleteTopic { .. } | MetadataRecord::CreateAcl(_) | MetadataRecord::DeleteAcl(_) => Ok(()),
+        }
+    }
+
+    // Every partition needs at least one replica, on brokers that exist and
+    // each at most once. The first one leads.
+    fn check_replicas(&self, replicas: &[Vec<u32>]) -> Result<(), BrokerError> {
+        if replicas.is_empty() {
+            return Err(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                "A topic needs at least one partition",
+            ));
+        }
+        for (partition, brokers) in replicas.iter().enumerate() {
+            let distinct: HashSet<&u32> = brokers.iter().collect();
+            let valid = !brokers.is_empty()
+                && distinct.len() == brokers.len()
+                && brokers.iter().all(|broker| *broker < self.broker_count);
+            if !valid {
+                return Err(BrokerError::new(
+                    ErrorCode::MalformedRequest,
+                    format!(
+                        "Partition {} can't be replicated to brokers {:?} of {}",
+                        partition, brokers, self.broker_count
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The broker's metadata as records that rebuild it, for snapshots
+    fn metadata_records(&self) -> Vec<MetadataRecord> {
+        let topics = self.topics.read();
+        let mut names: Vec<&String> = topics.keys().collect();
+        names.sort();
+        let mut records: Vec<MetadataRecord> = names
+            .into_iter()
+            .map(|topic| {
+                let state = &topics[topic];
+                MetadataRecord::CreateTopic {
+                    topic: topic.clone(),
+                    partitioner: state.strategy,
+                    config: state.config,
+                    replicas: state.replicas.clone(),
+                }
+            })
+            .collect();
+        records.extend(self.acls.read().iter().cloned().map(MetadataRecord::CreateAcl));
+        records
+    }
+
+    // Makes the broker's metadata what a snapshot says. Topics it doesn't
+    // have, or has differently, were deleted in the entries it stands for.
+    fn restore_metadata(&self, records: Vec<MetadataRecord>) {
+        let stale: Vec<String> = self
+            .topics
+            .read()
+            .iter()
+            .filter(|(name, state)| {
+                !records.iter().any(|record| {
+                    matches!(record, MetadataRecord::CreateTopic { topic, partitioner, config, replicas }
+                        if topic == *name && *partitioner == state.strategy && *config == state.config && *replicas == state.replicas)
+                })
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for topic in stale {
+            self.delete_topic(&topic);
+        }
+
+        self.acls.write().clear();
+        for record in &records {
+            // Topics it has already are left as they are
+            let _ = self.apply_metadata_record(record);
+        }
+    }
+
+    fn not_controller(&self, leader: Option<u32>) -> BrokerError {
+        let message = match leader {
+            Some(leader) => format!("Broker {} does not lead the metadata quorum, broker {} does", self.broker_id, leader),
+            None => "The controllers have not elected a leader".to_string(),
+        };
+        BrokerError::new(ErrorCode::NotController, message)
+    }
+
+    fn not_committed() -> BrokerError {
+        BrokerError::new(
+            ErrorCode::NotController,
+            "The quorum leader changed before the change was committed, it may or may not have been",
+        )
+    }
+
+    fn no_controllers(&self) -> BrokerError {
+        BrokerError::new(
+            ErrorCode::UnsupportedOperation,
+            format!("Broker {} keeps its metadata to itself, it has no controllers", self.broker_id),
+        )
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_protocol::{AssignmentStrategy, GroupAssignmentResponse, GroupJoinedResponse, TopicPartitions};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+type Partition = (String, u32);
+
+// Keeps track of the consumer groups on this broker and shares the partitions
+// it hosts among their members. The assignment is worked out here as soon as
+// membership changes, members pick it up with SyncGroup or by consuming.
+pub(crate) struct GroupCoordinator {
+    groups: Mutex<HashMap<String, Group>>,
+    // Wakes group consumers so they move on to their new partitions
+    pub(crate) rebalanced: Notify,
+}
+
+struct Group {
+    generation: u32,
+    strategy: AssignmentStrategy,
+    // Ordered by when they joined, the first one's preferences win
+    members: Vec<String>,
+    sessions: HashMap<String, Member>,
+    assignment: HashMap<String, Vec<Partition>>,
+}
+
+struct Member {
+    topics: Vec<String>,
+    strategies: Vec<AssignmentStrategy>,
+    session_timeout: Duration,
+    last_seen: Instant,
+}
+
+pub(crate) struct JoinRequest {
+    pub(crate) member_id: Option<String>,
+    pub(crate) topics: Vec<String>,
+    pub(crate) strategies: Vec<AssignmentStrategy>,
+    pub(crate) session_timeout: Duration,
+}
+
+impl GroupCoordinator {
+    pub(crate) fn new() -> Self {
+        Self {
+            groups: Mutex::new(HashMap::new()),
+            rebalanced: Notify::new(),
+        }
+    }
+
+    // `partitions` lists a topic's partitions that can be handed out
+    pub(crate) fn join(
+        &self,
+        group_id: &str,
+        request: JoinRequest,
+        partitions: impl Fn(&str) -> Vec<u32>,
+    ) -> Result<GroupJoinedResponse, BrokerError> {
+        let mut groups = self.groups.lock();
+
+        let member_id = match request.member_id {
+            Some(member_id) => {
+                let known = groups
+                    .get(group_id)
+                    .is_some_and(|group| group.sessions.contains_key(&member_id));
+                if !known {
+                    return Err(unknown_member(group_id, &member_id));
+                }
+                member_id
+            }
+            None => Uuid::new_v4().to_string(),
+        };
+
+        let group = groups.entry(group_id.to_string()).or_insert_with(|| Group {
+            generation: 0,
+            strategy: AssignmentStrategy::Range,
+            members: Vec::new(),
+            sessions: HashMap::new(),
+            assignment: HashMap::new(),
+        });
+
+        let changed = match group.sessions.get(&member_id) {
+            Some(member) => member.topics != request.topics || member.strategies != request.strategies,
+            None => true,
+        };
+        let member = Member {
+            topics: request.topics,
+            strategies: request.strategies,
+            session_timeout: request.session_timeout,
+            last_seen: Instant::now(),
+        };
+
+        // Check the strategies still agree before letting the member in
+        let others: Vec<&Member> = group
+            .sessions
+            .iter()
+            .filter(|(id, _)| **id != member_id)
+            .map(|(_, member)| member)
+            .collect();
+        let leader = group
+            .members
+            .first()
+            .filter(|id| **id != member_id)
+            .and_then(|id| group.sessions.get(id))
+            .unwrap_or(&member);
+        let strategy = leader.strategies.iter().find(|strategy| {
+            member.strategies.contains(strategy)
+                && others.iter().all(|other| other.strategies.contains(strategy))
+        });
+        let Some(strategy) = strategy.copied() else {
+            return Err(BrokerError::new(
+                ErrorCode::InconsistentGroupProtocol,
+                format!("No assignment strategy is supported by every member of {}", group_id),
+            ));
+        };
+
+        if !group.members.contains(&member_id) {
+            group.members.push(member_id.clone());
+        }
+        group.sessions.insert(member_id.clone(), member);
+        if changed {
+            group.strategy = strategy;
+            group.rebalance(&partitions);
+            self.rebalanced.notify_waiters();
+        }
+
+        Ok(GroupJoinedResponse {
+            group_id: group_id.to_string(),
+            member_id,
+            generation: group.generation,
+            strategy: group.strategy,
+            members: group.members.clone(),
+        })
+    }
+
+    pub(crate) fn sync(&self, group_id: &str, member_id: &str) -> Result<GroupAssignmentResponse, BrokerError> {
+        let mut groups = self.groups.lock();
+        let group = groups
+            .get_mut(group_id)
+            .filter(|group| group.sessions.contains_key(member_id))
+            .ok_or_else(|| unknown_member(group_id, member_id))?;
+        group.touch(member_id);
+
+        let mut assignment: Vec<TopicPartitions> = Vec::new();
+        for (topic, partition) in group.assignment.get(member_id).into_iter().flatten() {
+            match assignment.iter_mut().find(|assigned| assigned.topic == *topic) {
+                Some(assigned) => assigned.partitions.push(*partition),
+                None => assignment.push(TopicPartitions {
+                    topic: topic.clone(),
+                    partitions: vec![*partition],
+                }),
+            }
+        }
+
+        Ok(GroupAssignmentResponse {
+            generation: group.generation,
+            assignment,
+        })
+    }
+
+    pub(crate) fn heartbeat(&self, group_id: &str, member_id: &str, generation: u32) -> Result<(), BrokerError> {
+        let mut groups = self.groups.lock();
+        let group = groups
+            .get_mut(group_id)
+            .filter(|group| group.sessions.contains_key(member_id))
+            .ok_or_else(|| unknown_member(group_id, member_id))?;
+        group.touch(member_id);
+
+        if generation != group.generation {
+            return Err(BrokerError::new(
+                ErrorCode::RebalanceInProgress,
+                format!("Group {} is at generation {}", group_id, group.generation),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn leave(
+        &self,
+        group_id: &str,
+        member_id: &str,
+        partitions: impl Fn(&str) -> Vec<u32>,
+    ) -> Result<(), BrokerError> {
+        let mut groups = self.groups.lock();
+        let group = groups
+            .get_mut(group_id)
+            .filter(|group| group.sessions.contains_key(member_id))
+            .ok_or_else(|| unknown_member(group_id, member_id))?;
+
+        group.remove(member_id);
+        group.rebalance(&partitions);
+        if group.members.is_empty() {
+            groups.remove(group_id);
+        }
+        self.rebalanced.notify_waiters();
+        Ok(())
+    }
+
+    // Drop members whose session ran out and rebalance what they had
+    pub(crate) fn expire(&self, partitions: impl Fn(&str) -> Vec<u32>) {
+        let mut groups = self.groups.lock();
+        let now = Instant::now();
+        let mut rebalanced = false;
+
+        for (group_id, group) in groups.iter_mut() {
+            let expired: Vec<String> = group
+                .sessions
+                .iter()
+                .filter(|(_, member)| now.duration_since(member.last_seen) > member.session_timeout)
+                .map(|(member_id, _)| member_id.clone())
+                .collect();
+            if expired.is_empty() {
+                continue;
+            }
+
+            for member_id in &expired {
+                println!("Member {} of group {} timed out", member_id, group_id);
+                group.remove(member_id);
+            }
+            group.rebalance(&partitions);
+            rebalanced = true;
+        }
+
+        groups.retain(|_, group| !group.members.is_empty());
+        if rebalanced {
+            self.rebalanced.notify_waiters();
+        }
+    }
+
+    // None once the member has left or been evicted
+    pub(crate) fn assignment(&self, group_id: &str, member_id: &str) -> Option<Vec<Partition>> {
+        let groups = self.groups.lock();
+        let group = groups.get(group_id)?;
+        group.sessions.contains_key(member_id).then(|| {
+            group.assignment.get(member_id).cloned().unwrap_or_default()
+        })
+    }
+}
+
+fn unknown_member(group_id: &str, member_id: &str) -> BrokerError {
+    BrokerError::new(
+        ErrorCode::UnknownMember,
+        format!("{} is not a member of group {}", member_id, group_id),
+    )
+}
+
+impl Group {
+    fn touch(&mut self, member_id: &str) {
+        if let Some(member) = self.sessions.get_mut(member_id) {
+            member.last_seen = Instant::now();
+        }
+    }
+
+    fn remove(&mut self, member_id: &str) {
+        self.members.retain(|id| id != member_id);
+        self.sessions.remove(member_id);
+        self.assignment.remove(member_id);
+    }
+
+    // Starts a new generation with a fresh assignment
+    fn rebalance(&mut self, partitions: &impl Fn(&str) -> Vec<u32>) {
+        let mut members: Vec<(&str, &[String])> = self
+            .members
+            .iter()
+            .filter_map(|id| Some((id.as_str(), self.sessions.get(id)?.topics.as_slice())))
+            .collect();
+        members.sort();
+
+        let mut topics: Vec<&String> = members.iter().flat_map(|(_, topics)| topics.iter()).collect();
+        topics.sort();
+        topics.dedup();
+        let available: Vec<Partition> = topics
+            .into_iter()
+            .flat_map(|topic| partitions(topic).into_iter().map(move |partition| (topic.clone(), partition)))
+            .collect();
+
+        self.assignment = match self.strategy {
+            AssignmentStrategy::Range => assign_range(&members, &available),
+            AssignmentStrategy::RoundRobin => assign_round_robin(&members, &available),
+            AssignmentStrategy::Sticky => assign_sticky(&members, &available, &self.assignment),
+        };
+        self.generation += 1;
+    }
+}
+
+fn subscribed(topics: &[String], topic: &str) -> bool {
+    topics.iter().any(|subscribed| subscribed == topic)
+}
+
+// Per topic, the members subscribed to it split its partitions into contiguous
+// runs, the first few getting one extra when they don't divide evenly
+fn assign_range(members: &[(&str, &[String])], available: &[Partition]) -> HashMap<String, Vec<Partition>> {
+    let mut assignment: HashMap<String, Vec<Partition>> = HashMap::new();
+
+    let mut by_topic: BTreeMap<&str, Vec<&Partition>> = BTreeMap::new();
+    for partition in available {
+        by_topic.entry(partition.0.as_str()).or_default().push(partition);
+    }
+
+    for (topic, partitions) in by_topic {
+        let consumers: Vec<&str> = members
+            .iter()
+            .filter(|(_, topics)| subscribed(topics, topic))
+            .map(|(id, _)| *id)
+            .collect();
+        if consumers.is_empty() {
+            continue;
+        }
+
+        let share = partitions.len() / consumers.len();
+        let extra = partitions.len() % consumers.len();
+        let mut remaining = partitions.into_iter();
+        for (i, consumer) in consumers.into_iter().enumerate() {
+            let count = share + usize::from(i < extra);
+            let entry = assignment.entry(consumer.to_string()).or_default();
+            entry.extend(remaining.by_ref().take(count).cloned());
+        }
+    }
+
+    assignment
+}
+
+// Every partition goes to the next member in turn that subscribes to its topic
+fn assign_round_robin(members: &[(&str, &[String])], available: &[Partition]) -> HashMap<String, Vec<Partition>> {
+    let mut assignment: HashMap<String, Vec<Partition>> = HashMap::new();
+    if members.is_empty() {
+        return assignment;
+    }
+
+    let mut next = 0;
+    for partition in available {
+        for offset in 0..members.len() {
+            let (id, topics) = members[(next + offset) % members.len()];
+            if subscribed(topics, &partition.0) {
+                assignment.entry(id.to_string()).or_default().push(partition.clone());
+                next = (next + offset + 1) % members.len();
+                break;
+            }
+        }
+    }
+
+    assignment
+}
+
+// Members keep the partitions they already had, up to their fair share, and
+// the rest go to whoever has the fewest
+fn assign_sticky(
+    members: &[(&str, &[String])],
+    available: &[Partition],
+    previous: &HashMap<String, Vec<Partition>>,
+) -> HashMap<String, Vec<Partition>> {
+    let mut assignment: HashMap<String, Vec<Partition>> = HashMap::new();
+    if members.is_empty() {
+        return assignment;
+    }
+    let quota = available.len().div_ceil(members.len());
+
+    let mut taken: Vec<&Partition> = Vec::new();
+    for (id, topics) in members {
+        let kept: Vec<Partition> = previous
+            .get(*id)
+            .into_iter()
+            .flatten()
+            .filter(|partition| available.contains(partition) && subscribed(topics, &partition.0))
+            .take(quota)
+            .cloned()
+            .collect();
+        taken.extend(available.iter().filter(|partition| kept.contains(partition)));
+        assignment.insert(id.to_string(), kept);
+    }
+
+    for partition in available.iter().filter(|partition| !taken.contains(partition)) {
+        let least_loaded = members
+            .iter()
+            .filter(|(_, topics)| subscribed(topics, &partition.0))
+            .min_by_key(|(id, _)| assignment.get(*id).map_or(0, Vec::len));
+        if let Some((id, _)) = least_loaded {
+            assignment.entry(id.to_string()).or_default().push(partition.clone());
+        }
+    }
+
+    for partitions in assignment.values_mut() {
+        partitions.sort();
+    }
+    assignment
+}
@@ -0,0 +1,43 @@
+use parking_lot::Mutex;
+use rafka_protocol::Credit;
+use tokio::sync::Notify;
+
+// What a consumer still lets the broker push to it. Consumers that asked for
+// no flow control get an unlimited account.
+pub(crate) struct CreditAccount {
+    remaining: Mutex<Option<Credit>>,
+    // Wakes the consumer's delivery task when it gets more credit
+    pub(crate) granted: Notify,
+}
+
+impl CreditAccount {
+    pub(crate) fn new(initial: Option<Credit>) -> Self {
+        Self {
+            remaining: Mutex::new(initial),
+            granted: Notify::new(),
+        }
+    }
+
+    // Charges a delivery if there is credit left, false if it has to wait.
+    // The last delivery may overdraw, whatever is left just goes to zero.
+    pub(crate) fn take(&self, messages: u64, bytes: u64) -> bool {
+        let mut remaining = self.remaining.lock();
+        let Some(credit) = remaining.as_mut() else {
+            return true;
+        };
+        if credit.messages == 0 || credit.bytes == 0 {
+            return false;
+        }
+        credit.messages = credit.messages.saturating_sub(messages);
+        credit.bytes = credit.bytes.saturating_sub(bytes);
+        true
+    }
+
+    pub(crate) fn grant(&self, credit: Credit) {
+        if let Some(remaining) = self.remaining.lock().as_mut() {
+            remaining.messages = remaining.messages.saturating_add(credit.messages);
+            remaining.bytes = remaining.bytes.saturating_add(credit.bytes);
+        }
+        self.granted.notify_waiters();
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+
+use parking_lot::Mutex;
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_protocol::{EncodedBatch, ProducerIdentity};
+
+// How many of a producer's latest batches are remembered per partition, so a
+// retry of any of them is still recognised
+const RECENT_BATCHES: usize = 5;
+
+// Hands out producer ids and remembers what idempotent producers last
+// appended, to drop retried batches and refuse ones that skip ahead
+pub(crate) struct ProducerStates {
+    broker_id: u32,
+    broker_count: u32,
+    registered: Mutex<Registered>,
+    partitions: Mutex<HashMap<(String, u32, i64), PartitionState>>,
+}
+
+// Producer ids handed out by this broker
+#[derive(Default)]
+struct Registered {
+    // Ids stay with a client id after it disconnects, so a producer that
+    // comes back gets its id with a new epoch
+    ids: HashMap<String, i64>,
+    epochs: HashMap<i64, i16>,
+    next: i64,
+}
+
+struct PartitionState {
+    epoch: i16,
+    recent: VecDeque<AppendedBatch>,
+}
+
+struct AppendedBatch {
+    first_sequence: i32,
+    last_sequence: i32,
+    offset: i64,
+}
+
+pub(crate) enum Appended {
+    Stored(i64),
+    // Already stored by an earlier attempt, at this offset
+    Duplicate(i64),
+}
+
+impl ProducerStates {
+    pub(crate) fn new(broker_id: u32, broker_count: u32) -> Self {
+        Self {
+            broker_id,
+            broker_count,
+            registered: Mutex::new(Registered::default()),
+            partitions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Every broker counts up in steps of the broker count from its own id,
+    // so no two brokers hand out the same producer id
+    pub(crate) fn assign(&self, client_id: &str) -> ProducerIdentity {
+        let mut registered = self.registered.lock();
+        let producer_id = match registered.ids.get(client_id) {
+            Some(producer_id) => *producer_id,
+            None => {
+                let producer_id = registered.next * self.broker_count as i64 + self.broker_id as i64;
+                registered.next += 1;
+                registered.ids.insert(client_id.to_string(), producer_id);
+                producer_id
+            }
+        };
+        let producer_epoch = match registered.epochs.get(&producer_id) {
+            Some(epoch) => epoch.saturating_add(1),
+            None => 0,
+        };
+        registered.epochs.insert(producer_id, producer_epoch);
+        ProducerIdentity {
+            producer_id,
+            producer_epoch,
+        }
+    }
+
+    // Runs `append` unless the batch is a retry or out of order. Batches
+    // without a producer id are always appended. None when `append` is.
+    pub(crate) fn append_once(
+        &self,
+        topic: &str,
+        partition: u32,
+        batch: &EncodedBatch,
+        append: impl FnOnce() -> Option<i64>,
+    ) -> Result<Option<Appended>, BrokerError> {
+        let producer_id = batch.producer_id();
+        if producer_id < 0 {
+            return Ok(append().map(Appended::Stored));
+        }
+        let epoch = batch.producer_epoch();
+        let first_sequence = batch.base_sequence();
+        let last_sequence = first_sequence.wrapping_add(batch.offset_count() as i32 - 1);
+
+        // Producers registered here are fenced as soon as a new epoch is handed
+        // out, others once the new epoch writes to the partition
+        let registered_epoch = self.registered.lock().epochs.get(&producer_id).copied();
+        if let Some(registered_epoch) = registered_epoch.filter(|registered| epoch < *registered) {
+            return Err(Self::fenced(producer_id, epoch, registered_epoch));
+        }
+
+        // Held over the append so retries racing each other can't both get in
+        let mut partitions = self.partitions.lock();
+        let key = (topic.to_string(), partition, producer_id);
+        match partitions.get(&key) {
+            Some(state) if epoch < state.epoch => {
+                return Err(Self::fenced(producer_id, epoch, state.epoch));
+            }
+            Some(state) if epoch == state.epoch => {
+                let retried = state.recent.iter().find(|appended| {
+                    appended.first_sequence == first_sequence && appended.last_sequence == last_sequence
+                });
+                if let Some(appended) = retried {
+                    return Ok(Some(Appended::Duplicate(appended.offset)));
+                }
+                let expected = state.recent.back().map_or(0, |appended| appended.last_sequence.wrapping_add(1));
+                if first_sequence != expected {
+                    return Err(Self::out_of_order(producer_id, topic, partition, first_sequence, expected));
+                }
+            }
+            // A producer, or a new epoch of one, starts from the beginning
+            _ if first_sequence != 0 => {
+                return Err(Self::out_of_order(producer_id, topic, partition, first_sequence, 0));
+            }
+            _ => {}
+        }
+
+        let Some(offset) = append() else {
+            return Ok(None);
+        };
+        let state = partitions.entry(key).or_insert_with(|| PartitionState {
+            epoch,
+            recent: VecDeque::new(),
+        });
+        if state.epoch != epoch {
+            state.epoch = epoch;
+            state.recent.clear();
+        }
+        if state.recent.len() == RECENT_BATCHES {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(AppendedBatch {
+            first_sequence,
+            last_sequence,
+            offset,
+        });
+        Ok(Some(Appended::Stored(offset)))
+    }
+
+    // Only the latest epoch of a producer registered here may run transactions
+    pub(crate) fn check_current(&self, producer: ProducerIdentity) -> Result<(), BrokerError> {
+        match self.registered.lock().epochs.get(&producer.producer_id) {
+            Some(epoch) if *epoch == producer.producer_epoch => Ok(()),
+            Some(epoch) if producer.producer_epoch < *epoch => {
+                Err(Self::fenced(producer.producer_id, producer.producer_epoch, *epoch))
+            }
+            _ => Err(BrokerError::new(
+                ErrorCode::InvalidTransactionState,
+                format!(
+                    "Producer {} epoch {} did not register with broker {}",
+                    producer.producer_id, producer.producer_epoch, self.broker_id
+                ),
+            )),
+        }
+    }
+
+    // Fences the producer's current epoch, as if it had registered again
+    pub(crate) fn bump_epoch(&self, producer_id: i64) {
+        if let Some(epoch) = self.registered.lock().epochs.get_mut(&producer_id) {
+            *epoch = epoch.saturating_add(1);
+        }
+    }
+
+    // Sequences start over if the topic is created again
+    pub(crate) fn forget_topic(&self, topic: &str) {
+        self.partitions.lock().retain(|(stored, _, _), _| stored != topic);
+    }
+
+    fn fenced(producer_id: i64, epoch: i16, current: i16) -> BrokerError {
+        BrokerError::new(
+            ErrorCode::ProducerFenced,
+            format!("Producer {} epoch {} has been replaced by epoch {}", producer_id, epoch, current),
+        )
+    }
+
+    fn out_of_order(producer_id: i64, topic: &str, partition: u32, got: i32, expected: i32) -> BrokerError {
+        BrokerError::new(
+            ErrorCode::OutOfOrderSequence,
+            format!(
+                "Producer {} sent sequence {} to {} partition {}, expected {}",
+                producer_id, got, topic, partition, expected
+            ),
+        )
+    }
+}
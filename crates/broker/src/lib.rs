@@ -1,2 +1,13 @@
 pub mod broker;
-pub use broker::Broker;
\ No newline at end of file
+mod coordinator;
+mod credit;
+mod idempotence;
+mod metrics;
+mod raft;
+mod registry;
+mod replication;
+mod shutdown;
+mod transactions;
+
+pub use broker::Broker;
+pub use shutdown::ShutdownHandle;
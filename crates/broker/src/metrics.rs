@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rafka_protocol::{ApiKey, LatencyBucket, LatencyHistogram};
+
+// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+// Counters the broker bumps as it works. Partition sizes and consumer lag are
+// not kept here, they are read from storage when asked for.
+pub(crate) struct Metrics {
+    started: Instant,
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    connected_clients: AtomicU64,
+    rates: Mutex<Rates>,
+    latency: Mutex<HashMap<ApiKey, Histogram>>,
+}
+
+// Totals as of the last sample and the per second rates since the one before
+struct Rates {
+    sampled_at: Instant,
+    totals: [u64; 4],
+    per_sec: [f64; 4],
+}
+
+struct Histogram {
+    count: u64,
+    sum: Duration,
+    // Not cumulative, one per entry of LATENCY_BUCKETS plus one for slower requests
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+// What `Metrics::snapshot` hands out
+pub(crate) struct Snapshot {
+    pub(crate) uptime: Duration,
+    pub(crate) totals: [u64; 4],
+    pub(crate) per_sec: [f64; 4],
+    pub(crate) connected_clients: u64,
+    pub(crate) request_latency: Vec<LatencyHistogram>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            messages_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            connected_clients: AtomicU64::new(0),
+            rates: Mutex::new(Rates {
+                sampled_at: now,
+                totals: [0; 4],
+                per_sec: [0.0; 4],
+            }),
+            latency: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn record_in(&self, messages: u64, bytes: u64) {
+        self.messages_in.fetch_add(messages, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_out(&self, messages: u64, bytes: u64) {
+        self.messages_out.fetch_add(messages, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe_request(&self, api_key: ApiKey, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| elapsed.as_secs_f64() <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        let mut latency = self.latency.lock();
+        let histogram = latency.entry(api_key).or_insert_with(|| Histogram {
+            count: 0,
+            sum: Duration::ZERO,
+            buckets: [0; LATENCY_BUCKETS.len() + 1],
+        });
+        histogram.count += 1;
+        histogram.sum += elapsed;
+        histogram.buckets[bucket] += 1;
+    }
+
+    // Called about once a second to work out the rates
+    pub(crate) fn sample(&self) {
+        let totals = self.totals();
+        let mut rates = self.rates.lock();
+        let elapsed = rates.sampled_at.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        for (i, total) in totals.iter().enumerate() {
+            rates.per_sec[i] = (total - rates.totals[i]) as f64 / elapsed;
+        }
+        rates.totals = totals;
+        rates.sampled_at = Instant::now();
+    }
+
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        let latency = self.latency.lock();
+        let mut request_latency: Vec<LatencyHistogram> = latency
+            .iter()
+            .map(|(api_key, histogram)| {
+                let mut cumulative = 0;
+                let buckets = LATENCY_BUCKETS
+                    .iter()
+                    .zip(histogram.buckets)
+                    .map(|(le_secs, count)| {
+                        cumulative += count;
+                        LatencyBucket {
+                            le_secs: *le_secs,
+                            count: cumulative,
+                        }
+                    })
+                    .collect();
+                LatencyHistogram {
+                    api_key: *api_key,
+                    count: histogram.count,
+                    sum_secs: histogram.sum.as_secs_f64(),
+                    buckets,
+                }
+            })
+            .collect();
+        request_latency.sort_by_key(|histogram| histogram.api_key.to_string());
+
+        Snapshot {
+            uptime: self.started.elapsed(),
+            totals: self.totals(),
+            per_sec: self.rates.lock().per_sec,
+            connected_clients: self.connected_clients.load(Ordering::Relaxed),
+            request_latency,
+        }
+    }
+
+    // messages in, bytes in, messages out, bytes out
+    fn totals(&self) -> [u64; 4] {
+        [
+            self.messages_in.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.messages_out.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        ]
+    }
+}
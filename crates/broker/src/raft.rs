@@ -0,0 +1,598 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_protocol::{BrokerMessage, MetadataEntry, MetadataRecord, MetadataSnapshot, QuorumDescription};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+// Most entries one AppendEntries carries, a broker far behind catches up over several
+const MAX_APPEND_ENTRIES: usize = 500;
+
+// The cluster's metadata log, kept in sync with Raft. The controllers elect a
+// leader among themselves, which appends every change and sends its log on to
+// all the other brokers, controllers or not. An entry is committed once a
+// majority of the controllers have it. Talking to other brokers is left to
+// the broker: poll says what to send where, and their answers are handed back.
+pub(crate) struct Raft {
+    id: u32,
+    election_timeout: Duration,
+    state: Mutex<RaftState>,
+    // Where the term, vote and log survive restarts, if anywhere
+    path: Option<PathBuf>,
+    // Wakes whoever applies committed entries
+    pub(crate) committed: Notify,
+    // Wakes whoever sends the leader's entries out
+    pub(crate) appended: Notify,
+}
+
+struct RaftState {
+    term: u64,
+    voted_for: Option<u32>,
+    // Entries up to its last index are only kept as this
+    snapshot: MetadataSnapshot,
+    // Entries after the snapshot, in index order
+    log: Vec<MetadataEntry>,
+    role: Role,
+    leader_id: Option<u32>,
+    commit_index: u64,
+    // When a controller that hasn't heard from a leader stands for election
+    election_deadline: Instant,
+    votes: HashSet<u32>,
+    // The leader's view of every other broker's log
+    progress: HashMap<u32, Progress>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct Progress {
+    // The first entry to send next
+    next_index: u64,
+    // The broker's log is known to match the leader's up to here
+    match_index: u64,
+    // One request at a time, until it is answered or given up on
+    in_flight: bool,
+    sent_at: Option<Instant>,
+}
+
+// What the log has committed past what was applied
+pub(crate) enum Committed {
+    // Replaces everything applied so far
+    Snapshot(MetadataSnapshot),
+    Entries(Vec<MetadataEntry>),
+}
+
+pub(crate) enum Refused {
+    // Only the leader appends, this is who it is if known
+    NotLeader(Option<u32>),
+    Invalid(BrokerError),
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_term, |entry| entry.term)
+    }
+
+    // None past the end of the log, or for entries only the snapshot has
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        let position = index.checked_sub(self.snapshot.last_index + 1)?;
+        self.log.get(position as usize).map(|entry| entry.term)
+    }
+
+    // Entries from `index` on, which must be past the snapshot
+    fn entries_from(&self, index: u64) -> &[MetadataEntry] {
+        let position = (index - self.snapshot.last_index - 1) as usize;
+        &self.log[position.min(self.log.len())..]
+    }
+
+    fn truncate_from(&mut self, index: u64) {
+        self.log.truncate((index - self.snapshot.last_index - 1) as usize);
+    }
+
+    // A change of controllers counts as soon as it is in the log
+    fn controllers_at(&self, index: u64) -> (u64, Vec<u32>) {
+        self.log
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.record {
+                MetadataRecord::SetControllers { controllers } => Some((entry.index, controllers.clone())),
+                _ => None,
+            })
+            .unwrap_or_else(|| (self.snapshot.last_index, self.snapshot.controllers.clone()))
+    }
+
+    fn controllers(&self) -> Vec<u32> {
+        self.controllers_at(self.last_index()).1
+    }
+
+    fn has_majority(&self, brokers: &HashSet<u32>) -> bool {
+        let controllers = self.controllers();
+        let agreeing = controllers.iter().filter(|controller| brokers.contains(controller)).count();
+        agreeing * 2 > controllers.len()
+    }
+
+    // Back to following, in `term` if that is later than the current one
+    fn step_down(&mut self, term: u64, leader_id: Option<u32>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader_id = leader_id;
+        self.votes.clear();
+        self.progress.clear();
+    }
+}
+
+impl Raft {
+    // Starts out following with `controllers` voting, unless `path` has the
+    // state of an earlier run
+    pub(crate) fn open(
+        id: u32,
+        controllers: Vec<u32>,
+        election_timeout: Duration,
+        path: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        let mut state = RaftState {
+            term: 0,
+            voted_for: None,
+            snapshot: MetadataSnapshot {
+                last_index: 0,
+                last_term: 0,
+                controllers,
+                records: Vec::new(),
+            },
+            log: Vec::new(),
+            role: Role::Follower,
+            leader_id: None,
+            commit_index: 0,
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+        };
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let saved: (u64, Option<u32>, MetadataSnapshot, Vec<MetadataEntry>) =
+                serde_json::from_slice(&fs::read(path)?).map_err(io::Error::other)?;
+            (state.term, state.voted_for, state.snapshot, state.log) = saved;
+            state.commit_index = state.snapshot.last_index;
+        }
+
+        let raft = Self {
+            id,
+            election_timeout,
+            state: Mutex::new(state),
+            path,
+            committed: Notify::new(),
+            appended: Notify::new(),
+        };
+        raft.state.lock().election_deadline = raft.next_election_deadline();
+        Ok(raft)
+    }
+
+    // Somewhere between one and two election timeouts from now, so
+    // controllers rarely stand for election at the same time
+    fn next_election_deadline(&self) -> Instant {
+        let range = self.election_timeout.as_millis().max(1);
+        let jitter = Uuid::new_v4().as_u128() % range;
+        Instant::now() + self.election_timeout + Duration::from_millis(jitter as u64)
+    }
+
+    // The term, vote and log have to be on disk before anyone is told about them
+    fn save(&self, state: &RaftState) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let saved = (state.term, state.voted_for, &state.snapshot, &state.log);
+        let written = serde_json::to_vec(&saved).map_err(io::Error::other).and_then(|bytes| {
+            let partial = path.with_extension("tmp");
+            fs::write(&partial, bytes)?;
+            fs::rename(&partial, path)
+        });
+        if let Err(e) = written {
+            eprintln!("Could not save the metadata log to {}: {}", path.display(), e);
+        }
+    }
+
+    // What to send where right now: vote requests from a new candidate, or
+    // entries and heartbeats from the leader to each of `brokers`
+    pub(crate) fn poll(&self, brokers: &[u32]) -> Vec<(u32, BrokerMessage)> {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        match state.role {
+            Role::Leader => brokers
+                .iter()
+                .filter(|broker| **broker != self.id)
+                .filter_map(|broker| Some((*broker, self.next_message(&mut state, *broker, now)?)))
+                .collect(),
+            // Only controllers stand for election
+            _ if now >= state.election_deadline && state.controllers().contains(&self.id) => {
+                self.start_election(&mut state)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn start_election(&self, state: &mut RaftState) -> Vec<(u32, BrokerMessage)> {
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.voted_for = Some(self.id);
+        state.leader_id = None;
+        state.votes = HashSet::from([self.id]);
+        state.election_deadline = self.next_election_deadline();
+        self.save(state);
+
+        if state.has_majority(&state.votes) {
+            self.become_leader(state);
+            return Vec::new();
+        }
+        let request = BrokerMessage::RequestVote {
+            term: state.term,
+            candidate_id: self.id,
+            last_log_index: state.last_index(),
+            last_log_term: state.last_term(),
+        };
+        state
+            .controllers()
+            .into_iter()
+            .filter(|controller| *controller != self.id)
+            .map(|controller| (controller, request.clone()))
+            .collect()
+    }
+
+    fn become_leader(&self, state: &mut RaftState) {
+        state.role = Role::Leader;
+        state.leader_id = Some(self.id);
+        state.progress.clear();
+        self.append(state, MetadataRecord::LeaderChange { leader_id: self.id });
+    }
+
+    fn append(&self, state: &mut RaftState, record: MetadataRecord) -> u64 {
+        let index = state.last_index() + 1;
+        state.log.push(MetadataEntry {
+            term: state.term,
+            index,
+            record,
+        });
+        self.save(state);
+        // With no other controller to wait for it is committed already
+        self.advance_commit(state);
+        self.appended.notify_one();
+        index
+    }
+
+    fn next_message(&self, state: &mut RaftState, broker: u32, now: Instant) -> Option<BrokerMessage> {
+        let heartbeat_interval = self.election_timeout / 4;
+        let last_index = state.last_index();
+        let progress = state.progress.entry(broker).or_insert(Progress {
+            next_index: last_index,
+            match_index: 0,
+            in_flight: false,
+            sent_at: None,
+        });
+        let heartbeat_due = progress.sent_at.is_none_or(|sent_at| now >= sent_at + heartbeat_interval);
+        if progress.in_flight || (progress.next_index > last_index && !heartbeat_due) {
+            return None;
+        }
+        progress.in_flight = true;
+        progress.sent_at = Some(now);
+        let next_index = progress.next_index.max(1);
+
+        if next_index <= state.snapshot.last_index {
+            return Some(BrokerMessage::InstallSnapshot {
+                term: state.term,
+                leader_id: self.id,
+                snapshot: state.snapshot.clone(),
+            });
+        }
+        let prev_log_index = next_index - 1;
+        Some(BrokerMessage::AppendEntries {
+            term: state.term,
+            leader_id: self.id,
+            prev_log_index,
+            prev_log_term: state.term_at(prev_log_index).unwrap_or(0),
+            entries: state.entries_from(next_index).iter().take(MAX_APPEND_ENTRIES).cloned().collect(),
+            leader_commit: state.commit_index,
+        })
+    }
+
+    // An entry of the current term is committed once a majority of the
+    // controllers have it, and every entry before it along with it
+    fn advance_commit(&self, state: &mut RaftState) {
+        let (changed_at, controllers) = state.controllers_at(state.last_index());
+        let mut matched: Vec<u64> = controllers
+            .iter()
+            .map(|controller| match *controller == self.id {
+                true => state.last_index(),
+                false => state.progress.get(controller).map_or(0, |progress| progress.match_index),
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let Some(&majority) = matched.get(controllers.len() / 2) else {
+            return;
+        };
+        if majority <= state.commit_index || state.term_at(majority) != Some(state.term) {
+            return;
+        }
+        state.commit_index = majority;
+        self.committed.notify_one();
+
+        // A leader that removed itself hands over once that is committed
+        if !controllers.contains(&self.id) && changed_at <= state.commit_index {
+            state.step_down(state.term, None);
+        }
+    }
+
+    pub(crate) fn request_vote(&self, term: u64, candidate_id: u32, last_log_index: u64, last_log_term: u64) -> (u64, bool) {
+        let mut state = self.state.lock();
+        let newer = term > state.term;
+        if newer {
+            state.step_down(term, None);
+        }
+        let up_to_date = (last_log_term, last_log_index) >= (state.last_term(), state.last_index());
+        let granted = term == state.term
+            && state.voted_for.is_none_or(|voted_for| voted_for == candidate_id)
+            && up_to_date;
+        if granted {
+            state.voted_for = Some(candidate_id);
+            state.election_deadline = self.next_election_deadline();
+        }
+        if newer || granted {
+            self.save(&state);
+        }
+        (state.term, granted)
+    }
+
+    pub(crate) fn vote_received(&self, from: u32, term: u64, granted: bool) {
+        let mut state = self.state.lock();
+        if term > state.term {
+            state.step_down(term, None);
+            self.save(&state);
+            return;
+        }
+        if state.role != Role::Candidate || term != state.term || !granted {
+            return;
+        }
+        state.votes.insert(from);
+        if state.has_majority(&state.votes) {
+            self.become_leader(&mut state);
+        }
+    }
+
+    // Follows whoever sent it, if its term is current. Answers with the term
+    // and whether the log now matches the leader's up to the last entry sent,
+    // with where the leader should go back to if it doesn't.
+    pub(crate) fn append_entries(
+        &self,
+        term: u64,
+        leader_id: u32,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<MetadataEntry>,
+        leader_commit: u64,
+    ) -> (u64, bool, u64) {
+        let mut state = self.state.lock();
+        if term < state.term {
+            return (state.term, false, 0);
+        }
+        self.follow(&mut state, term, leader_id);
+
+        if prev_log_index > state.last_index() {
+            return (term, false, state.last_index());
+        }
+        // Committed entries are the same on every log, so the leader can
+        // start over from there
+        if prev_log_index >= state.snapshot.last_index && state.term_at(prev_log_index) != Some(prev_log_term) {
+            return (term, false, state.commit_index);
+        }
+
+        let match_index = prev_log_index + entries.len() as u64;
+        let mut changed = false;
+        for entry in entries {
+            if entry.index <= state.snapshot.last_index {
+                continue;
+            }
+            if let Some(existing) = state.term_at(entry.index) {
+                if existing == entry.term {
+                    continue;
+                }
+                // Never committed, a later leader replaced it
+                state.truncate_from(entry.index);
+            }
+            state.log.push(entry);
+            changed = true;
+        }
+        if changed {
+            self.save(&state);
+        }
+
+        let commit_index = leader_commit.min(match_index);
+        if commit_index > state.commit_index {
+            state.commit_index = commit_index;
+            self.committed.notify_one();
+        }
+        (term, true, match_index)
+    }
+
+    // Replaces the log up to the snapshot, keeping whatever follows it if the
+    // log agrees with the snapshot
+    pub(crate) fn install_snapshot(&self, term: u64, leader_id: u32, snapshot: MetadataSnapshot) -> (u64, bool, u64) {
+        let mut state = self.state.lock();
+        if term < state.term {
+            return (state.term, false, 0);
+        }
+        self.follow(&mut state, term, leader_id);
+
+        let last_index = snapshot.last_index;
+        if last_index <= state.commit_index {
+            return (term, true, last_index);
+        }
+        if state.term_at(last_index) == Some(snapshot.last_term) {
+            let covered = (last_index - state.snapshot.last_index) as usize;
+            state.log.drain(..covered);
+        } else {
+            state.log.clear();
+        }
+        state.snapshot = snapshot;
+        state.commit_index = last_index;
+        self.save(&state);
+        self.committed.notify_one();
+        (term, true, last_index)
+    }
+
+    fn follow(&self, state: &mut RaftState, term: u64, leader_id: u32) {
+        if term > state.term || state.role != Role::Follower {
+            state.step_down(term, Some(leader_id));
+            self.save(state);
+        }
+        state.leader_id = Some(leader_id);
+        state.election_deadline = self.next_election_deadline();
+    }
+
+    // The answer to an AppendEntries or InstallSnapshot this broker sent as leader
+    pub(crate) fn entries_appended(&self, from: u32, term: u64, success: bool, match_index: u64) {
+        let mut state = self.state.lock();
+        if let Some(progress) = state.progress.get_mut(&from) {
+            progress.in_flight = false;
+        }
+        if term > state.term {
+            state.step_down(term, None);
+            self.save(&state);
+            return;
+        }
+        if state.role != Role::Leader || term != state.term {
+            return;
+        }
+        let Some(progress) = state.progress.get_mut(&from) else {
+            return;
+        };
+        if success {
+            progress.match_index = progress.match_index.max(match_index);
+            progress.next_index = progress.match_index + 1;
+            self.advance_commit(&mut state);
+        } else {
+            progress.next_index = match_index + 1;
+        }
+        // There may be more to send it
+        self.appended.notify_one();
+    }
+
+    // A request to `broker` failed or went unanswered, it gets another one
+    pub(crate) fn unreachable(&self, broker: u32) {
+        if let Some(progress) = self.state.lock().progress.get_mut(&broker) {
+            progress.in_flight = false;
+        }
+    }
+
+    // Appends a record to the log if this broker leads, returning its index
+    // and term. It takes effect once that entry is committed and applied.
+    pub(crate) fn propose(&self, record: MetadataRecord) -> Result<(u64, u64), Refused> {
+        let mut state = self.state.lock();
+        if state.role != Role::Leader {
+            return Err(Refused::NotLeader(state.leader_id));
+        }
+        if let MetadataRecord::SetControllers { controllers } = &record {
+            Self::check_controller_change(&state, controllers).map_err(Refused::Invalid)?;
+        }
+        let index = self.append(&mut state, record);
+        Ok((index, state.term))
+    }
+
+    // One controller in or out at a time, so the old and the new controllers
+    // can't both make up a majority on their own
+    fn check_controller_change(state: &RaftState, controllers: &[u32]) -> Result<(), BrokerError> {
+        let (changed_at, current) = state.controllers_at(state.last_index());
+        if changed_at > state.commit_index {
+            return Err(BrokerError::new(
+                ErrorCode::NotController,
+                "The previous change of controllers is not committed yet",
+            ));
+        }
+        let added = controllers.iter().filter(|controller| !current.contains(controller)).count();
+        let removed = current.iter().filter(|controller| !controllers.contains(controller)).count();
+        if controllers.is_empty() || added + removed != 1 {
+            return Err(BrokerError::new(
+                ErrorCode::MalformedRequest,
+                format!("Controllers {:?} can't follow {:?}, only one may change at a time", controllers, current),
+            ));
+        }
+        Ok(())
+    }
+
+    // Everything committed after `applied`, starting with the snapshot if
+    // it covers entries that were never applied
+    pub(crate) fn committed_since(&self, applied: u64) -> Option<Committed> {
+        let state = self.state.lock();
+        if state.snapshot.last_index > applied {
+            return Some(Committed::Snapshot(state.snapshot.clone()));
+        }
+        if state.commit_index <= applied {
+            return None;
+        }
+        let entries = state.entries_from(applied + 1);
+        let count = (state.commit_index - applied) as usize;
+        Some(Committed::Entries(entries[..count].to_vec()))
+    }
+
+    // Entries kept besides the snapshot
+    pub(crate) fn log_len(&self) -> usize {
+        self.state.lock().log.len()
+    }
+
+    // Drops the entries up to `index`, which must be applied already and
+    // `records` rebuild
+    pub(crate) fn compact(&self, index: u64, records: Vec<MetadataRecord>) {
+        let mut state = self.state.lock();
+        if index <= state.snapshot.last_index || index > state.commit_index {
+            return;
+        }
+        let last_term = state.term_at(index).expect("a committed entry past the snapshot");
+        let (_, controllers) = state.controllers_at(index);
+        let covered = (index - state.snapshot.last_index) as usize;
+        state.log.drain(..covered);
+        state.snapshot = MetadataSnapshot {
+            last_index: index,
+            last_term,
+            controllers,
+            records,
+        };
+        self.save(&state);
+    }
+
+    pub(crate) fn leader(&self) -> Option<u32> {
+        self.state.lock().leader_id
+    }
+
+    pub(crate) fn controllers(&self) -> Vec<u32> {
+        self.state.lock().controllers()
+    }
+
+    pub(crate) fn describe(&self, applied_index: u64) -> QuorumDescription {
+        let state = self.state.lock();
+        QuorumDescription {
+            broker_id: self.id,
+            leader_id: state.leader_id,
+            term: state.term,
+            controllers: state.controllers(),
+            commit_index: state.commit_index,
+            applied_index,
+            snapshot_index: state.snapshot.last_index,
+        }
+    }
+}
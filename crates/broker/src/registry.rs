@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_protocol::ClientSession;
+
+// The client types Register accepts. Brokers register on the connections
+// they open to each other.
+const CLIENT_TYPES: [&str; 4] = ["producer", "consumer", "admin", "broker"];
+
+// Clients that registered, by client id. Sessions go away with the
+// connection they registered on.
+pub(crate) struct ClientRegistry {
+    sessions: Mutex<HashMap<String, ClientSession>>,
+}
+
+impl ClientRegistry {
+    pub(crate) fn new() -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Registering again on the same connection just updates the session
+    pub(crate) fn register(&self, session: ClientSession) -> Result<(), BrokerError> {
+        if !CLIENT_TYPES.contains(&session.client_type.as_str()) {
+            return Err(BrokerError::new(
+                ErrorCode::UnknownClientType,
+                format!(
+                    "Unknown client type {}, expected one of {}",
+                    session.client_type,
+                    CLIENT_TYPES.join(", ")
+                ),
+            ));
+        }
+
+        let mut sessions = self.sessions.lock();
+        if let Some(existing) = sessions.get(&session.client_id) {
+            if existing.addr != session.addr {
+                return Err(BrokerError::new(
+                    ErrorCode::DuplicateClientId,
+                    format!("{} is already registered from {}", session.client_id, existing.addr),
+                ));
+            }
+        }
+        sessions.insert(session.client_id.clone(), session);
+        Ok(())
+    }
+
+    // Drops every session registered on the connection from `addr`
+    pub(crate) fn disconnected(&self, addr: &str) {
+        self.sessions.lock().retain(|_, session| session.addr != addr);
+    }
+
+    // Whether a broker registered on the connection from `addr`
+    pub(crate) fn is_broker(&self, addr: &str) -> bool {
+        self.sessions
+            .lock()
+            .values()
+            .any(|session| session.addr == addr && session.client_type == "broker")
+    }
+
+    pub(crate) fn list(&self) -> Vec<ClientSession> {
+        let mut clients: Vec<ClientSession> = self.sessions.lock().values().cloned().collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+// What the leader of partitions knows about their followers, learned from
+// the fetches they send. A follower is in sync while it has caught up with
+// the leader's log within the last `lag_time`.
+pub(crate) struct ReplicaTracker {
+    followers: Mutex<HashMap<(String, u32), HashMap<u32, Follower>>>,
+    lag_time: Duration,
+}
+
+struct Follower {
+    // Where the follower's copy ends, as of its last fetch
+    log_end_offset: i64,
+    // Where the leader's log ended when it last answered the follower
+    fetched_to: i64,
+    caught_up_at: Instant,
+}
+
+impl Follower {
+    // Partitions start out with every replica in sync
+    fn new(now: Instant) -> Self {
+        Self {
+            log_end_offset: 0,
+            fetched_to: 0,
+            caught_up_at: now,
+        }
+    }
+}
+
+impl ReplicaTracker {
+    pub(crate) fn new(lag_time: Duration) -> Self {
+        Self {
+            followers: Mutex::new(HashMap::new()),
+            lag_time,
+        }
+    }
+
+    // A fetch from `offset` says the follower has everything before it. It
+    // has caught up if that is as far as the leader's log went the last time.
+    pub(crate) fn fetched(&self, topic: &str, partition: u32, replica_id: u32, offset: i64, leader_end: i64) {
+        let now = Instant::now();
+        let mut followers = self.followers.lock();
+        let follower = followers
+            .entry((topic.to_string(), partition))
+            .or_default()
+            .entry(replica_id)
+            .or_insert_with(|| Follower::new(now));
+        if offset >= follower.fetched_to {
+            follower.caught_up_at = now;
+        }
+        follower.log_end_offset = offset;
+        follower.fetched_to = leader_end;
+    }
+
+    // The followers that are in sync, with where their copies end
+    pub(crate) fn in_sync(&self, topic: &str, partition: u32, replicas: &[u32]) -> Vec<(u32, i64)> {
+        let now = Instant::now();
+        let mut followers = self.followers.lock();
+        let partition = followers.entry((topic.to_string(), partition)).or_default();
+        replicas
+            .iter()
+            .filter_map(|replica_id| {
+                let follower = partition.entry(*replica_id).or_insert_with(|| Follower::new(now));
+                (now.duration_since(follower.caught_up_at) <= self.lag_time)
+                    .then_some((*replica_id, follower.log_end_offset))
+            })
+            .collect()
+    }
+
+    pub(crate) fn forget_topic(&self, topic: &str) {
+        self.followers.lock().retain(|(followed, _), _| followed != topic);
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{watch, Notify};
+
+// Shared by everything that has to stop with the broker: the listeners, the
+// background tasks, client connections and the tasks answering them
+pub(crate) struct Shutdown {
+    requested: watch::Sender<bool>,
+    in_flight: AtomicUsize,
+    drained: Notify,
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> Self {
+        Self {
+            requested: watch::Sender::new(false),
+            in_flight: AtomicUsize::new(0),
+            drained: Notify::new(),
+        }
+    }
+
+    pub(crate) fn request(&self) {
+        self.requested.send_replace(true);
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    // Resolves once shutdown is asked for, straight away if it already was
+    pub(crate) async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        let _ = requested.wait_for(|requested| *requested).await;
+    }
+
+    // Work shutdown waits for, it is done when the guard is dropped
+    pub(crate) fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    pub(crate) async fn drained(&self) {
+        loop {
+            let drained = self.drained.notified();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+pub(crate) struct InFlight(Arc<Shutdown>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+// Stops a broker that is serving, from anywhere. See Broker::shutdown_handle.
+#[derive(Clone)]
+pub struct ShutdownHandle(pub(crate) Arc<Shutdown>);
+
+impl ShutdownHandle {
+    // Returns right away, `serve` returns once connections have drained
+    pub fn shutdown(&self) {
+        self.0.request();
+    }
+}
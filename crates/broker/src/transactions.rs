@@ -0,0 +1,124 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rafka_core::message::{BrokerError, ErrorCode};
+use rafka_protocol::{ProducerIdentity, TxnOffset};
+
+// Open transactions of the producers this broker handed ids to, one per
+// producer at a time. Ending one is left to the broker, which has to reach
+// every partition the transaction touched.
+pub(crate) struct TransactionCoordinator {
+    ongoing: Mutex<HashMap<i64, Transaction>>,
+}
+
+pub(crate) struct Transaction {
+    pub(crate) producer: ProducerIdentity,
+    pub(crate) partitions: BTreeSet<(String, u32)>,
+    // Committed along with the transaction, dropped if it aborts
+    pub(crate) offsets: Vec<TxnOffset>,
+    deadline: Instant,
+}
+
+impl TransactionCoordinator {
+    pub(crate) fn new() -> Self {
+        Self {
+            ongoing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn begin(&self, producer: ProducerIdentity, timeout: Duration) -> Result<(), BrokerError> {
+        let mut ongoing = self.ongoing.lock();
+        if ongoing.contains_key(&producer.producer_id) {
+            return Err(BrokerError::new(
+                ErrorCode::InvalidTransactionState,
+                format!("Producer {} already has a transaction in progress", producer.producer_id),
+            ));
+        }
+        ongoing.insert(
+            producer.producer_id,
+            Transaction {
+                producer,
+                partitions: BTreeSet::new(),
+                offsets: Vec::new(),
+                deadline: Instant::now() + timeout,
+            },
+        );
+        Ok(())
+    }
+
+    pub(crate) fn add_partitions(
+        &self,
+        producer: ProducerIdentity,
+        partitions: impl IntoIterator<Item = (String, u32)>,
+    ) -> Result<(), BrokerError> {
+        let mut ongoing = self.ongoing.lock();
+        let transaction = Self::current(&mut ongoing, producer)?;
+        transaction.partitions.extend(partitions);
+        Ok(())
+    }
+
+    // A later offset for the same consumer and partition replaces the earlier one
+    pub(crate) fn add_offsets(&self, producer: ProducerIdentity, offsets: Vec<TxnOffset>) -> Result<(), BrokerError> {
+        let mut ongoing = self.ongoing.lock();
+        let transaction = Self::current(&mut ongoing, producer)?;
+        for offset in offsets {
+            transaction.offsets.retain(|added| {
+                (&added.consumer_id, &added.topic, added.partition)
+                    != (&offset.consumer_id, &offset.topic, offset.partition)
+            });
+            transaction.offsets.push(offset);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn end(&self, producer: ProducerIdentity) -> Result<Transaction, BrokerError> {
+        let mut ongoing = self.ongoing.lock();
+        Self::current(&mut ongoing, producer)?;
+        Ok(ongoing.remove(&producer.producer_id).expect("checked above"))
+    }
+
+    // A transaction left open by an older epoch of the producer, which
+    // nobody can end any more
+    pub(crate) fn take_stale(&self, producer: ProducerIdentity) -> Option<Transaction> {
+        let mut ongoing = self.ongoing.lock();
+        let stale = ongoing
+            .get(&producer.producer_id)
+            .is_some_and(|transaction| transaction.producer.producer_epoch < producer.producer_epoch);
+        if stale {
+            ongoing.remove(&producer.producer_id)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn take_expired(&self) -> Vec<Transaction> {
+        let now = Instant::now();
+        let mut ongoing = self.ongoing.lock();
+        let expired: Vec<i64> = ongoing
+            .iter()
+            .filter(|(_, transaction)| transaction.deadline <= now)
+            .map(|(producer_id, _)| *producer_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|producer_id| ongoing.remove(&producer_id))
+            .collect()
+    }
+
+    fn current(
+        ongoing: &mut HashMap<i64, Transaction>,
+        producer: ProducerIdentity,
+    ) -> Result<&mut Transaction, BrokerError> {
+        match ongoing.get_mut(&producer.producer_id) {
+            Some(transaction) if transaction.producer == producer => Ok(transaction),
+            _ => Err(BrokerError::new(
+                ErrorCode::InvalidTransactionState,
+                format!(
+                    "Producer {} epoch {} has no transaction in progress",
+                    producer.producer_id, producer.producer_epoch
+                ),
+            )),
+        }
+    }
+}
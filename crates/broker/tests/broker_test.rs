@@ -1 +1,1505 @@
-//test
\ No newline at end of file
+use std::time::Duration;
+
+use bytes::Bytes;
+use rafka_broker::Broker;
+use rafka_storage::db::RetentionPolicy;
+use rafka_core::message::{AckStatus, ErrorCode};
+use rafka_core::partitioner::PartitionStrategy;
+use rafka_protocol::{
+    AclBinding, AclOperation, Acks, ApiKey, BrokerMessage, BrokerResponse, Compression, Connection, ConnectionError, Credit, EncodedBatch, FrameReader,
+    FrameWriter, IsolationLevel, MetadataRecord, ProducerIdentity, QuorumDescription, Record, RecordBatch, Request, Response, TopicConfig,
+    TopicPartitions, TxnOffset, IDEMPOTENCE,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+async fn start_broker(address: &'static str, partition_id: u32, total_partitions: u32) {
+    start_broker_with_retention(address, partition_id, total_partitions, None).await;
+}
+
+async fn start_broker_with_retention(
+    address: &'static str,
+    partition_id: u32,
+    total_partitions: u32,
+    retention_policy: Option<RetentionPolicy>,
+) {
+    tokio::spawn(async move {
+        Broker::new(partition_id, total_partitions, retention_policy)
+            .serve(address)
+            .await
+            .unwrap();
+    });
+    sleep(Duration::from_millis(50)).await;
+}
+
+fn publish(key: &str, payload: &str) -> BrokerMessage {
+    BrokerMessage::Publish {
+        key: key.to_string(),
+        topic: "tests".to_string(),
+        payload: payload.as_bytes().to_vec(),
+        headers: Vec::new(),
+        acks: Acks::Leader,
+    }
+}
+
+#[tokio::test]
+async fn test_api_versions_handshake() {
+    const ADDRESS: &str = "127.0.0.1:50061";
+    start_broker(ADDRESS, 0, 1).await;
+
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+    let versions = connection.versions();
+
+    assert_eq!(versions.version(ApiKey::Publish), Some(ApiKey::Publish.current_version()));
+    assert!(versions.supports(ApiKey::ListClients));
+}
+
+#[tokio::test]
+async fn test_typed_replies() {
+    const ADDRESS: &str = "127.0.0.1:50062";
+    // Second of two brokers, "key-0" hashes to partition 1 and "key-1" to partition 0
+    start_broker(ADDRESS, 1, 2).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let BrokerResponse::Ack(ack) = connection.request(publish("key-0", "hello")).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    assert_eq!(ack.status, AckStatus::Success);
+    assert_eq!((ack.partition, ack.offset), (1, 0));
+
+    let BrokerResponse::Ack(ack) = connection.request(publish("key-1", "hello")).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    let AckStatus::Error(error) = ack.status else {
+        panic!("expected a rejected publish");
+    };
+    assert_eq!(error.code, ErrorCode::NotLeader);
+    assert_eq!(ack.partition, 0);
+
+    let update = BrokerMessage::UpdateOffset {
+        consumer_id: "consumer".to_string(),
+        topic: "missing".to_string(),
+        partition: 0,
+        offset: 3,
+    };
+    let BrokerResponse::Error(error) = connection.request(update).await.unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.code, ErrorCode::UnknownTopic);
+}
+
+#[tokio::test]
+async fn test_pipelined_requests() {
+    const ADDRESS: &str = "127.0.0.1:50063";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    // Put every publish on the wire before looking at any ack
+    let mut pending = Vec::new();
+    for i in 0..50 {
+        pending.push(connection.send(publish("key", &format!("message-{}", i))).await.unwrap());
+    }
+
+    // Acks still arrive in order even though we wait on them back to front
+    for (i, pending) in pending.into_iter().enumerate().rev() {
+        let BrokerResponse::Ack(ack) = pending.response().await.unwrap() else {
+            panic!("expected an ack");
+        };
+        assert_eq!(ack.offset, i as i64);
+    }
+}
+
+#[tokio::test]
+async fn test_malformed_request_keeps_correlation_id() {
+    const ADDRESS: &str = "127.0.0.1:50064";
+    start_broker(ADDRESS, 0, 1).await;
+
+    let (reader, writer) = TcpStream::connect(ADDRESS).await.unwrap().into_split();
+    let mut reader = FrameReader::new(reader);
+    let mut writer = FrameWriter::new(writer);
+
+    // A request type this broker has never heard of
+    writer
+        .write_frame(br#"{"correlation_id":42,"message":{"FromTheFuture":{}}}"#)
+        .await
+        .unwrap();
+
+    let response: Response = reader.read_message().await.unwrap().unwrap();
+    assert_eq!(response.correlation_id, 42);
+    let BrokerResponse::Error(error) = response.response else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.code, ErrorCode::MalformedRequest);
+}
+
+#[tokio::test]
+async fn test_batches_delivered_as_sent() {
+    const ADDRESS: &str = "127.0.0.1:50065";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    connection.request(subscribe("consumer", "tests")).await.unwrap();
+    let mut deliveries = connection.stream(consume("consumer")).await.unwrap();
+
+    // Move the offsets along so the delivered batch needs a new base offset
+    connection.request(publish("key", "first")).await.unwrap();
+
+    let records = (0..3)
+        .map(|i| Record::new(i, Some(Bytes::from("key")), Bytes::from(format!("value-{}", i))))
+        .collect();
+    let batch = RecordBatch::new(0, 1_000, records)
+        .with_compression(Compression::Zstd)
+        .encoded();
+    let produce = BrokerMessage::Produce {
+        topic: "tests".to_string(),
+        partition: None,
+        records: batch.as_bytes().clone(),
+        acks: Acks::Leader,
+    };
+    let BrokerResponse::Ack(ack) = connection.request(produce).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    assert_eq!(ack.offset, 1);
+
+    let _first = deliveries.recv().await.unwrap();
+    let BrokerResponse::Records(delivery) = deliveries.recv().await.unwrap() else {
+        panic!("expected records");
+    };
+    // Byte for byte what was sent, apart from the base offset
+    assert_eq!(delivery.records, batch.with_base_offset(1).into_bytes());
+}
+
+fn subscribe(consumer_id: &str, topic: &str) -> BrokerMessage {
+    BrokerMessage::Subscribe {
+        consumer_id: consumer_id.to_string(),
+        topic: topic.to_string(),
+    }
+}
+
+fn consume(consumer_id: &str) -> BrokerMessage {
+    BrokerMessage::Consume {
+        consumer_id: consumer_id.to_string(),
+        topics: vec!["tests".to_string()],
+        group_id: None,
+        credit: None,
+        isolation: IsolationLevel::ReadUncommitted,
+    }
+}
+
+async fn next_offset(deliveries: &mut tokio::sync::mpsc::Receiver<BrokerResponse>) -> i64 {
+    let BrokerResponse::Records(delivery) = deliveries.recv().await.unwrap() else {
+        panic!("expected records");
+    };
+    EncodedBatch::split_all(delivery.records).unwrap()[0].base_offset()
+}
+
+#[tokio::test]
+async fn test_replay_from_storage() {
+    const ADDRESS: &str = "127.0.0.1:50066";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    // Published before anyone is consuming
+    for i in 0..3 {
+        connection.request(publish("key", &format!("message-{}", i))).await.unwrap();
+    }
+
+    connection.request(subscribe("replay", "tests")).await.unwrap();
+    let mut deliveries = connection.stream(consume("replay")).await.unwrap();
+    for offset in 0..3 {
+        assert_eq!(next_offset(&mut deliveries).await, offset);
+    }
+
+    let update = BrokerMessage::UpdateOffset {
+        consumer_id: "replay".to_string(),
+        topic: "tests".to_string(),
+        partition: 0,
+        offset: 1,
+    };
+    connection.request(update).await.unwrap();
+
+    // Picks up after the committed offset
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+    let mut deliveries = connection.stream(consume("replay")).await.unwrap();
+    assert_eq!(next_offset(&mut deliveries).await, 2);
+}
+
+#[tokio::test]
+async fn test_retention_policy_applied() {
+    const ADDRESS: &str = "127.0.0.1:50067";
+    let retention_policy = RetentionPolicy {
+        max_age: Duration::from_secs(60),
+        max_bytes: 1024,
+    };
+    start_broker_with_retention(ADDRESS, 0, 1, Some(retention_policy)).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    for _ in 0..10 {
+        connection.request(publish("key", &"x".repeat(500))).await.unwrap();
+    }
+
+    // Only the newest batches fit in 1KB
+    connection.request(subscribe("late", "tests")).await.unwrap();
+    let mut deliveries = connection.stream(consume("late")).await.unwrap();
+    assert!(next_offset(&mut deliveries).await >= 8);
+}
+
+#[tokio::test]
+async fn test_consume_scoped_to_subscriptions() {
+    const ADDRESS: &str = "127.0.0.1:50068";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    // Not subscribed to anything yet
+    let response = connection.request(consume("scoped")).await.unwrap();
+    let BrokerResponse::Error(error) = response else {
+        panic!("expected an error, got {:?}", response);
+    };
+    assert_eq!(error.code, ErrorCode::NotSubscribed);
+
+    let publish_to = |topic: &str, payload: &str| BrokerMessage::Publish {
+        key: "key".to_string(),
+        topic: topic.to_string(),
+        payload: payload.as_bytes().to_vec(),
+        headers: Vec::new(),
+        acks: Acks::Leader,
+    };
+    connection.request(publish_to("orders", "order-1")).await.unwrap();
+    connection.request(publish_to("payments", "payment-1")).await.unwrap();
+    connection.request(publish_to("audit", "audit-1")).await.unwrap();
+
+    connection.request(subscribe("scoped", "orders")).await.unwrap();
+    connection.request(subscribe("scoped", "payments")).await.unwrap();
+    let all_subscribed = BrokerMessage::Consume {
+        consumer_id: "scoped".to_string(),
+        topics: Vec::new(),
+        group_id: None,
+        credit: None,
+        isolation: IsolationLevel::ReadUncommitted,
+    };
+    let mut deliveries = connection.stream(all_subscribed).await.unwrap();
+
+    let mut topics = Vec::new();
+    for _ in 0..2 {
+        let BrokerResponse::Records(delivery) = deliveries.recv().await.unwrap() else {
+            panic!("expected records");
+        };
+        topics.push(delivery.topic);
+    }
+    topics.sort();
+    assert_eq!(topics, vec!["orders", "payments"]);
+
+    // Nothing from the topic that was never subscribed to
+    let extra = tokio::time::timeout(Duration::from_millis(200), deliveries.recv()).await;
+    assert!(extra.is_err());
+}
+
+#[tokio::test]
+async fn test_many_partitions_per_broker() {
+    const ADDRESS: &str = "127.0.0.1:50069";
+    // First of two brokers, so it hosts partitions 0 and 2 of a four partition topic
+    start_broker(ADDRESS, 0, 2).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let create = |topic: &str| BrokerMessage::CreateTopic {
+        topic: topic.to_string(),
+        partitions: 4,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig::default(),
+    };
+    let response = connection.request(create("orders")).await.unwrap();
+    assert!(matches!(response, BrokerResponse::TopicCreated { partitions: 4, .. }));
+    let BrokerResponse::Error(error) = connection.request(create("orders")).await.unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.code, ErrorCode::TopicAlreadyExists);
+
+    let produce = |partition: u32, value: &str| {
+        let record = Record::new(0, None, Bytes::from(value.to_string()));
+        BrokerMessage::Produce {
+            topic: "orders".to_string(),
+            partition: Some(partition),
+            records: RecordBatch::new(0, 0, vec![record]).encoded().into_bytes(),
+            acks: Acks::Leader,
+        }
+    };
+    let ack = |response: BrokerResponse| {
+        let BrokerResponse::Ack(ack) = response else {
+            panic!("expected an ack, got {:?}", response);
+        };
+        ack
+    };
+
+    // Each partition counts its own offsets
+    for (partition, offset) in [(0, 0), (2, 0), (2, 1), (0, 1)] {
+        let ack = ack(connection.request(produce(partition, "order")).await.unwrap());
+        assert_eq!(ack.status, AckStatus::Success);
+        assert_eq!((ack.partition, ack.offset), (partition, offset));
+    }
+
+    let ack = ack(connection.request(produce(1, "order")).await.unwrap());
+    let AckStatus::Error(error) = ack.status else {
+        panic!("expected a rejected produce");
+    };
+    assert_eq!(error.code, ErrorCode::NotLeader);
+
+    let BrokerResponse::Error(error) = connection.request(produce(4, "order")).await.unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.code, ErrorCode::UnknownTopic);
+
+    // A consumer gets every partition hosted here
+    connection.request(subscribe("reader", "orders")).await.unwrap();
+    let consume = BrokerMessage::Consume {
+        consumer_id: "reader".to_string(),
+        topics: vec!["orders".to_string()],
+        group_id: None,
+        credit: None,
+        isolation: IsolationLevel::ReadUncommitted,
+    };
+    let mut deliveries = connection.stream(consume).await.unwrap();
+    let mut delivered = Vec::new();
+    for _ in 0..4 {
+        let BrokerResponse::Records(delivery) = deliveries.recv().await.unwrap() else {
+            panic!("expected records");
+        };
+        let offset = EncodedBatch::split_all(delivery.records).unwrap()[0].base_offset();
+        delivered.push((delivery.partition, offset));
+    }
+    delivered.sort();
+    assert_eq!(delivered, vec![(0, 0), (0, 1), (2, 0), (2, 1)]);
+}
+
+#[tokio::test]
+async fn test_topic_partitioner() {
+    const ADDRESS: &str = "127.0.0.1:50060";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let create = BrokerMessage::CreateTopic {
+        topic: "spread".to_string(),
+        partitions: 3,
+        partitioner: PartitionStrategy::RoundRobin,
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+
+    // Same key every time, round robin ignores it
+    let mut partitions = Vec::new();
+    for _ in 0..4 {
+        let produce = BrokerMessage::Produce {
+            topic: "spread".to_string(),
+            partition: None,
+            records: RecordBatch::new(0, 0, vec![Record::new(0, Some(Bytes::from("key")), Bytes::from("value"))])
+                .encoded()
+                .into_bytes(),
+            acks: Acks::Leader,
+        };
+        let BrokerResponse::Ack(ack) = connection.request(produce).await.unwrap() else {
+            panic!("expected an ack");
+        };
+        partitions.push(ack.partition);
+    }
+    assert_eq!(partitions, vec![0, 1, 2, 0]);
+}
+
+#[tokio::test]
+async fn test_not_leader_redirect_and_forwarding() {
+    const FIRST: &str = "127.0.0.1:50058";
+    const SECOND: &str = "127.0.0.1:50059";
+    let peers = vec![FIRST.to_string(), SECOND.to_string()];
+    // Only the second broker forwards
+    for (broker_id, address) in [(0, FIRST), (1, SECOND)] {
+        let mut broker = Broker::new(broker_id, 2, None).with_peers(peers.clone());
+        if broker_id == 1 {
+            broker = broker.with_forwarding();
+        }
+        tokio::spawn(async move { broker.serve(address).await.unwrap() });
+    }
+    sleep(Duration::from_millis(50)).await;
+
+    // "key-0" belongs on partition 1, hosted by the second broker
+    let first = Connection::connect(FIRST).await.unwrap();
+    let BrokerResponse::Ack(ack) = first.request(publish("key-0", "redirected")).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    let AckStatus::Error(error) = ack.status else {
+        panic!("expected a rejected publish");
+    };
+    assert_eq!(error.code, ErrorCode::NotLeader);
+    let leader = error.leader.unwrap();
+    assert_eq!((leader.broker_id, leader.addr.as_deref()), (1, Some(SECOND)));
+
+    // "key-1" belongs on partition 0, the second broker passes it along
+    let second = Connection::connect(SECOND).await.unwrap();
+    let BrokerResponse::Ack(ack) = second.request(publish("key-1", "forwarded")).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    assert_eq!(ack.status, AckStatus::Success);
+    assert_eq!((ack.partition, ack.offset), (0, 0));
+
+    // And it really is stored on the first
+    first.request(subscribe("reader", "tests")).await.unwrap();
+    let mut deliveries = first.stream(consume("reader")).await.unwrap();
+    assert_eq!(next_offset(&mut deliveries).await, 0);
+}
+
+#[tokio::test]
+async fn test_fetch_long_poll() {
+    const ADDRESS: &str = "127.0.0.1:50085";
+    // First of two brokers, so it hosts partition 0 only
+    start_broker(ADDRESS, 0, 2).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let create = BrokerMessage::CreateTopic {
+        topic: "pulled".to_string(),
+        partitions: 2,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+
+    let fetch = |partition: u32, offset: i64, max_wait_ms: u64| BrokerMessage::Fetch {
+        topic: "pulled".to_string(),
+        partition,
+        offset,
+        max_bytes: 1024 * 1024,
+        max_wait_ms,
+        isolation: IsolationLevel::ReadUncommitted,
+        replica_id: None,
+    };
+    let error = |response: BrokerResponse| match response {
+        BrokerResponse::Error(error) => error,
+        other => panic!("expected an error, got {:?}", other),
+    };
+
+    let not_leader = error(connection.request(fetch(1, 0, 0)).await.unwrap());
+    assert_eq!(not_leader.code, ErrorCode::NotLeader);
+    assert_eq!(not_leader.leader.map(|leader| leader.broker_id), Some(1));
+    let out_of_range = error(connection.request(fetch(0, 5, 0)).await.unwrap());
+    assert_eq!(out_of_range.code, ErrorCode::InvalidOffset);
+
+    // Nothing there yet, so the fetch waits for the append
+    let waiting = {
+        let connection = connection.clone();
+        tokio::spawn(async move { connection.request(fetch(0, 0, 5_000)).await.unwrap() })
+    };
+    sleep(Duration::from_millis(100)).await;
+    assert!(!waiting.is_finished());
+
+    let record = Record::new(0, None, Bytes::from("pulled"));
+    let produce = BrokerMessage::Produce {
+        topic: "pulled".to_string(),
+        partition: Some(0),
+        records: RecordBatch::new(0, 0, vec![record]).encoded().into_bytes(),
+        acks: Acks::Leader,
+    };
+    connection.request(produce).await.unwrap();
+
+    let started = std::time::Instant::now();
+    let BrokerResponse::Fetched(fetched) = waiting.await.unwrap() else {
+        panic!("expected fetched records");
+    };
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!((fetched.high_watermark, fetched.log_start_offset), (1, 0));
+    let batches = EncodedBatch::split_all(fetched.records).unwrap();
+    let batch = batches[0].decode().unwrap();
+    assert_eq!(batch.records[0].value.as_deref(), Some(&b"pulled"[..]));
+
+    // Caught up: answered empty once max_wait is up
+    let started = std::time::Instant::now();
+    let BrokerResponse::Fetched(fetched) = connection.request(fetch(0, 1, 200)).await.unwrap() else {
+        panic!("expected an empty fetch");
+    };
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(fetched.records.is_empty());
+    assert_eq!(fetched.high_watermark, 1);
+}
+
+#[tokio::test]
+async fn test_consume_paced_by_credit() {
+    const ADDRESS: &str = "127.0.0.1:50086";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    connection.request(subscribe("paced", "tests")).await.unwrap();
+    let consume = BrokerMessage::Consume {
+        consumer_id: "paced".to_string(),
+        topics: vec!["tests".to_string()],
+        group_id: None,
+        credit: Some(Credit { messages: 2, bytes: 1024 * 1024 }),
+        isolation: IsolationLevel::ReadUncommitted,
+    };
+    let mut deliveries = connection.stream(consume).await.unwrap();
+
+    for i in 0..4 {
+        connection.request(publish("key", &format!("message-{}", i))).await.unwrap();
+    }
+    assert_eq!(next_offset(&mut deliveries).await, 0);
+    assert_eq!(next_offset(&mut deliveries).await, 1);
+
+    // Out of credit, the rest waits however long it takes
+    let waited = tokio::time::timeout(Duration::from_millis(200), deliveries.recv()).await;
+    assert!(waited.is_err());
+
+    let grant = BrokerMessage::GrantCredit {
+        consumer_id: "paced".to_string(),
+        credit: Credit { messages: 1, bytes: 1024 },
+    };
+    let response = connection.request(grant).await.unwrap();
+    assert!(matches!(response, BrokerResponse::CreditGranted));
+    assert_eq!(next_offset(&mut deliveries).await, 2);
+    let waited = tokio::time::timeout(Duration::from_millis(200), deliveries.recv()).await;
+    assert!(waited.is_err());
+
+    // Granting to a consumer that isn't consuming is an error
+    let grant = BrokerMessage::GrantCredit {
+        consumer_id: "nobody".to_string(),
+        credit: Credit { messages: 1, bytes: 1024 },
+    };
+    let response = connection.request(grant).await.unwrap();
+    assert!(matches!(response, BrokerResponse::Error(e) if e.code == ErrorCode::NotSubscribed));
+}
+
+#[tokio::test]
+async fn test_topic_administration() {
+    const ADDRESS: &str = "127.0.0.1:50087";
+    tokio::spawn(async move {
+        Broker::new(0, 2, None)
+            .with_auto_create_topics(false)
+            .serve(ADDRESS)
+            .await
+            .unwrap();
+    });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let error = |response: BrokerResponse| match response {
+        BrokerResponse::Error(error) => error.code,
+        other => panic!("expected an error, got {:?}", other),
+    };
+
+    // Unknown topics are no longer created on first use
+    assert_eq!(error(connection.request(publish("key", "lost")).await.unwrap()), ErrorCode::UnknownTopic);
+    assert_eq!(error(connection.request(subscribe("reader", "tests")).await.unwrap()), ErrorCode::UnknownTopic);
+
+    // Keeps hardly anything, so old batches go as new ones come in
+    let create = BrokerMessage::CreateTopic {
+        topic: "tests".to_string(),
+        partitions: 2,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig {
+            retention_ms: None,
+            retention_bytes: Some(1),
+        },
+    };
+    connection.request(create).await.unwrap();
+    for i in 0..3 {
+        let produce = BrokerMessage::Produce {
+            topic: "tests".to_string(),
+            partition: Some(0),
+            records: RecordBatch::new(0, 0, vec![Record::new(0, None, Bytes::from(format!("value-{}", i)))])
+                .encoded()
+                .into_bytes(),
+            acks: Acks::Leader,
+        };
+        connection.request(produce).await.unwrap();
+    }
+    let commit = BrokerMessage::UpdateOffset {
+        consumer_id: "reader".to_string(),
+        topic: "tests".to_string(),
+        partition: 0,
+        offset: 2,
+    };
+    connection.request(commit).await.unwrap();
+
+    let response = connection.request(BrokerMessage::ListTopics).await.unwrap();
+    let BrokerResponse::TopicList { topics } = response else {
+        panic!("expected a topic list");
+    };
+    assert_eq!(topics, vec!["tests".to_string()]);
+
+    let describe = || BrokerMessage::DescribeTopic { topic: "tests".to_string() };
+    let BrokerResponse::TopicDescribed(description) = connection.request(describe()).await.unwrap() else {
+        panic!("expected a description");
+    };
+    assert_eq!(description.config.retention_bytes, Some(1));
+    assert_eq!(description.partitions.len(), 2);
+    let hosted = &description.partitions[0];
+    assert_eq!((hosted.leader, hosted.high_watermark), (0, Some(3)));
+    assert!(hosted.log_start_offset.unwrap() > 0);
+    // Partition 1 is on the other broker
+    let elsewhere = &description.partitions[1];
+    assert_eq!((elsewhere.leader, elsewhere.high_watermark), (1, None));
+
+    let delete = || BrokerMessage::DeleteTopic { topic: "tests".to_string() };
+    let response = connection.request(delete()).await.unwrap();
+    assert!(matches!(response, BrokerResponse::TopicDeleted { .. }));
+    assert_eq!(error(connection.request(delete()).await.unwrap()), ErrorCode::UnknownTopic);
+    assert_eq!(error(connection.request(describe()).await.unwrap()), ErrorCode::UnknownTopic);
+    let response = connection.request(BrokerMessage::ListTopics).await.unwrap();
+    assert!(matches!(response, BrokerResponse::TopicList { topics } if topics.is_empty()));
+
+    // Recreated, it starts from scratch, committed offsets included
+    let create = BrokerMessage::CreateTopic {
+        topic: "tests".to_string(),
+        partitions: 2,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+    let BrokerResponse::TopicDescribed(description) = connection.request(describe()).await.unwrap() else {
+        panic!("expected a description");
+    };
+    assert_eq!(description.partitions[0].high_watermark, Some(0));
+    connection.request(subscribe("reader", "tests")).await.unwrap();
+    let mut deliveries = connection.stream(consume("reader")).await.unwrap();
+    connection.request(publish("key-1", "fresh")).await.unwrap();
+    assert_eq!(next_offset(&mut deliveries).await, 0);
+}
+
+#[tokio::test]
+async fn test_metadata() {
+    const ADDRESS: &str = "127.0.0.1:50088";
+    // Second of three brokers, told nothing about the others
+    start_broker(ADDRESS, 1, 3).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let create = BrokerMessage::CreateTopic {
+        topic: "orders".to_string(),
+        partitions: 4,
+        partitioner: PartitionStrategy::RoundRobin,
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+
+    let metadata = |topics: Option<Vec<String>>| BrokerMessage::Metadata { topics };
+    let BrokerResponse::Metadata(everything) = connection.request(metadata(None)).await.unwrap() else {
+        panic!("expected metadata");
+    };
+    assert_eq!(everything.broker_id, 1);
+    let brokers: Vec<_> = everything
+        .brokers
+        .iter()
+        .map(|broker| (broker.broker_id, broker.addr.as_deref()))
+        .collect();
+    assert_eq!(brokers, vec![(0, None), (1, Some(ADDRESS)), (2, None)]);
+    assert_eq!(everything.topics.len(), 1);
+    let orders = &everything.topics[0];
+    assert_eq!((orders.topic.as_str(), orders.partitioner), ("orders", PartitionStrategy::RoundRobin));
+    let leaders: Vec<_> = orders.partitions.iter().map(|partition| (partition.partition, partition.leader)).collect();
+    assert_eq!(leaders, vec![(0, 0), (1, 1), (2, 2), (3, 0)]);
+
+    // Named topics are created as they would be on publish
+    let BrokerResponse::Metadata(named) = connection
+        .request(metadata(Some(vec!["fresh".to_string()])))
+        .await
+        .unwrap()
+    else {
+        panic!("expected metadata");
+    };
+    let topics: Vec<_> = named.topics.iter().map(|topic| (topic.topic.as_str(), topic.partitions.len())).collect();
+    assert_eq!(topics, vec![("fresh", 3)]);
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    const ADDRESS: &str = "127.0.0.1:50089";
+    const METRICS_ADDRESS: &str = "127.0.0.1:50090";
+    tokio::spawn(async move {
+        Broker::new(0, 1, None)
+            .with_metrics_listener(METRICS_ADDRESS)
+            .serve(ADDRESS)
+            .await
+            .unwrap();
+    });
+    sleep(Duration::from_millis(100)).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    for payload in ["one", "two", "three"] {
+        connection.request(publish("key", payload)).await.unwrap();
+    }
+    let update = BrokerMessage::UpdateOffset {
+        consumer_id: "consumer".to_string(),
+        topic: "tests".to_string(),
+        partition: 0,
+        offset: 0,
+    };
+    connection.request(update).await.unwrap();
+
+    let BrokerResponse::Metrics(metrics) = connection.request(BrokerMessage::GetMetrics).await.unwrap() else {
+        panic!("expected metrics");
+    };
+    assert_eq!(metrics.messages_in, 3);
+    assert!(metrics.bytes_in > 0);
+    assert_eq!(metrics.messages_out, 0);
+    assert_eq!(metrics.connected_clients, 1);
+    let partitions: Vec<_> = metrics
+        .partitions
+        .iter()
+        .map(|partition| (partition.topic.as_str(), partition.partition, partition.high_watermark))
+        .collect();
+    assert_eq!(partitions, vec![("tests", 0, 3)]);
+    let lag: Vec<_> = metrics.consumer_lag.iter().map(|lag| (lag.consumer_id.as_str(), lag.lag)).collect();
+    assert_eq!(lag, vec![("consumer", 2)]);
+    let publish_latency = metrics
+        .request_latency
+        .iter()
+        .find(|histogram| histogram.api_key == ApiKey::Publish)
+        .unwrap();
+    assert_eq!(publish_latency.count, 3);
+    assert_eq!(publish_latency.buckets.last().unwrap().count, 3);
+
+    let mut scrape = TcpStream::connect(METRICS_ADDRESS).await.unwrap();
+    scrape.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    scrape.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("rafka_messages_in_total{broker=\"0\"} 3"));
+    assert!(response.contains("rafka_consumer_lag{broker=\"0\",consumer=\"consumer\",topic=\"tests\",partition=\"0\"} 2"));
+    assert!(response.contains("rafka_request_latency_seconds_count{broker=\"0\",api=\"Publish\"} 3"));
+
+    let mut scrape = TcpStream::connect(METRICS_ADDRESS).await.unwrap();
+    scrape.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    scrape.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[tokio::test]
+async fn test_client_registry() {
+    const ADDRESS: &str = "127.0.0.1:50091";
+    start_broker(ADDRESS, 0, 1).await;
+    // A bare socket, so the test can hang up on the broker
+    let (reader, writer) = TcpStream::connect(ADDRESS).await.unwrap().into_split();
+    let mut first_reader = FrameReader::new(reader);
+    let mut first = FrameWriter::new(writer);
+    let second = Connection::connect(ADDRESS).await.unwrap();
+
+    let register = |client_id: &str, client_type: &str| BrokerMessage::Register {
+        client_id: client_id.to_string(),
+        client_type: client_type.to_string(),
+        client_version: Some("1.2.3".to_string()),
+        features: vec!["compression".to_string()],
+    };
+    let request = Request {
+        correlation_id: 1,
+        message: register("writer", "producer"),
+    };
+    first.write_message(&request).await.unwrap();
+    let response: Response = first_reader.read_message().await.unwrap().unwrap();
+    let BrokerResponse::Registered { client_id, .. } = response.response else {
+        panic!("expected registration");
+    };
+    assert_eq!(client_id, "writer");
+    second.request(register("reader", "consumer")).await.unwrap();
+
+    // Ids belong to one connection, types must be known
+    let BrokerResponse::Error(error) = second.request(register("writer", "consumer")).await.unwrap() else {
+        panic!("expected duplicate id to be rejected");
+    };
+    assert_eq!(error.code, ErrorCode::DuplicateClientId);
+    let BrokerResponse::Error(error) = second.request(register("other", "robot")).await.unwrap() else {
+        panic!("expected unknown type to be rejected");
+    };
+    assert_eq!(error.code, ErrorCode::UnknownClientType);
+
+    let BrokerResponse::ClientList { clients } = second.request(BrokerMessage::ListClients).await.unwrap() else {
+        panic!("expected client list");
+    };
+    let sessions: Vec<_> = clients
+        .iter()
+        .map(|client| (client.client_id.as_str(), client.client_type.as_str(), client.client_version.as_deref()))
+        .collect();
+    assert_eq!(sessions, vec![("reader", "consumer", Some("1.2.3")), ("writer", "producer", Some("1.2.3"))]);
+    assert_eq!(clients[1].features, vec!["compression".to_string()]);
+    assert!(clients.iter().all(|client| client.connected_at_ms > 0 && client.addr.starts_with("127.0.0.1:")));
+
+    // Sessions end with their connection, freeing the id
+    drop((first, first_reader));
+    sleep(Duration::from_millis(100)).await;
+    let BrokerResponse::ClientList { clients } = second.request(BrokerMessage::ListClients).await.unwrap() else {
+        panic!("expected client list");
+    };
+    let ids: Vec<_> = clients.iter().map(|client| client.client_id.as_str()).collect();
+    assert_eq!(ids, vec!["reader"]);
+    let response = second.request(register("writer", "producer")).await.unwrap();
+    assert!(matches!(response, BrokerResponse::Registered { .. }));
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    const ADDRESS: &str = "127.0.0.1:50092";
+    let broker = Broker::new(0, 1, None);
+    let shutdown = broker.shutdown_handle();
+    let serving = tokio::spawn(async move { broker.serve(ADDRESS).await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+    let idle = Connection::connect(ADDRESS).await.unwrap();
+
+    connection.request(subscribe("consumer", "tests")).await.unwrap();
+    let mut deliveries = connection.stream(consume("consumer")).await.unwrap();
+    connection.request(publish("key", "before")).await.unwrap();
+    assert_eq!(next_offset(&mut deliveries).await, 0);
+
+    // A long poll is answered early rather than cut off
+    let fetch = BrokerMessage::Fetch {
+        topic: "tests".to_string(),
+        partition: 0,
+        offset: 1,
+        max_bytes: 1024,
+        max_wait_ms: 60_000,
+        isolation: IsolationLevel::ReadUncommitted,
+        replica_id: None,
+    };
+    let polling = connection.send(fetch).await.unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    shutdown.shutdown();
+    let BrokerResponse::Fetched(fetched) = polling.response().await.unwrap() else {
+        panic!("expected the long poll to be answered");
+    };
+    assert!(fetched.records.is_empty());
+    let BrokerResponse::Error(error) = deliveries.recv().await.unwrap() else {
+        panic!("expected consumers to be told to go elsewhere");
+    };
+    assert_eq!(error.code, ErrorCode::ShuttingDown);
+
+    tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap().unwrap();
+    // Every connection is told, not just the ones waiting on a reply
+    for connection in [&connection, &idle] {
+        let refused = connection.request(publish("key", "after")).await;
+        assert!(matches!(refused, Err(ConnectionError::ShuttingDown)), "{:?}", refused.err());
+    }
+    assert!(TcpStream::connect(ADDRESS).await.is_err());
+}
+
+#[tokio::test]
+async fn test_idempotent_produce() {
+    const ADDRESS: &str = "127.0.0.1:50093";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let register = BrokerMessage::Register {
+        client_id: "idempotent".to_string(),
+        client_type: "producer".to_string(),
+        client_version: None,
+        features: vec![IDEMPOTENCE.to_string()],
+    };
+    let BrokerResponse::Registered { producer: Some(identity), .. } = connection.request(register.clone()).await.unwrap() else {
+        panic!("expected a producer id");
+    };
+    assert_eq!(identity.producer_epoch, 0);
+
+    let produce = |epoch: i16, sequence: i32| {
+        let records = (0..3)
+            .map(|i| Record::new(i, Some(Bytes::from("key")), Bytes::from(format!("value-{}", i))))
+            .collect();
+        let batch = RecordBatch::new(0, 1_000, records).with_producer(identity.producer_id, epoch, sequence);
+        BrokerMessage::Produce {
+            topic: "tests".to_string(),
+            partition: Some(0),
+            records: batch.encoded().into_bytes(),
+            acks: Acks::Leader,
+        }
+    };
+    let offset = |response: BrokerResponse| match response {
+        BrokerResponse::Ack(ack) => ack.offset,
+        other => panic!("expected an ack, got {:?}", other),
+    };
+    let error_code = |response: BrokerResponse| match response {
+        BrokerResponse::Error(error) => error.code,
+        other => panic!("expected an error, got {:?}", other),
+    };
+
+    assert_eq!(offset(connection.request(produce(0, 0)).await.unwrap()), 0);
+    // A retry is acked with the offset it got the first time and not stored again
+    assert_eq!(offset(connection.request(produce(0, 0)).await.unwrap()), 0);
+    assert_eq!(error_code(connection.request(produce(0, 5)).await.unwrap()), ErrorCode::OutOfOrderSequence);
+    assert_eq!(offset(connection.request(produce(0, 3)).await.unwrap()), 3);
+
+    // Registering again fences off the old epoch
+    let BrokerResponse::Registered { producer: Some(renewed), .. } = connection.request(register).await.unwrap() else {
+        panic!("expected a producer id");
+    };
+    assert_eq!(renewed, ProducerIdentity { producer_epoch: 1, ..identity });
+    assert_eq!(error_code(connection.request(produce(0, 6)).await.unwrap()), ErrorCode::ProducerFenced);
+    assert_eq!(offset(connection.request(produce(1, 0)).await.unwrap()), 6);
+
+    let describe = BrokerMessage::DescribeTopic { topic: "tests".to_string() };
+    let BrokerResponse::TopicDescribed(description) = connection.request(describe).await.unwrap() else {
+        panic!("expected a description");
+    };
+    assert_eq!(description.partitions[0].high_watermark, Some(9));
+}
+
+#[tokio::test]
+async fn test_transactions() {
+    const ADDRESS: &str = "127.0.0.1:50094";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let register = BrokerMessage::Register {
+        client_id: "transactional".to_string(),
+        client_type: "producer".to_string(),
+        client_version: None,
+        features: vec![IDEMPOTENCE.to_string()],
+    };
+    let BrokerResponse::Registered { producer: Some(producer), .. } = connection.request(register).await.unwrap() else {
+        panic!("expected a producer id");
+    };
+
+    let begin = |timeout_ms: u64| BrokerMessage::BeginTransaction { producer, timeout_ms };
+    let add = BrokerMessage::AddPartitionsToTxn {
+        producer,
+        partitions: vec![TopicPartitions { topic: "tests".to_string(), partitions: vec![0] }],
+    };
+    let produce = |sequence: i32| {
+        let records = (0..2)
+            .map(|i| Record::new(i, Some(Bytes::from("key")), Bytes::from(format!("value-{}", i))))
+            .collect();
+        let batch = RecordBatch::new(0, 1_000, records)
+            .with_producer(producer.producer_id, producer.producer_epoch, sequence)
+            .transactional();
+        BrokerMessage::Produce {
+            topic: "tests".to_string(),
+            partition: Some(0),
+            records: batch.encoded().into_bytes(),
+            acks: Acks::Leader,
+        }
+    };
+    let fetch = |offset: i64, isolation: IsolationLevel| BrokerMessage::Fetch {
+        topic: "tests".to_string(),
+        partition: 0,
+        offset,
+        max_bytes: 1024 * 1024,
+        max_wait_ms: 0,
+        isolation,
+        replica_id: None,
+    };
+    let fetched = |response: BrokerResponse| match response {
+        BrokerResponse::Fetched(fetched) => {
+            let batches = EncodedBatch::split_all(fetched.records.clone()).unwrap().len();
+            (batches, fetched.next_offset, fetched.last_stable_offset)
+        }
+        other => panic!("expected records, got {:?}", other),
+    };
+    let error_code = |response: BrokerResponse| match response {
+        BrokerResponse::Error(error) => error.code,
+        other => panic!("expected an error, got {:?}", other),
+    };
+
+    assert!(matches!(connection.request(begin(60_000)).await.unwrap(), BrokerResponse::TransactionBegun));
+    assert!(matches!(connection.request(add.clone()).await.unwrap(), BrokerResponse::PartitionsAddedToTxn));
+    assert!(matches!(connection.request(produce(0)).await.unwrap(), BrokerResponse::Ack(_)));
+    connection.request(publish("key", "plain")).await.unwrap();
+
+    // Nothing past the open transaction for read_committed readers
+    assert_eq!(fetched(connection.request(fetch(0, IsolationLevel::ReadCommitted)).await.unwrap()), (0, 0, 0));
+    assert_eq!(fetched(connection.request(fetch(0, IsolationLevel::ReadUncommitted)).await.unwrap()), (2, 3, 0));
+
+    let offsets = BrokerMessage::TxnCommitOffsets {
+        producer,
+        offsets: vec![TxnOffset {
+            consumer_id: "reader".to_string(),
+            topic: "tests".to_string(),
+            partition: 0,
+            offset: 1,
+        }],
+    };
+    assert!(matches!(connection.request(offsets).await.unwrap(), BrokerResponse::TxnOffsetsAdded));
+    let commit = BrokerMessage::EndTransaction { producer, commit: true };
+    assert!(matches!(
+        connection.request(commit.clone()).await.unwrap(),
+        BrokerResponse::TransactionEnded { committed: true }
+    ));
+    // The commit marker at offset 3 is skipped
+    assert_eq!(fetched(connection.request(fetch(0, IsolationLevel::ReadCommitted)).await.unwrap()), (2, 4, 4));
+    let BrokerResponse::Metrics(metrics) = connection.request(BrokerMessage::GetMetrics).await.unwrap() else {
+        panic!("expected metrics");
+    };
+    assert_eq!(metrics.consumer_lag[0].consumer_id, "reader");
+    assert_eq!(metrics.consumer_lag[0].committed_offset, 1);
+
+    // Aborted records are never seen, readers just move past them
+    connection.request(begin(60_000)).await.unwrap();
+    connection.request(add.clone()).await.unwrap();
+    assert!(matches!(connection.request(produce(2)).await.unwrap(), BrokerResponse::Ack(_)));
+    let abort = BrokerMessage::EndTransaction { producer, commit: false };
+    connection.request(abort).await.unwrap();
+    assert_eq!(fetched(connection.request(fetch(4, IsolationLevel::ReadCommitted)).await.unwrap()), (0, 7, 7));
+    assert_eq!(error_code(connection.request(commit.clone()).await.unwrap()), ErrorCode::InvalidTransactionState);
+
+    // A transaction that runs out of time is aborted and its producer fenced
+    connection.request(begin(100)).await.unwrap();
+    connection.request(add).await.unwrap();
+    sleep(Duration::from_millis(300)).await;
+    assert_eq!(error_code(connection.request(commit).await.unwrap()), ErrorCode::ProducerFenced);
+    assert_eq!(error_code(connection.request(produce(4)).await.unwrap()), ErrorCode::ProducerFenced);
+}
+
+#[tokio::test]
+async fn test_produce_acks() {
+    const ADDRESS: &str = "127.0.0.1:50095";
+    start_broker(ADDRESS, 0, 1).await;
+
+    let (reader, writer) = TcpStream::connect(ADDRESS).await.unwrap().into_split();
+    let mut reader = FrameReader::new(reader);
+    let mut writer = FrameWriter::new(writer);
+
+    let produce = |partition: u32, acks: Acks| BrokerMessage::Produce {
+        topic: "tests".to_string(),
+        partition: Some(partition),
+        records: RecordBatch::new(0, 0, vec![Record::new(0, None, Bytes::from("value"))])
+            .encoded()
+            .into_bytes(),
+        acks,
+    };
+    let requests = [
+        produce(0, Acks::None),
+        // Not answered even though the topic has no such partition
+        produce(7, Acks::None),
+        produce(0, Acks::Leader),
+        produce(0, Acks::All { timeout_ms: 1_000 }),
+    ];
+    for (correlation_id, message) in requests.into_iter().enumerate() {
+        let request = Request {
+            correlation_id: correlation_id as u64,
+            message,
+        };
+        writer.write_message_with_data(&request, request.message.data()).await.unwrap();
+    }
+
+    // The first reply is the acks=Leader one, which comes after the unacked batch
+    for (correlation_id, offset) in [(2, 1), (3, 2)] {
+        let response: Response = reader.read_message().await.unwrap().unwrap();
+        assert_eq!(response.correlation_id, correlation_id);
+        let BrokerResponse::Ack(ack) = response.response else {
+            panic!("expected an ack, got {:?}", response.response);
+        };
+        assert_eq!(ack.offset, offset);
+    }
+}
+
+#[tokio::test]
+async fn test_replication() {
+    const LEADER: &str = "127.0.0.1:50096";
+    const FOLLOWER: &str = "127.0.0.1:50097";
+    let peers = vec![LEADER.to_string(), FOLLOWER.to_string()];
+    let replicated_broker = |broker_id: u32| {
+        Broker::new(broker_id, 2, None)
+            .with_peers(peers.clone())
+            .with_replication_factor(2)
+            .with_replica_lag_time(Duration::from_secs(1))
+    };
+
+    let leader = replicated_broker(0);
+    tokio::spawn(async move { leader.serve(LEADER).await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(LEADER).await.unwrap();
+
+    let create = BrokerMessage::CreateTopic {
+        topic: "replicated".to_string(),
+        partitions: 1,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+    let produce = |acks: Acks| BrokerMessage::Produce {
+        topic: "replicated".to_string(),
+        partition: Some(0),
+        records: RecordBatch::new(0, 0, vec![Record::new(0, None, Bytes::from("value"))])
+            .encoded()
+            .into_bytes(),
+        acks,
+    };
+    let fetch = |max_wait_ms: u64| BrokerMessage::Fetch {
+        topic: "replicated".to_string(),
+        partition: 0,
+        offset: 0,
+        max_bytes: 1024,
+        max_wait_ms,
+        isolation: IsolationLevel::ReadUncommitted,
+        replica_id: None,
+    };
+    let in_sync_replicas = |response: BrokerResponse| {
+        let BrokerResponse::Metadata(metadata) = response else {
+            panic!("expected metadata, got {:?}", response);
+        };
+        let partition = &metadata.topics[0].partitions[0];
+        assert_eq!(partition.replicas, vec![0, 1]);
+        partition.in_sync_replicas.clone()
+    };
+
+    // The follower isn't running yet, so nothing is committed
+    let BrokerResponse::Ack(ack) = connection.request(produce(Acks::Leader)).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    assert_eq!(ack.offset, 0);
+    let BrokerResponse::Error(error) = connection.request(produce(Acks::All { timeout_ms: 100 })).await.unwrap() else {
+        panic!("expected acks=All to time out");
+    };
+    assert_eq!(error.code, ErrorCode::NotEnoughReplicas);
+    let BrokerResponse::Fetched(fetched) = connection.request(fetch(0)).await.unwrap() else {
+        panic!("expected a fetch response");
+    };
+    assert_eq!(fetched.high_watermark, 0);
+    assert!(fetched.records.is_empty());
+
+    // Once it has copied the log, consumers get to read it
+    let follower = replicated_broker(1);
+    tokio::spawn(async move { follower.serve(FOLLOWER).await.unwrap() });
+    let BrokerResponse::Fetched(fetched) = connection.request(fetch(2_000)).await.unwrap() else {
+        panic!("expected a fetch response");
+    };
+    assert_eq!(fetched.high_watermark, 2);
+    assert_eq!(EncodedBatch::split_all(fetched.records).unwrap().len(), 2);
+    let metadata = BrokerMessage::Metadata { topics: None };
+    assert_eq!(in_sync_replicas(connection.request(metadata.clone()).await.unwrap()), vec![0, 1]);
+
+    let BrokerResponse::Ack(ack) = connection.request(produce(Acks::All { timeout_ms: 2_000 })).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    assert_eq!(ack.offset, 2);
+
+    // The follower learned of the topic from the leader and has all of it
+    let follower = Connection::connect(FOLLOWER).await.unwrap();
+    assert_eq!(in_sync_replicas(follower.request(metadata).await.unwrap()), vec![0, 1]);
+    // It hears the leader's high watermark on its next fetch
+    let mut copied = 0;
+    for _ in 0..20 {
+        let BrokerResponse::Metrics(metrics) = follower.request(BrokerMessage::GetMetrics).await.unwrap() else {
+            panic!("expected metrics");
+        };
+        let copy = metrics.partitions.iter().find(|partition| partition.topic == "replicated").unwrap();
+        copied = copy.high_watermark;
+        if copied == 3 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(copied, 3);
+    let BrokerResponse::Error(error) = follower.request(fetch(0)).await.unwrap() else {
+        panic!("expected followers to send readers to the leader");
+    };
+    assert_eq!(error.code, ErrorCode::NotLeader);
+}
+
+#[tokio::test]
+async fn test_acks_all_does_not_hold_up_connection() {
+    const LEADER: &str = "127.0.0.1:50112";
+    // Never started, so nothing gets past the leader
+    const FOLLOWER: &str = "127.0.0.1:50113";
+    let leader = Broker::new(0, 2, None)
+        .with_peers(vec![LEADER.to_string(), FOLLOWER.to_string()])
+        .with_replication_factor(2);
+    tokio::spawn(async move { leader.serve(LEADER).await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(LEADER).await.unwrap();
+
+    let create = BrokerMessage::CreateTopic {
+        topic: "waiting".to_string(),
+        partitions: 1,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+    let produce = BrokerMessage::Produce {
+        topic: "waiting".to_string(),
+        partition: Some(0),
+        records: RecordBatch::new(0, 0, vec![Record::new(0, None, Bytes::from("value"))])
+            .encoded()
+            .into_bytes(),
+        acks: Acks::All { timeout_ms: 1_000 },
+    };
+
+    // Other requests on the connection are answered while the ack waits
+    let started = tokio::time::Instant::now();
+    let (produced, listed) = tokio::join!(connection.request(produce), async {
+        sleep(Duration::from_millis(50)).await;
+        let topics = list_topics(&connection).await;
+        (topics, started.elapsed())
+    });
+    assert_eq!(listed.0, vec!["waiting".to_string()]);
+    assert!(listed.1 < Duration::from_millis(500), "listing took {:?}", listed.1);
+    let BrokerResponse::Error(error) = produced.unwrap() else {
+        panic!("expected acks=All to time out");
+    };
+    assert_eq!(error.code, ErrorCode::NotEnoughReplicas);
+}
+
+async fn describe_quorum(connection: &Connection) -> QuorumDescription {
+    match connection.request(BrokerMessage::DescribeQuorum).await.unwrap() {
+        BrokerResponse::QuorumDescribed(description) => description,
+        other => panic!("expected a quorum description, got {:?}", other),
+    }
+}
+
+async fn list_topics(connection: &Connection) -> Vec<String> {
+    match connection.request(BrokerMessage::ListTopics).await.unwrap() {
+        BrokerResponse::TopicList { topics } => topics,
+        other => panic!("expected a topic list, got {:?}", other),
+    }
+}
+
+// The leader every one of `connections` agrees on, once they do on one other than `previous`
+async fn await_quorum_leader(connections: &[&Connection], previous: Option<u32>) -> u32 {
+    for _ in 0..100 {
+        let mut leaders = Vec::new();
+        for connection in connections {
+            leaders.push(describe_quorum(connection).await.leader_id);
+        }
+        if leaders[0].is_some() && leaders[0] != previous && leaders.iter().all(|leader| *leader == leaders[0]) {
+            return leaders[0].unwrap();
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the controllers never agreed on a leader");
+}
+
+#[tokio::test]
+async fn test_metadata_quorum() {
+    const ADDRESSES: [&str; 3] = ["127.0.0.1:50098", "127.0.0.1:50099", "127.0.0.1:50100"];
+    let peers: Vec<String> = ADDRESSES.iter().map(|address| address.to_string()).collect();
+    let mut shutdown_handles = Vec::new();
+    for (broker_id, address) in ADDRESSES.into_iter().enumerate() {
+        let broker = Broker::new(broker_id as u32, 3, None)
+            .with_peers(peers.clone())
+            .with_controllers(vec![0, 1, 2])
+            .with_election_timeout(Duration::from_millis(150));
+        shutdown_handles.push(broker.shutdown_handle());
+        tokio::spawn(async move { broker.serve(address).await.unwrap() });
+    }
+    sleep(Duration::from_millis(50)).await;
+    let mut connections = Vec::new();
+    for address in ADDRESSES {
+        connections.push(Connection::connect(address).await.unwrap());
+    }
+    let leader = await_quorum_leader(&connections.iter().collect::<Vec<_>>(), None).await;
+    let follower = &connections[(leader as usize + 1) % 3];
+
+    // Created through a follower, which hands it to the leader
+    let create = |topic: &str| BrokerMessage::CreateTopic {
+        topic: topic.to_string(),
+        partitions: 3,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig { retention_ms: Some(60_000), retention_bytes: None },
+    };
+    let response = follower.request(create("quorum")).await.unwrap();
+    assert!(matches!(response, BrokerResponse::TopicCreated { partitions: 3, .. }), "{:?}", response);
+    assert_eq!(list_topics(follower).await, vec!["quorum".to_string()]);
+    let BrokerResponse::Error(error) = connections[leader as usize].request(create("quorum")).await.unwrap() else {
+        panic!("expected the topic to exist on the leader too");
+    };
+    assert_eq!(error.code, ErrorCode::TopicAlreadyExists);
+
+    // Every broker ends up with the same topic, config and partition leaders
+    for connection in &connections {
+        let mut description = None;
+        for _ in 0..50 {
+            match connection.request(BrokerMessage::DescribeTopic { topic: "quorum".to_string() }).await.unwrap() {
+                BrokerResponse::TopicDescribed(described) => {
+                    description = Some(described);
+                    break;
+                }
+                _ => sleep(Duration::from_millis(20)).await,
+            }
+        }
+        let description = description.expect("the topic to reach every broker");
+        assert_eq!(description.config.retention_ms, Some(60_000));
+        let leaders: Vec<u32> = description.partitions.iter().map(|partition| partition.leader).collect();
+        assert_eq!(leaders, vec![0, 1, 2]);
+    }
+
+    let acl = AclBinding {
+        principal: "User:alice".to_string(),
+        topic: "quorum".to_string(),
+        operation: AclOperation::Write,
+        allow: true,
+    };
+    let response = follower.request(BrokerMessage::CreateAcl { acl: acl.clone() }).await.unwrap();
+    assert!(matches!(response, BrokerResponse::AclCreated), "{:?}", response);
+    let BrokerResponse::AclList { acls } = follower.request(BrokerMessage::ListAcls).await.unwrap() else {
+        panic!("expected the ACLs");
+    };
+    assert_eq!(acls, vec![acl.clone()]);
+    let BrokerResponse::Error(error) = follower.request(BrokerMessage::RemoveController { broker_id: 7 }).await.unwrap() else {
+        panic!("expected an unknown broker to be refused");
+    };
+    assert_eq!(error.code, ErrorCode::MalformedRequest);
+
+    // Records only come from brokers, and even they can't place partitions
+    // on brokers that don't exist
+    let leader_connection = &connections[leader as usize];
+    let propose = |replicas: Vec<Vec<u32>>| BrokerMessage::ProposeMetadata {
+        record: MetadataRecord::CreateTopic {
+            topic: "placed".to_string(),
+            partitioner: PartitionStrategy::default(),
+            config: TopicConfig::default(),
+            replicas,
+        },
+    };
+    let BrokerResponse::Error(error) = leader_connection.request(propose(vec![vec![0]])).await.unwrap() else {
+        panic!("expected clients to be refused");
+    };
+    assert_eq!(error.code, ErrorCode::UnsupportedOperation);
+    let register = BrokerMessage::Register {
+        client_id: "impostor".to_string(),
+        client_type: "broker".to_string(),
+        client_version: None,
+        features: Vec::new(),
+    };
+    leader_connection.request(register).await.unwrap();
+    for replicas in [vec![], vec![vec![]], vec![vec![0], vec![3]], vec![vec![1, 1]]] {
+        let BrokerResponse::Error(error) = leader_connection.request(propose(replicas.clone())).await.unwrap() else {
+            panic!("expected replicas {:?} to be refused", replicas);
+        };
+        assert_eq!(error.code, ErrorCode::MalformedRequest);
+    }
+
+    // The other two elect a new leader, which carries on with the same log
+    let term = describe_quorum(&connections[leader as usize]).await.term;
+    shutdown_handles[leader as usize].shutdown();
+    let survivors: Vec<&Connection> = (0..3).filter(|id| *id != leader).map(|id| &connections[id as usize]).collect();
+    await_quorum_leader(&survivors, Some(leader)).await;
+    let description = describe_quorum(survivors[0]).await;
+    assert!(description.term > term, "{:?}", description);
+    assert_eq!(description.controllers, vec![0, 1, 2]);
+
+    let response = survivors[0].request(BrokerMessage::DeleteTopic { topic: "quorum".to_string() }).await.unwrap();
+    assert!(matches!(response, BrokerResponse::TopicDeleted { .. }), "{:?}", response);
+    let mut topics = vec!["quorum".to_string()];
+    for _ in 0..50 {
+        topics = list_topics(survivors[1]).await;
+        if topics.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(topics.is_empty());
+    let BrokerResponse::AclList { acls } = survivors[1].request(BrokerMessage::ListAcls).await.unwrap() else {
+        panic!("expected the ACLs");
+    };
+    assert_eq!(acls, vec![acl]);
+}
+
+#[tokio::test]
+async fn test_metadata_log_survives_restarts() {
+    const ADDRESS: &str = "127.0.0.1:50101";
+    const RESTARTED: &str = "127.0.0.1:50102";
+    let dir = std::env::temp_dir().join(format!("rafka-metadata-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let controller = |address: &str| {
+        Broker::new(0, 1, None)
+            .with_peers(vec![address.to_string()])
+            .with_controllers(vec![0])
+            .with_election_timeout(Duration::from_millis(50))
+            .with_metadata_dir(dir.clone())
+    };
+
+    let broker = controller(ADDRESS);
+    let shutdown = broker.shutdown_handle();
+    let serving = tokio::spawn(async move { broker.serve(ADDRESS).await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+    let create = BrokerMessage::CreateTopic {
+        topic: "kept".to_string(),
+        partitions: 2,
+        partitioner: PartitionStrategy::RoundRobin,
+        config: TopicConfig::default(),
+    };
+    let response = connection.request(create).await.unwrap();
+    assert!(matches!(response, BrokerResponse::TopicCreated { .. }), "{:?}", response);
+    // Topics only come from the log, a publish asks for one to be created
+    let response = connection.request(publish("key", "value")).await.unwrap();
+    assert!(matches!(response, BrokerResponse::Error(_)), "{:?}", response);
+    let mut topics = Vec::new();
+    for _ in 0..50 {
+        topics = list_topics(&connection).await;
+        if topics.len() == 2 {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(topics, vec!["kept".to_string(), "tests".to_string()]);
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap().unwrap();
+
+    let broker = controller(RESTARTED);
+    tokio::spawn(async move { broker.serve(RESTARTED).await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(RESTARTED).await.unwrap();
+    for _ in 0..50 {
+        topics = list_topics(&connection).await;
+        if !topics.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(topics, vec!["kept".to_string(), "tests".to_string()]);
+    let description = describe_quorum(&connection).await;
+    assert_eq!(description.leader_id, Some(0));
+    assert!(description.term >= 2, "{:?}", description);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_zero_broker_count() {
+    const ADDRESS: &str = "127.0.0.1:50109";
+    start_broker(ADDRESS, 0, 0).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    let BrokerResponse::Ack(ack) = connection.request(publish("key", "value")).await.unwrap() else {
+        panic!("expected the publish to be acked");
+    };
+    assert!(matches!(ack.status, AckStatus::Success), "{:?}", ack.status);
+    assert_eq!((ack.partition, ack.offset), (0, 0));
+}
+
+#[tokio::test]
+async fn test_batches_with_bad_offset_deltas_rejected() {
+    const ADDRESS: &str = "127.0.0.1:50110";
+    start_broker(ADDRESS, 0, 1).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    // Valid crcs, but offset counts of -1, 0 and a repeated offset
+    let tampered = [vec![-2], vec![], vec![0, 0]];
+    for deltas in tampered {
+        let records = deltas
+            .iter()
+            .map(|delta| Record::new(*delta, None, Bytes::from("value")))
+            .collect();
+        let produce = BrokerMessage::Produce {
+            topic: "tests".to_string(),
+            partition: Some(0),
+            records: RecordBatch::new(0, 0, records).encoded().into_bytes(),
+            acks: Acks::Leader,
+        };
+        let BrokerResponse::Error(error) = connection.request(produce).await.unwrap() else {
+            panic!("expected offset deltas {:?} to be rejected", deltas);
+        };
+        assert_eq!(error.code, ErrorCode::MalformedRequest);
+    }
+
+    let BrokerResponse::Ack(ack) = connection.request(publish("key", "value")).await.unwrap() else {
+        panic!("expected the publish to be acked");
+    };
+    assert_eq!((ack.partition, ack.offset), (0, 0));
+}
+
+#[tokio::test]
+async fn test_zero_default_partitions() {
+    const ADDRESS: &str = "127.0.0.1:50111";
+    let broker = Broker::new(0, 1, None).with_default_partitions(0);
+    tokio::spawn(async move { broker.serve(ADDRESS).await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let connection = Connection::connect(ADDRESS).await.unwrap();
+
+    // Auto-created with one partition, which a keyless publish goes to
+    let BrokerResponse::Ack(ack) = connection.request(publish("", "value")).await.unwrap() else {
+        panic!("expected the publish to be acked");
+    };
+    assert!(matches!(ack.status, AckStatus::Success), "{:?}", ack.status);
+    assert_eq!((ack.partition, ack.offset), (0, 0));
+}
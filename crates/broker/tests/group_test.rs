@@ -0,0 +1,233 @@
+use std::time::Duration;
+
+use rafka_broker::Broker;
+use rafka_core::message::ErrorCode;
+use rafka_core::partitioner::PartitionStrategy;
+use rafka_protocol::{
+    Acks, AssignmentStrategy, BrokerMessage, BrokerResponse, Connection, GroupJoinedResponse,
+    IsolationLevel, TopicConfig, TopicPartitions,
+};
+use tokio::time::sleep;
+
+async fn start_broker(address: &'static str) -> Connection {
+    tokio::spawn(async move {
+        Broker::new(0, 1, None).serve(address).await.unwrap();
+    });
+    sleep(Duration::from_millis(50)).await;
+    Connection::connect(address).await.unwrap()
+}
+
+async fn create_topic(connection: &Connection, topic: &str, partitions: u32) {
+    let create = BrokerMessage::CreateTopic {
+        topic: topic.to_string(),
+        partitions,
+        partitioner: PartitionStrategy::default(),
+        config: TopicConfig::default(),
+    };
+    connection.request(create).await.unwrap();
+}
+
+async fn join(
+    connection: &Connection,
+    topics: &[&str],
+    strategies: Vec<AssignmentStrategy>,
+    session_timeout_ms: u64,
+) -> Result<GroupJoinedResponse, ErrorCode> {
+    let join = BrokerMessage::JoinGroup {
+        group_id: "workers".to_string(),
+        member_id: None,
+        topics: topics.iter().map(|topic| topic.to_string()).collect(),
+        strategies,
+        session_timeout_ms,
+    };
+    match connection.request(join).await.unwrap() {
+        BrokerResponse::GroupJoined(joined) => Ok(joined),
+        BrokerResponse::Error(error) => Err(error.code),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+async fn sync(connection: &Connection, member_id: &str) -> (u32, Vec<TopicPartitions>) {
+    let sync = BrokerMessage::SyncGroup {
+        group_id: "workers".to_string(),
+        member_id: member_id.to_string(),
+    };
+    let BrokerResponse::GroupAssignment(assigned) = connection.request(sync).await.unwrap() else {
+        panic!("expected an assignment");
+    };
+    (assigned.generation, assigned.assignment)
+}
+
+async fn heartbeat(connection: &Connection, member_id: &str, generation: u32) -> Option<ErrorCode> {
+    let heartbeat = BrokerMessage::Heartbeat {
+        group_id: "workers".to_string(),
+        member_id: member_id.to_string(),
+        generation,
+    };
+    match connection.request(heartbeat).await.unwrap() {
+        BrokerResponse::HeartbeatAck => None,
+        BrokerResponse::Error(error) => Some(error.code),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+fn partitions_of(assignment: &[TopicPartitions], topic: &str) -> Vec<u32> {
+    assignment
+        .iter()
+        .filter(|assigned| assigned.topic == topic)
+        .flat_map(|assigned| assigned.partitions.clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_range_rebalance_on_join_and_leave() {
+    let connection = start_broker("127.0.0.1:50081").await;
+    create_topic(&connection, "jobs", 4).await;
+
+    let first = join(&connection, &["jobs"], vec![AssignmentStrategy::Range], 10_000).await.unwrap();
+    assert_eq!(first.generation, 1);
+    let (_, assignment) = sync(&connection, &first.member_id).await;
+    assert_eq!(partitions_of(&assignment, "jobs"), vec![0, 1, 2, 3]);
+
+    let second = join(&connection, &["jobs"], vec![AssignmentStrategy::Range], 10_000).await.unwrap();
+    assert_eq!(second.generation, 2);
+    assert_eq!(second.members, vec![first.member_id.clone(), second.member_id.clone()]);
+
+    // The first member finds out about the rebalance on its next heartbeat
+    assert_eq!(
+        heartbeat(&connection, &first.member_id, 1).await,
+        Some(ErrorCode::RebalanceInProgress)
+    );
+    let (generation, first_assignment) = sync(&connection, &first.member_id).await;
+    assert_eq!(generation, 2);
+    assert_eq!(heartbeat(&connection, &first.member_id, 2).await, None);
+
+    // Two contiguous halves, between them every partition
+    let (_, second_assignment) = sync(&connection, &second.member_id).await;
+    let mut halves = vec![
+        partitions_of(&first_assignment, "jobs"),
+        partitions_of(&second_assignment, "jobs"),
+    ];
+    halves.sort();
+    assert_eq!(halves, vec![vec![0, 1], vec![2, 3]]);
+
+    let leave = BrokerMessage::LeaveGroup {
+        group_id: "workers".to_string(),
+        member_id: second.member_id.clone(),
+    };
+    assert!(matches!(connection.request(leave).await.unwrap(), BrokerResponse::GroupLeft));
+    let (generation, assignment) = sync(&connection, &first.member_id).await;
+    assert_eq!(generation, 3);
+    assert_eq!(partitions_of(&assignment, "jobs"), vec![0, 1, 2, 3]);
+    assert_eq!(heartbeat(&connection, &second.member_id, 3).await, Some(ErrorCode::UnknownMember));
+}
+
+#[tokio::test]
+async fn test_session_timeout_evicts_member() {
+    let connection = start_broker("127.0.0.1:50082").await;
+    create_topic(&connection, "jobs", 2).await;
+
+    let steady = join(&connection, &["jobs"], vec![AssignmentStrategy::RoundRobin], 10_000).await.unwrap();
+    let silent = join(&connection, &["jobs"], vec![AssignmentStrategy::RoundRobin], 200).await.unwrap();
+    let (_, assignment) = sync(&connection, &steady.member_id).await;
+    assert_eq!(partitions_of(&assignment, "jobs").len(), 1);
+
+    // Never heartbeats, so its partition comes back
+    sleep(Duration::from_millis(500)).await;
+    let (_, assignment) = sync(&connection, &steady.member_id).await;
+    assert_eq!(partitions_of(&assignment, "jobs"), vec![0, 1]);
+    assert_eq!(heartbeat(&connection, &silent.member_id, 2).await, Some(ErrorCode::UnknownMember));
+}
+
+#[tokio::test]
+async fn test_round_robin_and_sticky_strategies() {
+    let connection = start_broker("127.0.0.1:50083").await;
+    create_topic(&connection, "left", 3).await;
+    create_topic(&connection, "right", 3).await;
+
+    // Only strategies every member supports can be picked
+    let strategies = vec![AssignmentStrategy::Sticky, AssignmentStrategy::RoundRobin];
+    let first = join(&connection, &["left", "right"], strategies.clone(), 10_000).await.unwrap();
+    let second = join(&connection, &["left", "right"], vec![AssignmentStrategy::RoundRobin], 10_000)
+        .await
+        .unwrap();
+    assert_eq!(second.strategy, AssignmentStrategy::RoundRobin);
+    assert_eq!(
+        join(&connection, &["left"], vec![AssignmentStrategy::Range], 10_000).await.err(),
+        Some(ErrorCode::InconsistentGroupProtocol)
+    );
+
+    // Dealt out one at a time across both topics
+    let (_, first_assignment) = sync(&connection, &first.member_id).await;
+    let (_, second_assignment) = sync(&connection, &second.member_id).await;
+    let counts = |assignment: &[TopicPartitions]| {
+        (partitions_of(assignment, "left").len(), partitions_of(assignment, "right").len())
+    };
+    let mut counts = vec![counts(&first_assignment), counts(&second_assignment)];
+    counts.sort();
+    assert_eq!(counts, vec![(1, 2), (2, 1)]);
+
+    let leave = BrokerMessage::LeaveGroup {
+        group_id: "workers".to_string(),
+        member_id: second.member_id,
+    };
+    connection.request(leave).await.unwrap();
+
+    // With the RoundRobin member gone the group moves to Sticky, and a new
+    // member only takes partitions, nobody else's partitions move around
+    let (_, before) = sync(&connection, &first.member_id).await;
+    let third = join(&connection, &["left", "right"], strategies, 10_000).await.unwrap();
+    assert_eq!(third.strategy, AssignmentStrategy::Sticky);
+    let (_, after) = sync(&connection, &first.member_id).await;
+    let (_, taken) = sync(&connection, &third.member_id).await;
+    for topic in ["left", "right"] {
+        let kept = partitions_of(&after, topic);
+        assert!(kept.iter().all(|partition| partitions_of(&before, topic).contains(partition)));
+    }
+    let total = |assignment: &[TopicPartitions]| {
+        assignment.iter().map(|assigned| assigned.partitions.len()).sum::<usize>()
+    };
+    assert_eq!((total(&after), total(&taken)), (3, 3));
+}
+
+#[tokio::test]
+async fn test_group_consume_follows_assignment() {
+    let connection = start_broker("127.0.0.1:50084").await;
+    create_topic(&connection, "jobs", 2).await;
+
+    let member = join(&connection, &["jobs"], vec![AssignmentStrategy::Range], 10_000).await.unwrap();
+    let consume = BrokerMessage::Consume {
+        consumer_id: member.member_id.clone(),
+        topics: Vec::new(),
+        group_id: Some("workers".to_string()),
+        credit: None,
+        isolation: IsolationLevel::ReadUncommitted,
+    };
+    let mut deliveries = connection.stream(consume).await.unwrap();
+
+    let publish = BrokerMessage::Publish {
+        key: "key".to_string(),
+        topic: "jobs".to_string(),
+        payload: b"job".to_vec(),
+        headers: Vec::new(),
+        acks: Acks::Leader,
+    };
+    let BrokerResponse::Ack(ack) = connection.request(publish).await.unwrap() else {
+        panic!("expected an ack");
+    };
+    let BrokerResponse::Records(delivery) = deliveries.recv().await.unwrap() else {
+        panic!("expected records");
+    };
+    assert_eq!((delivery.topic.as_str(), delivery.partition), ("jobs", ack.partition));
+
+    // Leaving ends the stream
+    let leave = BrokerMessage::LeaveGroup {
+        group_id: "workers".to_string(),
+        member_id: member.member_id,
+    };
+    connection.request(leave).await.unwrap();
+    let BrokerResponse::Error(error) = deliveries.recv().await.unwrap() else {
+        panic!("expected an error");
+    };
+    assert_eq!(error.code, ErrorCode::UnknownMember);
+}
@@ -0,0 +1,356 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use rafka_broker::Broker;
+use rafka_protocol::kafka::{api_key, error_code, Decoder, Encoder, Record, RecordBatch};
+use rafka_protocol::{BrokerMessage, BrokerResponse, Connection, FrameReader, FrameWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Instant};
+
+// Requests are encoded by hand here, the same bytes a Kafka client would send
+
+async fn start_broker(address: &'static str, kafka_address: &'static str) {
+    tokio::spawn(async move {
+        Broker::new(0, 1, None)
+            .with_kafka_listener(kafka_address)
+            .serve(address)
+            .await
+            .unwrap();
+    });
+    sleep(Duration::from_millis(50)).await;
+}
+
+struct KafkaClient {
+    reader: FrameReader<OwnedReadHalf>,
+    writer: FrameWriter<OwnedWriteHalf>,
+    correlation_id: i32,
+}
+
+impl KafkaClient {
+    async fn connect(addr: &str) -> Self {
+        let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        Self {
+            reader: FrameReader::new(reader),
+            writer: FrameWriter::new(writer),
+            correlation_id: 0,
+        }
+    }
+
+    async fn send(&mut self, api_key: i16, api_version: i16, body: Encoder) -> i32 {
+        self.correlation_id += 1;
+        let mut request = Encoder::new();
+        request
+            .i16(api_key)
+            .i16(api_version)
+            .i32(self.correlation_id)
+            .nullable_string(Some("kafka-test"))
+            .raw(&body.finish());
+        self.writer.write_frame(&request.finish()).await.unwrap();
+        self.correlation_id
+    }
+
+    async fn receive(&mut self, correlation_id: i32) -> Decoder {
+        let frame = self.reader.read_frame().await.unwrap().unwrap();
+        let mut response = Decoder::new(frame);
+        assert_eq!(response.i32().unwrap(), correlation_id);
+        response
+    }
+
+    async fn request(&mut self, api_key: i16, api_version: i16, body: Encoder) -> Decoder {
+        let correlation_id = self.send(api_key, api_version, body).await;
+        self.receive(correlation_id).await
+    }
+
+    async fn produce(&mut self, topic: &str, values: &[&str]) -> i64 {
+        let (error, base_offset) = self.produce_batch(topic, None, Self::batch(values)).await;
+        assert_eq!(error, error_code::NONE);
+        base_offset
+    }
+
+    fn batch(values: &[&str]) -> RecordBatch {
+        let records = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Record {
+                offset_delta: i as i32,
+                timestamp_delta: 0,
+                key: None,
+                value: Some(Bytes::copy_from_slice(value.as_bytes())),
+                headers: Vec::new(),
+            })
+            .collect();
+        RecordBatch::new(0, 0, records)
+    }
+
+    // (error code, base offset) of a produce to partition 0
+    async fn produce_batch(&mut self, topic: &str, transactional_id: Option<&str>, batch: RecordBatch) -> (i16, i64) {
+        let mut encoded = Encoder::new();
+        batch.encode(&mut encoded);
+        let batch = encoded.finish();
+
+        let mut body = Encoder::new();
+        body.nullable_string(transactional_id)
+            .i16(1) // acks
+            .i32(1000)
+            .i32(1)
+            .string(topic)
+            .i32(1)
+            .i32(0)
+            .nullable_bytes(Some(&batch));
+        let mut response = self.request(api_key::PRODUCE, 3, body).await;
+
+        assert_eq!(response.i32().unwrap(), 1);
+        assert_eq!(response.string().unwrap(), topic);
+        assert_eq!(response.i32().unwrap(), 1);
+        assert_eq!(response.i32().unwrap(), 0);
+        (response.i16().unwrap(), response.i64().unwrap())
+    }
+
+    fn fetch_body(topic: &str, offset: i64, max_wait_ms: i32) -> Encoder {
+        let mut body = Encoder::new();
+        body.i32(-1)
+            .i32(max_wait_ms)
+            .i32(1) // min_bytes
+            .i32(1024 * 1024)
+            .i8(0)
+            .i32(1)
+            .string(topic)
+            .i32(1)
+            .i32(0)
+            .i64(offset)
+            .i32(1024 * 1024);
+        body
+    }
+
+    // Values fetched from partition 0 of a single topic fetch (v4)
+    fn fetched_values(mut response: Decoder) -> (i16, Vec<(i64, String)>) {
+        let _throttle = response.i32().unwrap();
+        assert_eq!(response.i32().unwrap(), 1);
+        let _topic = response.string().unwrap();
+        assert_eq!(response.i32().unwrap(), 1);
+        assert_eq!(response.i32().unwrap(), 0);
+        let error = response.i16().unwrap();
+        let _high_watermark = response.i64().unwrap();
+        let _last_stable_offset = response.i64().unwrap();
+        let _aborted = response.i32().unwrap();
+
+        let values = match response.nullable_bytes().unwrap() {
+            Some(records) => RecordBatch::decode_all(records)
+                .unwrap()
+                .into_iter()
+                .flat_map(|batch| {
+                    let base_offset = batch.base_offset;
+                    batch.records.into_iter().map(move |record| {
+                        let value = String::from_utf8(record.value.unwrap().to_vec()).unwrap();
+                        (base_offset + record.offset_delta as i64, value)
+                    })
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        (error, values)
+    }
+}
+
+#[tokio::test]
+async fn test_api_versions_and_metadata() {
+    start_broker("127.0.0.1:50071", "127.0.0.1:50072").await;
+    let mut client = KafkaClient::connect("127.0.0.1:50072").await;
+
+    let mut response = client.request(api_key::API_VERSIONS, 2, Encoder::new()).await;
+    assert_eq!(response.i16().unwrap(), error_code::NONE);
+    let apis = response.array(|d| Ok((d.i16()?, d.i16()?, d.i16()?))).unwrap();
+    assert!(apis.contains(&(api_key::PRODUCE, 3, 7)));
+    assert!(apis.contains(&(api_key::FETCH, 4, 11)));
+
+    // Too new a version still gets the list, at v0
+    let mut response = client.request(api_key::API_VERSIONS, 99, Encoder::new()).await;
+    assert_eq!(response.i16().unwrap(), error_code::UNSUPPORTED_VERSION);
+    assert_eq!(response.array(|d| Ok((d.i16()?, d.i16()?, d.i16()?))).unwrap().len(), apis.len());
+
+    let mut body = Encoder::new();
+    body.array(&["orders"], |out, topic| {
+        out.string(topic);
+    });
+    let mut response = client.request(api_key::METADATA, 1, body).await;
+
+    let brokers = response
+        .array(|d| Ok((d.i32()?, d.string()?, d.i32()?, d.nullable_string()?)))
+        .unwrap();
+    assert_eq!(brokers, vec![(0, "127.0.0.1".to_string(), 50072, None)]);
+    assert_eq!(response.i32().unwrap(), 0); // controller
+
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.i16().unwrap(), error_code::NONE);
+    assert_eq!(response.string().unwrap(), "orders");
+    assert!(!response.bool().unwrap());
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.i16().unwrap(), error_code::NONE);
+    assert_eq!(response.i32().unwrap(), 0); // partition
+    assert_eq!(response.i32().unwrap(), 0); // leader
+}
+
+#[tokio::test]
+async fn test_produce_then_fetch() {
+    start_broker("127.0.0.1:50073", "127.0.0.1:50074").await;
+    let mut client = KafkaClient::connect("127.0.0.1:50074").await;
+
+    assert_eq!(client.produce("events", &["first", "second"]).await, 0);
+    assert_eq!(client.produce("events", &["third"]).await, 2);
+
+    let response = client
+        .request(api_key::FETCH, 4, KafkaClient::fetch_body("events", 1, 0))
+        .await;
+    let (error, mut values) = KafkaClient::fetched_values(response);
+    assert_eq!(error, error_code::NONE);
+    // Whole batches come back, clients skip the records before the fetch offset
+    values.retain(|(offset, _)| *offset >= 1);
+    assert_eq!(values, vec![(1, "second".to_string()), (2, "third".to_string())]);
+
+    let response = client
+        .request(api_key::FETCH, 4, KafkaClient::fetch_body("events", 10, 0))
+        .await;
+    assert_eq!(KafkaClient::fetched_values(response).0, error_code::OFFSET_OUT_OF_RANGE);
+
+    // Latest offset (-1) is the next one to be written
+    let mut body = Encoder::new();
+    body.i32(-1).i32(1).string("events").i32(1).i32(0).i64(-1);
+    let mut response = client.request(api_key::LIST_OFFSETS, 1, body).await;
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.string().unwrap(), "events");
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.i32().unwrap(), 0);
+    assert_eq!(response.i16().unwrap(), error_code::NONE);
+    let _timestamp = response.i64().unwrap();
+    assert_eq!(response.i64().unwrap(), 3);
+}
+
+#[tokio::test]
+async fn test_fetch_waits_for_records() {
+    start_broker("127.0.0.1:50075", "127.0.0.1:50076").await;
+    let mut producer = KafkaClient::connect("127.0.0.1:50076").await;
+    let mut consumer = KafkaClient::connect("127.0.0.1:50076").await;
+    producer.produce("waiting", &["before"]).await;
+
+    let started = Instant::now();
+    let correlation_id = consumer
+        .send(api_key::FETCH, 4, KafkaClient::fetch_body("waiting", 1, 5000))
+        .await;
+    sleep(Duration::from_millis(100)).await;
+    producer.produce("waiting", &["after"]).await;
+
+    let (_, values) = KafkaClient::fetched_values(consumer.receive(correlation_id).await);
+    assert_eq!(values, vec![(1, "after".to_string())]);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_group_offsets() {
+    start_broker("127.0.0.1:50077", "127.0.0.1:50078").await;
+    let mut client = KafkaClient::connect("127.0.0.1:50078").await;
+    client.produce("payments", &["a", "b"]).await;
+
+    let mut body = Encoder::new();
+    body.string("billing")
+        .i32(-1)
+        .string("")
+        .i64(-1)
+        .i32(1)
+        .string("payments")
+        .i32(1)
+        .i32(0)
+        .i64(2)
+        .nullable_string(None);
+    let mut response = client.request(api_key::OFFSET_COMMIT, 2, body).await;
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.string().unwrap(), "payments");
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.i32().unwrap(), 0);
+    assert_eq!(response.i16().unwrap(), error_code::NONE);
+
+    let mut body = Encoder::new();
+    body.string("billing").i32(1).string("payments").i32(2).i32(0).i32(1);
+    let mut response = client.request(api_key::OFFSET_FETCH, 1, body).await;
+    assert_eq!(response.i32().unwrap(), 1);
+    assert_eq!(response.string().unwrap(), "payments");
+    let partitions = response
+        .array(|d| Ok((d.i32()?, d.i64()?, d.nullable_string()?, d.i16()?)))
+        .unwrap();
+    assert_eq!(partitions[0].1, 2);
+    // Never committed
+    assert_eq!(partitions[1].1, -1);
+
+    // Native consumers of the group pick up where the Kafka ones left off
+    let connection = Connection::connect("127.0.0.1:50077").await.unwrap();
+    let BrokerResponse::Metrics(metrics) = connection.request(BrokerMessage::GetMetrics).await.unwrap() else {
+        panic!("expected metrics");
+    };
+    let lag = metrics.consumer_lag.iter().find(|lag| lag.consumer_id == "billing").unwrap();
+    assert_eq!((lag.committed_offset, lag.lag), (1, 0));
+}
+
+#[tokio::test]
+async fn test_idempotent_produce() {
+    start_broker("127.0.0.1:50103", "127.0.0.1:50104").await;
+    let mut client = KafkaClient::connect("127.0.0.1:50104").await;
+    assert_eq!(client.produce("numbered", &["plain"]).await, 0);
+
+    let numbered = |values: &[&str], sequence| KafkaClient::batch(values).with_producer(7, 0, sequence);
+    let first = client.produce_batch("numbered", None, numbered(&["a", "b"], 0)).await;
+    assert_eq!(first, (error_code::NONE, 1));
+    // A retry is acked with the offset it was stored at
+    let retried = client.produce_batch("numbered", None, numbered(&["a", "b"], 0)).await;
+    assert_eq!(retried, (error_code::NONE, 1));
+    let skipped = client.produce_batch("numbered", None, numbered(&["d"], 3)).await;
+    assert_eq!(skipped.0, error_code::OUT_OF_ORDER_SEQUENCE_NUMBER);
+    let next = client.produce_batch("numbered", None, numbered(&["c"], 2)).await;
+    assert_eq!(next, (error_code::NONE, 3));
+    let new_epoch = client
+        .produce_batch("numbered", None, KafkaClient::batch(&["e"]).with_producer(7, 1, 0))
+        .await;
+    assert_eq!(new_epoch, (error_code::NONE, 4));
+    let stale = client.produce_batch("numbered", None, numbered(&["f"], 3)).await;
+    assert_eq!(stale.0, error_code::INVALID_PRODUCER_EPOCH);
+
+    let response = client
+        .request(api_key::FETCH, 4, KafkaClient::fetch_body("numbered", 0, 0))
+        .await;
+    let (_, values) = KafkaClient::fetched_values(response);
+    let values: Vec<&str> = values.iter().map(|(_, value)| value.as_str()).collect();
+    assert_eq!(values, vec!["plain", "a", "b", "c", "e"]);
+}
+
+#[tokio::test]
+async fn test_transactional_produce_is_refused() {
+    start_broker("127.0.0.1:50105", "127.0.0.1:50106").await;
+    let mut client = KafkaClient::connect("127.0.0.1:50106").await;
+
+    let batch = KafkaClient::batch(&["open"]).with_producer(3, 0, 0).transactional();
+    let refused = client.produce_batch("payments", Some("payments-txn"), batch.clone()).await;
+    assert_eq!(refused.0, error_code::INVALID_TXN_STATE);
+    let refused = client.produce_batch("payments", None, batch).await;
+    assert_eq!(refused.0, error_code::INVALID_TXN_STATE);
+
+    // Nothing reached the log, read_committed or not
+    assert_eq!(client.produce("payments", &["plain"]).await, 0);
+}
+
+#[tokio::test]
+async fn test_shutdown_moves_clients_on() {
+    const KAFKA_ADDRESS: &str = "127.0.0.1:50108";
+    let broker = Broker::new(0, 1, None).with_kafka_listener(KAFKA_ADDRESS);
+    let shutdown = broker.shutdown_handle();
+    let serving = tokio::spawn(async move { broker.serve("127.0.0.1:50107").await.unwrap() });
+    sleep(Duration::from_millis(50)).await;
+    let mut client = KafkaClient::connect(KAFKA_ADDRESS).await;
+    assert_eq!(client.produce("draining", &["before"]).await, 0);
+
+    // Requests that still come in are pointed at another leader
+    shutdown.shutdown();
+    let refused = client.produce_batch("draining", None, KafkaClient::batch(&["after"])).await;
+    assert_eq!(refused.0, error_code::NOT_LEADER_OR_FOLLOWER);
+
+    tokio::time::timeout(Duration::from_secs(2), serving).await.unwrap().unwrap();
+    assert!(client.reader.read_frame().await.unwrap().is_none());
+}
@@ -14,14 +14,39 @@ pub enum Commands {
         #[arg(short, long, default_value = "50051")]
         port: u16,
 
-        #[arg(long, default_value = "0")]
-        partition: u32,
+        /// Index of this broker, it hosts partition p of a topic when p % broker count is this
+        #[arg(long, alias = "partition", default_value = "0")]
+        broker_id: u32,
 
-        #[arg(short, long, default_value = "1")]
-        total_partition: u32,
+        #[arg(short = 't', long, alias = "total-partition", default_value = "1")]
+        broker_count: u32,
+
+        /// Partitions given to topics created on first use, one per broker by default
+        #[arg(long)]
+        default_partitions: Option<u32>,
+
+        /// Only create topics when asked to, publishing or subscribing to an unknown topic fails
+        #[arg(long)]
+        no_auto_create_topics: bool,
 
         #[arg(short, long, default_value = "1")]
         retention_secs: u64,
+
+        /// Address of every broker in the cluster, in broker id order. Can be repeated.
+        #[arg(long = "peer")]
+        peers: Vec<String>,
+
+        /// Pass publishes for partitions on other brokers along to them
+        #[arg(long)]
+        forward: bool,
+
+        /// Also accept Kafka clients on this port
+        #[arg(long)]
+        kafka_port: Option<u16>,
+
+        /// Serve Prometheus metrics at /metrics on this port
+        #[arg(long)]
+        metrics_port: Option<u16>,
     },
 
     /// Start a consumer for the message broker
@@ -35,6 +60,7 @@ pub enum Commands {
 
     /// Produces messages for a list of brokers
     Producer {
+        /// Brokers to bootstrap from, the first one that answers is used. Can be repeated.
         #[arg(short, long, default_value = "127.0.0.1:50051")]
         brokers: Vec<String>,
 
@@ -46,6 +72,10 @@ pub enum Commands {
 
         #[arg(short, long, default_value = "Hello, world!")]
         message: String,
+
+        /// Attach a header, as key=value. Can be repeated.
+        #[arg(long = "header")]
+        headers: Vec<String>,
     },
 }
 
@@ -1,149 +1,372 @@
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use chrono::DateTime;
+use rafka_core::message::{ErrorCode, Message};
 use tokio::sync::mpsc;
-use serde::{Serialize, Deserialize};
+use tokio::task::AbortHandle;
+use rafka_protocol::{
+    ApiKey, AssignmentStrategy, BrokerMessage, BrokerResponse, Connection, Credit, EncodedBatch,
+    IsolationLevel, TopicPartitions,
+};
 use uuid::Uuid;
+use bytes::Bytes;
 use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum BrokerMessage {
-    Publish {
-        key: String,
-        topic: String,
-        payload: Vec<u8>,
-    },
-    Subscribe {
-        consumer_id: String,
-        topic: String,
-    },
-    Consume {
-        consumer_id: String,
-    },
-    Register {
-        client_id: String,
-        client_type: String,
-    },
-    UpdateOffset {
-        consumer_id: String,
-        topic: String,
-        offset: i64,
-    },
+pub struct Consumer {
+    connection: Connection,
+    consumer_id: String,
+    current_offset: i64,
+    session_timeout: Duration,
+    credit: Option<Credit>,
+    isolation: IsolationLevel,
+    group: Option<Arc<Mutex<Membership>>>,
+    heartbeat: Option<AbortHandle>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ConsumeResponse {
-    message_id: String,
-    topic: String,
-    payload: Vec<u8>,
-    sent_at: i64,
-    offset: i64,
+// Where this consumer stands in its group, kept up to date by the heartbeat task
+struct Membership {
+    group_id: String,
+    member_id: String,
+    generation: u32,
+    assignment: Vec<TopicPartitions>,
 }
 
-pub struct Consumer {
-    stream: TcpStream,
-    consumer_id: String,
-    current_offset: i64,
+// One answer to `fetch`
+#[derive(Debug)]
+pub struct Fetched {
+    pub messages: Vec<Message>,
+    // Where the next fetch should start
+    pub next_offset: i64,
+    // Offset the next message appended to the partition will get
+    pub high_watermark: i64,
 }
 
 impl Consumer {
     pub async fn new(addr: &str) -> Result<Self, Box<dyn Error>> {
-        let stream = TcpStream::connect(addr).await?;
+        let connection = Connection::connect(addr).await?;
         let consumer_id = Uuid::new_v4().to_string();
         
-        let mut consumer = Self {
-            stream,
+        let consumer = Self {
+            connection,
             consumer_id,
             current_offset: 0,
+            session_timeout: Duration::from_secs(10),
+            credit: None,
+            isolation: IsolationLevel::default(),
+            group: None,
+            heartbeat: None,
         };
 
         //reg
-        let register_msg = BrokerMessage::Register {
-            client_id: consumer.consumer_id.clone(),
-            client_type: "consumer".to_string(),
-        };
-        
-        consumer.send_message(&register_msg).await?;
-        let _response = consumer.read_response().await?;
-        
-        println!("Consumer registered with ID: {}", consumer.consumer_id);
+        if consumer.connection.versions().supports(ApiKey::Register) {
+            let register_msg = BrokerMessage::Register {
+                client_id: consumer.consumer_id.clone(),
+                client_type: "consumer".to_string(),
+                client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                features: Vec::new(),
+            };
+
+            match consumer.connection.request(register_msg).await? {
+                BrokerResponse::Registered { .. } => {}
+                BrokerResponse::Error(error) => return Err(error.into()),
+                other => return Err(format!("Unexpected response to register: {:?}", other).into()),
+            }
+
+            println!("Consumer registered with ID: {}", consumer.consumer_id);
+        }
         Ok(consumer)
     }
 
-    async fn send_message(&mut self, message: &BrokerMessage) -> Result<(), Box<dyn Error>> {
-        let message_bytes = serde_json::to_vec(message)?;
-        self.stream.write_all(&message_bytes).await?;
-        Ok(())
+    // Caps what the broker pushes ahead of the application: at most about this
+    // many messages and bytes are in flight or waiting to be received. Credit
+    // goes back to the broker as the application takes messages.
+    pub fn with_credit(mut self, messages: u64, bytes: u64) -> Self {
+        self.credit = Some(Credit { messages, bytes });
+        self
+    }
+
+    // With ReadCommitted, records of transactions only show up once committed
+    // and never if aborted
+    pub fn with_isolation_level(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = isolation;
+        self
     }
 
-    async fn read_response(&mut self) -> Result<String, Box<dyn Error>> {
-        let mut buffer = vec![0; 1024];
-        let n = self.stream.read(&mut buffer).await?;
-        let response = String::from_utf8(buffer[..n].to_vec())?;
-        Ok(response)
+    // How long the group waits to hear from this consumer before handing its
+    // partitions to someone else. Heartbeats go out three times as often.
+    pub fn with_session_timeout(mut self, timeout: Duration) -> Self {
+        self.session_timeout = timeout;
+        self
     }
 
     pub async fn subscribe(&mut self, topic: String) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::Subscribe)?;
+
         let subscribe_msg = BrokerMessage::Subscribe {
             consumer_id: self.consumer_id.clone(),
             topic,
         };
         
-        self.send_message(&subscribe_msg).await?;
-        let response = self.read_response().await?;
-        
-        if response == "Subscribed successfully" {
-            Ok(())
-        } else {
-            Err(response.into())
+        match self.connection.request(subscribe_msg).await? {
+            BrokerResponse::Subscribed { .. } => Ok(()),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to subscribe: {:?}", other).into()),
         }
     }
 
+    // Just the payloads of consume_messages
     pub async fn consume(&mut self, topic: String) -> Result<mpsc::Receiver<Vec<u8>>, Box<dyn Error>> {
+        let mut messages = self.consume_messages(topic).await?;
         let (tx, rx) = mpsc::channel(100);
-        
-        // Create a new connection for consuming messages
-        let mut consume_stream = self.stream.try_clone().await?;
-        
-        // Send consume request
+
+        tokio::spawn(async move {
+            while let Some(message) = messages.recv().await {
+                if tx.send(message.payload).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
+    // Every message delivered on the topic, headers included. A message's id
+    // is its topic, partition and offset. The topic must be subscribed to first.
+    pub async fn consume_messages(&mut self, topic: String) -> Result<mpsc::Receiver<Message>, Box<dyn Error>> {
+        let consume_msg = BrokerMessage::Consume {
+            consumer_id: self.consumer_id.clone(),
+            topics: vec![topic],
+            group_id: None,
+            credit: self.credit,
+            isolation: self.isolation,
+        };
+        self.stream_messages(consume_msg).await
+    }
+
+    // Messages from every subscribed topic on one stream, including topics
+    // subscribed to after this is called
+    pub async fn consume_all(&mut self) -> Result<mpsc::Receiver<Message>, Box<dyn Error>> {
         let consume_msg = BrokerMessage::Consume {
             consumer_id: self.consumer_id.clone(),
+            topics: Vec::new(),
+            group_id: None,
+            credit: self.credit,
+            isolation: self.isolation,
+        };
+        self.stream_messages(consume_msg).await
+    }
+
+    // Join a group to share the topics' partitions with its other members,
+    // strategies in order of preference. Returns this consumer's partitions,
+    // which change as members come and go.
+    pub async fn join_group(
+        &mut self,
+        group_id: String,
+        topics: Vec<String>,
+        strategies: Vec<AssignmentStrategy>,
+    ) -> Result<Vec<TopicPartitions>, Box<dyn Error>> {
+        self.connection.require(ApiKey::JoinGroup)?;
+
+        let join_msg = BrokerMessage::JoinGroup {
+            group_id: group_id.clone(),
+            member_id: None,
+            topics,
+            strategies,
+            session_timeout_ms: self.session_timeout.as_millis() as u64,
+        };
+        let joined = match self.connection.request(join_msg).await? {
+            BrokerResponse::GroupJoined(joined) => joined,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to join: {:?}", other).into()),
+        };
+
+        let sync_msg = BrokerMessage::SyncGroup {
+            group_id: group_id.clone(),
+            member_id: joined.member_id.clone(),
+        };
+        let synced = match self.connection.request(sync_msg).await? {
+            BrokerResponse::GroupAssignment(synced) => synced,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to sync: {:?}", other).into()),
+        };
+
+        let membership = Arc::new(Mutex::new(Membership {
+            group_id,
+            member_id: joined.member_id,
+            generation: synced.generation,
+            assignment: synced.assignment.clone(),
+        }));
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        let heartbeat = tokio::spawn(Self::heartbeat(
+            self.connection.clone(),
+            membership.clone(),
+            self.session_timeout / 3,
+        ));
+        self.heartbeat = Some(heartbeat.abort_handle());
+        self.group = Some(membership);
+
+        Ok(synced.assignment)
+    }
+
+    // This consumer's partitions as of the last heartbeat
+    pub fn assignment(&self) -> Vec<TopicPartitions> {
+        self.group
+            .as_ref()
+            .map(|group| group.lock().unwrap().assignment.clone())
+            .unwrap_or_default()
+    }
+
+    // Messages from the partitions assigned to this consumer, following every
+    // rebalance. Offsets are committed for the whole group.
+    pub async fn consume_group(&mut self) -> Result<mpsc::Receiver<Message>, Box<dyn Error>> {
+        let Some(group) = &self.group else {
+            return Err("Not a member of a group, join one first".into());
+        };
+        let consume_msg = {
+            let membership = group.lock().unwrap();
+            BrokerMessage::Consume {
+                consumer_id: membership.member_id.clone(),
+                topics: Vec::new(),
+                group_id: Some(membership.group_id.clone()),
+                credit: self.credit,
+                isolation: self.isolation,
+            }
+        };
+        self.stream_messages(consume_msg).await
+    }
+
+    pub async fn leave_group(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(group) = self.group.take() else {
+            return Ok(());
+        };
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+
+        let leave_msg = {
+            let membership = group.lock().unwrap();
+            BrokerMessage::LeaveGroup {
+                group_id: membership.group_id.clone(),
+                member_id: membership.member_id.clone(),
+            }
         };
+        match self.connection.request(leave_msg).await? {
+            BrokerResponse::GroupLeft => Ok(()),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to leave: {:?}", other).into()),
+        }
+    }
+
+    // Keeps the session alive and picks up the new assignment after a rebalance
+    async fn heartbeat(connection: Connection, membership: Arc<Mutex<Membership>>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            let (group_id, member_id, generation) = {
+                let membership = membership.lock().unwrap();
+                (membership.group_id.clone(), membership.member_id.clone(), membership.generation)
+            };
+            let heartbeat_msg = BrokerMessage::Heartbeat {
+                group_id: group_id.clone(),
+                member_id: member_id.clone(),
+                generation,
+            };
+
+            match connection.request(heartbeat_msg).await {
+                Ok(BrokerResponse::Error(error)) if error.code == ErrorCode::RebalanceInProgress => {
+                    let sync_msg = BrokerMessage::SyncGroup { group_id, member_id };
+                    if let Ok(BrokerResponse::GroupAssignment(synced)) = connection.request(sync_msg).await {
+                        let mut membership = membership.lock().unwrap();
+                        membership.generation = synced.generation;
+                        membership.assignment = synced.assignment;
+                    }
+                }
+                Ok(BrokerResponse::Error(error)) => {
+                    // Most likely the session expired, join_group again to get back in
+                    eprintln!("Stopped heartbeating to group {}: {}", group_id, error);
+                    break;
+                }
+                Ok(_) => {}
+                Err(_) => break, // Connection closed
+            }
+        }
+    }
+
+    async fn stream_messages(&mut self, consume_msg: BrokerMessage) -> Result<mpsc::Receiver<Message>, Box<dyn Error>> {
+        let version = self.connection.require(ApiKey::Consume)?;
+        self.require_isolation(version >= 6)?;
+        let (tx, rx) = mpsc::channel(100);
         
-        self.send_message(&consume_msg).await?;
+        // Paced consumers hand credit back as deliveries are received
+        let paced_consumer_id = match &consume_msg {
+            BrokerMessage::Consume { consumer_id, credit: Some(_), .. } => Some(consumer_id.clone()),
+            _ => None,
+        };
+        if paced_consumer_id.is_some() {
+            self.connection.require(ApiKey::GrantCredit)?;
+        }
+
+        // Deliveries come back on the same connection, tagged with this request's id
+        let mut deliveries = self.connection.stream(consume_msg).await?;
 
         // Spawn a task to continuously read messages
-        let consumer_id = self.consumer_id.clone();
-        let topic_clone = topic.clone();
+        let connection = self.connection.clone();
+        let consumer_id = self.offsets_owner();
         
         tokio::spawn(async move {
-            let mut buffer = vec![0; 1024 * 64]; // 64KB buffer
-            
-            loop {
-                match consume_stream.read(&mut buffer).await {
-                    Ok(n) if n > 0 => {
-                        if let Ok(message) = serde_json::from_slice::<ConsumeResponse>(&buffer[..n]) {
-                            // Send the message payload to the channel
-                            if tx.send(message.payload).await.is_err() {
-                                break;
-                            }
-                            
-                            // Send offset update
-                            if let Ok(mut update_stream) = consume_stream.try_clone().await {
-                                let update_msg = BrokerMessage::UpdateOffset {
-                                    consumer_id: consumer_id.clone(),
-                                    topic: topic_clone.clone(),
-                                    offset: message.offset,
-                                };
-                                
-                                if let Ok(msg_bytes) = serde_json::to_vec(&update_msg) {
-                                    let _ = update_stream.write_all(&msg_bytes).await;
-                                }
-                            }
-                        }
+            // Ends when the connection closes
+            'deliveries: while let Some(response) = deliveries.recv().await {
+                let delivery = match response {
+                    BrokerResponse::Records(delivery) => delivery,
+                    BrokerResponse::Error(error) => {
+                        eprintln!("Consume failed: {}", error);
+                        break;
                     }
-                    Ok(0) => break, // Connection closed
-                    Err(_) => break, // Error occurred
                     _ => continue,
+                };
+
+                let charged = Credit {
+                    messages: EncodedBatch::split_all(delivery.records.clone())
+                        .map(|batches| batches.iter().map(|batch| batch.offset_count() as u64).sum())
+                        .unwrap_or(0),
+                    bytes: delivery.records.len() as u64,
+                };
+
+                let mut last_offset = None;
+                for (offset, message) in decode_messages(&delivery.topic, delivery.partition, delivery.records) {
+                    if tx.send(message).await.is_err() {
+                        break 'deliveries;
+                    }
+                    last_offset = Some(offset);
+                }
+
+                // Everything is with the application now, so the broker may send as much again
+                if let Some(consumer_id) = &paced_consumer_id {
+                    let grant = BrokerMessage::GrantCredit {
+                        consumer_id: consumer_id.clone(),
+                        credit: charged,
+                    };
+                    if connection.send(grant).await.is_err() {
+                        break;
+                    }
+                }
+                let Some(offset) = last_offset else {
+                    continue;
+                };
+                
+                // Commit the offset without waiting for the broker to confirm it
+                let update_msg = BrokerMessage::UpdateOffset {
+                    consumer_id: consumer_id.clone(),
+                    topic: delivery.topic,
+                    partition: delivery.partition,
+                    offset,
+                };
+                
+                if connection.send(update_msg).await.is_err() {
+                    break;
                 }
             }
         });
@@ -151,21 +374,144 @@ impl Consumer {
         Ok(rx)
     }
 
-    pub async fn update_offset(&mut self, topic: String, offset: i64) -> Result<(), Box<dyn Error>> {
+    // Pull messages of one partition starting at `offset`, at the consumer's
+    // own pace. The broker holds the request for up to `max_wait` if there is
+    // nothing to return yet. Offsets are not committed, see `update_offset`.
+    pub async fn fetch(
+        &self,
+        topic: &str,
+        partition: u32,
+        offset: i64,
+        max_bytes: u32,
+        max_wait: Duration,
+    ) -> Result<Fetched, Box<dyn Error>> {
+        let version = self.connection.require(ApiKey::Fetch)?;
+        self.require_isolation(version >= 1)?;
+
+        let fetch_msg = BrokerMessage::Fetch {
+            topic: topic.to_string(),
+            partition,
+            offset,
+            max_bytes,
+            max_wait_ms: max_wait.as_millis() as u64,
+            isolation: self.isolation,
+            replica_id: None,
+        };
+
+        let fetched = match self.connection.request(fetch_msg).await? {
+            BrokerResponse::Fetched(fetched) => fetched,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to fetch: {:?}", other).into()),
+        };
+
+        // Batches come back whole, so the first can start before `offset`
+        let mut next_offset = offset;
+        let messages = decode_messages(topic, partition, fetched.records)
+            .into_iter()
+            .filter(|(message_offset, _)| *message_offset >= offset)
+            .map(|(message_offset, message)| {
+                next_offset = message_offset + 1;
+                message
+            })
+            .collect();
+
+        // The broker knows when markers or hidden records follow the last message
+        Ok(Fetched {
+            messages,
+            next_offset: next_offset.max(fetched.next_offset),
+            high_watermark: fetched.high_watermark,
+        })
+    }
+
+    pub async fn update_offset(
+        &mut self,
+        topic: String,
+        partition: u32,
+        offset: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::UpdateOffset)?;
+
         let update_msg = BrokerMessage::UpdateOffset {
-            consumer_id: self.consumer_id.clone(),
+            consumer_id: self.offsets_owner(),
             topic,
+            partition,
             offset,
         };
         
-        self.send_message(&update_msg).await?;
-        let response = self.read_response().await?;
-        
-        if response.contains("Offset updated") {
-            self.current_offset = offset;
-            Ok(())
-        } else {
-            Err(response.into())
+        match self.connection.request(update_msg).await? {
+            BrokerResponse::OffsetUpdated { offset, .. } => {
+                self.current_offset = offset;
+                Ok(())
+            }
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to offset update: {:?}", other).into()),
+        }
+    }
+
+    // Older brokers would quietly hand out uncommitted records
+    fn require_isolation(&self, supported: bool) -> Result<(), Box<dyn Error>> {
+        if self.isolation == IsolationLevel::ReadCommitted && !supported {
+            return Err("Broker does not support read_committed consumers".into());
+        }
+        Ok(())
+    }
+
+    // Offsets are kept per group for group members
+    fn offsets_owner(&self) -> String {
+        match &self.group {
+            Some(group) => group.lock().unwrap().group_id.clone(),
+            None => self.consumer_id.clone(),
         }
     }
-}
\ No newline at end of file
+}
+
+// Messages in back to back record batches, with their offsets. Whatever
+// can't be decoded is skipped.
+fn decode_messages(topic: &str, partition: u32, records: Bytes) -> Vec<(i64, Message)> {
+    let batches = match EncodedBatch::split_all(records) {
+        Ok(batches) => batches,
+        Err(e) => {
+            eprintln!("Skipping undecodable delivery: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut messages = Vec::new();
+    for batch in batches {
+        // Transaction markers, nothing for the application
+        if batch.is_control() {
+            continue;
+        }
+        let batch = match batch.decode() {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("Skipping undecodable batch: {}", e);
+                continue;
+            }
+        };
+
+        for record in batch.records {
+            let offset = batch.base_offset + record.offset_delta as i64;
+            let timestamp = DateTime::from_timestamp_millis(batch.base_timestamp + record.timestamp_delta)
+                .unwrap_or_default();
+            let message = Message {
+                id: format!("{}-{}-{}", topic, partition, offset),
+                topic: topic.to_string(),
+                headers: record.message_headers(),
+                payload: record.value.map(|value| value.to_vec()).unwrap_or_default(),
+                timestamp,
+            };
+            messages.push((offset, message));
+        }
+    }
+    messages
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        // Let the session run out so the group moves on without us
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod consumer;
-pub use consumer::Consumer;
\ No newline at end of file
+pub use consumer::{Consumer, Fetched};
+pub use rafka_protocol::{AssignmentStrategy, IsolationLevel, TopicPartitions};
@@ -1 +1,2 @@
-pub mod message;
\ No newline at end of file
+pub mod message;
+pub mod partitioner;
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use std::fmt;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
@@ -8,6 +9,9 @@ pub struct Message {
     pub topic: String,
     pub payload: Vec<u8>,
     pub timestamp: DateTime<Utc>,
+    // In the order they were added, the same key can appear more than once
+    #[serde(default)]
+    pub headers: Vec<Header>,
 }
 
 impl Message {
@@ -17,20 +21,194 @@ impl Message {
             topic,
             payload,
             timestamp: Utc::now(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.push(Header::new(key, value));
+        self
+    }
+
+    // First value stored under `key`
+    pub fn header(&self, key: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|header| header.key == key)
+            .map(|header| header.value.as_slice())
+    }
+}
+
+// Metadata carried next to the payload, such as a trace id or content type
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+impl Header {
+    pub fn new(key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
         }
     }
 }
 
+// Broker's reply to a publish
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageAck {
     pub message_id: String,
+    pub topic: String,
+    pub partition: u32,
+    pub offset: i64,
+    pub timestamp: DateTime<Utc>,
     pub status: AckStatus,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AckStatus {
     Success,
-    Error(String),
+    Error(BrokerError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrokerError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader: Option<Leader>,
+}
+
+impl BrokerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            leader: None,
+        }
+    }
+
+    pub fn not_leader(message: impl Into<String>, leader: Leader) -> Self {
+        Self {
+            leader: Some(leader),
+            ..Self::new(ErrorCode::NotLeader, message)
+        }
+    }
+}
+
+// The broker that owns a partition, where a NotLeader request should be retried
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leader {
+    pub broker_id: u32,
+    // None when the broker that answered doesn't know its peers' addresses
+    pub addr: Option<String>,
+}
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({}): {}", self.code, u16::from(self.code), self.message)
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+// Sent on the wire as its number, so variants can be renamed freely but
+// the numbers below must never change meaning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    // Also what any code this build does not know decodes to
+    Unknown,
+    MalformedRequest,
+    UnsupportedOperation,
+    UnknownTopic,
+    // The partition lives on another broker, see BrokerError::leader
+    NotLeader,
+    InvalidOffset,
+    FrameTooLarge,
+    // Consuming a topic without subscribing to it first, or granting credit
+    // to a consumer that isn't streaming
+    NotSubscribed,
+    TopicAlreadyExists,
+    // Group member the coordinator doesn't know, maybe its session expired
+    UnknownMember,
+    RebalanceInProgress,
+    // No assignment strategy every member of the group supports
+    InconsistentGroupProtocol,
+    // Register with a client type the broker doesn't take
+    UnknownClientType,
+    // Register with an id another connected client already has
+    DuplicateClientId,
+    // The broker is going away, try another one
+    ShuttingDown,
+    // An idempotent producer's batch isn't the next one in its sequence
+    OutOfOrderSequence,
+    // A newer epoch of the producer id has registered since
+    ProducerFenced,
+    // A transaction request that doesn't fit the producer's transaction, such
+    // as committing when none was begun
+    InvalidTransactionState,
+    // An acks=All produce that not every in-sync replica acknowledged in
+    // time. The batch is on the leader all the same.
+    NotEnoughReplicas,
+    // A metadata change the controllers couldn't commit, because they have
+    // no leader or it lost its leadership before the change got through
+    NotController,
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Unknown => 1,
+            ErrorCode::MalformedRequest => 2,
+            ErrorCode::UnsupportedOperation => 3,
+            ErrorCode::UnknownTopic => 4,
+            ErrorCode::NotLeader => 5,
+            ErrorCode::InvalidOffset => 6,
+            ErrorCode::FrameTooLarge => 7,
+            ErrorCode::NotSubscribed => 8,
+            ErrorCode::TopicAlreadyExists => 9,
+            ErrorCode::UnknownMember => 10,
+            ErrorCode::RebalanceInProgress => 11,
+            ErrorCode::InconsistentGroupProtocol => 12,
+            ErrorCode::UnknownClientType => 13,
+            ErrorCode::DuplicateClientId => 14,
+            ErrorCode::ShuttingDown => 15,
+            ErrorCode::OutOfOrderSequence => 16,
+            ErrorCode::ProducerFenced => 17,
+            ErrorCode::InvalidTransactionState => 18,
+            ErrorCode::NotEnoughReplicas => 19,
+            ErrorCode::NotController => 20,
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            2 => ErrorCode::MalformedRequest,
+            3 => ErrorCode::UnsupportedOperation,
+            4 => ErrorCode::UnknownTopic,
+            5 => ErrorCode::NotLeader,
+            6 => ErrorCode::InvalidOffset,
+            7 => ErrorCode::FrameTooLarge,
+            8 => ErrorCode::NotSubscribed,
+            9 => ErrorCode::TopicAlreadyExists,
+            10 => ErrorCode::UnknownMember,
+            11 => ErrorCode::RebalanceInProgress,
+            12 => ErrorCode::InconsistentGroupProtocol,
+            13 => ErrorCode::UnknownClientType,
+            14 => ErrorCode::DuplicateClientId,
+            15 => ErrorCode::ShuttingDown,
+            16 => ErrorCode::OutOfOrderSequence,
+            17 => ErrorCode::ProducerFenced,
+            18 => ErrorCode::InvalidTransactionState,
+            19 => ErrorCode::NotEnoughReplicas,
+            20 => ErrorCode::NotController,
+            _ => ErrorCode::Unknown,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Picks which of a topic's partitions a message goes to. Producers use one to
+// route their own messages, brokers use one per topic for messages that arrive
+// without a partition.
+pub trait Partitioner: Send + Sync {
+    // `partitions` is at least 1, the result must be below it
+    fn partition(&self, topic: &str, key: Option<&[u8]>, partitions: u32) -> u32;
+}
+
+// The built-in partitioners, by name, so a topic's can be chosen over the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PartitionStrategy {
+    #[default]
+    Murmur2,
+    ConsistentHash,
+    RoundRobin,
+    Sticky,
+}
+
+impl PartitionStrategy {
+    pub fn partitioner(self) -> Arc<dyn Partitioner> {
+        match self {
+            PartitionStrategy::Murmur2 => Arc::new(Murmur2Partitioner::default()),
+            PartitionStrategy::ConsistentHash => Arc::new(ConsistentHashPartitioner::default()),
+            PartitionStrategy::RoundRobin => Arc::new(RoundRobinPartitioner::default()),
+            PartitionStrategy::Sticky => Arc::new(StickyPartitioner::default()),
+        }
+    }
+}
+
+// Kafka's default: the same key lands on the same partition as it would with
+// a Kafka client. Messages without a key stick to one partition at a time.
+#[derive(Default)]
+pub struct Murmur2Partitioner {
+    unkeyed: StickyPartitioner,
+}
+
+impl Partitioner for Murmur2Partitioner {
+    fn partition(&self, topic: &str, key: Option<&[u8]>, partitions: u32) -> u32 {
+        match key {
+            Some(key) => (murmur2(key) & 0x7fff_ffff) as u32 % partitions,
+            None => self.unkeyed.partition(topic, None, partitions),
+        }
+    }
+}
+
+// Jump consistent hashing: adding a partition only moves about 1/n of the
+// keys, all of them onto the new partition. Unkeyed messages are sticky.
+#[derive(Default)]
+pub struct ConsistentHashPartitioner {
+    unkeyed: StickyPartitioner,
+}
+
+impl Partitioner for ConsistentHashPartitioner {
+    fn partition(&self, topic: &str, key: Option<&[u8]>, partitions: u32) -> u32 {
+        match key {
+            Some(key) => jump_hash(fnv1a(key), partitions),
+            None => self.unkeyed.partition(topic, None, partitions),
+        }
+    }
+}
+
+// Every message goes to the next partition, keys are ignored
+#[derive(Default)]
+pub struct RoundRobinPartitioner {
+    next: AtomicU64,
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&self, _topic: &str, _key: Option<&[u8]>, partitions: u32) -> u32 {
+        (self.next.fetch_add(1, Ordering::Relaxed) % partitions as u64) as u32
+    }
+}
+
+// Sends runs of messages to the same partition before moving to the next, so
+// they batch together while load still spreads out. Keys are ignored.
+pub struct StickyPartitioner {
+    messages_per_partition: u64,
+    sent: AtomicU64,
+}
+
+impl StickyPartitioner {
+    pub fn new(messages_per_partition: u64) -> Self {
+        Self {
+            messages_per_partition: messages_per_partition.max(1),
+            sent: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Default for StickyPartitioner {
+    fn default() -> Self {
+        Self::new(100)
+    }
+}
+
+impl Partitioner for StickyPartitioner {
+    fn partition(&self, _topic: &str, _key: Option<&[u8]>, partitions: u32) -> u32 {
+        let sent = self.sent.fetch_add(1, Ordering::Relaxed);
+        ((sent / self.messages_per_partition) % partitions as u64) as u32
+    }
+}
+
+// Kafka's murmur2, bit for bit, including the seed
+pub fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate().rev() {
+            h ^= (*byte as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Lamping and Veach, "A Fast, Minimal Memory, Consistent Hash Algorithm"
+fn jump_hash(mut key: u64, buckets: u32) -> u32 {
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < buckets as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2_862_933_555_777_941_757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as u32
+}
@@ -0,0 +1,61 @@
+use rafka_core::partitioner::{
+    murmur2, ConsistentHashPartitioner, Murmur2Partitioner, PartitionStrategy, Partitioner,
+    RoundRobinPartitioner, StickyPartitioner,
+};
+
+#[test]
+fn test_murmur2_matches_kafka() {
+    // Values from Kafka's own UtilsTest
+    assert_eq!(murmur2(b"21"), -973932308);
+    assert_eq!(murmur2(b"foobar"), -790332482);
+    assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+    assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+    assert_eq!(murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8"), -58897971);
+    assert_eq!(murmur2(b"abc"), 479470107);
+}
+
+#[test]
+fn test_anagrams_spread_out() {
+    let partitioner = Murmur2Partitioner::default();
+    let partitions: Vec<u32> = ["abc", "acb", "bac", "bca", "cab", "cba"]
+        .iter()
+        .map(|key| partitioner.partition("topic", Some(key.as_bytes()), 64))
+        .collect();
+    assert!(partitions.windows(2).any(|pair| pair[0] != pair[1]));
+}
+
+#[test]
+fn test_consistent_hash_moves_few_keys() {
+    let partitioner = ConsistentHashPartitioner::default();
+    let keys: Vec<String> = (0..1000).map(|i| format!("key-{}", i)).collect();
+
+    let mut moved = 0;
+    for key in &keys {
+        let before = partitioner.partition("topic", Some(key.as_bytes()), 10);
+        let after = partitioner.partition("topic", Some(key.as_bytes()), 11);
+        assert!(before < 10 && after < 11);
+        if before != after {
+            // Only ever onto the new partition
+            assert_eq!(after, 10);
+            moved += 1;
+        }
+    }
+    // About 1/11 of the keys
+    assert!(moved > 40 && moved < 150, "{} keys moved", moved);
+}
+
+#[test]
+fn test_unkeyed_partitioners() {
+    let round_robin = RoundRobinPartitioner::default();
+    let partitions: Vec<u32> = (0..6).map(|_| round_robin.partition("topic", None, 3)).collect();
+    assert_eq!(partitions, vec![0, 1, 2, 0, 1, 2]);
+
+    let sticky = StickyPartitioner::new(2);
+    let partitions: Vec<u32> = (0..6).map(|_| sticky.partition("topic", None, 3)).collect();
+    assert_eq!(partitions, vec![0, 0, 1, 1, 2, 2]);
+
+    // The hash partitioners fall back to sticky without a key
+    let murmur2 = PartitionStrategy::Murmur2.partitioner();
+    let first = murmur2.partition("topic", None, 3);
+    assert_eq!(murmur2.partition("topic", None, 3), first);
+}
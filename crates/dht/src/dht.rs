@@ -1,7 +1,7 @@
 use std::{error::Error, collections::HashSet, sync::Arc};
 use futures::stream::StreamExt;
 use libp2p::{
-    kad::{self, store::MemoryStore, Mode, QueryResult},
+    kad::{self, store::MemoryStore},
     mdns, noise,
     swarm::{NetworkBehaviour, SwarmEvent},
     tcp, yamux, PeerId, Multiaddr,
@@ -144,7 +144,7 @@ mod tests {
         });
 
         // Create second node
-        let (mut node2, mut events_rx2) = DHTNode::new().await?;
+        let (mut node2, _) = DHTNode::new().await?;
         info!("Created node2");
         
         // Connect node2 to node1
@@ -205,9 +205,9 @@ mod tests {
         
         // Create nodes
         let (mut node1, mut events_rx1) = DHTNode::new().await?;
-        let (mut node2, mut events_rx2) = DHTNode::new().await?;
-        let (mut node3, mut events_rx3) = DHTNode::new().await?;
-        let (mut node4, mut events_rx4) = DHTNode::new().await?;
+        let (mut node2, _) = DHTNode::new().await?;
+        let (mut node3, _) = DHTNode::new().await?;
+        let (mut node4, _) = DHTNode::new().await?;
 
         // Start bootstrap node (node1)
         let addr1 = "/ip4/127.0.0.1/tcp/0".parse()?;
@@ -226,7 +226,7 @@ mod tests {
         
         // Connect node2
         node2.connect_to_peer(listen_addr1.clone()).await?;
-        let node2_peers = node2.get_peers();
+        let _node2_peers = node2.get_peers();
         let node2_handle = tokio::spawn(async move {
             if let Err(e) = node2.start(None).await {
                 error!("Node2 error: {}", e);
@@ -1 +1 @@
-mod dht;
\ No newline at end of file
+pub mod dht;
\ No newline at end of file
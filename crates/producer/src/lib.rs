@@ -1,2 +1,3 @@
 mod producer;
-pub use producer::Producer;
\ No newline at end of file
+pub use producer::{PendingAck, Producer};
+pub use rafka_protocol::{AclBinding, AclOperation, Acks, ClientSession, MetricsResponse, PartitionDescription, QuorumDescription, TopicConfig, TopicDescription, TxnOffset};
\ No newline at end of file
@@ -1,63 +1,422 @@
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use serde::{Serialize, Deserialize};
+use rafka_core::message::{AckStatus, Header, MessageAck};
+use rafka_core::partitioner::{PartitionStrategy, Partitioner};
+use bytes::Bytes;
+use chrono::Utc;
+use rafka_protocol::{
+    AclBinding, Acks, ApiKey, BrokerMessage, BrokerResponse, ClientSession, Compression, Connection, MetadataResponse, MetricsResponse,
+    PendingResponse, ProducerIdentity, QuorumDescription, Record, RecordBatch, TopicConfig, TopicDescription, TopicPartitions, TxnOffset,
+    IDEMPOTENCE,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum BrokerMessage {
-    Publish {
-        key: String,
-        topic: String,
-        payload: Vec<u8>,
-    },
-    Register {
-        client_id: String,
-        client_type: String,
-    },
+// How often the ack of an idempotent send is waited for again, after resending
+// it, before the send is given up on
+const MAX_RETRIES: u32 = 3;
+
+// Idempotent batches that were written but not acknowledged yet, by partition
+// and first sequence
+type Unacked = Arc<Mutex<HashMap<(String, u32), BTreeMap<i32, UnackedBatch>>>>;
+
+struct UnackedBatch {
+    message: BrokerMessage,
+    // Nobody waits for its ack anymore, it goes out again before the next
+    // batch to the partition
+    given_up: bool,
 }
 
 pub struct Producer {
-    stream: TcpStream,
+    connection: Connection,
+    addr: String,
     producer_id: String,
+    compression: Compression,
+    // Without one the broker partitions with the topic's own partitioner
+    partitioner: Option<Arc<dyn Partitioner>>,
+    topic_partitioners: HashMap<String, Arc<dyn Partitioner>>,
+    // Partition counts of the topics this producer knows about, only those
+    // topics are partitioned here
+    partition_counts: HashMap<String, u32>,
+    // Everything below comes from cluster metadata, see refresh_metadata
+    broker_id: Option<u32>,
+    broker_addrs: HashMap<u32, String>,
+    // Broker hosting each partition of a topic
+    leaders: HashMap<String, Vec<u32>>,
+    // The topic's own partitioner, for topics no partitioner was given for
+    topic_strategies: HashMap<String, Arc<dyn Partitioner>>,
+    // Opened on first use for partitions hosted on other brokers
+    broker_connections: Mutex<HashMap<u32, Connection>>,
+    // Set by enable_idempotence
+    identity: Option<ProducerIdentity>,
+    // Next sequence number for each partition. Locked until the batch is
+    // written, so batches reach a broker in sequence order.
+    sequences: tokio::sync::Mutex<HashMap<(String, u32), i32>>,
+    // Resent under their own sequences when the ack is lost, so the broker
+    // can tell a retry from a new batch
+    unacked: Unacked,
+    // Partitions the open transaction has written to, None outside of one
+    transaction: Mutex<Option<HashSet<(String, u32)>>>,
+    transaction_timeout: Duration,
+    acks: Acks,
+    // How long a send may take to be acknowledged, or with Acks::None to be
+    // written. For Acks::All the broker's replica timeout comes on top.
+    ack_timeout: Duration,
+}
+
+// A publish that is on the wire and waiting for the broker to acknowledge it
+pub struct PendingAck {
+    state: AckState,
+}
+
+enum AckState {
+    Waiting {
+        pending: PendingResponse,
+        timeout: Duration,
+        // Set for idempotent sends
+        retry: Option<Retry>,
+    },
+    // Sent with Acks::None, there is nothing to wait for
+    Sent(MessageAck),
+}
+
+// What it takes to resend an idempotent batch exactly as it was
+struct Retry {
+    connection: Connection,
+    message: BrokerMessage,
+    unacked: Unacked,
+    partition: (String, u32),
+    sequence: i32,
+    acknowledged: bool,
+}
+
+impl Drop for Retry {
+    fn drop(&mut self) {
+        let mut unacked = self.unacked.lock().unwrap();
+        let Some(batches) = unacked.get_mut(&self.partition) else {
+            return;
+        };
+        if self.acknowledged {
+            batches.remove(&self.sequence);
+        } else if let Some(batch) = batches.get_mut(&self.sequence) {
+            batch.given_up = true;
+        }
+    }
+}
+
+impl PendingAck {
+    // With Acks::None this is the ack of a send that was written, with an
+    // offset of -1. If the broker was left to pick the partition it says 0.
+    // Idempotent sends whose ack doesn't come are resent, a broker that
+    // already has the batch acks it with the offset it was stored at.
+    pub async fn ack(self) -> Result<MessageAck, Box<dyn Error>> {
+        let (mut pending, timeout, mut retry) = match self.state {
+            AckState::Waiting { pending, timeout, retry } => (pending, timeout, retry),
+            AckState::Sent(ack) => return Ok(ack),
+        };
+        let mut retries = 0;
+        let response = loop {
+            let retry = {
+                let lost: Box<dyn Error> = match tokio::time::timeout(timeout, pending.response()).await {
+                    Ok(Ok(response)) => break response,
+                    Ok(Err(e)) => e.into(),
+                    Err(_) => format!("Broker did not acknowledge the publish within {:?}", timeout).into(),
+                };
+                match retry.as_ref() {
+                    Some(retry) if retries < MAX_RETRIES => retry,
+                    _ => return Err(lost),
+                }
+            };
+            retries += 1;
+            pending = retry.connection.send(retry.message.clone()).await?;
+        };
+        let ack = match response {
+            BrokerResponse::Ack(ack) => match ack.status {
+                AckStatus::Success => ack,
+                AckStatus::Error(error) => return Err(error.into()),
+            },
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to publish: {:?}", other).into()),
+        };
+        if let Some(retry) = retry.as_mut() {
+            retry.acknowledged = true;
+        }
+        Ok(ack)
+    }
 }
 
 impl Producer {
     pub async fn new(addr: &str) -> Result<Self, Box<dyn Error>> {
-        let stream = TcpStream::connect(addr).await?;
         let producer_id = Uuid::new_v4().to_string();
+        let producer = Self::connect(addr, producer_id).await?;
+
+        // Register with broker, if it keeps track of clients at all
+        if producer.connection.versions().supports(ApiKey::Register) {
+            producer.register(Vec::new()).await?;
+            println!("Producer registered with ID: {}", producer.producer_id);
+        }
         
-        let mut producer = Self {
-            stream,
-            producer_id,
-        };
+        Ok(producer)
+    }
 
-        // Register with broker
+    async fn register(&self, features: Vec<String>) -> Result<Option<ProducerIdentity>, Box<dyn Error>> {
         let register_msg = BrokerMessage::Register {
-            client_id: producer.producer_id.clone(),
+            client_id: self.producer_id.clone(),
             client_type: "producer".to_string(),
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            features,
         };
-        
-        producer.send_message(&register_msg).await?;
-        let response = producer.read_response().await?;
-        
-        println!("Producer registered with ID: {}", producer.producer_id);
-        println!("Registration response: {}", response);
-        
-        Ok(producer)
+
+        match self.connection.request(register_msg).await? {
+            BrokerResponse::Registered { producer, .. } => Ok(producer),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to register: {:?}", other).into()),
+        }
     }
 
-    async fn send_message(&mut self, message: &BrokerMessage) -> Result<(), Box<dyn Error>> {
-        let message_bytes = serde_json::to_vec(message)?;
-        self.stream.write_all(&message_bytes).await?;
+    // Gets a producer id from the connected broker and numbers every batch
+    // from then on, so brokers can drop batches that are sent twice. Only
+    // topics in the cluster metadata can be published to after this, as the
+    // producer has to know which partition a batch goes to.
+    pub async fn enable_idempotence(&mut self) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::Register)?;
+
+        let identity = self
+            .register(vec![IDEMPOTENCE.to_string()])
+            .await?
+            .ok_or("Broker did not hand out a producer id")?;
+        // A new epoch starts its sequences over, and the broker has aborted
+        // whatever transaction the old one left open
+        self.identity = Some(identity);
+        self.sequences.get_mut().clear();
+        self.unacked.lock().unwrap().clear();
+        *self.transaction.get_mut().unwrap() = None;
+        self.refresh_metadata().await?;
         Ok(())
     }
 
-    async fn read_response(&mut self) -> Result<String, Box<dyn Error>> {
-        let mut buffer = vec![0; 1024];
-        let n = self.stream.read(&mut buffer).await?;
-        let response = String::from_utf8(buffer[..n].to_vec())?;
-        Ok(response)
+    // Everything sent from here until commit_transaction or abort_transaction
+    // becomes visible to read_committed consumers all at once, or not at all.
+    // Needs enable_idempotence first. The connected broker runs the transaction.
+    pub async fn begin_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::BeginTransaction)?;
+        let producer = self
+            .identity
+            .ok_or("Transactions need an idempotent producer, call enable_idempotence first")?;
+        if self.transaction.get_mut().unwrap().is_some() {
+            return Err("A transaction is already in progress".into());
+        }
+
+        let begin_msg = BrokerMessage::BeginTransaction {
+            producer,
+            timeout_ms: self.transaction_timeout.as_millis() as u64,
+        };
+        match self.connection.request(begin_msg).await? {
+            BrokerResponse::TransactionBegun => {
+                *self.transaction.get_mut().unwrap() = Some(HashSet::new());
+                Ok(())
+            }
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to begin transaction: {:?}", other).into()),
+        }
+    }
+
+    // Commits consumer offsets along with the open transaction, so consuming
+    // and producing take effect together
+    pub async fn send_offsets_to_transaction(&self, offsets: Vec<TxnOffset>) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::TxnCommitOffsets)?;
+        let producer = self.transactional_identity()?;
+
+        let offsets_msg = BrokerMessage::TxnCommitOffsets { producer, offsets };
+        match self.connection.request(offsets_msg).await? {
+            BrokerResponse::TxnOffsetsAdded => Ok(()),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to transaction offsets: {:?}", other).into()),
+        }
+    }
+
+    // Acks of everything sent in the transaction should be awaited first.
+    // A transaction that ran past its timeout has already been aborted by the
+    // broker, which also fenced this producer until enable_idempotence is
+    // called again.
+    pub async fn commit_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        self.end_transaction(true).await
+    }
+
+    pub async fn abort_transaction(&mut self) -> Result<(), Box<dyn Error>> {
+        self.end_transaction(false).await
+    }
+
+    // Over once the broker answers, whatever it says
+    async fn end_transaction(&mut self, commit: bool) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::EndTransaction)?;
+        let producer = self.transactional_identity()?;
+
+        let end_msg = BrokerMessage::EndTransaction { producer, commit };
+        let response = self.connection.request(end_msg).await?;
+        *self.transaction.get_mut().unwrap() = None;
+        match response {
+            BrokerResponse::TransactionEnded { .. } => Ok(()),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to end transaction: {:?}", other).into()),
+        }
+    }
+
+    fn transactional_identity(&self) -> Result<ProducerIdentity, Box<dyn Error>> {
+        match (self.identity, self.transaction.lock().unwrap().is_some()) {
+            (Some(identity), true) => Ok(identity),
+            _ => Err("No transaction in progress, call begin_transaction first".into()),
+        }
+    }
+
+    // Tells the broker running the transaction about a partition before the
+    // transaction first writes to it. False outside of a transaction.
+    async fn add_to_transaction(&self, topic: &str, partition: Option<u32>) -> Result<bool, Box<dyn Error>> {
+        let partition = {
+            let transaction = self.transaction.lock().unwrap();
+            let Some(added) = transaction.as_ref() else {
+                return Ok(false);
+            };
+            let partition = Self::known_partition(topic, partition)?;
+            if added.contains(&(topic.to_string(), partition)) {
+                return Ok(true);
+            }
+            partition
+        };
+
+        let add_msg = BrokerMessage::AddPartitionsToTxn {
+            producer: self.transactional_identity()?,
+            partitions: vec![TopicPartitions {
+                topic: topic.to_string(),
+                partitions: vec![partition],
+            }],
+        };
+        match self.connection.request(add_msg).await? {
+            BrokerResponse::PartitionsAddedToTxn => {
+                if let Some(added) = self.transaction.lock().unwrap().as_mut() {
+                    added.insert((topic.to_string(), partition));
+                }
+                Ok(true)
+            }
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to adding partitions: {:?}", other).into()),
+        }
+    }
+
+    fn known_partition(topic: &str, partition: Option<u32>) -> Result<u32, Box<dyn Error>> {
+        partition.ok_or_else(|| {
+            format!("Topic {} is not in the cluster metadata, idempotent producers need its partitions", topic).into()
+        })
+    }
+
+    // Connects to the first of `addrs` that answers and learns the rest of the
+    // cluster from it, so every publish goes straight to its partition's broker
+    pub async fn bootstrap(addrs: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut last_error: Box<dyn Error> = "No broker addresses given".into();
+        for addr in addrs {
+            match Self::new(addr).await {
+                Ok(mut producer) => {
+                    producer.refresh_metadata().await?;
+                    return Ok(producer);
+                }
+                Err(e) => {
+                    eprintln!("Could not reach broker {}: {}", addr, e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect(addr: &str, producer_id: String) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            connection: Connection::connect(addr).await?,
+            addr: addr.to_string(),
+            producer_id,
+            compression: Compression::None,
+            partitioner: None,
+            topic_partitioners: HashMap::new(),
+            partition_counts: HashMap::new(),
+            broker_id: None,
+            broker_addrs: HashMap::new(),
+            leaders: HashMap::new(),
+            topic_strategies: HashMap::new(),
+            broker_connections: Mutex::new(HashMap::new()),
+            identity: None,
+            sequences: tokio::sync::Mutex::new(HashMap::new()),
+            unacked: Arc::new(Mutex::new(HashMap::new())),
+            transaction: Mutex::new(None),
+            transaction_timeout: Duration::from_secs(60),
+            acks: Acks::Leader,
+            ack_timeout: Duration::from_secs(30),
+        })
+    }
+
+    // Asks the connected broker which brokers there are and where every topic's
+    // partitions live. Topics created later are only known after another refresh.
+    pub async fn refresh_metadata(&mut self) -> Result<MetadataResponse, Box<dyn Error>> {
+        self.connection.require(ApiKey::Metadata)?;
+
+        let metadata = match self.connection.request(BrokerMessage::Metadata { topics: None }).await? {
+            BrokerResponse::Metadata(metadata) => metadata,
+            BrokerResponse::Error(error) => return Err(error.into()),
+            other => return Err(format!("Unexpected response to metadata: {:?}", other).into()),
+        };
+
+        self.broker_id = Some(metadata.broker_id);
+        self.broker_addrs = metadata
+            .brokers
+            .iter()
+            .filter_map(|broker| Some((broker.broker_id, broker.addr.clone()?)))
+            .collect();
+        for topic in &metadata.topics {
+            let leaders = topic.partitions.iter().map(|partition| partition.leader).collect();
+            self.partition_counts.insert(topic.topic.clone(), topic.partitions.len() as u32);
+            self.leaders.insert(topic.topic.clone(), leaders);
+            self.topic_strategies.insert(topic.topic.clone(), topic.partitioner.partitioner());
+        }
+        Ok(metadata)
+    }
+
+    // How long the broker lets a transaction run before aborting it
+    pub fn with_transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.transaction_timeout = timeout;
+        self
+    }
+
+    // When the broker acknowledges what this producer sends
+    pub fn with_acks(mut self, acks: Acks) -> Self {
+        self.acks = acks;
+        self
+    }
+
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    // Compress every batch sent from now on
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Partition messages here instead of leaving it to the broker
+    pub fn with_partitioner(mut self, partitioner: Arc<dyn Partitioner>) -> Self {
+        self.partitioner = Some(partitioner);
+        self
+    }
+
+    // Overrides the producer's partitioner for one topic
+    pub fn with_topic_partitioner(mut self, topic: &str, partitioner: Arc<dyn Partitioner>) -> Self {
+        self.topic_partitioners.insert(topic.to_string(), partitioner);
+        self
+    }
+
+    pub fn with_partition_count(mut self, topic: &str, partitions: u32) -> Self {
+        self.partition_counts.insert(topic.to_string(), partitions);
+        self
     }
 
     pub async fn publish(
@@ -65,17 +424,181 @@ impl Producer {
         topic: String,
         message: String,
         key: String,
-    ) -> Result<(), Box<dyn Error>> {
-        let publish_msg = BrokerMessage::Publish {
-            key,
-            topic,
-            payload: message.into_bytes(),
+        headers: Vec<Header>,
+    ) -> Result<MessageAck, Box<dyn Error>> {
+        let pending = self.send(topic, message, key, headers).await?;
+        let ack = pending.ack().await?;
+        
+        println!(
+            "Published to partition {} with offset {}",
+            ack.partition, ack.offset
+        );
+        Ok(ack)
+    }
+
+    // Write a publish without waiting for its ack, so many can be in flight on
+    // one connection. The broker assigns offsets in the order send is called.
+    pub async fn send(
+        &self,
+        topic: String,
+        message: String,
+        key: String,
+        headers: Vec<Header>,
+    ) -> Result<PendingAck, Box<dyn Error>> {
+        let partition = self.choose_partition(&topic, key.as_bytes());
+        let record = Record::new(0, Some(Bytes::from(key)), Bytes::from(message)).with_headers(headers);
+        self.send_batch(topic, partition, vec![record]).await
+    }
+
+    // None leaves the choice to the broker, as does a count of 0 partitions
+    fn choose_partition(&self, topic: &str, key: &[u8]) -> Option<u32> {
+        let partitions = *self.partition_counts.get(topic).filter(|partitions| **partitions > 0)?;
+        let partitioner = self
+            .topic_partitioners
+            .get(topic)
+            .or(self.partitioner.as_ref())
+            .or_else(|| self.topic_strategies.get(topic))?;
+        Some(partitioner.partition(topic, Some(key), partitions))
+    }
+
+    // The connection to the partition's broker, the connected broker when
+    // that isn't known
+    async fn connection_for(&self, topic: &str, partition: Option<u32>) -> Result<Connection, Box<dyn Error>> {
+        let leader = partition.and_then(|partition| self.leaders.get(topic)?.get(partition as usize).copied());
+        let (Some(leader), Some(broker_id)) = (leader, self.broker_id) else {
+            return Ok(self.connection.clone());
+        };
+        if leader == broker_id {
+            return Ok(self.connection.clone());
+        }
+        let Some(addr) = self.broker_addrs.get(&leader) else {
+            return Ok(self.connection.clone());
         };
 
-        self.send_message(&publish_msg).await?;
-        let response = self.read_response().await?;
-        
-        println!("Response from broker: {}", response);
+        if let Some(connection) = self.broker_connections.lock().unwrap().get(&leader) {
+            return Ok(connection.clone());
+        }
+        let connection = Connection::connect(addr).await?;
+        self.broker_connections.lock().unwrap().insert(leader, connection.clone());
+        Ok(connection)
+    }
+
+    // Send messages as one record batch, acknowledged with the offset of the first
+    async fn send_batch(
+        &self,
+        topic: String,
+        partition: Option<u32>,
+        records: Vec<Record>,
+    ) -> Result<PendingAck, Box<dyn Error>> {
+        let connection = self.connection_for(&topic, partition).await?;
+        // Older brokers would acknowledge any acks level once appended
+        if connection.require(ApiKey::Produce)? < 3 && self.acks != Acks::Leader {
+            return Err(format!("Broker does not support {:?} acks", self.acks).into());
+        }
+
+        let mut batch = RecordBatch::new(0, Utc::now().timestamp_millis(), records)
+            .with_compression(self.compression);
+        if self.add_to_transaction(&topic, partition).await? {
+            batch = batch.transactional();
+        }
+
+        // The sequence only moves on once the batch is written, a batch that
+        // never left leaves no gap behind
+        let mut sequences = self.sequences.lock().await;
+        let mut numbered = None;
+        if let Some(identity) = self.identity {
+            let key = (topic.clone(), Self::known_partition(&topic, partition)?);
+            self.resend_given_up(&connection, &key).await?;
+            let sequence = sequences.get(&key).copied().unwrap_or(0);
+            batch = batch.with_producer(identity.producer_id, identity.producer_epoch, sequence);
+            numbered = Some((key, sequence, batch.records.len() as i32));
+        }
+
+        let produce_msg = BrokerMessage::Produce {
+            topic: topic.clone(),
+            partition,
+            records: batch.encoded().into_bytes(),
+            acks: self.acks,
+        };
+        let retry = numbered.as_ref().map(|(key, sequence, _)| Retry {
+            connection: connection.clone(),
+            message: produce_msg.clone(),
+            unacked: self.unacked.clone(),
+            partition: key.clone(),
+            sequence: *sequence,
+            acknowledged: false,
+        });
+
+        let state = match self.acks {
+            Acks::None => {
+                tokio::time::timeout(self.ack_timeout, connection.send_only(produce_msg))
+                    .await
+                    .map_err(|_| format!("Could not write the publish within {:?}", self.ack_timeout))??;
+                AckState::Sent(MessageAck {
+                    message_id: String::new(),
+                    topic,
+                    partition: partition.unwrap_or(0),
+                    offset: -1,
+                    timestamp: Utc::now(),
+                    status: AckStatus::Success,
+                })
+            }
+            Acks::Leader | Acks::All { .. } => AckState::Waiting {
+                pending: connection.send(produce_msg).await?,
+                timeout: self.ack_wait(),
+                retry,
+            },
+        };
+
+        if let Some((key, sequence, count)) = numbered {
+            if let AckState::Waiting { retry: Some(retry), .. } = &state {
+                let batch = UnackedBatch {
+                    message: retry.message.clone(),
+                    given_up: false,
+                };
+                self.unacked.lock().unwrap().entry(key.clone()).or_default().insert(sequence, batch);
+            }
+            sequences.insert(key, sequence.wrapping_add(count));
+        }
+        drop(sequences);
+        Ok(PendingAck { state })
+    }
+
+    // How long each ack of a send is waited for
+    fn ack_wait(&self) -> Duration {
+        match self.acks {
+            Acks::All { timeout_ms } => self.ack_timeout + Duration::from_millis(timeout_ms),
+            Acks::None | Acks::Leader => self.ack_timeout,
+        }
+    }
+
+    // Batches to the partition that were given up on go out again, in order
+    // and under their own sequences, before anything newer does
+    async fn resend_given_up(&self, connection: &Connection, key: &(String, u32)) -> Result<(), Box<dyn Error>> {
+        let given_up: Vec<(i32, BrokerMessage)> = match self.unacked.lock().unwrap().get(key) {
+            Some(batches) => batches
+                .iter()
+                .filter(|(_, batch)| batch.given_up)
+                .map(|(sequence, batch)| (*sequence, batch.message.clone()))
+                .collect(),
+            None => return Ok(()),
+        };
+        for (sequence, message) in given_up {
+            let retry = Retry {
+                connection: connection.clone(),
+                message: message.clone(),
+                unacked: self.unacked.clone(),
+                partition: key.clone(),
+                sequence,
+                acknowledged: false,
+            };
+            let state = AckState::Waiting {
+                pending: connection.send(message).await?,
+                timeout: self.ack_wait(),
+                retry: Some(retry),
+            };
+            PendingAck { state }.ack().await?;
+        }
         Ok(())
     }
 
@@ -84,30 +607,223 @@ impl Producer {
         &mut self,
         topic: String,
         messages: Vec<(String, String)>, // (key, message) pairs
-    ) -> Result<Vec<String>, Box<dyn Error>> {
-        let mut responses = Vec::new();
-        
-        for (key, message) in messages {
-            let publish_msg = BrokerMessage::Publish {
-                key,
-                topic: topic.clone(),
-                payload: message.into_bytes(),
+    ) -> Result<Vec<MessageAck>, Box<dyn Error>> {
+        // One batch per partition, so the broker acks once for each
+        let mut batches: Vec<(Option<u32>, Vec<usize>, Vec<Record>)> = Vec::new();
+        for (i, (key, message)) in messages.into_iter().enumerate() {
+            let partition = self.choose_partition(&topic, key.as_bytes());
+            let position = match batches.iter().position(|(p, _, _)| *p == partition) {
+                Some(position) => position,
+                None => {
+                    batches.push((partition, Vec::new(), Vec::new()));
+                    batches.len() - 1
+                }
             };
+            let (_, indices, records) = &mut batches[position];
+            records.push(Record::new(records.len() as i32, Some(Bytes::from(key)), Bytes::from(message)));
+            indices.push(i);
+        }
 
-            self.send_message(&publish_msg).await?;
-            let response = self.read_response().await?;
-            responses.push(response);
+        let count = batches.iter().map(|(_, indices, _)| indices.len()).sum();
+        let mut acks = vec![None; count];
+        for (partition, indices, records) in batches {
+            let ack = self.send_batch(topic.clone(), partition, records).await?.ack().await?;
+            for (delta, i) in indices.into_iter().enumerate() {
+                // Unacknowledged sends have no offsets to count from
+                let offset = if ack.offset < 0 { ack.offset } else { ack.offset + delta as i64 };
+                acks[i] = Some(MessageAck {
+                    offset,
+                    ..ack.clone()
+                });
+            }
         }
         
-        Ok(responses)
+        Ok(acks.into_iter().flatten().collect())
+    }
+
+    // Create a topic on the connected broker with the given number of partitions.
+    // `partitioner` is what the broker uses for messages that arrive without a partition.
+    pub async fn create_topic(
+        &mut self,
+        topic: String,
+        partitions: u32,
+        partitioner: PartitionStrategy,
+    ) -> Result<(), Box<dyn Error>> {
+        self.create_topic_with_config(topic, partitions, partitioner, TopicConfig::default())
+            .await
+    }
+
+    // Like create_topic, with settings of its own such as retention
+    pub async fn create_topic_with_config(
+        &mut self,
+        topic: String,
+        partitions: u32,
+        partitioner: PartitionStrategy,
+        config: TopicConfig,
+    ) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::CreateTopic)?;
+
+        let create_msg = BrokerMessage::CreateTopic {
+            topic: topic.clone(),
+            partitions,
+            partitioner,
+            config,
+        };
+        match self.connection.request(create_msg).await? {
+            BrokerResponse::TopicCreated { .. } => {
+                self.partition_counts.insert(topic, partitions);
+                // Learn where the new partitions live
+                if self.broker_id.is_some() {
+                    self.refresh_metadata().await?;
+                }
+                Ok(())
+            }
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to topic creation: {:?}", other).into()),
+        }
+    }
+
+    // Deletes the topic on the connected broker, with its messages and offsets
+    pub async fn delete_topic(&mut self, topic: &str) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::DeleteTopic)?;
+
+        let delete_msg = BrokerMessage::DeleteTopic {
+            topic: topic.to_string(),
+        };
+        match self.connection.request(delete_msg).await? {
+            BrokerResponse::TopicDeleted { .. } => {
+                self.partition_counts.remove(topic);
+                self.leaders.remove(topic);
+                self.topic_strategies.remove(topic);
+                Ok(())
+            }
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to topic deletion: {:?}", other).into()),
+        }
+    }
+
+    // Names of the topics the connected broker knows about
+    pub async fn list_topics(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        self.connection.require(ApiKey::ListTopics)?;
+
+        match self.connection.request(BrokerMessage::ListTopics).await? {
+            BrokerResponse::TopicList { topics } => Ok(topics),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to topic listing: {:?}", other).into()),
+        }
+    }
+
+    pub async fn describe_topic(&self, topic: &str) -> Result<TopicDescription, Box<dyn Error>> {
+        self.connection.require(ApiKey::DescribeTopic)?;
+
+        let describe_msg = BrokerMessage::DescribeTopic {
+            topic: topic.to_string(),
+        };
+        match self.connection.request(describe_msg).await? {
+            BrokerResponse::TopicDescribed(description) => Ok(description),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to topic description: {:?}", other).into()),
+        }
+    }
+
+    // Counters and partition stats of the connected broker
+    pub async fn metrics(&self) -> Result<MetricsResponse, Box<dyn Error>> {
+        self.connection.require(ApiKey::GetMetrics)?;
+
+        match self.connection.request(BrokerMessage::GetMetrics).await? {
+            BrokerResponse::Metrics(metrics) => Ok(metrics),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to metrics request: {:?}", other).into()),
+        }
+    }
+
+    // Clients registered with the connected broker, by client id
+    pub async fn list_clients(&self) -> Result<Vec<ClientSession>, Box<dyn Error>> {
+        self.connection.require(ApiKey::ListClients)?;
+
+        match self.connection.request(BrokerMessage::ListClients).await? {
+            BrokerResponse::ClientList { clients } => Ok(clients),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to client listing: {:?}", other).into()),
+        }
+    }
+
+    // Who leads the metadata quorum, as the connected broker sees it
+    pub async fn describe_quorum(&self) -> Result<QuorumDescription, Box<dyn Error>> {
+        self.connection.require(ApiKey::DescribeQuorum)?;
+
+        match self.connection.request(BrokerMessage::DescribeQuorum).await? {
+            BrokerResponse::QuorumDescribed(description) => Ok(description),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to quorum description: {:?}", other).into()),
+        }
+    }
+
+    // Makes the broker a controller, returning the controllers after
+    pub async fn add_controller(&self, broker_id: u32) -> Result<Vec<u32>, Box<dyn Error>> {
+        self.change_controllers(ApiKey::AddController, BrokerMessage::AddController { broker_id })
+            .await
+    }
+
+    pub async fn remove_controller(&self, broker_id: u32) -> Result<Vec<u32>, Box<dyn Error>> {
+        self.change_controllers(ApiKey::RemoveController, BrokerMessage::RemoveController { broker_id })
+            .await
+    }
+
+    async fn change_controllers(&self, api: ApiKey, message: BrokerMessage) -> Result<Vec<u32>, Box<dyn Error>> {
+        self.connection.require(api)?;
+
+        match self.connection.request(message).await? {
+            BrokerResponse::ControllersChanged { controllers } => Ok(controllers),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to controller change: {:?}", other).into()),
+        }
+    }
+
+    pub async fn create_acl(&self, acl: AclBinding) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::CreateAcl)?;
+
+        match self.connection.request(BrokerMessage::CreateAcl { acl }).await? {
+            BrokerResponse::AclCreated => Ok(()),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to ACL creation: {:?}", other).into()),
+        }
+    }
+
+    pub async fn delete_acl(&self, acl: AclBinding) -> Result<(), Box<dyn Error>> {
+        self.connection.require(ApiKey::DeleteAcl)?;
+
+        match self.connection.request(BrokerMessage::DeleteAcl { acl }).await? {
+            BrokerResponse::AclDeleted => Ok(()),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to ACL deletion: {:?}", other).into()),
+        }
+    }
+
+    // ACLs of the connected broker, in the order they were created
+    pub async fn list_acls(&self) -> Result<Vec<AclBinding>, Box<dyn Error>> {
+        self.connection.require(ApiKey::ListAcls)?;
+
+        match self.connection.request(BrokerMessage::ListAcls).await? {
+            BrokerResponse::AclList { acls } => Ok(acls),
+            BrokerResponse::Error(error) => Err(error.into()),
+            other => Err(format!("Unexpected response to ACL listing: {:?}", other).into()),
+        }
     }
 
     // Get a new stream for parallel publishing if needed
     pub async fn clone_connection(&self) -> Result<Self, Box<dyn Error>> {
-        let stream = self.stream.try_clone().await?;
-        Ok(Self {
-            stream,
-            producer_id: self.producer_id.clone(),
-        })
+        let mut producer = Self::connect(&self.addr, self.producer_id.clone()).await?;
+        producer.compression = self.compression;
+        producer.partitioner = self.partitioner.clone();
+        producer.topic_partitioners = self.topic_partitioners.clone();
+        producer.partition_counts = self.partition_counts.clone();
+        producer.broker_id = self.broker_id;
+        producer.broker_addrs = self.broker_addrs.clone();
+        producer.leaders = self.leaders.clone();
+        producer.topic_strategies = self.topic_strategies.clone();
+        producer.acks = self.acks;
+        producer.ack_timeout = self.ack_timeout;
+        Ok(producer)
     }
-}
\ No newline at end of file
+}
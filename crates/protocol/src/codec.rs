@@ -0,0 +1,225 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Every frame on the wire is a 4 byte big-endian length followed by that many bytes of body
+pub const HEADER_LEN: usize = 4;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024; // 8MB
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    // The length header announced a body bigger than we are willing to buffer
+    FrameTooLarge { size: usize, max: usize },
+    // The peer closed the connection in the middle of a frame
+    Truncated { expected: usize, received: usize },
+    // The frame was complete but its body could not be decoded
+    Malformed(serde_json::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "I/O error: {}", e),
+            FrameError::FrameTooLarge { size, max } => {
+                write!(f, "Frame of {} bytes exceeds the maximum of {} bytes", size, max)
+            }
+            FrameError::Truncated { expected, received } => write!(
+                f,
+                "Connection closed mid-frame after {} of {} bytes",
+                received, expected
+            ),
+            FrameError::Malformed(e) => write!(f, "Malformed frame body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Io(e) => Some(e),
+            FrameError::Malformed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FrameError {
+    fn from(e: serde_json::Error) -> Self {
+        FrameError::Malformed(e)
+    }
+}
+
+impl FrameError {
+    // After a malformed body the stream is still aligned on a frame boundary,
+    // every other error leaves it in an unknown state
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, FrameError::Malformed(_))
+    }
+}
+
+// Prefix a body with its length header
+pub fn encode_frame(body: &[u8], max_frame_size: usize) -> Result<Bytes, FrameError> {
+    if body.len() > max_frame_size {
+        return Err(FrameError::FrameTooLarge {
+            size: body.len(),
+            max: max_frame_size,
+        });
+    }
+
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + body.len());
+    frame.put_u32(body.len() as u32);
+    frame.put_slice(body);
+    Ok(frame.freeze())
+}
+
+// Split one complete frame off the front of `buffer`, if there is one
+pub fn decode_frame(buffer: &mut BytesMut, max_frame_size: usize) -> Result<Option<Bytes>, FrameError> {
+    if buffer.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if len > max_frame_size {
+        return Err(FrameError::FrameTooLarge {
+            size: len,
+            max: max_frame_size,
+        });
+    }
+
+    if buffer.len() < HEADER_LEN + len {
+        buffer.reserve(HEADER_LEN + len - buffer.len());
+        return Ok(None);
+    }
+
+    buffer.advance(HEADER_LEN);
+    Ok(Some(buffer.split_to(len).freeze()))
+}
+
+// A JSON body may be followed by raw bytes in the same frame. Record batches
+// travel this way instead of as JSON number arrays.
+pub fn split_message<T: DeserializeOwned>(frame: &Bytes) -> Result<(T, Bytes), serde_json::Error> {
+    let mut stream = serde_json::Deserializer::from_slice(frame).into_iter::<T>();
+    // Only an empty frame yields nothing, which from_slice reports properly
+    let message = stream.next().unwrap_or_else(|| serde_json::from_slice(frame))?;
+    let data = frame.slice(stream.byte_offset()..);
+    Ok((message, data))
+}
+
+pub struct FrameReader<R> {
+    inner: R,
+    buffer: BytesMut,
+    max_frame_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_max_frame_size(inner, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(inner: R, max_frame_size: usize) -> Self {
+        Self {
+            inner,
+            buffer: BytesMut::with_capacity(8 * 1024),
+            max_frame_size,
+        }
+    }
+
+    // Returns Ok(None) when the peer closes the connection cleanly between frames.
+    // Cancel safe, partially read frames stay buffered for the next call.
+    pub async fn read_frame(&mut self) -> Result<Option<Bytes>, FrameError> {
+        loop {
+            if let Some(frame) = decode_frame(&mut self.buffer, self.max_frame_size)? {
+                return Ok(Some(frame));
+            }
+
+            if self.inner.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(self.truncated());
+            }
+        }
+    }
+
+    pub async fn read_message<T: DeserializeOwned>(&mut self) -> Result<Option<T>, FrameError> {
+        match self.read_frame().await? {
+            Some(frame) => Ok(Some(serde_json::from_slice(&frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn read_message_with_data<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(T, Bytes)>, FrameError> {
+        match self.read_frame().await? {
+            Some(frame) => Ok(Some(split_message(&frame)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn truncated(&self) -> FrameError {
+        let received = self.buffer.len();
+        let expected = if received >= HEADER_LEN {
+            HEADER_LEN
+                + u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]])
+                    as usize
+        } else {
+            HEADER_LEN
+        };
+        FrameError::Truncated { expected, received }
+    }
+}
+
+pub struct FrameWriter<W> {
+    inner: W,
+    max_frame_size: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_max_frame_size(inner, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(inner: W, max_frame_size: usize) -> Self {
+        Self {
+            inner,
+            max_frame_size,
+        }
+    }
+
+    // Header and body go out in a single write so frames from tasks sharing
+    // a writer never interleave
+    pub async fn write_frame(&mut self, body: &[u8]) -> Result<(), FrameError> {
+        let frame = encode_frame(body, self.max_frame_size)?;
+        self.inner.write_all(&frame).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    pub async fn write_message<T: Serialize>(&mut self, message: &T) -> Result<(), FrameError> {
+        let body = serde_json::to_vec(message)?;
+        self.write_frame(&body).await
+    }
+
+    pub async fn write_message_with_data<T: Serialize>(
+        &mut self,
+        message: &T,
+        data: &[u8],
+    ) -> Result<(), FrameError> {
+        let mut body = serde_json::to_vec(message)?;
+        body.extend_from_slice(data);
+        self.write_frame(&body).await
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use rafka_core::message::{BrokerError, ErrorCode};
+
+use crate::codec::{FrameError, FrameReader, FrameWriter};
+use crate::protocol::{
+    ApiKey, BrokerMessage, BrokerResponse, NegotiatedVersions, Request, Response, UnsupportedApi,
+};
+
+// Stream replies waiting for a slow reader hold up every other reply on the connection,
+// so give them some room
+const STREAM_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    Frame(FrameError),
+    Unsupported(UnsupportedApi),
+    // The broker went away before answering
+    Closed,
+    // The broker said it is draining, requests have to go to another one
+    ShuttingDown,
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Frame(e) => write!(f, "{}", e),
+            ConnectionError::Unsupported(e) => write!(f, "{}", e),
+            ConnectionError::Closed => write!(f, "Connection closed by broker"),
+            ConnectionError::ShuttingDown => write!(f, "Broker is shutting down, reconnect to another one"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectionError::Frame(e) => Some(e),
+            ConnectionError::Unsupported(e) => Some(e),
+            ConnectionError::Closed | ConnectionError::ShuttingDown => None,
+        }
+    }
+}
+
+impl From<FrameError> for ConnectionError {
+    fn from(e: FrameError) -> Self {
+        ConnectionError::Frame(e)
+    }
+}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Frame(FrameError::Io(e))
+    }
+}
+
+impl From<UnsupportedApi> for ConnectionError {
+    fn from(e: UnsupportedApi) -> Self {
+        ConnectionError::Unsupported(e)
+    }
+}
+
+enum Pending {
+    Once(oneshot::Sender<BrokerResponse>),
+    Stream(mpsc::Sender<BrokerResponse>),
+}
+
+struct Shared {
+    writer: Mutex<FrameWriter<OwnedWriteHalf>>,
+    pending: std::sync::Mutex<HashMap<u64, Pending>>,
+    next_correlation_id: AtomicU64,
+    closed: AtomicBool,
+    // The broker's notice that it is shutting down, once it has sent one
+    shutting_down: std::sync::Mutex<Option<BrokerError>>,
+    versions: NegotiatedVersions,
+}
+
+// A broker connection that many tasks can use at once. Requests are written in
+// the order send is called and the broker answers them in that order, replies
+// are routed back to the caller by correlation id.
+#[derive(Clone)]
+pub struct Connection {
+    shared: Arc<Shared>,
+}
+
+// A request that has been written to the broker and is waiting for its reply
+pub struct PendingResponse {
+    rx: oneshot::Receiver<BrokerResponse>,
+}
+
+impl PendingResponse {
+    pub async fn response(self) -> Result<BrokerResponse, ConnectionError> {
+        self.rx.await.map_err(|_| ConnectionError::Closed)
+    }
+}
+
+impl Connection {
+    pub async fn connect(addr: &str) -> Result<Self, ConnectionError> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut reader = FrameReader::new(reader);
+        let mut writer = FrameWriter::new(writer);
+
+        let versions = Self::negotiate_versions(&mut reader, &mut writer).await?;
+
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(writer),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            // 0 is what the broker answers with when it could not read the id
+            next_correlation_id: AtomicU64::new(1),
+            closed: AtomicBool::new(false),
+            shutting_down: std::sync::Mutex::new(None),
+            versions,
+        });
+
+        tokio::spawn(Self::dispatch(shared.clone(), reader));
+
+        Ok(Self { shared })
+    }
+
+    // Done before the dispatcher starts, an old broker's reply can't be routed by id
+    async fn negotiate_versions(
+        reader: &mut FrameReader<OwnedReadHalf>,
+        writer: &mut FrameWriter<OwnedWriteHalf>,
+    ) -> Result<NegotiatedVersions, ConnectionError> {
+        let request = Request {
+            correlation_id: 0,
+            message: BrokerMessage::ApiVersions,
+        };
+        writer.write_message(&request).await?;
+        let frame = reader.read_frame().await?.ok_or(ConnectionError::Closed)?;
+
+        // Anything other than a version list means the broker predates ApiVersions,
+        // apis it lists that we don't know are already dropped while decoding
+        Ok(match serde_json::from_slice::<Response>(&frame) {
+            Ok(Response {
+                response: BrokerResponse::ApiVersions(response),
+                ..
+            }) => NegotiatedVersions::negotiate(&response.apis),
+            _ => NegotiatedVersions::legacy(),
+        })
+    }
+
+    async fn dispatch(shared: Arc<Shared>, mut reader: FrameReader<OwnedReadHalf>) {
+        loop {
+            let (
+                Response {
+                    correlation_id,
+                    mut response,
+                },
+                data,
+            ) = match reader.read_message_with_data().await {
+                Ok(Some(response)) => response,
+                Err(e) if e.is_recoverable() => continue,
+                Ok(None) | Err(_) => break,
+            };
+            response.attach(data);
+
+            // A draining broker sends this on no request's id. Replies to what
+            // it already read still follow, then it closes the connection.
+            if correlation_id == 0 {
+                if let BrokerResponse::Error(error) = &response {
+                    if error.code == ErrorCode::ShuttingDown {
+                        *shared.shutting_down.lock().unwrap() = Some(error.clone());
+                        continue;
+                    }
+                }
+            }
+
+            let stream = {
+                let mut pending = shared.pending.lock().unwrap();
+                match pending.remove(&correlation_id) {
+                    Some(Pending::Once(tx)) => {
+                        // The caller may have stopped waiting, that's fine
+                        let _ = tx.send(response);
+                        continue;
+                    }
+                    Some(Pending::Stream(tx)) => {
+                        pending.insert(correlation_id, Pending::Stream(tx.clone()));
+                        tx
+                    }
+                    // Nobody is waiting, e.g. a fire-and-forget request
+                    None => continue,
+                }
+            };
+
+            if stream.send(response).await.is_err() {
+                shared.pending.lock().unwrap().remove(&correlation_id);
+            }
+        }
+
+        // Dropping the senders wakes every waiter with ConnectionError::Closed,
+        // unless the broker said it was shutting down, then they are told so
+        shared.closed.store(true, Ordering::SeqCst);
+        let pending: Vec<_> = shared.pending.lock().unwrap().drain().map(|(_, pending)| pending).collect();
+        let notice = shared.shutting_down.lock().unwrap().clone();
+        if let Some(error) = notice {
+            for pending in pending {
+                let response = BrokerResponse::Error(error.clone());
+                match pending {
+                    Pending::Once(tx) => {
+                        let _ = tx.send(response);
+                    }
+                    Pending::Stream(tx) => {
+                        let _ = tx.try_send(response);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn versions(&self) -> &NegotiatedVersions {
+        &self.shared.versions
+    }
+
+    // Fails locally when the broker did not agree to any version of the request
+    pub fn require(&self, api: ApiKey) -> Result<u16, ConnectionError> {
+        Ok(self.shared.versions.require(api)?)
+    }
+
+    // Write a request without waiting for its reply. Await the returned
+    // PendingResponse to get it, or drop it to ignore the reply.
+    pub async fn send(&self, message: BrokerMessage) -> Result<PendingResponse, ConnectionError> {
+        let (tx, rx) = oneshot::channel();
+        self.write(message, Some(Pending::Once(tx))).await?;
+        Ok(PendingResponse { rx })
+    }
+
+    // For requests the broker doesn't answer, like an acks=None produce
+    pub async fn send_only(&self, message: BrokerMessage) -> Result<(), ConnectionError> {
+        self.write(message, None).await
+    }
+
+    pub async fn request(&self, message: BrokerMessage) -> Result<BrokerResponse, ConnectionError> {
+        self.send(message).await?.response().await
+    }
+
+    // For requests answered with any number of replies, like Consume
+    pub async fn stream(
+        &self,
+        message: BrokerMessage,
+    ) -> Result<mpsc::Receiver<BrokerResponse>, ConnectionError> {
+        let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+        self.write(message, Some(Pending::Stream(tx))).await?;
+        Ok(rx)
+    }
+
+    async fn write(&self, message: BrokerMessage, pending: Option<Pending>) -> Result<(), ConnectionError> {
+        let correlation_id = self.shared.next_correlation_id.fetch_add(1, Ordering::SeqCst);
+
+        // Registered before writing so the reply can't beat us to the map
+        if let Some(pending) = pending {
+            self.shared.pending.lock().unwrap().insert(correlation_id, pending);
+        }
+        let refused = if self.shared.shutting_down.lock().unwrap().is_some() {
+            Some(ConnectionError::ShuttingDown)
+        } else if self.shared.closed.load(Ordering::SeqCst) {
+            Some(ConnectionError::Closed)
+        } else {
+            None
+        };
+        if let Some(error) = refused {
+            self.shared.pending.lock().unwrap().remove(&correlation_id);
+            return Err(error);
+        }
+
+        let request = Request {
+            correlation_id,
+            message,
+        };
+        let mut writer = self.shared.writer.lock().await;
+        if let Err(e) = writer.write_message_with_data(&request, request.message.data()).await {
+            self.shared.pending.lock().unwrap().remove(&correlation_id);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+// Just enough of the Kafka binary protocol for existing Kafka clients to
+// produce to and consume from a Rafka broker. Frames use the same 4 byte
+// length prefix as our own protocol, so codec::FrameReader/FrameWriter apply.
+
+pub mod wire;
+
+use bytes::Bytes;
+
+pub use crate::records::{Compression, EncodedBatch, Record, RecordBatch};
+pub use wire::{DecodeError, DecodeResult, Decoder, Encoder};
+
+pub mod api_key {
+    pub const PRODUCE: i16 = 0;
+    pub const FETCH: i16 = 1;
+    pub const LIST_OFFSETS: i16 = 2;
+    pub const METADATA: i16 = 3;
+    pub const OFFSET_COMMIT: i16 = 8;
+    pub const OFFSET_FETCH: i16 = 9;
+    pub const FIND_COORDINATOR: i16 = 10;
+    pub const API_VERSIONS: i16 = 18;
+}
+
+pub mod error_code {
+    pub const UNKNOWN_SERVER_ERROR: i16 = -1;
+    pub const NONE: i16 = 0;
+    pub const OFFSET_OUT_OF_RANGE: i16 = 1;
+    pub const CORRUPT_MESSAGE: i16 = 2;
+    pub const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+    pub const LEADER_NOT_AVAILABLE: i16 = 5;
+    pub const NOT_LEADER_OR_FOLLOWER: i16 = 6;
+    pub const REQUEST_TIMED_OUT: i16 = 7;
+    pub const UNSUPPORTED_VERSION: i16 = 35;
+    pub const INVALID_REQUEST: i16 = 42;
+    pub const OUT_OF_ORDER_SEQUENCE_NUMBER: i16 = 45;
+    pub const INVALID_PRODUCER_EPOCH: i16 = 47;
+    pub const INVALID_TXN_STATE: i16 = 48;
+    pub const UNSUPPORTED_COMPRESSION_TYPE: i16 = 76;
+}
+
+// (api key, min version, max version) for everything the listener answers.
+// Versions stop before each API's first flexible version, whose tagged fields
+// we don't encode. FindCoordinator is outside the requested set but Kafka
+// consumers look up their group coordinator before committing offsets.
+pub const SUPPORTED_APIS: [(i16, i16, i16); 8] = [
+    (api_key::PRODUCE, 3, 7),
+    (api_key::FETCH, 4, 11),
+    (api_key::LIST_OFFSETS, 1, 5),
+    (api_key::METADATA, 0, 4),
+    (api_key::OFFSET_COMMIT, 2, 7),
+    (api_key::OFFSET_FETCH, 1, 5),
+    (api_key::FIND_COORDINATOR, 0, 2),
+    (api_key::API_VERSIONS, 0, 2),
+];
+
+pub fn supports(api_key: i16, api_version: i16) -> bool {
+    SUPPORTED_APIS
+        .iter()
+        .any(|(key, min, max)| *key == api_key && (*min..=*max).contains(&api_version))
+}
+
+// Request header v1, as sent with every non-flexible request version
+#[derive(Debug, Clone)]
+pub struct RequestHeader {
+    pub api_key: i16,
+    pub api_version: i16,
+    pub correlation_id: i32,
+    pub client_id: Option<String>,
+}
+
+impl RequestHeader {
+    // Split a request frame into its header and a decoder positioned at the body
+    pub fn decode(frame: Bytes) -> DecodeResult<(RequestHeader, Decoder)> {
+        let mut decoder = Decoder::new(frame);
+        let api_key = decoder.i16()?;
+        let api_version = decoder.i16()?;
+        let correlation_id = decoder.i32()?;
+        // Header v2 only adds tagged fields after this, which we never read
+        let client_id = decoder.nullable_string()?;
+
+        Ok((
+            RequestHeader {
+                api_key,
+                api_version,
+                correlation_id,
+                client_id,
+            },
+            decoder,
+        ))
+    }
+}
@@ -0,0 +1,264 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
+
+// Kafka's primitive types: big-endian integers, i16 length-prefixed strings,
+// i32 length-prefixed bytes and arrays, and zigzag varints inside records.
+// Only the non-flexible (pre tagged field) encodings are supported.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError(pub String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid Kafka message: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+pub struct Decoder {
+    buf: Bytes,
+}
+
+impl Decoder {
+    pub fn new(buf: Bytes) -> Self {
+        Self { buf }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.remaining()
+    }
+
+    fn ensure(&self, len: usize) -> DecodeResult<()> {
+        if self.buf.remaining() < len {
+            return Err(DecodeError(format!(
+                "needed {} more bytes but only {} are left",
+                len,
+                self.buf.remaining()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn i8(&mut self) -> DecodeResult<i8> {
+        self.ensure(1)?;
+        Ok(self.buf.get_i8())
+    }
+
+    pub fn bool(&mut self) -> DecodeResult<bool> {
+        Ok(self.i8()? != 0)
+    }
+
+    pub fn i16(&mut self) -> DecodeResult<i16> {
+        self.ensure(2)?;
+        Ok(self.buf.get_i16())
+    }
+
+    pub fn i32(&mut self) -> DecodeResult<i32> {
+        self.ensure(4)?;
+        Ok(self.buf.get_i32())
+    }
+
+    pub fn u32(&mut self) -> DecodeResult<u32> {
+        self.ensure(4)?;
+        Ok(self.buf.get_u32())
+    }
+
+    pub fn i64(&mut self) -> DecodeResult<i64> {
+        self.ensure(8)?;
+        Ok(self.buf.get_i64())
+    }
+
+    pub fn raw(&mut self, len: usize) -> DecodeResult<Bytes> {
+        self.ensure(len)?;
+        Ok(self.buf.split_to(len))
+    }
+
+    fn length(&mut self, len: i64) -> DecodeResult<Option<usize>> {
+        match len {
+            -1 => Ok(None),
+            len if len < -1 => Err(DecodeError(format!("negative length {}", len))),
+            len => Ok(Some(len as usize)),
+        }
+    }
+
+    pub fn nullable_string(&mut self) -> DecodeResult<Option<String>> {
+        let len = self.i16()?;
+        match self.length(len as i64)? {
+            Some(len) => {
+                let raw = self.raw(len)?;
+                String::from_utf8(raw.to_vec())
+                    .map(Some)
+                    .map_err(|e| DecodeError(e.to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn string(&mut self) -> DecodeResult<String> {
+        self.nullable_string()?
+            .ok_or_else(|| DecodeError("unexpected null string".to_string()))
+    }
+
+    pub fn nullable_bytes(&mut self) -> DecodeResult<Option<Bytes>> {
+        let len = self.i32()?;
+        match self.length(len as i64)? {
+            Some(len) => Ok(Some(self.raw(len)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn nullable_array<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> DecodeResult<T>,
+    ) -> DecodeResult<Option<Vec<T>>> {
+        let len = self.i32()?;
+        let Some(len) = self.length(len as i64)? else {
+            return Ok(None);
+        };
+
+        // Don't trust the count for the allocation, every item takes at least a byte
+        let mut items = Vec::with_capacity(len.min(self.remaining()));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(Some(items))
+    }
+
+    pub fn array<T>(&mut self, item: impl FnMut(&mut Self) -> DecodeResult<T>) -> DecodeResult<Vec<T>> {
+        Ok(self.nullable_array(item)?.unwrap_or_default())
+    }
+
+    pub fn varlong(&mut self) -> DecodeResult<i64> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.i8()? as u8;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                // Undo the zigzag encoding
+                return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+        Err(DecodeError("varint is too long".to_string()))
+    }
+
+    pub fn varint(&mut self) -> DecodeResult<i32> {
+        let value = self.varlong()?;
+        i32::try_from(value).map_err(|_| DecodeError(format!("varint {} overflows i32", value)))
+    }
+
+    // Varint length followed by that many bytes, -1 meaning null
+    pub fn varint_bytes(&mut self) -> DecodeResult<Option<Bytes>> {
+        let len = self.varint()?;
+        match self.length(len as i64)? {
+            Some(len) => Ok(Some(self.raw(len)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Encoder {
+    buf: BytesMut,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn finish(self) -> Bytes {
+        self.buf.freeze()
+    }
+
+    pub fn i8(&mut self, value: i8) -> &mut Self {
+        self.buf.put_i8(value);
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.i8(value as i8)
+    }
+
+    pub fn i16(&mut self, value: i16) -> &mut Self {
+        self.buf.put_i16(value);
+        self
+    }
+
+    pub fn i32(&mut self, value: i32) -> &mut Self {
+        self.buf.put_i32(value);
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.put_u32(value);
+        self
+    }
+
+    pub fn i64(&mut self, value: i64) -> &mut Self {
+        self.buf.put_i64(value);
+        self
+    }
+
+    pub fn raw(&mut self, value: &[u8]) -> &mut Self {
+        self.buf.put_slice(value);
+        self
+    }
+
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.i16(value.len() as i16).raw(value.as_bytes())
+    }
+
+    pub fn nullable_string(&mut self, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(value) => self.string(value),
+            None => self.i16(-1),
+        }
+    }
+
+    pub fn nullable_bytes(&mut self, value: Option<&[u8]>) -> &mut Self {
+        match value {
+            Some(value) => self.i32(value.len() as i32).raw(value),
+            None => self.i32(-1),
+        }
+    }
+
+    pub fn array<T>(&mut self, items: &[T], mut item: impl FnMut(&mut Self, &T)) -> &mut Self {
+        self.i32(items.len() as i32);
+        for value in items {
+            item(self, value);
+        }
+        self
+    }
+
+    pub fn varlong(&mut self, value: i64) -> &mut Self {
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        while value >= 0x80 {
+            self.buf.put_u8((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.buf.put_u8(value as u8);
+        self
+    }
+
+    pub fn varint(&mut self, value: i32) -> &mut Self {
+        self.varlong(value as i64)
+    }
+
+    pub fn varint_bytes(&mut self, value: Option<&[u8]>) -> &mut Self {
+        match value {
+            Some(value) => self.varint(value.len() as i32).raw(value),
+            None => self.varint(-1),
+        }
+    }
+}
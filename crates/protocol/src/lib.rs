@@ -1 +1,16 @@
-pub mod protocol;
\ No newline at end of file
+pub mod codec;
+pub mod kafka;
+pub mod connection;
+pub mod protocol;
+pub mod records;
+
+pub use codec::{FrameError, FrameReader, FrameWriter};
+pub use connection::{Connection, ConnectionError, PendingResponse};
+pub use records::{Compression, EncodedBatch, Record, RecordBatch};
+pub use protocol::{
+    AclBinding, AclOperation, Acks, ApiKey, ApiVersionRange, ApiVersionsResponse, AssignmentStrategy, BrokerMessage, BrokerMetadata,
+    BrokerResponse, ClientSession, ConsumerLag, Credit, FetchResponse, GroupAssignmentResponse, GroupJoinedResponse,
+    IsolationLevel, LatencyBucket, LatencyHistogram, MetadataEntry, MetadataRecord, MetadataResponse, MetadataSnapshot, MetricsResponse, NegotiatedVersions,
+    PartitionDescription, PartitionMetadata, PartitionStats, ProducerIdentity, QuorumDescription, RecordsResponse, Request, RequestHeader, Response, TopicConfig,
+    TopicDescription, TopicMetadata, TopicPartitions, TxnOffset, UnsupportedApi, IDEMPOTENCE,
+};
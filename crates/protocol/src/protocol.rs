@@ -1,3 +1,1003 @@
-fn Hi(){
-    print!("Hello, World!");
-}
\ No newline at end of file
+use bytes::Bytes;
+use rafka_core::message::{BrokerError, Header, MessageAck};
+use rafka_core::partitioner::PartitionStrategy;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+// Requests a client can send to a broker
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BrokerMessage {
+    // Sent first on every connection so both sides can agree on what they support
+    ApiVersions,
+    Publish {
+        key: String,
+        topic: String,
+        payload: Vec<u8>,
+        #[serde(default)]
+        headers: Vec<Header>,
+        #[serde(default)]
+        acks: Acks,
+    },
+    // Publish a whole record batch to one partition. Without a partition the
+    // broker picks it from the record keys, which must all map to the same one.
+    Produce {
+        topic: String,
+        #[serde(default)]
+        partition: Option<u32>,
+        // An encoded RecordBatch, carried after the JSON
+        #[serde(skip)]
+        records: Bytes,
+        #[serde(default)]
+        acks: Acks,
+    },
+    Subscribe {
+        consumer_id: String,
+        topic: String,
+    },
+    // Stream the listed topics, or every topic the consumer subscribed to
+    // (including ones it subscribes to later) when the list is empty. With a
+    // group, consumer_id is the member id and the member's assigned partitions
+    // are streamed instead, following every rebalance.
+    Consume {
+        consumer_id: String,
+        #[serde(default)]
+        topics: Vec<String>,
+        #[serde(default)]
+        group_id: Option<String>,
+        // How much the broker may send before waiting for GrantCredit. Without
+        // it deliveries are not paced at all.
+        #[serde(default)]
+        credit: Option<Credit>,
+        #[serde(default)]
+        isolation: IsolationLevel,
+    },
+    // Ids must be unique among connected clients, types are "producer",
+    // "consumer" or "admin". Producers asking for IDEMPOTENCE get a producer
+    // id, registering again bumps its epoch.
+    Register {
+        client_id: String,
+        client_type: String,
+        #[serde(default)]
+        client_version: Option<String>,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    // Every client registered with this broker
+    ListClients,
+    UpdateOffset {
+        consumer_id: String,
+        topic: String,
+        #[serde(default)]
+        partition: u32,
+        offset: i64,
+    },
+    GetMetrics,
+    CreateTopic {
+        topic: String,
+        partitions: u32,
+        // Used for messages published to the topic without a partition
+        #[serde(default)]
+        partitioner: PartitionStrategy,
+        #[serde(default)]
+        config: TopicConfig,
+    },
+    // Removes the topic with everything stored for it, committed offsets included
+    DeleteTopic {
+        topic: String,
+    },
+    ListTopics,
+    DescribeTopic {
+        topic: String,
+    },
+    // Brokers of the cluster and who hosts which partition. None asks for
+    // every topic, named topics are created if the broker auto-creates them.
+    Metadata {
+        #[serde(default)]
+        topics: Option<Vec<String>>,
+    },
+    // Join without a member id the first time, the broker hands one out.
+    // Strategies are in order of preference.
+    JoinGroup {
+        group_id: String,
+        member_id: Option<String>,
+        topics: Vec<String>,
+        strategies: Vec<AssignmentStrategy>,
+        session_timeout_ms: u64,
+    },
+    // The member's partitions in the current generation
+    SyncGroup {
+        group_id: String,
+        member_id: String,
+    },
+    // Keeps the member's session alive. Answered with RebalanceInProgress once
+    // `generation` is out of date, the member should SyncGroup again.
+    Heartbeat {
+        group_id: String,
+        member_id: String,
+        generation: u32,
+    },
+    LeaveGroup {
+        group_id: String,
+        member_id: String,
+    },
+    // Records of one partition from `offset` on, answered as soon as there are
+    // any or once `max_wait_ms` is up. Whole batches come back, so the first
+    // can start before `offset`. At least one batch is returned even if it is
+    // bigger than `max_bytes`.
+    Fetch {
+        topic: String,
+        partition: u32,
+        offset: i64,
+        max_bytes: u32,
+        max_wait_ms: u64,
+        #[serde(default)]
+        isolation: IsolationLevel,
+        // Set by a broker copying the partition as one of its followers. It
+        // gets records the leader has not committed yet, and the offset it
+        // asks for tells the leader how far its copy goes.
+        #[serde(default)]
+        replica_id: Option<u32>,
+    },
+    // Lets the broker send a flow-controlled consumer this much more
+    GrantCredit {
+        consumer_id: String,
+        credit: Credit,
+    },
+    // Transactions are run by the broker that handed out the producer id. One
+    // that isn't ended within `timeout_ms` is aborted and the producer fenced.
+    BeginTransaction {
+        producer: ProducerIdentity,
+        timeout_ms: u64,
+    },
+    // Every partition the transaction writes to has to be added before the
+    // first write, so ending the transaction reaches all of them
+    AddPartitionsToTxn {
+        producer: ProducerIdentity,
+        partitions: Vec<TopicPartitions>,
+    },
+    // Consumer offsets that are only committed if the transaction is
+    TxnCommitOffsets {
+        producer: ProducerIdentity,
+        offsets: Vec<TxnOffset>,
+    },
+    EndTransaction {
+        producer: ProducerIdentity,
+        commit: bool,
+    },
+    // From a transaction's coordinator to the brokers hosting its partitions:
+    // close the transaction on them, and store its offsets if it committed
+    WriteTxnMarkers {
+        producer: ProducerIdentity,
+        commit: bool,
+        partitions: Vec<TopicPartitions>,
+        offsets: Vec<TxnOffset>,
+    },
+    // From a controller standing for election to the other controllers
+    RequestVote {
+        term: u64,
+        candidate_id: u32,
+        // Where the candidate's metadata log ends, voters only pick one whose
+        // log is at least as up to date as theirs
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    // From the quorum leader to every other broker: the entries that follow
+    // `prev_log_index`, or none just to hold on to its leadership
+    AppendEntries {
+        term: u64,
+        leader_id: u32,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<MetadataEntry>,
+        leader_commit: u64,
+    },
+    // Sent instead of entries the leader no longer has in its log
+    InstallSnapshot {
+        term: u64,
+        leader_id: u32,
+        snapshot: MetadataSnapshot,
+    },
+    // Brokers hand metadata changes to the quorum leader with this. Answered
+    // once the change is committed and applied on the leader.
+    ProposeMetadata {
+        record: MetadataRecord,
+    },
+    // Controllers are added and removed one at a time, and only once the
+    // previous change is committed
+    AddController {
+        broker_id: u32,
+    },
+    RemoveController {
+        broker_id: u32,
+    },
+    CreateAcl {
+        acl: AclBinding,
+    },
+    DeleteAcl {
+        acl: AclBinding,
+    },
+    ListAcls,
+    // Who leads the metadata quorum and how far this broker has got with its log
+    DescribeQuorum,
+}
+
+impl BrokerMessage {
+    pub fn api_key(&self) -> ApiKey {
+        match self {
+            BrokerMessage::ApiVersions => ApiKey::ApiVersions,
+            BrokerMessage::Publish { .. } => ApiKey::Publish,
+            BrokerMessage::Produce { .. } => ApiKey::Produce,
+            BrokerMessage::Subscribe { .. } => ApiKey::Subscribe,
+            BrokerMessage::Consume { .. } => ApiKey::Consume,
+            BrokerMessage::Register { .. } => ApiKey::Register,
+            BrokerMessage::UpdateOffset { .. } => ApiKey::UpdateOffset,
+            BrokerMessage::GetMetrics => ApiKey::GetMetrics,
+            BrokerMessage::CreateTopic { .. } => ApiKey::CreateTopic,
+            BrokerMessage::DeleteTopic { .. } => ApiKey::DeleteTopic,
+            BrokerMessage::ListTopics => ApiKey::ListTopics,
+            BrokerMessage::DescribeTopic { .. } => ApiKey::DescribeTopic,
+            BrokerMessage::Metadata { .. } => ApiKey::Metadata,
+            BrokerMessage::ListClients => ApiKey::ListClients,
+            BrokerMessage::JoinGroup { .. } => ApiKey::JoinGroup,
+            BrokerMessage::SyncGroup { .. } => ApiKey::SyncGroup,
+            BrokerMessage::Heartbeat { .. } => ApiKey::Heartbeat,
+            BrokerMessage::LeaveGroup { .. } => ApiKey::LeaveGroup,
+            BrokerMessage::Fetch { .. } => ApiKey::Fetch,
+            BrokerMessage::GrantCredit { .. } => ApiKey::GrantCredit,
+            BrokerMessage::BeginTransaction { .. } => ApiKey::BeginTransaction,
+            BrokerMessage::AddPartitionsToTxn { .. } => ApiKey::AddPartitionsToTxn,
+            BrokerMessage::TxnCommitOffsets { .. } => ApiKey::TxnCommitOffsets,
+            BrokerMessage::EndTransaction { .. } => ApiKey::EndTransaction,
+            BrokerMessage::WriteTxnMarkers { .. } => ApiKey::WriteTxnMarkers,
+            BrokerMessage::RequestVote { .. } => ApiKey::RequestVote,
+            BrokerMessage::AppendEntries { .. } => ApiKey::AppendEntries,
+            BrokerMessage::InstallSnapshot { .. } => ApiKey::InstallSnapshot,
+            BrokerMessage::ProposeMetadata { .. } => ApiKey::ProposeMetadata,
+            BrokerMessage::AddController { .. } => ApiKey::AddController,
+            BrokerMessage::RemoveController { .. } => ApiKey::RemoveController,
+            BrokerMessage::CreateAcl { .. } => ApiKey::CreateAcl,
+            BrokerMessage::DeleteAcl { .. } => ApiKey::DeleteAcl,
+            BrokerMessage::ListAcls => ApiKey::ListAcls,
+            BrokerMessage::DescribeQuorum => ApiKey::DescribeQuorum,
+        }
+    }
+
+    // Raw bytes that go in the frame after the JSON
+    pub fn data(&self) -> &[u8] {
+        match self {
+            BrokerMessage::Produce { records, .. } => records,
+            _ => &[],
+        }
+    }
+
+    pub fn attach(&mut self, data: Bytes) {
+        if let BrokerMessage::Produce { records, .. } = self {
+            *records = data;
+        }
+    }
+}
+
+// Every request travels inside one of these, the broker echoes the id back
+// on the replies so a connection can have many requests in flight
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+    pub correlation_id: u64,
+    pub message: BrokerMessage,
+}
+
+// Just enough of a Request to answer it when the message itself can't be decoded
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct RequestHeader {
+    pub correlation_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response {
+    pub correlation_id: u64,
+    pub response: BrokerResponse,
+}
+
+// Replies are matched to requests by correlation id. Consume is answered with
+// a stream of Records replies that all carry the id of the Consume request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BrokerResponse {
+    // The shape of this reply must never change, it is how versions get agreed on
+    ApiVersions(ApiVersionsResponse),
+    Ack(MessageAck),
+    Subscribed { topic: String },
+    Registered {
+        client_id: String,
+        #[serde(default)]
+        producer: Option<ProducerIdentity>,
+    },
+    // Sorted by client id
+    ClientList { clients: Vec<ClientSession> },
+    OffsetUpdated {
+        topic: String,
+        #[serde(default)]
+        partition: u32,
+        offset: i64,
+    },
+    TopicCreated { topic: String, partitions: u32 },
+    TopicDeleted { topic: String },
+    // Sorted by name
+    TopicList { topics: Vec<String> },
+    TopicDescribed(TopicDescription),
+    Metadata(MetadataResponse),
+    Metrics(MetricsResponse),
+    GroupJoined(GroupJoinedResponse),
+    GroupAssignment(GroupAssignmentResponse),
+    HeartbeatAck,
+    GroupLeft,
+    Fetched(FetchResponse),
+    CreditGranted,
+    TransactionBegun,
+    PartitionsAddedToTxn,
+    TxnOffsetsAdded,
+    TransactionEnded { committed: bool },
+    TxnMarkersWritten,
+    VoteResult { term: u64, granted: bool },
+    // Answers AppendEntries and InstallSnapshot. On success the broker's log
+    // matches the leader's up to `match_index`, otherwise the leader should
+    // go back to the entry after it.
+    EntriesAppended { term: u64, success: bool, match_index: u64 },
+    // Where the change ended up in the metadata log
+    MetadataCommitted { index: u64 },
+    ControllersChanged { controllers: Vec<u32> },
+    AclCreated,
+    AclDeleted,
+    // In the order they were created
+    AclList { acls: Vec<AclBinding> },
+    QuorumDescribed(QuorumDescription),
+    Records(RecordsResponse),
+    Error(BrokerError),
+}
+
+impl BrokerResponse {
+    pub fn data(&self) -> &[u8] {
+        match self {
+            BrokerResponse::Records(response) => &response.records,
+            BrokerResponse::Fetched(response) => &response.records,
+            _ => &[],
+        }
+    }
+
+    pub fn attach(&mut self, data: Bytes) {
+        match self {
+            BrokerResponse::Records(response) => response.records = data,
+            BrokerResponse::Fetched(response) => response.records = data,
+            _ => {}
+        }
+    }
+}
+
+// Pushed to consumers for every batch published, exactly as the producer sent
+// it apart from the base offset
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordsResponse {
+    pub topic: String,
+    pub partition: u32,
+    // An encoded RecordBatch, carried after the JSON
+    #[serde(skip)]
+    pub records: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchResponse {
+    pub topic: String,
+    pub partition: u32,
+    // Records before it are on every in-sync replica, the ones after it
+    // aren't handed to consumers yet
+    pub high_watermark: i64,
+    // Oldest offset still retained
+    pub log_start_offset: i64,
+    // Offset of the first record whose transaction is still open, or the
+    // high watermark when none is
+    #[serde(default)]
+    pub last_stable_offset: i64,
+    // Where the next fetch should start. Can be past the records returned
+    // when what follows them is hidden by the isolation level.
+    #[serde(default)]
+    pub next_offset: i64,
+    // Encoded RecordBatches back to back, empty if nothing arrived in time
+    #[serde(skip)]
+    pub records: Bytes,
+}
+
+// Settings of a single topic, anything left out falls back to the broker's own
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TopicConfig {
+    // How long batches are kept
+    #[serde(default)]
+    pub retention_ms: Option<u64>,
+    // How big each partition may grow before its oldest batches go
+    #[serde(default)]
+    pub retention_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicDescription {
+    pub topic: String,
+    pub partitioner: PartitionStrategy,
+    pub config: TopicConfig,
+    pub partitions: Vec<PartitionDescription>,
+}
+
+// Feature a producer registers with to have its batches deduplicated
+pub const IDEMPOTENCE: &str = "idempotence";
+
+// Stamped on an idempotent producer's batches along with a sequence number per
+// partition. Producer ids are unique across the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProducerIdentity {
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+}
+
+// When the broker acknowledges a Publish or Produce
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Acks {
+    // Not at all, the producer never hears whether it was stored
+    None,
+    // Once the batch is appended to the partition leader's log
+    #[default]
+    Leader,
+    // Once every in-sync replica of the partition has the batch. If they don't
+    // within `timeout_ms` the producer gets NotEnoughReplicas, though the
+    // batch stays on the leader.
+    All { timeout_ms: u64 },
+}
+
+// What consumers see of records written in transactions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    // Everything, including records of transactions that are later aborted
+    #[default]
+    ReadUncommitted,
+    // Only records of committed transactions, and nothing past a transaction
+    // that is still open
+    ReadCommitted,
+}
+
+// An offset committed as part of a transaction, like UpdateOffset would
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxnOffset {
+    // The group id for group members
+    pub consumer_id: String,
+    pub topic: String,
+    pub partition: u32,
+    pub offset: i64,
+}
+
+// A change to the cluster's metadata, as kept in the controllers' log and
+// applied by every broker in log order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum MetadataRecord {
+    // Written by a newly elected quorum leader, committing it commits every
+    // entry of earlier terms before it
+    LeaderChange { leader_id: u32 },
+    CreateTopic {
+        topic: String,
+        partitioner: PartitionStrategy,
+        config: TopicConfig,
+        // The brokers keeping each partition, its leader first
+        replicas: Vec<Vec<u32>>,
+    },
+    DeleteTopic { topic: String },
+    CreateAcl(AclBinding),
+    DeleteAcl(AclBinding),
+    // The controllers voting from this entry on, whether it is committed yet or not
+    SetControllers { controllers: Vec<u32> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MetadataEntry {
+    // The leader's term when it was appended
+    pub term: u64,
+    // Position in the log, the first entry is 1
+    pub index: u64,
+    pub record: MetadataRecord,
+}
+
+// Everything committed up to `last_index`, as the records that rebuild it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MetadataSnapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub controllers: Vec<u32>,
+    pub records: Vec<MetadataRecord>,
+}
+
+// Lets `principal` do `operation` on a topic, or keeps it from doing so.
+// Brokers keep ACLs in their metadata but don't check them yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AclBinding {
+    pub principal: String,
+    // "*" for every topic
+    pub topic: String,
+    pub operation: AclOperation,
+    pub allow: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclOperation {
+    All,
+    Read,
+    Write,
+    Create,
+    Delete,
+    Describe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuorumDescription {
+    // The broker that answered
+    pub broker_id: u32,
+    // None while an election is going on, as far as the broker knows
+    pub leader_id: Option<u32>,
+    pub term: u64,
+    pub controllers: Vec<u32>,
+    // Entries of the metadata log up to here are committed
+    pub commit_index: u64,
+    // and the broker's metadata reflects them up to here
+    pub applied_index: u64,
+    // The broker no longer keeps entries up to here, just a snapshot of them
+    pub snapshot_index: u64,
+}
+
+// A registered client, as long as the connection it registered on is open
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientSession {
+    pub client_id: String,
+    pub client_type: String,
+    // Where the connection comes from
+    pub addr: String,
+    // When the connection was opened, in milliseconds since the epoch
+    pub connected_at_ms: i64,
+    pub client_version: Option<String>,
+    pub features: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionDescription {
+    pub partition: u32,
+    // Broker hosting the partition
+    pub leader: u32,
+    // Only known for partitions hosted by the broker that answered
+    pub log_start_offset: Option<i64>,
+    pub high_watermark: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetadataResponse {
+    // The broker that answered
+    pub broker_id: u32,
+    pub brokers: Vec<BrokerMetadata>,
+    // Unknown topics are left out
+    pub topics: Vec<TopicMetadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BrokerMetadata {
+    pub broker_id: u32,
+    // None when the answering broker wasn't told where it is
+    pub addr: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopicMetadata {
+    pub topic: String,
+    pub partitioner: PartitionStrategy,
+    pub partitions: Vec<PartitionMetadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionMetadata {
+    pub partition: u32,
+    // Broker hosting the partition
+    pub leader: u32,
+    // Brokers keeping a copy, the leader first
+    #[serde(default)]
+    pub replicas: Vec<u32>,
+    // Replicas that are caught up with the leader. Only known to the leader,
+    // other brokers list every replica.
+    #[serde(default)]
+    pub in_sync_replicas: Vec<u32>,
+}
+
+// A snapshot of one broker. Totals count from when the broker started.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetricsResponse {
+    pub broker_id: u32,
+    pub uptime_secs: u64,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    // Averaged over the last second
+    pub messages_in_per_sec: f64,
+    pub bytes_in_per_sec: f64,
+    pub messages_out_per_sec: f64,
+    pub bytes_out_per_sec: f64,
+    pub connected_clients: u64,
+    // Partitions hosted by this broker, by topic and partition
+    pub partitions: Vec<PartitionStats>,
+    // For every consumer or group with a committed offset on this broker
+    pub consumer_lag: Vec<ConsumerLag>,
+    // Only requests this broker has answered at least once
+    pub request_latency: Vec<LatencyHistogram>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionStats {
+    pub topic: String,
+    pub partition: u32,
+    pub log_start_offset: i64,
+    pub high_watermark: i64,
+    pub size_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerLag {
+    // The group id for group members
+    pub consumer_id: String,
+    pub topic: String,
+    pub partition: u32,
+    pub committed_offset: i64,
+    // Messages appended after the committed one
+    pub lag: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatencyHistogram {
+    pub api_key: ApiKey,
+    pub count: u64,
+    pub sum_secs: f64,
+    // Cumulative, like Prometheus: each counts requests that took at most `le_secs`
+    pub buckets: Vec<LatencyBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LatencyBucket {
+    pub le_secs: f64,
+    pub count: u64,
+}
+
+// Deliveries a consumer is ready for. A delivery is sent as long as there is
+// some of both left and is then charged in full, so one large batch can't
+// stall a consumer for good.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Credit {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+// How a group's partitions are shared out among its members
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssignmentStrategy {
+    // Each member gets a contiguous run of every topic's partitions
+    Range,
+    // Partitions of all topics are dealt out one at a time
+    RoundRobin,
+    // Like RoundRobin, but members keep what they had when they can
+    Sticky,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupJoinedResponse {
+    pub group_id: String,
+    pub member_id: String,
+    pub generation: u32,
+    pub strategy: AssignmentStrategy,
+    // Every member of this generation, the first to join first
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopicPartitions {
+    pub topic: String,
+    pub partitions: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GroupAssignmentResponse {
+    pub generation: u32,
+    pub assignment: Vec<TopicPartitions>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ApiKey {
+    ApiVersions,
+    Publish,
+    Produce,
+    Subscribe,
+    Consume,
+    Register,
+    UpdateOffset,
+    GetMetrics,
+    CreateTopic,
+    JoinGroup,
+    SyncGroup,
+    Heartbeat,
+    LeaveGroup,
+    Fetch,
+    GrantCredit,
+    DeleteTopic,
+    ListTopics,
+    DescribeTopic,
+    Metadata,
+    ListClients,
+    BeginTransaction,
+    AddPartitionsToTxn,
+    TxnCommitOffsets,
+    EndTransaction,
+    WriteTxnMarkers,
+    RequestVote,
+    AppendEntries,
+    InstallSnapshot,
+    ProposeMetadata,
+    AddController,
+    RemoveController,
+    CreateAcl,
+    DeleteAcl,
+    ListAcls,
+    DescribeQuorum,
+}
+
+impl ApiKey {
+    pub const ALL: [ApiKey; 35] = [
+        ApiKey::ApiVersions,
+        ApiKey::Publish,
+        ApiKey::Produce,
+        ApiKey::Subscribe,
+        ApiKey::Consume,
+        ApiKey::Register,
+        ApiKey::UpdateOffset,
+        ApiKey::GetMetrics,
+        ApiKey::CreateTopic,
+        ApiKey::JoinGroup,
+        ApiKey::SyncGroup,
+        ApiKey::Heartbeat,
+        ApiKey::LeaveGroup,
+        ApiKey::Fetch,
+        ApiKey::GrantCredit,
+        ApiKey::DeleteTopic,
+        ApiKey::ListTopics,
+        ApiKey::DescribeTopic,
+        ApiKey::Metadata,
+        ApiKey::ListClients,
+        ApiKey::BeginTransaction,
+        ApiKey::AddPartitionsToTxn,
+        ApiKey::TxnCommitOffsets,
+        ApiKey::EndTransaction,
+        ApiKey::WriteTxnMarkers,
+        ApiKey::RequestVote,
+        ApiKey::AppendEntries,
+        ApiKey::InstallSnapshot,
+        ApiKey::ProposeMetadata,
+        ApiKey::AddController,
+        ApiKey::RemoveController,
+        ApiKey::CreateAcl,
+        ApiKey::DeleteAcl,
+        ApiKey::ListAcls,
+        ApiKey::DescribeQuorum,
+    ];
+
+    // Highest version of each request this build knows how to encode and decode.
+    // Bump when a request or its reply gains fields.
+    pub const fn current_version(self) -> u16 {
+        match self {
+            ApiKey::ApiVersions
+            | ApiKey::GetMetrics
+            | ApiKey::JoinGroup
+            | ApiKey::SyncGroup
+            | ApiKey::Heartbeat
+            | ApiKey::LeaveGroup
+            | ApiKey::GrantCredit
+            | ApiKey::DeleteTopic
+            | ApiKey::ListTopics
+            | ApiKey::DescribeTopic
+            | ApiKey::Metadata
+            | ApiKey::ListClients
+            | ApiKey::BeginTransaction
+            | ApiKey::AddPartitionsToTxn
+            | ApiKey::TxnCommitOffsets
+            | ApiKey::EndTransaction
+            | ApiKey::WriteTxnMarkers
+            | ApiKey::RequestVote
+            | ApiKey::AppendEntries
+            | ApiKey::InstallSnapshot
+            | ApiKey::ProposeMetadata
+            | ApiKey::AddController
+            | ApiKey::RemoveController
+            | ApiKey::CreateAcl
+            | ApiKey::DeleteAcl
+            | ApiKey::ListAcls
+            | ApiKey::DescribeQuorum => 0,
+            // v1: replies are BrokerResponse instead of free-form text
+            ApiKey::Subscribe => 1,
+            // v1: isolation level, replies say where to fetch next
+            // v2: followers fetch as replicas
+            ApiKey::Fetch => 2,
+            // v1: replies are BrokerResponse instead of free-form text
+            // v2: client version and features, answered with Registered
+            // v3: producers can ask for a producer id
+            ApiKey::Register => 3,
+            // v2: NotLeader errors say which broker to go to
+            // v3: acks level
+            ApiKey::Publish => 3,
+            // v1: can name the partition
+            // v2: NotLeader errors say which broker to go to
+            // v3: acks level
+            ApiKey::Produce => 3,
+            // v1: picks the topic's partitioner
+            // v2: per-topic config
+            ApiKey::CreateTopic => 2,
+            // v2: offsets are per partition
+            ApiKey::UpdateOffset => 2,
+            // v2: deliveries are record batches
+            // v3: only subscribed topics are delivered, optionally just some of them
+            // v4: can consume as a group member
+            // v5: deliveries can be paced with credit
+            // v6: isolation level
+            ApiKey::Consume => 6,
+        }
+    }
+
+    // Oldest version this build can still talk, raise it when dropping support for one
+    pub const fn min_version(self) -> u16 {
+        match self {
+            ApiKey::ApiVersions
+            | ApiKey::Produce
+            | ApiKey::GetMetrics
+            | ApiKey::CreateTopic
+            | ApiKey::JoinGroup
+            | ApiKey::SyncGroup
+            | ApiKey::Heartbeat
+            | ApiKey::LeaveGroup
+            | ApiKey::Fetch
+            | ApiKey::GrantCredit
+            | ApiKey::DeleteTopic
+            | ApiKey::ListTopics
+            | ApiKey::DescribeTopic
+            | ApiKey::Metadata
+            | ApiKey::ListClients
+            | ApiKey::BeginTransaction
+            | ApiKey::AddPartitionsToTxn
+            | ApiKey::TxnCommitOffsets
+            | ApiKey::EndTransaction
+            | ApiKey::WriteTxnMarkers
+            | ApiKey::RequestVote
+            | ApiKey::AppendEntries
+            | ApiKey::InstallSnapshot
+            | ApiKey::ProposeMetadata
+            | ApiKey::AddController
+            | ApiKey::RemoveController
+            | ApiKey::CreateAcl
+            | ApiKey::DeleteAcl
+            | ApiKey::ListAcls
+            | ApiKey::DescribeQuorum => 0,
+            ApiKey::Publish | ApiKey::Subscribe | ApiKey::Register | ApiKey::UpdateOffset => 1,
+            ApiKey::Consume => 2,
+        }
+    }
+}
+
+impl fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiVersionRange {
+    pub api_key: ApiKey,
+    pub min_version: u16,
+    pub max_version: u16,
+}
+
+impl ApiVersionRange {
+    pub const fn new(api_key: ApiKey, min_version: u16, max_version: u16) -> Self {
+        Self {
+            api_key,
+            min_version,
+            max_version,
+        }
+    }
+
+    // Every version this build knows
+    pub const fn current(api_key: ApiKey) -> Self {
+        Self::new(api_key, api_key.min_version(), api_key.current_version())
+    }
+}
+
+// Broker's answer to BrokerMessage::ApiVersions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiVersionsResponse {
+    #[serde(deserialize_with = "known_apis")]
+    pub apis: Vec<ApiVersionRange>,
+}
+
+// A newer broker lists apis this build has never heard of, those are skipped
+// rather than failing the whole reply
+fn known_apis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ApiVersionRange>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Known(ApiVersionRange),
+        Unknown(IgnoredAny),
+    }
+
+    Ok(Vec::<Entry>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Entry::Known(range) => Some(range),
+            Entry::Unknown(_) => None,
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedApi(pub ApiKey);
+
+impl fmt::Display for UnsupportedApi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Broker does not support {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedApi {}
+
+// The version of each request both sides of a connection understand
+#[derive(Debug, Clone, Default)]
+pub struct NegotiatedVersions {
+    versions: HashMap<ApiKey, u16>,
+}
+
+impl NegotiatedVersions {
+    // Pick the highest version inside both our range and the broker's for every api
+    pub fn negotiate(broker_apis: &[ApiVersionRange]) -> Self {
+        let versions = broker_apis
+            .iter()
+            .filter_map(|range| {
+                let api = range.api_key;
+                let version = api.current_version().min(range.max_version);
+                let floor = api.min_version().max(range.min_version);
+                (version >= floor).then_some((api, version))
+            })
+            .collect();
+
+        Self { versions }
+    }
+
+    // Brokers that predate ApiVersions reject it as a malformed request,
+    // all they understand is the original v0 request set
+    pub fn legacy() -> Self {
+        let broker_apis: Vec<_> = [
+            ApiKey::Publish,
+            ApiKey::Subscribe,
+            ApiKey::Consume,
+            ApiKey::Register,
+            ApiKey::UpdateOffset,
+        ]
+        .into_iter()
+        .map(|api| ApiVersionRange::new(api, 0, 0))
+        .collect();
+
+        Self::negotiate(&broker_apis)
+    }
+
+    pub fn version(&self, api: ApiKey) -> Option<u16> {
+        self.versions.get(&api).copied()
+    }
+
+    pub fn supports(&self, api: ApiKey) -> bool {
+        self.versions.contains_key(&api)
+    }
+
+    pub fn require(&self, api: ApiKey) -> Result<u16, UnsupportedApi> {
+        self.version(api).ok_or(UnsupportedApi(api))
+    }
+}
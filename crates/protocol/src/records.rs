@@ -0,0 +1,508 @@
+use bytes::{Bytes, BytesMut};
+use rafka_core::message::Header;
+use std::io::Read;
+
+use crate::kafka::wire::{DecodeError, DecodeResult, Decoder, Encoder};
+
+// Messages travel and are stored as record batches: a base offset, records
+// holding offset and timestamp deltas plus optional headers, a CRC32C over the
+// batch and optional per-batch compression. The layout is Kafka's v2 ("magic 2")
+// record batch, so the Kafka listener can serve stored batches unchanged.
+// The only control batches are Kafka's transaction markers.
+
+const MAGIC: i8 = 2;
+const COMPRESSION_MASK: i16 = 0x07;
+const TRANSACTIONAL_FLAG: i16 = 0x10;
+const CONTROL_FLAG: i16 = 0x20;
+// Control record types, in the record key
+const ABORT_MARKER: i16 = 0;
+const COMMIT_MARKER: i16 = 1;
+
+// Byte positions inside an encoded batch
+const BATCH_LENGTH_END: usize = 12;
+const MAGIC_POS: usize = 16;
+const CRC_POS: usize = 17;
+// Everything from the attributes on is covered by the crc
+const CRC_START: usize = 21;
+const PRODUCER_ID_POS: usize = 43;
+const PRODUCER_EPOCH_POS: usize = 51;
+const BASE_SEQUENCE_POS: usize = 53;
+const RECORDS_POS: usize = 61;
+
+// How snappy output is framed by the Java snappy library Kafka clients use
+const XERIAL_MAGIC: [u8; 8] = [0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+const XERIAL_HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    // Kafka's codes, gzip (1) is not supported
+    fn from_attributes(attributes: i16) -> DecodeResult<Compression> {
+        match attributes & COMPRESSION_MASK {
+            0 => Ok(Compression::None),
+            2 => Ok(Compression::Snappy),
+            3 => Ok(Compression::Lz4),
+            4 => Ok(Compression::Zstd),
+            other => Err(DecodeError(format!("compression type {} is not supported", other))),
+        }
+    }
+
+    fn attribute(self) -> i16 {
+        match self {
+            Compression::None => 0,
+            Compression::Snappy => 2,
+            Compression::Lz4 => 3,
+            Compression::Zstd => 4,
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(data)
+                .expect("snappy can compress any input"),
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                std::io::Write::write_all(&mut encoder, data).expect("writing to a Vec can't fail");
+                encoder.finish().expect("writing to a Vec can't fail")
+            }
+            Compression::Zstd => zstd::bulk::compress(data, 0).expect("zstd can compress any input"),
+        }
+    }
+
+    fn decompress(self, data: &[u8]) -> DecodeResult<Vec<u8>> {
+        let invalid = |e: &dyn std::fmt::Display| DecodeError(format!("invalid {:?} data: {}", self, e));
+
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Snappy if data.starts_with(&XERIAL_MAGIC) => {
+                let mut blocks = Decoder::new(Bytes::copy_from_slice(&data[XERIAL_HEADER_LEN.min(data.len())..]));
+                let mut out = Vec::new();
+                while blocks.remaining() > 0 {
+                    let len = blocks.i32()?;
+                    let block = blocks.raw(len.max(0) as usize)?;
+                    out.extend(snap::raw::Decoder::new().decompress_vec(&block).map_err(|e| invalid(&e))?);
+                }
+                Ok(out)
+            }
+            Compression::Snappy => snap::raw::Decoder::new().decompress_vec(data).map_err(|e| invalid(&e)),
+            Compression::Lz4 => {
+                let mut out = Vec::new();
+                lz4_flex::frame::FrameDecoder::new(data)
+                    .read_to_end(&mut out)
+                    .map_err(|e| invalid(&e))?;
+                Ok(out)
+            }
+            Compression::Zstd => {
+                let mut out = Vec::new();
+                zstd::stream::read::Decoder::new(data)
+                    .and_then(|mut decoder| decoder.read_to_end(&mut out))
+                    .map_err(|e| invalid(&e))?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset_delta: i32,
+    pub timestamp_delta: i64,
+    pub key: Option<Bytes>,
+    pub value: Option<Bytes>,
+    pub headers: Vec<(String, Option<Bytes>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordBatch {
+    pub base_offset: i64,
+    pub partition_leader_epoch: i32,
+    pub attributes: i16,
+    pub base_timestamp: i64,
+    pub max_timestamp: i64,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub base_sequence: i32,
+    pub records: Vec<Record>,
+}
+
+impl RecordBatch {
+    // An uncompressed batch of records with no producer id
+    pub fn new(base_offset: i64, base_timestamp: i64, records: Vec<Record>) -> Self {
+        let max_timestamp = records
+            .iter()
+            .map(|record| base_timestamp + record.timestamp_delta)
+            .max()
+            .unwrap_or(base_timestamp);
+
+        Self {
+            base_offset,
+            partition_leader_epoch: -1,
+            attributes: 0,
+            base_timestamp,
+            max_timestamp,
+            producer_id: -1,
+            producer_epoch: -1,
+            base_sequence: -1,
+            records,
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.attributes = (self.attributes & !COMPRESSION_MASK) | compression.attribute();
+        self
+    }
+
+    // Lets the broker recognise a retry of a batch it already has
+    pub fn with_producer(mut self, producer_id: i64, producer_epoch: i16, base_sequence: i32) -> Self {
+        self.producer_id = producer_id;
+        self.producer_epoch = producer_epoch;
+        self.base_sequence = base_sequence;
+        self
+    }
+
+    // Part of a transaction, hidden from read_committed consumers until committed
+    pub fn transactional(mut self) -> Self {
+        self.attributes |= TRANSACTIONAL_FLAG;
+        self
+    }
+
+    // Ends the producer's transaction on the partition it is written to. Laid
+    // out like Kafka's: the key holds a version and the marker type, the value
+    // a version and the coordinator epoch, which is always 0 here.
+    pub fn control_marker(producer_id: i64, producer_epoch: i16, commit: bool, timestamp: i64) -> Self {
+        let marker = if commit { COMMIT_MARKER } else { ABORT_MARKER };
+        let mut key = Encoder::new();
+        key.i16(0).i16(marker);
+        let mut value = Encoder::new();
+        value.i16(0).i32(0);
+
+        let record = Record::new(0, Some(key.finish()), value.finish());
+        let mut batch = Self::new(0, timestamp, vec![record]).with_producer(producer_id, producer_epoch, -1);
+        batch.attributes |= TRANSACTIONAL_FLAG | CONTROL_FLAG;
+        batch
+    }
+
+    pub fn compression(&self) -> DecodeResult<Compression> {
+        Compression::from_attributes(self.attributes)
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.attributes & TRANSACTIONAL_FLAG != 0
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.attributes & CONTROL_FLAG != 0
+    }
+
+    pub fn last_offset_delta(&self) -> i32 {
+        self.records.last().map(|record| record.offset_delta).unwrap_or(0)
+    }
+
+    // Several batches can be sent back to back, as in a Kafka produce request
+    pub fn decode_all(buf: Bytes) -> DecodeResult<Vec<RecordBatch>> {
+        EncodedBatch::split_all(buf)?
+            .iter()
+            .map(EncodedBatch::decode)
+            .collect()
+    }
+
+    pub fn encode(&self, out: &mut Encoder) {
+        out.raw(&self.encoded().into_bytes());
+    }
+
+    pub fn encoded(&self) -> EncodedBatch {
+        let mut records = Encoder::new();
+        for record in &self.records {
+            record.encode(&mut records);
+        }
+        // An unknown compression can only come from a batch we decoded, which would have failed
+        let compression = self.compression().unwrap_or_default();
+        let records = compression.compress(&records.finish());
+
+        let mut body = Encoder::new();
+        body.i16(self.attributes)
+            .i32(self.last_offset_delta())
+            .i64(self.base_timestamp)
+            .i64(self.max_timestamp)
+            .i64(self.producer_id)
+            .i16(self.producer_epoch)
+            .i32(self.base_sequence)
+            .i32(self.records.len() as i32)
+            .raw(&records);
+        let body = body.finish();
+
+        let mut out = Encoder::new();
+        out.i64(self.base_offset)
+            .i32((CRC_START - BATCH_LENGTH_END + body.len()) as i32)
+            .i32(self.partition_leader_epoch)
+            .i8(MAGIC)
+            .u32(crc32c::crc32c(&body))
+            .raw(&body);
+        EncodedBatch { bytes: out.finish() }
+    }
+}
+
+// A batch still in its wire form. This is what brokers store and hand to
+// consumers, only the base offset is ever rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedBatch {
+    bytes: Bytes,
+}
+
+impl EncodedBatch {
+    // Split back to back batches apart, checking their framing and crc but
+    // leaving the records alone
+    pub fn split_all(mut buf: Bytes) -> DecodeResult<Vec<EncodedBatch>> {
+        let mut batches = Vec::new();
+        while !buf.is_empty() {
+            let mut header = Decoder::new(buf.slice(..BATCH_LENGTH_END.min(buf.len())));
+            let _base_offset = header.i64()?;
+            let batch_length = header.i32()?;
+            if batch_length < (RECORDS_POS - BATCH_LENGTH_END) as i32 {
+                return Err(DecodeError(format!("batch length {} is too short", batch_length)));
+            }
+
+            let len = BATCH_LENGTH_END + batch_length as usize;
+            if buf.len() < len {
+                return Err(DecodeError(format!(
+                    "batch of {} bytes but only {} are left",
+                    len,
+                    buf.len()
+                )));
+            }
+
+            let batch = EncodedBatch { bytes: buf.split_to(len) };
+            if batch.bytes[MAGIC_POS] as i8 != MAGIC {
+                return Err(DecodeError(format!(
+                    "unsupported record batch magic {}",
+                    batch.bytes[MAGIC_POS] as i8
+                )));
+            }
+            if crc32c::crc32c(&batch.bytes[CRC_START..]) != batch.i32_at(CRC_POS) as u32 {
+                return Err(DecodeError("record batch CRC mismatch".to_string()));
+            }
+            batches.push(batch);
+        }
+        Ok(batches)
+    }
+
+    fn i32_at(&self, pos: usize) -> i32 {
+        i32::from_be_bytes(self.bytes[pos..pos + 4].try_into().unwrap())
+    }
+
+    pub fn base_offset(&self) -> i64 {
+        i64::from_be_bytes(self.bytes[..8].try_into().unwrap())
+    }
+
+    fn attributes(&self) -> i16 {
+        i16::from_be_bytes(self.bytes[CRC_START..CRC_START + 2].try_into().unwrap())
+    }
+
+    pub fn compression(&self) -> DecodeResult<Compression> {
+        Compression::from_attributes(self.attributes())
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.attributes() & TRANSACTIONAL_FLAG != 0
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.attributes() & CONTROL_FLAG != 0
+    }
+
+    // Whether a transaction marker commits, None for any other batch
+    pub fn marker_commits(&self) -> Option<bool> {
+        if !self.is_control() {
+            return None;
+        }
+        let batch = self.decode().ok()?;
+        let mut key = Decoder::new(batch.records.first()?.key.clone()?);
+        let _version = key.i16().ok()?;
+        Some(key.i16().ok()? == COMMIT_MARKER)
+    }
+
+    // How many offsets the batch takes up
+    pub fn offset_count(&self) -> i64 {
+        self.i32_at(CRC_START + 2) as i64 + 1
+    }
+
+    pub fn record_count(&self) -> i32 {
+        self.i32_at(RECORDS_POS - 4)
+    }
+
+    // -1 unless the batch comes from an idempotent producer
+    pub fn producer_id(&self) -> i64 {
+        i64::from_be_bytes(self.bytes[PRODUCER_ID_POS..PRODUCER_ID_POS + 8].try_into().unwrap())
+    }
+
+    pub fn producer_epoch(&self) -> i16 {
+        i16::from_be_bytes(self.bytes[PRODUCER_EPOCH_POS..PRODUCER_EPOCH_POS + 2].try_into().unwrap())
+    }
+
+    pub fn base_sequence(&self) -> i32 {
+        self.i32_at(BASE_SEQUENCE_POS)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // The base offset sits outside the crc, so it can be changed without
+    // touching the rest of the batch
+    pub fn with_base_offset(&self, base_offset: i64) -> EncodedBatch {
+        let mut bytes = BytesMut::from(&self.bytes[..]);
+        bytes[..8].copy_from_slice(&base_offset.to_be_bytes());
+        EncodedBatch { bytes: bytes.freeze() }
+    }
+
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.bytes
+    }
+
+    pub fn decode(&self) -> DecodeResult<RecordBatch> {
+        let mut batch = Decoder::new(self.bytes.clone());
+        let base_offset = batch.i64()?;
+        let _batch_length = batch.i32()?;
+        let partition_leader_epoch = batch.i32()?;
+        let _magic = batch.i8()?;
+        let _crc = batch.u32()?;
+        let attributes = batch.i16()?;
+        let last_offset_delta = batch.i32()?;
+        let base_timestamp = batch.i64()?;
+        let max_timestamp = batch.i64()?;
+        let producer_id = batch.i64()?;
+        let producer_epoch = batch.i16()?;
+        let base_sequence = batch.i32()?;
+        let count = batch.i32()?;
+
+        let compressed = batch.raw(batch.remaining())?;
+        let records = Compression::from_attributes(attributes)?.decompress(&compressed)?;
+        let mut records = Decoder::new(Bytes::from(records));
+
+        // Don't trust the count for the allocation, every record takes a few bytes
+        let mut decoded = Vec::with_capacity((count.max(0) as usize).min(records.remaining()));
+        for _ in 0..count {
+            decoded.push(Record::decode(&mut records)?);
+        }
+
+        // The header's offset count is what gets stored, so it has to agree
+        // with the records: at least one, each a later offset than the last
+        let deltas_valid = decoded.first().is_some_and(|first| first.offset_delta >= 0)
+            && decoded.windows(2).all(|pair| pair[0].offset_delta < pair[1].offset_delta)
+            && decoded.last().is_some_and(|last| last.offset_delta == last_offset_delta);
+        if !deltas_valid {
+            return Err(DecodeError(format!(
+                "Batch of {} records claims a last offset delta of {}",
+                decoded.len(),
+                last_offset_delta
+            )));
+        }
+
+        Ok(RecordBatch {
+            base_offset,
+            partition_leader_epoch,
+            attributes,
+            base_timestamp,
+            max_timestamp,
+            producer_id,
+            producer_epoch,
+            base_sequence,
+            records: decoded,
+        })
+    }
+}
+
+impl Record {
+    // A record with just a key and value
+    pub fn new(offset_delta: i32, key: Option<Bytes>, value: Bytes) -> Self {
+        Self {
+            offset_delta,
+            timestamp_delta: 0,
+            key,
+            value: Some(value),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_headers(mut self, headers: Vec<Header>) -> Self {
+        self.headers = headers
+            .into_iter()
+            .map(|header| (header.key, Some(Bytes::from(header.value))))
+            .collect();
+        self
+    }
+
+    // Null header values, which only Kafka clients send, come back empty
+    pub fn message_headers(&self) -> Vec<Header> {
+        self.headers
+            .iter()
+            .map(|(key, value)| Header::new(key.clone(), value.as_deref().unwrap_or_default()))
+            .collect()
+    }
+
+    fn decode(decoder: &mut Decoder) -> DecodeResult<Record> {
+        let length = decoder.varint()?;
+        if length < 0 {
+            return Err(DecodeError(format!("negative record length {}", length)));
+        }
+        let mut record = Decoder::new(decoder.raw(length as usize)?);
+
+        let _attributes = record.i8()?;
+        let timestamp_delta = record.varlong()?;
+        let offset_delta = record.varint()?;
+        let key = record.varint_bytes()?;
+        let value = record.varint_bytes()?;
+
+        let header_count = record.varint()?;
+        let mut headers = Vec::with_capacity((header_count.max(0) as usize).min(record.remaining()));
+        for _ in 0..header_count {
+            let name = record
+                .varint_bytes()?
+                .ok_or_else(|| DecodeError("null header key".to_string()))?;
+            let name = String::from_utf8(name.to_vec()).map_err(|e| DecodeError(e.to_string()))?;
+            headers.push((name, record.varint_bytes()?));
+        }
+
+        Ok(Record {
+            offset_delta,
+            timestamp_delta,
+            key,
+            value,
+            headers,
+        })
+    }
+
+    fn encode(&self, out: &mut Encoder) {
+        let mut record = Encoder::new();
+        record
+            .i8(0)
+            .varlong(self.timestamp_delta)
+            .varint(self.offset_delta)
+            .varint_bytes(self.key.as_deref())
+            .varint_bytes(self.value.as_deref())
+            .varint(self.headers.len() as i32);
+        for (name, value) in &self.headers {
+            record.varint_bytes(Some(name.as_bytes())).varint_bytes(value.as_deref());
+        }
+        let record = record.finish();
+
+        out.varint(record.len() as i32).raw(&record);
+    }
+}
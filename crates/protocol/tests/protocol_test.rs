@@ -0,0 +1,241 @@
+use rafka_protocol::codec::{encode_frame, DEFAULT_MAX_FRAME_SIZE, HEADER_LEN};
+use rafka_core::message::{BrokerError, ErrorCode};
+use bytes::Bytes;
+use rafka_protocol::{
+    ApiKey, ApiVersionRange, BrokerResponse, Compression, EncodedBatch, FrameError, FrameReader,
+    FrameWriter, NegotiatedVersions, Record, RecordBatch, Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{duplex, AsyncWriteExt};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Ping {
+    id: u32,
+    payload: Vec<u8>,
+}
+
+#[tokio::test]
+async fn test_roundtrip_pipelined_messages() {
+    let (client, server) = duplex(64 * 1024);
+    let mut writer = FrameWriter::new(client);
+    let mut reader = FrameReader::new(server);
+
+    for id in 0..3 {
+        writer.write_message(&Ping { id, payload: vec![id as u8; 10] }).await.unwrap();
+    }
+    drop(writer);
+
+    for id in 0..3 {
+        let ping: Ping = reader.read_message().await.unwrap().unwrap();
+        assert_eq!(ping, Ping { id, payload: vec![id as u8; 10] });
+    }
+    assert!(reader.read_message::<Ping>().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_frame_split_across_reads() {
+    // A tiny pipe forces the 200KB frame to arrive in many pieces
+    let (mut client, server) = duplex(512);
+    let body = vec![7u8; 200 * 1024];
+    let frame = encode_frame(&body, DEFAULT_MAX_FRAME_SIZE).unwrap();
+
+    let writer = tokio::spawn(async move {
+        for chunk in frame.chunks(333) {
+            client.write_all(chunk).await.unwrap();
+        }
+    });
+
+    let mut reader = FrameReader::new(server);
+    let received = reader.read_frame().await.unwrap().unwrap();
+    assert_eq!(received.len(), body.len());
+    assert_eq!(&received[..], &body[..]);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_oversized_frames_rejected() {
+    let (client, server) = duplex(1024);
+
+    let mut writer = FrameWriter::with_max_frame_size(client, 16);
+    let err = writer.write_frame(&[0u8; 17]).await.unwrap_err();
+    assert!(matches!(err, FrameError::FrameTooLarge { size: 17, max: 16 }));
+
+    // Bypass the writer's own limit to check the reader refuses it too
+    let mut raw = writer.into_inner();
+    raw.write_all(&encode_frame(&[0u8; 32], 64).unwrap()).await.unwrap();
+
+    let mut reader = FrameReader::with_max_frame_size(server, 16);
+    let err = reader.read_frame().await.unwrap_err();
+    assert!(matches!(err, FrameError::FrameTooLarge { size: 32, max: 16 }));
+    assert!(!err.is_recoverable());
+}
+
+#[tokio::test]
+async fn test_truncated_frame() {
+    let (mut client, server) = duplex(1024);
+    let frame = encode_frame(b"hello world", DEFAULT_MAX_FRAME_SIZE).unwrap();
+    client.write_all(&frame[..HEADER_LEN + 5]).await.unwrap();
+    drop(client);
+
+    let mut reader = FrameReader::new(server);
+    let err = reader.read_frame().await.unwrap_err();
+    assert!(matches!(err, FrameError::Truncated { expected: 15, received: 9 }));
+}
+
+#[tokio::test]
+async fn test_malformed_body_is_recoverable() {
+    let (client, server) = duplex(1024);
+    let mut writer = FrameWriter::new(client);
+    writer.write_frame(b"not json").await.unwrap();
+    writer.write_message(&Ping { id: 1, payload: vec![] }).await.unwrap();
+
+    let mut reader = FrameReader::new(server);
+    let err = reader.read_message::<Ping>().await.unwrap_err();
+    assert!(err.is_recoverable());
+
+    // The stream is still aligned, so the next frame decodes fine
+    let ping: Ping = reader.read_message().await.unwrap().unwrap();
+    assert_eq!(ping.id, 1);
+}
+
+#[test]
+fn test_negotiate_picks_common_version() {
+    let broker_apis = [
+        ApiVersionRange::new(ApiKey::Publish, 0, ApiKey::Publish.current_version() + 3),
+        // A broker that only speaks versions newer than ours
+        ApiVersionRange::new(ApiKey::Subscribe, ApiKey::Subscribe.current_version() + 1, 9),
+        ApiVersionRange::current(ApiKey::Register),
+    ];
+
+    let versions = NegotiatedVersions::negotiate(&broker_apis);
+    assert_eq!(versions.version(ApiKey::Publish), Some(ApiKey::Publish.current_version()));
+    assert!(!versions.supports(ApiKey::Subscribe));
+    assert!(versions.supports(ApiKey::Register));
+    assert_eq!(versions.require(ApiKey::GetMetrics).unwrap_err().0, ApiKey::GetMetrics);
+}
+
+#[test]
+fn test_unknown_apis_in_version_reply_are_skipped() {
+    // What a newer broker with one more request than this build would answer
+    let reply = serde_json::json!({
+        "correlation_id": 0,
+        "response": {"ApiVersions": {"apis": [
+            {"api_key": "Publish", "min_version": 1, "max_version": 1},
+            {"api_key": "SomeFutureApi", "min_version": 0, "max_version": 3},
+            {"api_key": "Consume", "min_version": 2, "max_version": 2},
+        ]}},
+    });
+    let response: Response = serde_json::from_value(reply).unwrap();
+    let BrokerResponse::ApiVersions(versions) = response.response else {
+        panic!("expected a version list");
+    };
+    assert_eq!(
+        versions.apis,
+        vec![ApiVersionRange::new(ApiKey::Publish, 1, 1), ApiVersionRange::new(ApiKey::Consume, 2, 2)]
+    );
+
+    let negotiated = NegotiatedVersions::negotiate(&versions.apis);
+    assert!(negotiated.supports(ApiKey::Publish));
+    assert!(negotiated.supports(ApiKey::Consume));
+}
+
+#[test]
+fn test_legacy_brokers_only_speak_text_replies() {
+    // Pre-ApiVersions brokers answer in free-form text, which this build no longer parses
+    let versions = NegotiatedVersions::legacy();
+    assert!(!versions.supports(ApiKey::Publish));
+    assert!(!versions.supports(ApiKey::UpdateOffset));
+    assert!(!versions.supports(ApiKey::ApiVersions));
+    assert!(!versions.supports(ApiKey::GetMetrics));
+}
+
+#[test]
+fn test_error_codes_are_stable_numbers() {
+    let error = BrokerResponse::Error(BrokerError::new(ErrorCode::UnknownTopic, "Topic x not found"));
+    let json = serde_json::to_value(&error).unwrap();
+    assert_eq!(json["Error"]["code"], 4);
+
+    // Codes from a newer broker still decode
+    let json = r#"{"Error":{"code":999,"message":"something new"}}"#;
+    let BrokerResponse::Error(error) = serde_json::from_str(json).unwrap() else {
+        panic!("expected an error response");
+    };
+    assert_eq!(error.code, ErrorCode::Unknown);
+}
+
+fn sample_batch(compression: Compression) -> RecordBatch {
+    let records = (0..10)
+        .map(|i| Record {
+            offset_delta: i,
+            timestamp_delta: i as i64,
+            key: Some(Bytes::from(format!("key-{}", i))),
+            value: Some(Bytes::from("value ".repeat(50))),
+            headers: vec![("trace-id".to_string(), Some(Bytes::from("abc"))), ("empty".to_string(), None)],
+        })
+        .collect();
+    RecordBatch::new(5, 1_700_000_000_000, records).with_compression(compression)
+}
+
+#[test]
+fn test_record_batch_roundtrip() {
+    for compression in [Compression::None, Compression::Snappy, Compression::Lz4, Compression::Zstd] {
+        let batch = sample_batch(compression);
+        let encoded = batch.encoded();
+        if compression != Compression::None {
+            assert!(encoded.len() < sample_batch(Compression::None).encoded().len());
+        }
+
+        // Two batches back to back, as a produce request can carry them
+        let mut buf = encoded.as_bytes().to_vec();
+        buf.extend_from_slice(encoded.as_bytes());
+        let decoded = RecordBatch::decode_all(Bytes::from(buf)).unwrap();
+        assert_eq!(decoded, vec![batch.clone(), batch]);
+    }
+}
+
+#[test]
+fn test_record_batch_crc() {
+    let encoded = sample_batch(Compression::Lz4).encoded();
+    assert_eq!(encoded.offset_count(), 10);
+    assert_eq!(encoded.record_count(), 10);
+    assert_eq!(encoded.producer_id(), -1);
+
+    let sequenced = sample_batch(Compression::None).with_producer(7, 2, 40).encoded();
+    assert_eq!((sequenced.producer_id(), sequenced.producer_epoch(), sequenced.base_sequence()), (7, 2, 40));
+    assert!(!sequenced.is_transactional());
+
+    let transactional = sample_batch(Compression::Zstd).with_producer(7, 2, 0).transactional().encoded();
+    assert!(transactional.is_transactional() && !transactional.is_control());
+    assert_eq!(transactional.compression().unwrap(), Compression::Zstd);
+
+    // Kafka's commit marker: key is version 0 and type 1, value is version 0 and epoch 0
+    let marker = RecordBatch::control_marker(7, 2, true, 0).encoded();
+    assert!(marker.is_transactional() && marker.is_control());
+    let record = &marker.decode().unwrap().records[0];
+    assert_eq!(record.key.as_deref(), Some(&[0, 0, 0, 1][..]));
+    assert_eq!(record.value.as_deref(), Some(&[0, 0, 0, 0, 0, 0][..]));
+    assert_eq!(marker.marker_commits(), Some(true));
+    assert_eq!(RecordBatch::control_marker(7, 2, false, 0).encoded().marker_commits(), Some(false));
+    assert_eq!(transactional.marker_commits(), None);
+
+    // Offsets the header claims have to match the records
+    let gapped = RecordBatch::new(0, 0, vec![Record::new(3, None, Bytes::from("value"))]).encoded();
+    assert_eq!(gapped.offset_count(), 4);
+    assert!(gapped.decode().is_ok());
+    let backwards = RecordBatch::new(0, 0, vec![Record::new(-2, None, Bytes::from("value"))]).encoded();
+    assert_eq!(backwards.offset_count(), -1);
+    assert!(backwards.decode().is_err());
+    assert!(RecordBatch::new(0, 0, Vec::new()).encoded().decode().is_err());
+
+    // The base offset is outside the crc, so moving it keeps the batch valid
+    let moved = encoded.with_base_offset(100);
+    let split = EncodedBatch::split_all(moved.as_bytes().clone()).unwrap();
+    assert_eq!(split[0].base_offset(), 100);
+    assert_eq!(split[0].decode().unwrap().records, sample_batch(Compression::Lz4).records);
+
+    let mut corrupt = encoded.into_bytes().to_vec();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    let err = EncodedBatch::split_all(Bytes::from(corrupt)).unwrap_err();
+    assert!(err.to_string().contains("CRC"));
+}
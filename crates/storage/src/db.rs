@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use std::collections::HashMap;
 use dashmap::DashMap;
 use bytes::Bytes;
 use parking_lot::RwLock;
@@ -22,22 +23,53 @@ impl Default for RetentionPolicy {
     }
 }
 
-// Public interface for message data
+// What a stored payload is to transactions. Records written in a transaction
+// stay hidden from read_committed readers until the producer's next marker
+// says whether the transaction was committed or aborted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BatchKind {
+    #[default]
+    Plain,
+    Transactional { producer_id: i64 },
+    Marker { producer_id: i64, commit: bool },
+}
+
+// Public interface for message data. A payload can hold a whole record batch,
+// which takes up the offsets from `offset` to `last_offset`.
 #[derive(Clone)]
 pub struct StoredMessage {
     pub offset: i64,
+    pub last_offset: i64,
     pub payload: Bytes,
     pub timestamp: SystemTime,
     pub partition_id: i32,
+    pub kind: BatchKind,
+}
+
+// A transaction that ended in an abort marker, from its first record to the marker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AbortedTransaction {
+    pub producer_id: i64,
+    pub first_offset: i64,
+    pub last_offset: i64,
+}
+
+// Messages a read_committed reader may see, and where its next read starts.
+// That can be past the last message when markers or aborted records follow it.
+pub struct CommittedRead {
+    pub messages: Vec<StoredMessage>,
+    pub next_offset: i64,
 }
 
 // Private implementation
 #[derive(Clone)]
 struct MessageEntry {
     offset: i64,
+    last_offset: i64,
     payload: Bytes,
     timestamp: SystemTime,
     partition_id: i32,
+    kind: BatchKind,
     acknowledged_by: DashMap<String, bool>,
 }
 
@@ -45,19 +77,71 @@ impl MessageEntry {
     fn to_stored_message(&self) -> StoredMessage {
         StoredMessage {
             offset: self.offset,
+            last_offset: self.last_offset,
             payload: self.payload.clone(),
             timestamp: self.timestamp,
             partition_id: self.partition_id,
+            kind: self.kind,
         }
     }
 }
 
+// Where each producer's open transaction starts, and the aborted ones still retained
+#[derive(Default)]
+struct TransactionIndex {
+    open: HashMap<i64, i64>,
+    aborted: Vec<AbortedTransaction>,
+}
+
+impl TransactionIndex {
+    fn appended(&mut self, kind: BatchKind, offset: i64) {
+        match kind {
+            BatchKind::Plain => {}
+            BatchKind::Transactional { producer_id } => {
+                self.open.entry(producer_id).or_insert(offset);
+            }
+            BatchKind::Marker { producer_id, commit } => {
+                // A marker with nothing open ends an empty transaction
+                if let Some(first_offset) = self.open.remove(&producer_id) {
+                    if !commit {
+                        self.aborted.push(AbortedTransaction {
+                            producer_id,
+                            first_offset,
+                            last_offset: offset,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_aborted(&self, entry: &MessageEntry) -> bool {
+        match entry.kind {
+            BatchKind::Transactional { producer_id } => self.aborted.iter().any(|aborted| {
+                aborted.producer_id == producer_id
+                    && (aborted.first_offset..=aborted.last_offset).contains(&entry.offset)
+            }),
+            _ => false,
+        }
+    }
+
+    // Offset of the first record that may still be aborted
+    fn last_stable_offset(&self, next_offset: i64) -> i64 {
+        self.open.values().copied().min().unwrap_or(next_offset)
+    }
+}
+
 // Represents a partition's message queue
 struct PartitionQueue {
     messages: RwLock<VecDeque<MessageEntry>>,
     next_offset: RwLock<i64>,
     retention_policy: RetentionPolicy,
     current_size: AtomicUsize,
+    // Only changed with `messages` write-locked
+    transactions: RwLock<TransactionIndex>,
+    // Records from here on are not committed yet and can't be read. None when
+    // records are committed as soon as they are appended.
+    high_watermark: RwLock<Option<i64>>,
 }
 
 impl PartitionQueue {
@@ -67,31 +151,102 @@ impl PartitionQueue {
             next_offset: RwLock::new(0),
             retention_policy,
             current_size: AtomicUsize::new(0),
+            transactions: RwLock::new(TransactionIndex::default()),
+            high_watermark: RwLock::new(None),
         }
     }
 
-    fn append(&self, payload: Bytes, partition_id: i32) -> i64 {
+    // `build` gets the first of the `offset_count` offsets assigned to the payload
+    fn append(
+        &self,
+        offset_count: i64,
+        partition_id: i32,
+        kind: BatchKind,
+        build: impl FnOnce(i64) -> Bytes,
+    ) -> i64 {
         let mut messages = self.messages.write();
         let mut next_offset = self.next_offset.write();
         
         let offset = *next_offset;
-        *next_offset += 1;
+        *next_offset += offset_count;
+        let payload = build(offset);
+        self.push(&mut messages, offset, offset_count, partition_id, kind, payload);
+        // Release the locks first, enforcing retention takes them again
+        drop(next_offset);
+        drop(messages);
+        self.enforce_retention_policy();
+        
+        offset
+    }
+
+    // Store a payload at the offset the partition's leader gave it. Anything
+    // already stored there is kept, and a gap means the leader no longer has
+    // what comes before `offset`, so the log starts over from there.
+    fn append_replica(
+        &self,
+        offset: i64,
+        offset_count: i64,
+        partition_id: i32,
+        kind: BatchKind,
+        payload: Bytes,
+    ) -> bool {
+        let mut messages = self.messages.write();
+        let mut next_offset = self.next_offset.write();
+        if offset < *next_offset {
+            return false;
+        }
+        if offset > *next_offset {
+            messages.clear();
+            self.current_size.store(0, Ordering::SeqCst);
+            *self.transactions.write() = TransactionIndex::default();
+        }
+
+        *next_offset = offset + offset_count;
+        self.push(&mut messages, offset, offset_count, partition_id, kind, payload);
+        drop(next_offset);
+        drop(messages);
+        self.enforce_retention_policy();
+        true
+    }
 
-        let entry = MessageEntry {
+    fn push(
+        &self,
+        messages: &mut VecDeque<MessageEntry>,
+        offset: i64,
+        offset_count: i64,
+        partition_id: i32,
+        kind: BatchKind,
+        payload: Bytes,
+    ) {
+        self.transactions.write().appended(kind, offset);
+        self.current_size.fetch_add(payload.len(), Ordering::SeqCst);
+        messages.push_back(MessageEntry {
             offset,
-            payload: payload.clone(),
+            last_offset: offset + offset_count - 1,
+            payload,
             timestamp: SystemTime::now(),
             partition_id,
+            kind,
             acknowledged_by: DashMap::new(),
-        };
+        });
+    }
 
-        // Update current size
-        self.current_size.fetch_add(payload.len(), Ordering::SeqCst);
-        
-        messages.push_back(entry);
-        self.enforce_retention_policy();
-        
-        offset
+    // Where committed records end
+    fn committed_end(&self, next_offset: i64) -> i64 {
+        self.high_watermark.read().unwrap_or(next_offset)
+    }
+
+    // Never moves back, nor past the end of the log. Returns whether more
+    // records are committed now.
+    fn set_high_watermark(&self, high_watermark: Option<i64>) -> bool {
+        // Held throughout, so an append can't take the watermark past the end
+        // read here
+        let end = self.next_offset.read();
+        let next_offset = *end;
+        let mut current = self.high_watermark.write();
+        let before = current.unwrap_or(next_offset);
+        *current = high_watermark.map(|high_watermark| high_watermark.clamp(before, next_offset));
+        current.unwrap_or(next_offset) > before
     }
 
     fn enforce_retention_policy(&self) {
@@ -112,22 +267,90 @@ impl PartitionQueue {
             break;
         }
 
+        // Aborts of transactions that are gone entirely no longer hide anything
+        if let Some(start) = messages.front().map(|entry| entry.offset) {
+            self.transactions.write().aborted.retain(|aborted| aborted.last_offset >= start);
+        }
+
         self.current_size.store(size, Ordering::SeqCst);
     }
 
-    fn read_from(&self, start_offset: i64, max_messages: usize) -> Vec<MessageEntry> {
+    // Entries from `start_offset` that end before `end_offset`
+    fn read_from(&self, start_offset: i64, end_offset: i64, max_messages: usize) -> Vec<MessageEntry> {
         let messages = self.messages.read();
         messages
             .iter()
-            .filter(|entry| entry.offset >= start_offset)
+            .filter(|entry| entry.last_offset >= start_offset && entry.last_offset < end_offset)
             .take(max_messages)
             .cloned()
             .collect()
     }
 
+    // Stops at the first record of a transaction still open and leaves out
+    // markers and aborted records. At most `max_messages` entries are looked
+    // at, kept or not.
+    fn read_committed(&self, start_offset: i64, max_messages: usize) -> CommittedRead {
+        let messages = self.messages.read();
+        let transactions = self.transactions.read();
+        let next_offset = *self.next_offset.read();
+        let stable = transactions.last_stable_offset(next_offset).min(self.committed_end(next_offset));
+
+        let mut read = CommittedRead {
+            messages: Vec::new(),
+            next_offset: start_offset,
+        };
+        for entry in messages
+            .iter()
+            .filter(|entry| entry.last_offset >= start_offset && entry.offset < stable)
+            .take(max_messages)
+        {
+            read.next_offset = read.next_offset.max(entry.last_offset + 1);
+            if matches!(entry.kind, BatchKind::Marker { .. }) || transactions.is_aborted(entry) {
+                continue;
+            }
+            read.messages.push(entry.to_stored_message());
+        }
+        read
+    }
+
+    fn last_stable_offset(&self) -> i64 {
+        let _messages = self.messages.read();
+        let next_offset = *self.next_offset.read();
+        let stable = self.transactions.read().last_stable_offset(next_offset);
+        stable.min(self.committed_end(next_offset))
+    }
+
+    // Aborted transactions overlapping `start_offset..end_offset`
+    fn aborted_transactions(&self, start_offset: i64, end_offset: i64) -> Vec<AbortedTransaction> {
+        self.transactions
+            .read()
+            .aborted
+            .iter()
+            .filter(|aborted| aborted.last_offset >= start_offset && aborted.first_offset < end_offset)
+            .copied()
+            .collect()
+    }
+
+    // First offset still held and the high watermark
+    fn log_offsets(&self) -> (i64, i64) {
+        let messages = self.messages.read();
+        let next_offset = *self.next_offset.read();
+        let start = messages.front().map(|entry| entry.offset).unwrap_or(next_offset);
+        (start, self.committed_end(next_offset))
+    }
+
+    fn find_by_timestamp(&self, timestamp: SystemTime) -> Option<MessageEntry> {
+        let messages = self.messages.read();
+        let end = self.committed_end(*self.next_offset.read());
+        messages
+            .iter()
+            .find(|entry| entry.timestamp >= timestamp && entry.last_offset < end)
+            .cloned()
+    }
+
     fn acknowledge(&self, offset: i64, consumer_id: &str) {
         let messages = self.messages.read();
-        if let Some(entry) = messages.iter().find(|e| e.offset == offset) {
+        if let Some(entry) = messages.iter().find(|e| (e.offset..=e.last_offset).contains(&offset)) {
             entry.acknowledged_by.insert(consumer_id.to_string(), true);
         }
     }
@@ -150,6 +373,12 @@ pub struct Storage {
     retention_policy: RwLock<RetentionPolicy>,
 }
 
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Storage {
     pub fn new() -> Self {
         Self::with_retention_policy(RetentionPolicy::default())
@@ -168,39 +397,185 @@ impl Storage {
     }
 
     pub fn create_partition(&self, topic: &str, partition_id: i32) -> bool {
+        self.create_partition_with_retention(topic, partition_id, *self.retention_policy.read())
+    }
+
+    // For topics that keep their data for longer or shorter than the rest
+    pub fn create_partition_with_retention(
+        &self,
+        topic: &str,
+        partition_id: i32,
+        retention_policy: RetentionPolicy,
+    ) -> bool {
         if let Some(partitions) = self.topics.get(topic) {
-            partitions.insert(partition_id, Arc::new(PartitionQueue::new(*self.retention_policy.read())));
+            partitions.insert(partition_id, Arc::new(PartitionQueue::new(retention_policy)));
             true
         } else {
             false
         }
     }
 
-    pub fn append(&self, topic: &str, partition_id: i32, message: &Bytes) -> Option<i64> {
-        if let Some(partitions) = self.topics.get(topic) {
-            if let Some(queue) = partitions.get(&partition_id) {
-                Some(queue.append(message.clone(), partition_id))
-            } else {
-                None
-            }
-        } else {
-            None
+    // Drops the topic's partitions and every consumer's offsets on it
+    pub fn delete_topic(&self, topic: &str) -> bool {
+        if self.topics.remove(topic).is_none() {
+            return false;
+        }
+        for offsets in self.consumer_offsets.iter() {
+            offsets.value().retain(|(offset_topic, _), _| offset_topic != topic);
         }
+        true
     }
 
+    // Like create_topic + create_partition, but keeps whatever is already stored
+    pub fn ensure_partition(&self, topic: &str, partition_id: i32) {
+        let partitions = self.topics.entry(topic.to_string()).or_default();
+        partitions
+            .entry(partition_id)
+            .or_insert_with(|| Arc::new(PartitionQueue::new(*self.retention_policy.read())));
+    }
+
+    pub fn append(&self, topic: &str, partition_id: i32, message: &Bytes) -> Option<i64> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.append(1, partition_id, BatchKind::Plain, |_| message.clone()))
+    }
+
+    // Store a payload spanning `offset_count` offsets, such as a record batch.
+    // `build` is handed the first offset so it can be written into the payload
+    // before anyone can read it. Returns that first offset.
+    pub fn append_batch(
+        &self,
+        topic: &str,
+        partition_id: i32,
+        offset_count: i64,
+        build: impl FnOnce(i64) -> Bytes,
+    ) -> Option<i64> {
+        self.append_batch_of(topic, partition_id, offset_count, BatchKind::Plain, build)
+    }
+
+    // Like append_batch, for payloads that take part in a transaction
+    pub fn append_batch_of(
+        &self,
+        topic: &str,
+        partition_id: i32,
+        offset_count: i64,
+        kind: BatchKind,
+        build: impl FnOnce(i64) -> Bytes,
+    ) -> Option<i64> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.append(offset_count, partition_id, kind, build))
+    }
+
+    // Committed messages only, see set_high_watermark
     pub fn read(&self, topic: &str, partition_id: i32, start_offset: i64) -> Option<Vec<StoredMessage>> {
-        if let Some(partitions) = self.topics.get(topic) {
-            if let Some(queue) = partitions.get(&partition_id) {
-                Some(queue.read_from(start_offset, 100)
-                    .into_iter()
-                    .map(|entry| entry.to_stored_message())
-                    .collect())
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        let end_offset = queue.committed_end(*queue.next_offset.read());
+        Some(queue.read_from(start_offset, end_offset, 100)
+            .into_iter()
+            .map(|entry| entry.to_stored_message())
+            .collect())
+    }
+
+    // Like read, but up to the end of the log, for followers copying it
+    pub fn read_log(&self, topic: &str, partition_id: i32, start_offset: i64) -> Option<Vec<StoredMessage>> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.read_from(start_offset, i64::MAX, 100)
+            .into_iter()
+            .map(|entry| entry.to_stored_message())
+            .collect())
+    }
+
+    // Store a payload copied from the partition's leader at the offset it has
+    // there. Returns false if it was already stored.
+    pub fn append_replica(
+        &self,
+        topic: &str,
+        partition_id: i32,
+        offset: i64,
+        offset_count: i64,
+        kind: BatchKind,
+        payload: Bytes,
+    ) -> Option<bool> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.append_replica(offset, offset_count, partition_id, kind, payload))
+    }
+
+    // Records past the high watermark are kept from readers until it moves
+    // past them. None commits everything appended, which is how partitions
+    // start out. Returns whether more records are committed now.
+    pub fn set_high_watermark(&self, topic: &str, partition_id: i32, high_watermark: Option<i64>) -> bool {
+        self.topics
+            .get(topic)
+            .and_then(|partitions| Some(partitions.get(&partition_id)?.set_high_watermark(high_watermark)))
+            .unwrap_or(false)
+    }
+
+    // The offset the next append will get, committed or not
+    pub fn log_end_offset(&self, topic: &str, partition_id: i32) -> Option<i64> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        let next_offset = *queue.next_offset.read();
+        Some(next_offset)
+    }
+
+    // Like read, for readers that only see committed transactions
+    pub fn read_committed(&self, topic: &str, partition_id: i32, start_offset: i64) -> Option<CommittedRead> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.read_committed(start_offset, 100))
+    }
+
+    // Offset of the first record whose transaction is still open, or the next
+    // offset when none is
+    pub fn last_stable_offset(&self, topic: &str, partition_id: i32) -> Option<i64> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.last_stable_offset())
+    }
+
+    pub fn aborted_transactions(
+        &self,
+        topic: &str,
+        partition_id: i32,
+        start_offset: i64,
+        end_offset: i64,
+    ) -> Vec<AbortedTransaction> {
+        self.topics
+            .get(topic)
+            .and_then(|partitions| {
+                let queue = partitions.get(&partition_id)?;
+                Some(queue.aborted_transactions(start_offset, end_offset))
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.iter().map(|topic| topic.key().clone()).collect()
+    }
+
+    pub fn has_partition(&self, topic: &str, partition_id: i32) -> bool {
+        self.topics
+            .get(topic)
+            .map(|partitions| partitions.contains_key(&partition_id))
+            .unwrap_or(false)
+    }
+
+    // (log start offset, high watermark) of a partition
+    pub fn log_offsets(&self, topic: &str, partition_id: i32) -> Option<(i64, i64)> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        Some(queue.log_offsets())
+    }
+
+    // Earliest retained message stored at or after `timestamp`
+    pub fn find_by_timestamp(&self, topic: &str, partition_id: i32, timestamp: SystemTime) -> Option<StoredMessage> {
+        let partitions = self.topics.get(topic)?;
+        let queue = partitions.get(&partition_id)?;
+        queue.find_by_timestamp(timestamp).map(|entry| entry.to_stored_message())
     }
 
     pub fn acknowledge(&self, topic: &str, partition_id: i32, offset: i64, consumer_id: &str) {
@@ -223,7 +598,7 @@ impl Storage {
     pub fn update_consumer_offset(&self, consumer_id: &str, topic: &str, partition_id: i32, offset: i64) {
         self.consumer_offsets
             .entry(consumer_id.to_string())
-            .or_insert_with(DashMap::new)
+            .or_default()
             .insert((topic.to_string(), partition_id), offset);
     }
 
@@ -235,6 +610,34 @@ impl Storage {
             .map(|r| *r.value())
     }
 
+    // Every stored position as (consumer, topic, partition, offset)
+    pub fn all_consumer_offsets(&self) -> Vec<(String, String, i32, i64)> {
+        self.consumer_offsets
+            .iter()
+            .flat_map(|offsets| {
+                let consumer_id = offsets.key().clone();
+                offsets
+                    .value()
+                    .iter()
+                    .map(|entry| (consumer_id.clone(), entry.key().0.clone(), entry.key().1, *entry.value()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    // Every (topic, partition) position stored for a consumer
+    pub fn consumer_offsets(&self, consumer_id: &str) -> Vec<(String, i32, i64)> {
+        self.consumer_offsets
+            .get(consumer_id)
+            .map(|offsets| {
+                offsets
+                    .iter()
+                    .map(|entry| (entry.key().0.clone(), entry.key().1, *entry.value()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     // Read messages from consumer's last position
     pub fn read_from_offset(&self, topic: &str, partition_id: i32, consumer_id: &str) -> Option<Vec<StoredMessage>> {
         let start_offset = self.get_consumer_offset(consumer_id, topic, partition_id)
@@ -281,6 +684,26 @@ impl Storage {
         }
     }
 
+    // Size and offsets of every partition, in no particular order
+    pub fn partition_metrics(&self) -> Vec<PartitionMetrics> {
+        let mut metrics = Vec::new();
+        for topic in self.topics.iter() {
+            for partition in topic.value().iter() {
+                let queue = partition.value();
+                let (log_start_offset, high_watermark) = queue.log_offsets();
+                metrics.push(PartitionMetrics {
+                    topic: topic.key().clone(),
+                    partition_id: *partition.key(),
+                    log_start_offset,
+                    high_watermark,
+                    size_bytes: queue.current_size.load(Ordering::SeqCst),
+                    batches: queue.messages.read().len(),
+                });
+            }
+        }
+        metrics
+    }
+
     pub async fn cleanup_old_messages(&self) {
         let _policy = *self.retention_policy.read();
         
@@ -304,6 +727,16 @@ pub struct StorageMetrics {
     pub oldest_message: SystemTime,
 }
 
+#[derive(Debug, Clone)]
+pub struct PartitionMetrics {
+    pub topic: String,
+    pub partition_id: i32,
+    pub log_start_offset: i64,
+    pub high_watermark: i64,
+    pub size_bytes: usize,
+    pub batches: usize,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -323,4 +756,106 @@ mod tests {
         let read_messages = storage.read("test", 0, offset).unwrap();
         assert_eq!(read_messages[0].payload, message);
     }
+
+    #[test]
+    fn test_delete_topic() {
+        let storage = Storage::new();
+        storage.create_topic("test".to_string());
+        storage.create_partition("test", 0);
+        storage.append("test", 0, &Bytes::from("hello world"));
+        storage.update_consumer_offset("consumer", "test", 0, 0);
+        storage.update_consumer_offset("consumer", "other", 0, 3);
+
+        assert!(storage.delete_topic("test"));
+        assert!(!storage.has_partition("test", 0));
+        assert_eq!(storage.get_consumer_offset("consumer", "test", 0), None);
+        assert_eq!(storage.get_consumer_offset("consumer", "other", 0), Some(3));
+        assert!(!storage.delete_topic("test"));
+    }
+
+    #[test]
+    fn test_read_committed() {
+        let storage = Storage::new();
+        storage.create_topic("test".to_string());
+        storage.create_partition("test", 0);
+        let append = |kind, payload: &'static str| {
+            storage.append_batch_of("test", 0, 1, kind, |_| Bytes::from(payload)).unwrap()
+        };
+        let committed = BatchKind::Transactional { producer_id: 1 };
+        let aborted = BatchKind::Transactional { producer_id: 2 };
+
+        append(BatchKind::Plain, "plain");
+        append(committed, "committed");
+        append(aborted, "aborted");
+        // Nothing past the open transactions yet
+        let read = storage.read_committed("test", 0, 0).unwrap();
+        assert_eq!(read.messages.len(), 1);
+        assert_eq!(read.next_offset, 1);
+        assert_eq!(storage.last_stable_offset("test", 0), Some(1));
+
+        append(BatchKind::Marker { producer_id: 2, commit: false }, "abort");
+        append(BatchKind::Marker { producer_id: 1, commit: true }, "commit");
+        let read = storage.read_committed("test", 0, 0).unwrap();
+        let payloads: Vec<_> = read.messages.iter().map(|message| message.payload.clone()).collect();
+        assert_eq!(payloads, vec![Bytes::from("plain"), Bytes::from("committed")]);
+        assert_eq!(read.next_offset, 5);
+        assert_eq!(storage.last_stable_offset("test", 0), Some(5));
+        assert_eq!(
+            storage.aborted_transactions("test", 0, 0, 5),
+            vec![AbortedTransaction { producer_id: 2, first_offset: 2, last_offset: 3 }]
+        );
+        assert!(storage.aborted_transactions("test", 0, 4, 5).is_empty());
+    }
+
+    #[test]
+    fn test_high_watermark() {
+        let storage = Storage::new();
+        storage.create_topic("test".to_string());
+        storage.create_partition("test", 0);
+        for payload in ["first", "second", "third"] {
+            storage.append_batch("test", 0, 1, |_| Bytes::from(payload)).unwrap();
+        }
+
+        // Only what is below the high watermark can be read
+        assert!(!storage.set_high_watermark("test", 0, Some(1)));
+        storage.append_batch("test", 0, 1, |_| Bytes::from("fourth")).unwrap();
+        assert_eq!(storage.log_offsets("test", 0), Some((0, 3)));
+        assert_eq!(storage.log_end_offset("test", 0), Some(4));
+        assert_eq!(storage.read("test", 0, 0).unwrap().len(), 3);
+        assert_eq!(storage.read_log("test", 0, 0).unwrap().len(), 4);
+
+        assert!(storage.set_high_watermark("test", 0, Some(10)));
+        assert_eq!(storage.log_offsets("test", 0), Some((0, 4)));
+        assert!(!storage.set_high_watermark("test", 0, Some(2)));
+        assert_eq!(storage.log_offsets("test", 0), Some((0, 4)));
+
+        // Copies keep the leader's offsets, starting over past a gap
+        storage.create_partition("test", 1);
+        assert_eq!(storage.append_replica("test", 1, 0, 2, BatchKind::Plain, Bytes::from("a")), Some(true));
+        assert_eq!(storage.append_replica("test", 1, 0, 2, BatchKind::Plain, Bytes::from("a")), Some(false));
+        assert_eq!(storage.append_replica("test", 1, 5, 1, BatchKind::Plain, Bytes::from("b")), Some(true));
+        assert_eq!(storage.log_offsets("test", 1), Some((5, 6)));
+    }
+
+    #[test]
+    fn test_high_watermark_races_appends() {
+        let storage = Storage::new();
+        storage.create_topic("test".to_string());
+        storage.create_partition("test", 0);
+        storage.set_high_watermark("test", 0, Some(0));
+
+        // Leaders raise the watermark from several tasks while appending
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..5_000 {
+                        storage.append_batch("test", 0, 1, |_| Bytes::from("record")).unwrap();
+                        let end = storage.log_end_offset("test", 0).unwrap();
+                        storage.set_high_watermark("test", 0, Some(end));
+                    }
+                });
+            }
+        });
+        assert_eq!(storage.log_offsets("test", 0), Some((0, 40_000)));
+    }
 }
@@ -1,6 +1,7 @@
 use rafka_broker::Broker;
 use rafka_cli::{Commands, CLI};
 use rafka_consumer::Consumer;
+use rafka_core::message::Header;
 use rafka_producer::Producer;
 use rafka_storage::db::RetentionPolicy;
 use std::time::Duration;
@@ -15,40 +16,108 @@ async fn main() -> Resulty {
         Commands::Consumer { port, partition } => start_consumer(port, partition).await,
         Commands::Broker {
             port,
-            partition,
-            total_partition,
+            broker_id,
+            broker_count,
+            default_partitions,
+            no_auto_create_topics,
             retention_secs,
-        } => start_broker(port, partition, total_partition, retention_secs).await,
+            peers,
+            forward,
+            kafka_port,
+            metrics_port,
+        } => {
+            let settings = BrokerSettings {
+                default_partitions,
+                auto_create_topics: !no_auto_create_topics,
+                retention_secs,
+                peers,
+                forward,
+                kafka_port,
+                metrics_port,
+            };
+            start_broker(port, broker_id, broker_count, settings).await
+        }
         Commands::Producer {
             brokers,
             key,
             message,
             topic,
-        } => start_producer(brokers, message, key, topic).await,
+            headers,
+        } => start_producer(brokers, message, key, topic, headers).await,
     }
 }
 
-async fn start_broker(
-    port: u16,
-    partition: u32,
-    total_partition: u32,
+struct BrokerSettings {
+    default_partitions: Option<u32>,
+    auto_create_topics: bool,
     retention_secs: u64,
-) -> Resulty {
+    peers: Vec<String>,
+    forward: bool,
+    kafka_port: Option<u16>,
+    metrics_port: Option<u16>,
+}
+
+async fn start_broker(port: u16, broker_id: u32, broker_count: u32, settings: BrokerSettings) -> Resulty {
     let retention_policy = RetentionPolicy {
-        max_age: Duration::from_secs(retention_secs),
+        max_age: Duration::from_secs(settings.retention_secs),
         max_bytes: 1024 * 1024 * 1024, // 1GB default
     };
 
     println!(
-        "Starting Rafka broker on 127.0.0.1:{} (partition {}/{})",
-        port, partition, total_partition
+        "Starting Rafka broker on 127.0.0.1:{} (broker {}/{})",
+        port, broker_id, broker_count
     );
 
-    let broker = Broker::new(partition, total_partition);
+    let mut broker = Broker::new(broker_id, broker_count, Some(retention_policy))
+        .with_peers(settings.peers)
+        .with_auto_create_topics(settings.auto_create_topics);
+    if let Some(partitions) = settings.default_partitions {
+        broker = broker.with_default_partitions(partitions);
+    }
+    if settings.forward {
+        broker = broker.with_forwarding();
+    }
+    if let Some(kafka_port) = settings.kafka_port {
+        broker = broker.with_kafka_listener(&format!("127.0.0.1:{}", kafka_port));
+    }
+    if let Some(metrics_port) = settings.metrics_port {
+        broker = broker.with_metrics_listener(&format!("127.0.0.1:{}", metrics_port));
+    }
+
+    let shutdown = broker.shutdown_handle();
+    tokio::spawn(async move {
+        stop_signal().await;
+        println!("Shutting down, letting clients finish");
+        shutdown.shutdown();
+    });
+
     broker.serve(&format!("127.0.0.1:{}", port)).await?;
     Ok(())
 }
 
+// Ctrl-C, or SIGTERM where there is such a thing
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 async fn start_consumer(port: u16, partition: u32) -> Resulty {
     let mut consumer = Consumer::new(&format!("127.0.0.1:{}", port)).await?;
 
@@ -62,7 +131,7 @@ async fn start_consumer(port: u16, partition: u32) -> Resulty {
     let mut rx = consumer.consume("greetings".to_string()).await?;
 
     while let Some(message) = rx.recv().await {
-        println!("Received message: {}", message);
+        println!("Received message: {}", String::from_utf8_lossy(&message));
     }
 
     Ok(())
@@ -73,16 +142,26 @@ async fn start_producer(
     message: String,
     key: String,
     topic: String,
+    headers: Vec<String>,
 ) -> Resulty {
+    let headers = headers
+        .iter()
+        .map(|header| match header.split_once('=') {
+            Some((key, value)) => Ok(Header::new(key, value)),
+            None => Err(format!("Header '{}' is not key=value", header)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
     println!(
-        "Publishing to 'greetings' topic with key '{}': {}",
-        key, message
+        "Publishing to '{}' topic with key '{}': {}",
+        topic, key, message
     );
 
-    let mut producer = Producer::new(&brokers[0]).await?;
+    // Any of the brokers will do, the rest are found through it
+    let mut producer = Producer::bootstrap(&brokers).await?;
 
     producer
-        .publish("greetings".to_string(), message, key)
+        .publish(topic, message, key, headers)
         .await?;
 
     Ok(())
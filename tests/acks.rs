@@ -0,0 +1,48 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_broker::Broker;
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::{Acks, Producer};
+    use tokio::{task, time::sleep};
+
+    use crate::common::DEFAULT_ADDRESS;
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "acked";
+
+        let broker = Broker::new(0, 1, None);
+        task::spawn(async move { broker.serve(DEFAULT_ADDRESS).await.unwrap() });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut admin = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        admin
+            .create_topic(String::from(TOPIC), 1, PartitionStrategy::RoundRobin)
+            .await
+            .unwrap();
+
+        // Unacknowledged sends come back at once, without offsets
+        let mut fire_and_forget = Producer::new(DEFAULT_ADDRESS).await.unwrap().with_acks(Acks::None);
+        let messages = (0..3).map(|i| (String::from("key"), format!("message-{}", i))).collect();
+        let acks = fire_and_forget.publish_batch(String::from(TOPIC), messages).await.unwrap();
+        assert!(acks.iter().all(|ack| ack.offset == -1));
+
+        // Same connection, so the broker gets to it after the unacknowledged ones
+        let mut durable = fire_and_forget
+            .with_acks(Acks::All { timeout_ms: 1_000 })
+            .with_ack_timeout(Duration::from_secs(1));
+        let ack = durable
+            .publish(String::from(TOPIC), String::from("durable"), String::from("key"), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(ack.offset, 3);
+
+        let description = admin.describe_topic(TOPIC).await.unwrap();
+        assert_eq!(description.partitions[0].high_watermark, Some(4));
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::time::Duration;
 
-use rafka_broker::Broker;
+use rafka_broker::{Broker, ShutdownHandle};
 use rafka_storage::db::RetentionPolicy;
 use tokio::task;
 
@@ -13,21 +13,25 @@ pub const DEFAULT_ADDRESS: &str = "127.0.0.1:50051";
 
 const ONE_GB: usize = 1024 * 1024 * 1024;
 
-pub async fn setup_brokers(number_of_brokers: usize, retention_secs: usize) {
+// Shutting the brokers down is up to the test, they run until the test ends otherwise
+pub async fn setup_brokers(number_of_brokers: usize, retention_secs: usize) -> Vec<ShutdownHandle> {
     let retention_policy = RetentionPolicy {
         max_age: Duration::from_secs(retention_secs as u64),
         max_bytes: ONE_GB,
     };
 
+    let mut handles = Vec::new();
     for i in 0..number_of_brokers {
-        let _ = task::spawn(async move {
+        let broker = Broker::new(
+            PARTITION as u32,
+            TOTAL_PARTITIONS as u32,
+            Some(retention_policy),
+        );
+        handles.push(broker.shutdown_handle());
+        task::spawn(async move {
             let address = &format!("127.0.0.1:{}", PORT + i);
-            let broker = Broker::new(
-                PARTITION as u32,
-                TOTAL_PARTITIONS as u32,
-                Some(retention_policy),
-            );
             broker.serve(address).await.unwrap();
         });
     }
+    handles
 }
@@ -0,0 +1,88 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use rafka_consumer::{AssignmentStrategy, Consumer};
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::Producer;
+    use tokio::time::{sleep, timeout};
+
+    use crate::common::{setup_brokers, DEFAULT_ADDRESS};
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "group-topic";
+        const GROUP: &str = "group";
+
+        tokio::spawn(async { setup_brokers(1, 60).await });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        producer
+            .create_topic(String::from(TOPIC), 4, PartitionStrategy::RoundRobin)
+            .await
+            .unwrap();
+
+        let mut consumers = Vec::new();
+        for _ in 0..2 {
+            let mut consumer = Consumer::new(DEFAULT_ADDRESS)
+                .await
+                .unwrap()
+                .with_session_timeout(Duration::from_millis(600));
+            consumer
+                .join_group(String::from(GROUP), vec![String::from(TOPIC)], vec![AssignmentStrategy::Range])
+                .await
+                .unwrap();
+            consumers.push(consumer);
+        }
+
+        // Wait for the first consumer's heartbeat to pick up the rebalance
+        sleep(Duration::from_millis(400)).await;
+        let mut assigned: Vec<u32> = consumers
+            .iter()
+            .flat_map(|consumer| consumer.assignment())
+            .flat_map(|assigned| assigned.partitions)
+            .collect();
+        assigned.sort();
+        assert_eq!(assigned, vec![0, 1, 2, 3]);
+
+        let mut streams = Vec::new();
+        for consumer in &mut consumers {
+            streams.push(consumer.consume_group().await.unwrap());
+        }
+
+        for i in 0..8 {
+            producer
+                .publish(String::from(TOPIC), format!("job-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+        }
+
+        // Each job goes to exactly one member, two partitions' worth each
+        let mut seen = HashSet::new();
+        for stream in &mut streams {
+            for _ in 0..4 {
+                let message = timeout(Duration::from_secs(5), stream.recv()).await.unwrap().unwrap();
+                assert!(seen.insert(message.payload));
+            }
+        }
+        assert_eq!(seen.len(), 8);
+
+        // Once the second member leaves, the first takes over all partitions
+        consumers[1].leave_group().await.unwrap();
+        for i in 8..12 {
+            producer
+                .publish(String::from(TOPIC), format!("job-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+        }
+        for _ in 8..12 {
+            let message = timeout(Duration::from_secs(5), streams[0].recv()).await.unwrap().unwrap();
+            assert!(seen.insert(message.payload));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_consumer::Consumer;
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::Producer;
+    use tokio::{task, time::sleep};
+
+    use crate::common::{setup_brokers, DEFAULT_ADDRESS};
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "pulled";
+
+        task::spawn(async { setup_brokers(1, 60).await });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        producer
+            .create_topic(String::from(TOPIC), 1, PartitionStrategy::default())
+            .await
+            .unwrap();
+        for i in 0..5 {
+            producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+        }
+
+        // A tiny max_bytes still returns a batch at a time, so the consumer
+        // walks the partition at its own pace
+        let consumer = Consumer::new(DEFAULT_ADDRESS).await.unwrap();
+        let mut offset = 0;
+        let mut payloads = Vec::new();
+        loop {
+            let fetched = consumer.fetch(TOPIC, 0, offset, 1, Duration::from_millis(100)).await.unwrap();
+            assert_eq!(fetched.high_watermark, 5);
+            if fetched.messages.is_empty() {
+                break;
+            }
+            assert_eq!(fetched.messages.len(), 1);
+            payloads.extend(fetched.messages.into_iter().map(|message| message.payload));
+            offset = fetched.next_offset;
+        }
+        let expected: Vec<Vec<u8>> = (0..5).map(|i| format!("message-{}", i).into_bytes()).collect();
+        assert_eq!(payloads, expected);
+        assert_eq!(offset, 5);
+    }
+}
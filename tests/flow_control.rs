@@ -0,0 +1,45 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_consumer::Consumer;
+    use rafka_producer::Producer;
+    use tokio::{task, time::{sleep, timeout}};
+
+    use crate::common::{setup_brokers, DEFAULT_ADDRESS};
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "paced";
+        const MESSAGES: usize = 50;
+
+        task::spawn(async { setup_brokers(1, 60).await });
+
+        sleep(Duration::from_millis(50)).await;
+
+        // Only a few messages in flight at a time, the consumer hands credit
+        // back as messages are received
+        let mut consumer = Consumer::new(DEFAULT_ADDRESS).await.unwrap().with_credit(3, 64 * 1024);
+        consumer.subscribe(String::from(TOPIC)).await.unwrap();
+        let mut rx = consumer.consume_messages(String::from(TOPIC)).await.unwrap();
+
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        for i in 0..MESSAGES {
+            producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+        }
+
+        // A slow reader still gets everything, in order
+        for i in 0..MESSAGES {
+            let message = timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+            assert_eq!(message.payload, format!("message-{}", i).into_bytes());
+            if i % 10 == 0 {
+                sleep(Duration::from_millis(20)).await;
+            }
+        }
+    }
+}
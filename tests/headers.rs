@@ -0,0 +1,60 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_consumer::Consumer;
+    use rafka_core::message::Header;
+    use rafka_producer::Producer;
+    use tokio::{task, time::sleep};
+
+    use crate::common::{setup_brokers, DEFAULT_ADDRESS};
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "traced";
+        const KEY: &str = "default-key";
+
+        task::spawn(async { setup_brokers(1, 1).await });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let consumer_task = task::spawn(async {
+            let topic = String::from(TOPIC);
+
+            let mut consumer = Consumer::new(DEFAULT_ADDRESS).await.unwrap();
+            consumer.subscribe(topic.clone()).await.unwrap();
+            let mut rx = consumer.consume_messages(topic).await.unwrap();
+
+            let message = rx.recv().await.unwrap();
+            assert_eq!(message.payload, b"{\"amount\":3}");
+            assert_eq!(message.header("trace-id"), Some(&b"abc123"[..]));
+            // Order and repeated keys survive the trip
+            let keys: Vec<_> = message.headers.iter().map(|header| header.key.as_str()).collect();
+            assert_eq!(keys, ["trace-id", "content-type", "hop", "hop"]);
+            assert_eq!(message.headers[3].value, b"2");
+        });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        let headers = vec![
+            Header::new("trace-id", "abc123"),
+            Header::new("content-type", "application/json"),
+            Header::new("hop", "1"),
+            Header::new("hop", "2"),
+        ];
+        producer
+            .publish(
+                String::from(TOPIC),
+                String::from("{\"amount\":3}"),
+                String::from(KEY),
+                headers,
+            )
+            .await
+            .unwrap();
+
+        consumer_task.await.unwrap();
+    }
+}
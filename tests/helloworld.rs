@@ -18,7 +18,7 @@ mod module {
         const KEY: &str = "default-key";
 
         // This threads doesnt end, so whe don't wait for it
-        let _ = task::spawn(async { setup_brokers(1, 1).await });
+        task::spawn(async { setup_brokers(1, 1).await });
 
         // Time for broker start
         sleep(Duration::from_millis(50)).await;
@@ -30,9 +30,8 @@ mod module {
             consumer.subscribe(topic.clone()).await.unwrap();
             let mut rx = consumer.consume(topic).await.unwrap();
 
-            while let Some(message) = rx.recv().await {
-                assert_eq!(message, MESSAGE);
-                return;
+            if let Some(message) = rx.recv().await {
+                assert_eq!(message, MESSAGE.as_bytes());
             }
         });
 
@@ -47,6 +46,7 @@ mod module {
                     String::from(TOPIC),
                     String::from(MESSAGE),
                     String::from(KEY),
+                    Vec::new(),
                 )
                 .await
                 .unwrap();
@@ -0,0 +1,146 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rafka_broker::Broker;
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::Producer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::{task, time::sleep};
+
+    use crate::common::DEFAULT_ADDRESS;
+
+    const SECOND_ADDRESS: &str = "127.0.0.1:50052";
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "numbered";
+
+        let peers = vec![DEFAULT_ADDRESS.to_string(), SECOND_ADDRESS.to_string()];
+        for (broker_id, address) in [(0, DEFAULT_ADDRESS), (1, SECOND_ADDRESS)] {
+            let broker = Broker::new(broker_id, 2, None).with_peers(peers.clone());
+            task::spawn(async move { broker.serve(address).await.unwrap() });
+        }
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut admins = Vec::new();
+        for address in [DEFAULT_ADDRESS, SECOND_ADDRESS] {
+            let mut admin = Producer::new(address).await.unwrap();
+            admin
+                .create_topic(String::from(TOPIC), 2, PartitionStrategy::RoundRobin)
+                .await
+                .unwrap();
+            admins.push(admin);
+        }
+
+        // The producer id comes from the first broker, the second one takes
+        // numbered batches for its partition all the same
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        producer.enable_idempotence().await.unwrap();
+
+        let mut offsets = Vec::new();
+        for i in 0..4 {
+            let ack = producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+            offsets.push((ack.partition, ack.offset));
+        }
+        offsets.sort();
+        assert_eq!(offsets, vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+
+        // Topics the producer has no metadata for can't be numbered
+        let unknown = producer
+            .publish(String::from("unknown"), String::from("message"), String::from("key"), Vec::new())
+            .await;
+        assert!(unknown.is_err());
+
+        for (partition, admin) in admins.iter().enumerate() {
+            let description = admin.describe_topic(TOPIC).await.unwrap();
+            assert_eq!(description.partitions[partition].high_watermark, Some(2));
+        }
+    }
+
+    // Forwards everything to `broker`, except the first reply after
+    // `drop_reply` is set, which never reaches the client
+    async fn lossy_proxy(address: &'static str, broker: &'static str, drop_reply: Arc<AtomicBool>) {
+        let listener = TcpListener::bind(address).await.unwrap();
+        task::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                let upstream = TcpStream::connect(broker).await.unwrap();
+                let (mut client_reader, mut client_writer) = client.into_split();
+                let (mut upstream_reader, mut upstream_writer) = upstream.into_split();
+                task::spawn(async move { tokio::io::copy(&mut client_reader, &mut upstream_writer).await });
+                let drop_reply = drop_reply.clone();
+                task::spawn(async move {
+                    loop {
+                        let mut header = [0u8; 4];
+                        if upstream_reader.read_exact(&mut header).await.is_err() {
+                            break;
+                        }
+                        let mut body = vec![0u8; u32::from_be_bytes(header) as usize];
+                        if upstream_reader.read_exact(&mut body).await.is_err() {
+                            break;
+                        }
+                        if drop_reply.swap(false, Ordering::SeqCst) {
+                            continue;
+                        }
+                        let written = client_writer.write_all(&header).await.and(client_writer.write_all(&body).await);
+                        if written.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_lost_ack_is_not_stored_twice() {
+        const TOPIC: &str = "retried";
+        const BROKER_ADDRESS: &str = "127.0.0.1:50053";
+        const PROXY_ADDRESS: &str = "127.0.0.1:50054";
+
+        let broker = Broker::new(0, 1, None).with_peers(vec![BROKER_ADDRESS.to_string()]);
+        task::spawn(async move { broker.serve(BROKER_ADDRESS).await.unwrap() });
+        let drop_reply = Arc::new(AtomicBool::new(false));
+        lossy_proxy(PROXY_ADDRESS, BROKER_ADDRESS, drop_reply.clone()).await;
+        sleep(Duration::from_millis(50)).await;
+
+        let mut admin = Producer::new(BROKER_ADDRESS).await.unwrap();
+        admin
+            .create_topic(String::from(TOPIC), 1, PartitionStrategy::RoundRobin)
+            .await
+            .unwrap();
+
+        let mut producer = Producer::new(PROXY_ADDRESS)
+            .await
+            .unwrap()
+            .with_ack_timeout(Duration::from_millis(200));
+        producer.enable_idempotence().await.unwrap();
+
+        let mut offsets = Vec::new();
+        for i in 0..3 {
+            // The broker stores the second message but its ack is lost, the
+            // resend is recognised by its sequence
+            drop_reply.store(i == 1, Ordering::SeqCst);
+            let ack = producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+            offsets.push(ack.offset);
+        }
+        assert_eq!(offsets, vec![0, 1, 2]);
+        assert!(!drop_reply.load(Ordering::SeqCst));
+
+        let description = admin.describe_topic(TOPIC).await.unwrap();
+        assert_eq!(description.partitions[0].high_watermark, Some(3));
+    }
+}
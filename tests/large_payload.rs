@@ -0,0 +1,55 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_consumer::Consumer;
+    use rafka_producer::Producer;
+    use tokio::{task, time::sleep};
+
+    use crate::common::{setup_brokers, DEFAULT_ADDRESS};
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "large-payloads";
+        const KEY: &str = "default-key";
+        // Well past the old 64KB read buffer
+        const PAYLOAD_SIZE: usize = 256 * 1024;
+
+        task::spawn(async { setup_brokers(1, 1).await });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let consumer_task = task::spawn(async {
+            let topic = String::from(TOPIC);
+
+            let mut consumer = Consumer::new(DEFAULT_ADDRESS).await.unwrap();
+            consumer.subscribe(topic.clone()).await.unwrap();
+            let mut rx = consumer.consume(topic).await.unwrap();
+
+            for expected in ['a', 'b', 'c'] {
+                let message = rx.recv().await.unwrap();
+                assert_eq!(message.len(), PAYLOAD_SIZE);
+                assert!(message.iter().all(|b| *b == expected as u8));
+            }
+        });
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        let messages = ['a', 'b', 'c']
+            .iter()
+            .map(|c| (String::from(KEY), c.to_string().repeat(PAYLOAD_SIZE)))
+            .collect();
+
+        // Sent as one record batch, acked per message
+        let responses = producer
+            .publish_batch(String::from(TOPIC), messages)
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 3);
+
+        consumer_task.await.unwrap();
+    }
+}
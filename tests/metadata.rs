@@ -0,0 +1,57 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_broker::Broker;
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::Producer;
+    use tokio::{task, time::sleep};
+
+    use crate::common::DEFAULT_ADDRESS;
+
+    const SECOND_ADDRESS: &str = "127.0.0.1:50052";
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "routed";
+
+        // Two brokers that know where each other are, neither forwards
+        let peers = vec![DEFAULT_ADDRESS.to_string(), SECOND_ADDRESS.to_string()];
+        for (broker_id, address) in [(0, DEFAULT_ADDRESS), (1, SECOND_ADDRESS)] {
+            let broker = Broker::new(broker_id, 2, None).with_peers(peers.clone());
+            task::spawn(async move { broker.serve(address).await.unwrap() });
+        }
+
+        sleep(Duration::from_millis(50)).await;
+
+        // Both brokers need the topic, each only creates it for itself
+        for address in [SECOND_ADDRESS, DEFAULT_ADDRESS] {
+            let mut admin = Producer::new(address).await.unwrap();
+            admin
+                .create_topic(String::from(TOPIC), 4, PartitionStrategy::RoundRobin)
+                .await
+                .unwrap();
+        }
+
+        // The first address is down, the second one is enough to find both brokers
+        let brokers = vec![String::from("127.0.0.1:50050"), SECOND_ADDRESS.to_string()];
+        let mut producer = Producer::bootstrap(&brokers).await.unwrap();
+        let metadata = producer.refresh_metadata().await.unwrap();
+        assert_eq!(metadata.broker_id, 1);
+        assert_eq!(metadata.brokers.len(), 2);
+
+        // Every partition is reached on the broker hosting it
+        let mut partitions = Vec::new();
+        for i in 0..4 {
+            let ack = producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+            partitions.push(ack.partition);
+        }
+        partitions.sort();
+        assert_eq!(partitions, vec![0, 1, 2, 3]);
+    }
+}
@@ -23,7 +23,7 @@ mod module {
         ];
         const KEYS: [&str; 6] = ["key-0", "key-1", "key-2", "key-3", "key-4", "key-5"];
 
-        let _ = task::spawn(async { setup_brokers(1, 1).await });
+        task::spawn(async { setup_brokers(1, 1).await });
 
         sleep(Duration::from_millis(50)).await;
 
@@ -38,7 +38,7 @@ mod module {
 
             let mut index = 0;
             while let Some(message) = rx.recv().await {
-                assert_eq!(message, EXPECTED_MESSAGES[index]);
+                assert_eq!(message, EXPECTED_MESSAGES[index].as_bytes());
 
                 index += 1;
                 if index == 5 {
@@ -59,6 +59,7 @@ mod module {
                         String::from(TOPIC),
                         String::from(EXPECTED_MESSAGES[i]),
                         String::from(KEYS[i]),
+                        Vec::new(),
                     )
                     .await
                     .unwrap();
@@ -77,6 +78,7 @@ mod module {
                         String::from(TOPIC),
                         String::from(EXPECTED_MESSAGES[i]),
                         String::from(KEYS[i]),
+                        Vec::new(),
                     )
                     .await
                     .unwrap();
@@ -28,7 +28,7 @@ mod module {
             "Message-9",
         ];
 
-        let _ = task::spawn(async { setup_brokers(BROKER_COUNT, 1).await });
+        task::spawn(async { setup_brokers(BROKER_COUNT, 1).await });
 
         sleep(Duration::from_millis(50)).await;
 
@@ -46,7 +46,7 @@ mod module {
                 let mut rx = consumer.consume(topic).await.unwrap();
 
                 while let Some(message) = rx.recv().await {
-                    assert_eq!(message, MESSAGES[index]);
+                    assert_eq!(message, MESSAGES[index].as_bytes());
 
                     index += BROKER_COUNT;
 
@@ -61,7 +61,7 @@ mod module {
 
         sleep(Duration::from_millis(50)).await;
 
-        for i in 0..MESSAGE_COUNT {
+        for (i, message) in MESSAGES.iter().enumerate().take(MESSAGE_COUNT) {
             let producer_task = task::spawn(async move {
                 let address = &format!("127.0.0.1:{}", (PORT + (i % BROKER_COUNT)));
 
@@ -70,8 +70,9 @@ mod module {
                 producer
                     .publish(
                         String::from(TOPIC),
-                        String::from(MESSAGES[i]),
+                        String::from(*message),
                         String::from("default-key"),
+                        Vec::new(),
                     )
                     .await
                     .unwrap();
@@ -0,0 +1,63 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rafka_core::partitioner::{PartitionStrategy, StickyPartitioner};
+    use rafka_producer::Producer;
+    use tokio::{task, time::sleep};
+
+    use crate::common::{setup_brokers, DEFAULT_ADDRESS};
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "spread";
+
+        task::spawn(async { setup_brokers(1, 1).await });
+
+        sleep(Duration::from_millis(50)).await;
+
+        // The broker partitions with the topic's partitioner
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        producer
+            .create_topic(String::from(TOPIC), 3, PartitionStrategy::RoundRobin)
+            .await
+            .unwrap();
+
+        let mut partitions = Vec::new();
+        for i in 0..3 {
+            let ack = producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+            partitions.push(ack.partition);
+        }
+        assert_eq!(partitions, vec![0, 1, 2]);
+
+        // Unless the producer picks the partition itself
+        let mut producer = Producer::new(DEFAULT_ADDRESS)
+            .await
+            .unwrap()
+            .with_partition_count(TOPIC, 3)
+            .with_topic_partitioner(TOPIC, Arc::new(StickyPartitioner::new(2)));
+
+        let messages = (0..4).map(|i| (format!("key-{}", i), format!("message-{}", i))).collect();
+        let acks = producer.publish_batch(String::from(TOPIC), messages).await.unwrap();
+        let partitions: Vec<_> = acks.iter().map(|ack| (ack.partition, ack.offset)).collect();
+        assert_eq!(partitions, vec![(0, 1), (0, 2), (1, 1), (1, 2)]);
+
+        // A count of 0 can't be partitioned over, the broker picks instead
+        let mut producer = Producer::new(DEFAULT_ADDRESS)
+            .await
+            .unwrap()
+            .with_partition_count(TOPIC, 0)
+            .with_topic_partitioner(TOPIC, Arc::new(StickyPartitioner::new(2)));
+        let ack = producer
+            .publish(String::from(TOPIC), String::from("message"), String::from("key"), Vec::new())
+            .await
+            .unwrap();
+        assert!(ack.partition < 3);
+    }
+}
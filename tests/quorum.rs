@@ -0,0 +1,92 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_broker::{Broker, ShutdownHandle};
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::Producer;
+    use tokio::{task, time::sleep};
+
+    use crate::common::DEFAULT_ADDRESS;
+
+    const ADDRESSES: [&str; 3] = [DEFAULT_ADDRESS, "127.0.0.1:50052", "127.0.0.1:50053"];
+
+    fn start(broker_id: u32) -> ShutdownHandle {
+        let peers = ADDRESSES.iter().map(|address| address.to_string()).collect();
+        let broker = Broker::new(broker_id, 3, None)
+            .with_peers(peers)
+            .with_controllers(vec![0, 1])
+            .with_election_timeout(Duration::from_millis(150))
+            .with_metadata_snapshot_entries(4);
+        let shutdown = broker.shutdown_handle();
+        task::spawn(async move { broker.serve(ADDRESSES[broker_id as usize]).await.unwrap() });
+        shutdown
+    }
+
+    async fn await_leader(admin: &Producer, other_than: Option<u32>) -> u32 {
+        for _ in 0..100 {
+            let description = admin.describe_quorum().await.unwrap();
+            if let Some(leader) = description.leader_id.filter(|leader| Some(*leader) != other_than) {
+                return leader;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no controller took the lead");
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let first = start(0);
+        start(1);
+        sleep(Duration::from_millis(50)).await;
+
+        let mut admin = Producer::new(ADDRESSES[1]).await.unwrap();
+        await_leader(&admin, None).await;
+        for i in 0..5 {
+            admin
+                .create_topic(format!("topic-{}", i), 3, PartitionStrategy::RoundRobin)
+                .await
+                .unwrap();
+        }
+
+        // By now the log has been compacted, so the late broker starts from a snapshot
+        start(2);
+        sleep(Duration::from_millis(50)).await;
+        let late = Producer::new(ADDRESSES[2]).await.unwrap();
+        let mut topics = Vec::new();
+        for _ in 0..100 {
+            topics = late.list_topics().await.unwrap();
+            if topics.len() == 5 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(topics.len(), 5);
+        let description = late.describe_quorum().await.unwrap();
+        assert!(description.snapshot_index > 0, "{:?}", description);
+        assert_eq!(description.controllers, vec![0, 1]);
+
+        assert_eq!(admin.add_controller(2).await.unwrap(), vec![0, 1, 2]);
+        assert_eq!(admin.remove_controller(0).await.unwrap(), vec![1, 2]);
+        first.shutdown();
+
+        // Broker 0 is gone, the remaining two controllers still make progress
+        let leader = await_leader(&late, Some(0)).await;
+        assert!(leader == 1 || leader == 2);
+        admin
+            .create_topic(String::from("after"), 3, PartitionStrategy::RoundRobin)
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            topics = late.list_topics().await.unwrap();
+            if topics.len() == 6 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert!(topics.contains(&String::from("after")));
+        assert_eq!(late.describe_quorum().await.unwrap().controllers, vec![1, 2]);
+    }
+}
@@ -0,0 +1,90 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_broker::Broker;
+    use rafka_consumer::Consumer;
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::{Acks, Producer};
+    use tokio::{task, time::sleep};
+
+    use crate::common::DEFAULT_ADDRESS;
+
+    const SECOND_ADDRESS: &str = "127.0.0.1:50052";
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "replicated";
+
+        let peers = vec![DEFAULT_ADDRESS.to_string(), SECOND_ADDRESS.to_string()];
+        let mut shutdown_handles = Vec::new();
+        for (broker_id, address) in [(0, DEFAULT_ADDRESS), (1, SECOND_ADDRESS)] {
+            let broker = Broker::new(broker_id, 2, None)
+                .with_peers(peers.clone())
+                .with_replication_factor(2)
+                .with_replica_lag_time(Duration::from_millis(500));
+            shutdown_handles.push(broker.shutdown_handle());
+            task::spawn(async move { broker.serve(address).await.unwrap() });
+        }
+
+        sleep(Duration::from_millis(50)).await;
+
+        for address in [DEFAULT_ADDRESS, SECOND_ADDRESS] {
+            let mut admin = Producer::new(address).await.unwrap();
+            admin
+                .create_topic(String::from(TOPIC), 2, PartitionStrategy::RoundRobin)
+                .await
+                .unwrap();
+        }
+
+        // Each broker leads one partition and follows the other
+        let mut producer = Producer::new(DEFAULT_ADDRESS)
+            .await
+            .unwrap()
+            .with_acks(Acks::All { timeout_ms: 2_000 });
+        let metadata = producer.refresh_metadata().await.unwrap();
+        let replicas: Vec<Vec<u32>> = metadata.topics[0]
+            .partitions
+            .iter()
+            .map(|partition| partition.replicas.clone())
+            .collect();
+        assert_eq!(replicas, vec![vec![0, 1], vec![1, 0]]);
+
+        // Acked once both brokers have them
+        let mut offsets = Vec::new();
+        for i in 0..4 {
+            let ack = producer
+                .publish(String::from(TOPIC), format!("message-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+            offsets.push((ack.partition, ack.offset));
+        }
+        offsets.sort();
+        assert_eq!(offsets, vec![(0, 0), (0, 1), (1, 0), (1, 1)]);
+
+        for (partition, address) in [(0, DEFAULT_ADDRESS), (1, SECOND_ADDRESS)] {
+            let consumer = Consumer::new(address).await.unwrap();
+            let fetched = consumer
+                .fetch(TOPIC, partition, 0, 1024, Duration::from_millis(100))
+                .await
+                .unwrap();
+            assert_eq!(fetched.high_watermark, 2);
+            assert_eq!(fetched.messages.len(), 2);
+        }
+
+        // A follower that goes away drops out of the in-sync replicas
+        shutdown_handles[1].shutdown();
+        let mut in_sync = Vec::new();
+        for _ in 0..40 {
+            let metadata = producer.refresh_metadata().await.unwrap();
+            in_sync = metadata.topics[0].partitions[0].in_sync_replicas.clone();
+            if in_sync == vec![0] {
+                break;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(in_sync, vec![0]);
+    }
+}
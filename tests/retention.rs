@@ -14,7 +14,7 @@ mod module {
         const RETENTION_SECS: usize = 10;
         const KEY: &str = "default-key";
 
-        let _ = task::spawn(async { setup_brokers(1, RETENTION_SECS).await });
+        task::spawn(async { setup_brokers(1, RETENTION_SECS).await });
 
         let mut threads = vec![];
 
@@ -28,9 +28,8 @@ mod module {
             consumer.subscribe(topic.clone()).await.unwrap();
             let mut rx = consumer.consume(topic).await.unwrap();
 
-            while let Some(message) = rx.recv().await {
-                assert_eq!(message, "message");
-                return;
+            if let Some(message) = rx.recv().await {
+                assert_eq!(message, b"message");
             }
         });
 
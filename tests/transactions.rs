@@ -0,0 +1,90 @@
+mod common;
+
+#[cfg(test)]
+mod module {
+    use std::time::Duration;
+
+    use rafka_broker::Broker;
+    use rafka_consumer::{Consumer, IsolationLevel};
+    use rafka_core::partitioner::PartitionStrategy;
+    use rafka_producer::{Producer, TxnOffset};
+    use tokio::{task, time::sleep};
+
+    use crate::common::DEFAULT_ADDRESS;
+
+    const SECOND_ADDRESS: &str = "127.0.0.1:50052";
+
+    #[tokio::test]
+    async fn test() {
+        const TOPIC: &str = "transfers";
+
+        let peers = vec![DEFAULT_ADDRESS.to_string(), SECOND_ADDRESS.to_string()];
+        for (broker_id, address) in [(0, DEFAULT_ADDRESS), (1, SECOND_ADDRESS)] {
+            let broker = Broker::new(broker_id, 2, None).with_peers(peers.clone());
+            task::spawn(async move { broker.serve(address).await.unwrap() });
+        }
+
+        sleep(Duration::from_millis(50)).await;
+
+        let mut admins = Vec::new();
+        for address in [DEFAULT_ADDRESS, SECOND_ADDRESS] {
+            let mut admin = Producer::new(address).await.unwrap();
+            admin
+                .create_topic(String::from(TOPIC), 2, PartitionStrategy::RoundRobin)
+                .await
+                .unwrap();
+            admins.push(admin);
+        }
+
+        // The first broker runs the transaction, the second hosts partition 1
+        let mut producer = Producer::new(DEFAULT_ADDRESS).await.unwrap();
+        producer.enable_idempotence().await.unwrap();
+        let consumer = Consumer::new(SECOND_ADDRESS)
+            .await
+            .unwrap()
+            .with_isolation_level(IsolationLevel::ReadCommitted);
+        let fetch = |offset: i64| consumer.fetch(TOPIC, 1, offset, 1024 * 1024, Duration::ZERO);
+
+        producer.begin_transaction().await.unwrap();
+        for i in 0..2 {
+            producer
+                .publish(String::from(TOPIC), format!("debit-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+        }
+        producer
+            .send_offsets_to_transaction(vec![TxnOffset {
+                consumer_id: String::from("ledger"),
+                topic: String::from(TOPIC),
+                partition: 1,
+                offset: 41,
+            }])
+            .await
+            .unwrap();
+        assert!(fetch(0).await.unwrap().messages.is_empty());
+
+        producer.commit_transaction().await.unwrap();
+        let fetched = fetch(0).await.unwrap();
+        assert_eq!(fetched.messages.len(), 1);
+        assert_eq!(fetched.messages[0].payload, b"debit-1");
+        // Past the commit marker
+        assert_eq!(fetched.next_offset, 2);
+
+        let metrics = admins[1].metrics().await.unwrap();
+        let lag = metrics.consumer_lag.iter().find(|lag| lag.consumer_id == "ledger").unwrap();
+        assert_eq!((lag.partition, lag.committed_offset), (1, 41));
+
+        // Aborted records never show up, the next fetch just starts past them
+        producer.begin_transaction().await.unwrap();
+        for i in 0..2 {
+            producer
+                .publish(String::from(TOPIC), format!("refund-{}", i), String::from("key"), Vec::new())
+                .await
+                .unwrap();
+        }
+        producer.abort_transaction().await.unwrap();
+        let fetched = fetch(2).await.unwrap();
+        assert!(fetched.messages.is_empty());
+        assert_eq!(fetched.next_offset, 4);
+    }
+}